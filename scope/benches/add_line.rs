@@ -0,0 +1,35 @@
+// Copyright 2018 The Synthesizer IO Authors.
+// 
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+// 
+//     https://www.apache.org/licenses/LICENSE-2.0
+// 
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Benchmarks of line drawing.
+
+#![feature(test)]
+
+extern crate test;
+extern crate synthesize_scope;
+
+use test::Bencher;
+use synthesize_scope::Scope;
+
+#[bench]
+fn line_45_degrees(b: &mut Bencher) {
+    let mut scope = Scope::new(640, 480);
+    b.iter(|| scope.add_line(100.0, 20.0, 500.0, 420.0, 1.0, 0.01))
+}
+
+#[bench]
+fn line_45_degrees_full_rect(b: &mut Bencher) {
+    let mut scope = Scope::new(640, 480);
+    b.iter(|| scope.add_line_full_rect(100.0, 20.0, 500.0, 420.0, 1.0, 0.01))
+}
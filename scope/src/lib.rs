@@ -22,16 +22,24 @@ use std::arch::x86::*;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
 
+#[cfg(all(target_arch = "aarch64", feature = "neon"))]
+use std::arch::aarch64::*;
+
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 use fearless_simd::{AvxF32, SimdF32};
 
 /// The box beyond which the gaussian can be clipped, as a multiple of radius.
 const CLIP_FACTOR: f32 = 2.5;
 
+/// Entries per unit of sqrt(glow) in the color lookup table.
+const LUT_SCALE: f32 = 256.0;
+
 pub struct Scope {
     width: usize,
     height: usize,
-    glow: Vec<f32>,
+
+    // one per channel, drawn in order
+    traces: Vec<Trace>,
 
     // time constant for fade, in _samples_
     tc: f32,
@@ -39,37 +47,423 @@ pub struct Scope {
     // fraction of scope width per sample
     sweep: f32,
 
-    // current horiz position, as fraction of total width
-    horiz: f32,
-
     // gain, where 1.0 is top to bottom of height
     gain: f32,
 
+    // minimum number of samples between triggers
+    holdoff: usize,
+
+    trigger_level: f32,
+    trigger_rising: bool,
+    trigger_mode: TriggerMode,
+
+    // spacing of the grid lines and of the ticks on the center cross, in
+    // pixels
+    grid_spacing: f32,
+    tick_spacing: f32,
+}
+
+// The glow and sweep of one channel, which triggers on its own signal.
+struct Trace {
+    width: usize,
+    height: usize,
+    glow: Vec<f32>,
+
+    // current horiz position, as fraction of total width
+    horiz: f32,
+
     xylast: Option<(f32, f32)>,
 
+    // samples elapsed since the last trigger
+    since_trigger: usize,
+
+    // samples spent waiting for the current trigger
+    waited: usize,
+
     state: ScopeState,
+
+    // offset and scale of each color channel, for the phosphor
+    tint: Tint,
+
+    // colors indexed by sqrt(glow), for the scalar fallback of `as_rgba`,
+    // up to where all the channels are saturated
+    lut: Vec<[u8; 3]>,
+}
+
+/// The color of the trace, after the phosphors of classic CRTs.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Phosphor {
+    /// Green, with a white-hot core, like a P31 phosphor.
+    Green,
+    /// Orange, like a P3.
+    Amber,
+    White,
+    Blue,
+}
+
+// For each of red, green and blue, the channel is sqrt(glow + offset) * scale,
+// as (offset, scale).
+type Tint = [(f32, f32); 3];
+
+impl Phosphor {
+    // The default for each channel, so that traces are told apart.
+    fn for_channel(ch: usize) -> Phosphor {
+        [Phosphor::Green, Phosphor::Amber, Phosphor::Blue, Phosphor::White][ch % 4]
+    }
+
+    fn tint(self) -> Tint {
+        match self {
+            Phosphor::Green => [(0.001, 64.0), (0.05, 255.0), (0.13, 224.0)],
+            Phosphor::Amber => [(0.05, 255.0), (0.1, 160.0), (0.001, 48.0)],
+            Phosphor::White => [(0.05, 240.0), (0.05, 248.0), (0.05, 255.0)],
+            Phosphor::Blue => [(0.001, 64.0), (0.1, 176.0), (0.05, 255.0)],
+        }
+    }
+}
+
+/// When the sweep starts.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TriggerMode {
+    /// On a trigger, or if none comes within the time of one sweep, anyway,
+    /// so that there's always a trace.
+    Auto,
+    /// Only on a trigger.
+    Normal,
+    /// On the next trigger, then the display freezes after one sweep until
+    /// `arm` is called.
+    Single,
 }
 
 enum ScopeState {
     WaitingForTrigger(f32),
     Scanning,
+    // driven by `provide_xy`, with no sweep
+    Xy,
+    // after a single sweep
+    Stopped,
 }
 
 impl Scope {
     // Create a new Scope instance of the given size.
     pub fn new(width: usize, height: usize) -> Scope {
-        let glow = vec![0.0; width * height];
+        let traces = vec![Trace::new(width, height, Phosphor::Green)];
         let tc = 1_500.0;
         let sweep = 0.002;
-        let horiz = 0.0;
         let gain = 1.0;
+        let holdoff = 0;
+        let trigger_level = 0.0;
+        let trigger_rising = true;
+        let trigger_mode = TriggerMode::Normal;
+        let grid_spacing = 60.0;
+        let tick_spacing = 12.0;
+        Scope { width, height, traces, tc, sweep, gain, holdoff, trigger_level, trigger_rising,
+            trigger_mode, grid_spacing, tick_spacing }
+    }
+
+    /// Set the color of the first trace. The default is `Phosphor::Green`.
+    pub fn set_phosphor(&mut self, phosphor: Phosphor) {
+        self.set_phosphor_ch(0, phosphor);
+    }
+
+    /// Set the color of the trace of channel `ch`. The defaults after the
+    /// first are amber, blue and white, repeating.
+    pub fn set_phosphor_ch(&mut self, ch: usize, phosphor: Phosphor) {
+        self.add_traces(ch + 1);
+        let trace = &mut self.traces[ch];
+        trace.tint = phosphor.tint();
+        trace.lut = color_lut(&trace.tint);
+    }
+
+    // Make sure there are at least `n` traces.
+    fn add_traces(&mut self, n: usize) {
+        while self.traces.len() < n {
+            let phosphor = Phosphor::for_channel(self.traces.len());
+            self.traces.push(Trace::new(self.width, self.height, phosphor));
+        }
+    }
+
+    /// Set the trigger: the sweep starts when the signal crosses `level`,
+    /// going up if `rising`, otherwise going down. The default is rising
+    /// through 0.
+    pub fn set_trigger(&mut self, level: f32, rising: bool) {
+        self.trigger_level = level;
+        self.trigger_rising = rising;
+    }
+
+    /// Set the trigger mode. The default is `TriggerMode::Normal`.
+    pub fn set_trigger_mode(&mut self, mode: TriggerMode) {
+        self.trigger_mode = mode;
+    }
+
+    /// Wait for a trigger again after a single sweep.
+    pub fn arm(&mut self) {
+        for trace in &mut self.traces {
+            if let ScopeState::Stopped = trace.state {
+                // NaN, so a trigger needs a crossing rather than the first sample.
+                trace.state = ScopeState::WaitingForTrigger(f32::NAN);
+            }
+        }
+    }
+
+    /// Set the timebase, as the number of samples one sweep spans. The
+    /// default is 500.
+    pub fn set_timebase(&mut self, samples_per_screen: f32) {
+        self.sweep = samples_per_screen.max(1.0).recip();
+    }
+
+    /// Set the vertical gain, where 1.0 maps -1..1 to the full height.
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain;
+    }
+
+    /// Set how long the trace glows: the time, in seconds, for the glow to
+    /// fade by a factor of e. The default is 1500 samples' worth.
+    pub fn set_persistence(&mut self, secs: f32, sample_rate: f32) {
+        self.tc = (secs * sample_rate).max(1.0);
+    }
+
+    /// Set the spacing of the grid lines, and of the ticks on the center
+    /// cross, in pixels, which needn't be whole. The default is 60 and 12.
+    pub fn set_grid_spacing(&mut self, grid: f32, ticks: f32) {
+        self.grid_spacing = grid.max(1.0);
+        self.tick_spacing = ticks.max(1.0);
+    }
+
+    /// Set the trigger holdoff, in samples. A new sweep won't be triggered until
+    /// at least this many samples after the start of the previous one, which keeps
+    /// the display stable on waveforms that cross the trigger level more than once
+    /// per period.
+    pub fn set_holdoff(&mut self, samples: usize) {
+        self.holdoff = samples;
+    }
+
+    // Add a dot to the glow of the first trace.
+    pub fn add_dot(&mut self, x: f32, y: f32, r: f32, amp: f32) {
+        self.traces[0].add_dot(x, y, r, amp);
+    }
+
+    pub fn add_line_step(&mut self, x0: f32, y0: f32, x1: f32, y1: f32, r: f32, amp: f32) {
+        self.traces[0].add_line_step(x0, y0, x1, y1, r, amp);
+    }
+
+    pub fn add_line(&mut self, x0: f32, y0: f32, x1: f32, y1: f32, r: f32, amp: f32) {
+        self.traces[0].add_line(x0, y0, x1, y1, r, amp);
+    }
+
+    // Draw over the whole bounding rect of the line, for comparison with
+    // `add_line`.
+    #[doc(hidden)]
+    pub fn add_line_full_rect(&mut self, x0: f32, y0: f32, x1: f32, y1: f32, r: f32, amp: f32) {
+        self.traces[0].draw_line((x0, y0), (x1, y1), r, amp, false);
+    }
+
+    pub fn as_rgba(&self) -> Vec<u8> {
+        let mut im = vec![0; self.width * self.height * 4];
+        self.render_into(&mut im);
+        im
+    }
+
+    /// Render the image into `im`, as `as_rgba` does, but without allocating.
+    /// It must be width * height * 4 bytes.
+    pub fn render_into(&self, im: &mut [u8]) {
+        assert_eq!(im.len(), self.width * self.height * 4);
+        let first = &self.traces[0];
+        if !first.as_rgba_body_simd(im) {
+            first.as_rgba_body_scalar(im, 0);
+        }
+        // The other traces add their light, less that of their background.
+        for trace in &self.traces[1..] {
+            let dark = trace.lut_color(0.0);
+            for (i, &x) in trace.glow.iter().enumerate() {
+                let color = trace.lut_color(x);
+                for c in 0..3 {
+                    let pixel = &mut im[i * 4 + c];
+                    *pixel = pixel.saturating_add(color[c] - dark[c]);
+                }
+            }
+        }
+        self.render_grid_lines(im);
+    }
+
+    pub fn fade(&mut self, factor: f32) {
+        for trace in &mut self.traces {
+            trace.fade(factor);
+        }
+    }
+
+    pub fn provide_samples(&mut self, samples: &[f32]) {
+        self.provide_samples_ch(0, samples);
+    }
+
+    /// Draw the samples of channel `ch`, each channel having its own trace
+    /// and trigger, with the settings shared.
+    pub fn provide_samples_ch(&mut self, ch: usize, samples: &[f32]) {
+        self.add_traces(ch + 1);
+        let trace = &mut self.traces[ch];
+        match trace.state {
+            ScopeState::Xy => {
+                trace.xylast = None;
+                trace.state = ScopeState::WaitingForTrigger(-1.0);
+            }
+            ScopeState::Stopped => return,
+            _ => (),
+        }
+        let factor = (-(samples.len() as f32) / self.tc).exp();
+        trace.fade(factor);
+        let mut amp = 2.0 * factor;
+        let ampgain = (1.0 / self.tc).exp();
+        let y0 = self.height as f32 * 0.5;
+        let yscale = y0 * self.gain;
+        for sample in samples {
+            trace.since_trigger = trace.since_trigger.saturating_add(1);
+            match trace.state {
+                ScopeState::Scanning => {
+                    let x = trace.horiz * (self.width as f32);
+                    let y = y0 - yscale * sample;
+                    if let Some((xlast, ylast)) = trace.xylast {
+                        trace.add_line(xlast, ylast, x, y, 1.0, amp);
+                        amp *= ampgain;
+                    }
+                    trace.xylast = Some((x, y));
+                    trace.horiz += self.sweep;
+                    if trace.horiz > 1.0 {
+                        trace.xylast = None;
+                        if self.trigger_mode == TriggerMode::Single {
+                            trace.state = ScopeState::Stopped;
+                            return;
+                        }
+                        trace.state = ScopeState::WaitingForTrigger(*sample);
+                    }
+                }
+                ScopeState::WaitingForTrigger(old) => {
+                    let level = self.trigger_level;
+                    let crossed = if self.trigger_rising {
+                        old < level && *sample > level
+                    } else {
+                        old > level && *sample < level
+                    };
+                    trace.waited += 1;
+                    let timed_out = self.trigger_mode == TriggerMode::Auto
+                        && trace.waited as f32 * self.sweep >= 1.0;
+                    if (crossed && trace.since_trigger >= self.holdoff) || timed_out {
+                        trace.since_trigger = 0;
+                        trace.waited = 0;
+                        trace.horiz = 0.0; // TODO: linear interp
+                        let x = trace.horiz * (self.width as f32);
+                        let y = y0 - yscale * sample;
+                        trace.xylast = Some((x, y));
+                        trace.state = ScopeState::Scanning;
+                    } else {
+                        trace.state = ScopeState::WaitingForTrigger(*sample);
+                    }
+                }
+                ScopeState::Xy | ScopeState::Stopped => unreachable!(),
+            }
+        }
+    }
+
+    /// Draw in XY mode, where the two signals drive the horizontal and
+    /// vertical deflection directly, as for Lissajous figures or a
+    /// vectorscope. There's no sweep or trigger; the trace joins consecutive
+    /// points. Both axes have the same scale, so with a gain of 1.0, a
+    /// signal from -1 to 1 spans the height. This switches the first trace into
+    /// XY mode until `provide_samples` is called again.
+    pub fn provide_xy(&mut self, xs: &[f32], ys: &[f32]) {
+        let trace = &mut self.traces[0];
+        if !matches!(trace.state, ScopeState::Xy) {
+            trace.xylast = None;
+            trace.state = ScopeState::Xy;
+        }
+        let n = xs.len().min(ys.len());
+        let factor = (-(n as f32) / self.tc).exp();
+        trace.fade(factor);
+        let mut amp = 2.0 * factor;
+        let ampgain = (1.0 / self.tc).exp();
+        let x0 = self.width as f32 * 0.5;
+        let y0 = self.height as f32 * 0.5;
+        let scale = y0 * self.gain;
+        for (x, y) in xs.iter().zip(ys) {
+            let x = x0 + scale * x;
+            let y = y0 - scale * y;
+            if let Some((xlast, ylast)) = trace.xylast {
+                trace.add_line(xlast, ylast, x, y, 1.0, amp);
+                amp *= ampgain;
+            }
+            trace.xylast = Some((x, y));
+        }
+    }
+
+    fn render_grid_lines(&self, im: &mut [u8]) {
+        let x2 = self.width / 2;
+        let y2 = self.height / 2;
+        let (xc, yc) = (x2 as f32, y2 as f32);
+        let tick_len = 6;
+        // distances from the center, up to the edge
+        let offsets = |half: f32, sp: f32| (1..).map(move |i| i as f32 * sp)
+            .take_while(move |&d| d < half);
+        self.render_hline(0, self.width, yc, im);
+        self.render_vline(xc, 0, self.height, im);
+        for d in offsets(yc, self.grid_spacing) {
+            self.render_hline(0, self.width, yc + d, im);
+            self.render_hline(0, self.width, yc - d, im);
+        }
+        for d in offsets(xc, self.grid_spacing) {
+            self.render_vline(xc + d, 0, self.height, im);
+            self.render_vline(xc - d, 0, self.height, im);
+        }
+        for d in offsets(yc, self.tick_spacing) {
+            self.render_hline(x2 - tick_len, x2 + tick_len, yc - d, im);
+            self.render_hline(x2 - tick_len, x2 + tick_len, yc + d, im);
+        }
+        for d in offsets(xc, self.tick_spacing) {
+            self.render_vline(xc + d, y2 - tick_len, y2 + tick_len, im);
+            self.render_vline(xc - d, y2 - tick_len, y2 + tick_len, im);
+        }
+    }
+
+    // Darken a line one pixel thick whose top edge is at `y`, blending it
+    // into the two rows it covers.
+    fn render_hline(&self, x0: usize, x1: usize, y: f32, im: &mut [u8]) {
+        let j = y.floor();
+        let frac = y - j;
+        for &(j, coverage) in &[(j as usize, 1.0 - frac), (j as usize + 1, frac)] {
+            if j < self.height && coverage > 0.0 {
+                for i in (j * self.width + x0)..(j * self.width + x1) {
+                    darken(&mut im[i * 4..i * 4 + 3], coverage);
+                }
+            }
+        }
+    }
+
+    fn render_vline(&self, x: f32, y0: usize, y1: usize, im: &mut [u8]) {
+        let i = x.floor();
+        let frac = x - i;
+        for &(i, coverage) in &[(i as usize, 1.0 - frac), (i as usize + 1, frac)] {
+            if i < self.width && coverage > 0.0 {
+                for j in y0..y1 {
+                    let i = j * self.width + i;
+                    darken(&mut im[i * 4..i * 4 + 3], coverage);
+                }
+            }
+        }
+    }
+}
+
+impl Trace {
+    fn new(width: usize, height: usize, phosphor: Phosphor) -> Trace {
+        let glow = vec![0.0; width * height];
+        let horiz = 0.0;
         let xylast = None;
+        let since_trigger = 0;
+        let waited = 0;
         let state = ScopeState::WaitingForTrigger(-1.0);
-        Scope { width, height, glow, tc, sweep, horiz, gain, xylast, state }
+        let tint = phosphor.tint();
+        let lut = color_lut(&tint);
+        Trace { width, height, glow, horiz, xylast, since_trigger, waited, state, tint, lut }
     }
 
     // Add a dot to the glow.
-    pub fn add_dot(&mut self, x: f32, y: f32, r: f32, amp: f32) {
+    fn add_dot(&mut self, x: f32, y: f32, r: f32, amp: f32) {
         let r_recip = r.recip();
         let i0 = ((x - CLIP_FACTOR * r).ceil().max(0.0) as usize).min(self.width);
         let i1 = ((x + CLIP_FACTOR * r).ceil().max(0.0) as usize).min(self.width);
@@ -84,7 +478,7 @@ impl Scope {
         }
     }
 
-    pub fn add_line_step(&mut self, x0: f32, y0: f32, x1: f32, y1: f32, r: f32, amp: f32) {
+    fn add_line_step(&mut self, x0: f32, y0: f32, x1: f32, y1: f32, r: f32, amp: f32) {
         let n = 20;
         let step = (n as f32).recip();
         let amp = amp / (n as f32);
@@ -94,7 +488,15 @@ impl Scope {
         }
     }
 
-    pub fn add_line(&mut self, x0: f32, y0: f32, x1: f32, y1: f32, r: f32, amp: f32) {
+    fn add_line(&mut self, x0: f32, y0: f32, x1: f32, y1: f32, r: f32, amp: f32) {
+        self.draw_line((x0, y0), (x1, y1), r, amp, true);
+    }
+
+    // Draw a line, over only the pixels within the clip distance of it on
+    // each scan line if `clip_rows`, otherwise over the whole bounding rect.
+    fn draw_line(&mut self, (x0, y0): (f32, f32), (x1, y1): (f32, f32), r: f32, amp: f32,
+        clip_rows: bool)
+    {
         let dx = x1 - x0;
         let dy = y1 - y0;
         let len2 = dx * dx + dy * dy;
@@ -118,9 +520,20 @@ impl Scope {
         let i1 = ((x0.max(x1) + CLIP_FACTOR * r).ceil().max(0.0) as usize).min(self.width);
         let j0 = ((y0.min(y1) - CLIP_FACTOR * r).ceil().max(0.0) as usize).min(self.height);
         let j1 = ((y0.max(y1) + CLIP_FACTOR * r).ceil().max(0.0) as usize).min(self.height);
-        // TODO: (i1-i0).min(j1-j0) is a measure of wastefulness of drawing the whole rect.
-        // If this is high, compute horiz bounds per scan line.
         for j in j0..j1 {
+            // v is the distance from the line in units of r, so the gaussian
+            // is clipped where |v| > CLIP_FACTOR. Unless the line is nearly
+            // horizontal, that bounds i on each scan line.
+            let (i0, i1) = if clip_rows && vx.abs() > 1e-3 {
+                let vj = vy * (j as f32) + v0;
+                let ia = (-CLIP_FACTOR - vj) / vx;
+                let ib = (CLIP_FACTOR - vj) / vx;
+                let row_i0 = (ia.min(ib).ceil().max(0.0) as usize).max(i0);
+                let row_i1 = (ia.max(ib).ceil().max(0.0) as usize).min(i1);
+                (row_i0, row_i1.max(row_i0))
+            } else {
+                (i0, i1)
+            };
             for i in i0..i1 {
                 let u = ux * (i as f32) + uy * (j as f32) + u0;
                 let v = vx * (i as f32) + vy * (j as f32) + v0;
@@ -143,9 +556,10 @@ impl Scope {
             15, 11, 7, 3, 14, 10, 6, 2, 13, 9, 5, 1, 12, 8, 4, 0);
         for i in (0..n).step_by(8) {
             let x = avx.from_slice(&self.glow[i..]);
-            let r = avx_sqrt_pos11(x + 0.001) * 64.0;
-            let g = avx_sqrt_pos11(x + 0.05) * 255.0;
-            let b = avx_sqrt_pos11(x + 0.13) * 224.0;
+            let [(r0, rs), (g0, gs), (b0, bs)] = self.tint;
+            let r = avx_sqrt_pos11(x + r0) * rs;
+            let g = avx_sqrt_pos11(x + g0) * gs;
+            let b = avx_sqrt_pos11(x + b0) * bs;
             let r = _mm256_cvttps_epi32(r.into());
             let g = _mm256_cvttps_epi32(g.into());
             let b = _mm256_cvttps_epi32(b.into());
@@ -158,116 +572,105 @@ impl Scope {
         }
     }
 
-    pub fn as_rgba(&self) -> Vec<u8> {
+    #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+    #[target_feature(enable = "neon")]
+    unsafe fn as_rgba_body_neon(&self, im: &mut [u8]) {
         let n = self.width * self.height;
-        let mut im = vec![255; n * 4];
-        if is_x86_feature_detected!("avx") {
-            unsafe { self.as_rgba_body_avx(&mut im); }
-        } else {
-            // TODO: lut is probably faster scalar fallback
-            for i in 0..n {
-                let x = self.glow[i];
-                let r = ((x + 0.001).sqrt() * 64.0).min(255.0) as u8;
-                let g = ((x + 0.05).sqrt() * 255.0).min(255.0) as u8;
-                let b = ((x + 0.13).sqrt() * 224.0).min(255.0) as u8;
-                im[i * 4 + 0] = r;
-                im[i * 4 + 1] = g;
-                im[i * 4 + 2] = b;
-            }
+        assert!(n == self.glow.len());
+        assert!(n * 4 == im.len());
+        let sqrt_scaled = |x: float32x4_t, offset: f32, scale: f32| {
+            vcvtq_u32_f32(vmulq_n_f32(vsqrtq_f32(vaddq_f32(x, vdupq_n_f32(offset))), scale))
+        };
+        // Eight pixels at a time, saturating to u8 and storing interleaved.
+        let n8 = n - n % 8;
+        for i in (0..n8).step_by(8) {
+            let lo = vld1q_f32(self.glow.as_ptr().add(i));
+            let hi = vld1q_f32(self.glow.as_ptr().add(i + 4));
+            let channel = |offset: f32, scale: f32| {
+                let lo = vqmovn_u32(sqrt_scaled(lo, offset, scale));
+                let hi = vqmovn_u32(sqrt_scaled(hi, offset, scale));
+                vqmovn_u16(vcombine_u16(lo, hi))
+            };
+            let [(r0, rs), (g0, gs), (b0, bs)] = self.tint;
+            let rgba = uint8x8x4_t(channel(r0, rs), channel(g0, gs), channel(b0, bs),
+                vdup_n_u8(0xff));
+            vst4_u8(im.as_mut_ptr().add(i * 4), rgba);
         }
-        self.render_grid_lines(&mut im);
-        im
+        self.as_rgba_body_scalar(im, n8);
     }
 
-    pub fn fade(&mut self, factor: f32) {
-        for x in &mut self.glow {
-            *x *= factor;
+    // Fill in the pixels from `start` on.
+    fn as_rgba_body_scalar(&self, im: &mut [u8], start: usize) {
+        for i in start..self.width * self.height {
+            im[i * 4..i * 4 + 3].copy_from_slice(&self.lut_color(self.glow[i]));
+            im[i * 4 + 3] = 0xff;
         }
     }
 
-    pub fn provide_samples(&mut self, samples: &[f32]) {
-        let factor = (-(samples.len() as f32) / self.tc).exp();
-        self.fade(factor);
-        let mut amp = 2.0 * factor;
-        let ampgain = (1.0 / self.tc).exp();
-        let y0 = self.height as f32 * 0.5;
-        let yscale = y0 * self.gain;
-        for sample in samples {
-            match self.state {
-                ScopeState::Scanning => {
-                    let x = self.horiz * (self.width as f32);
-                    let y = y0 - yscale * sample;
-                    if let Some((xlast, ylast)) = self.xylast {
-                        self.add_line(xlast, ylast, x, y, 1.0, amp);
-                        amp *= ampgain;
-                    }
-                    self.xylast = Some((x, y));
-                    self.horiz += self.sweep;
-                    if self.horiz > 1.0 {
-                        self.xylast = None;
-                        self.state = ScopeState::WaitingForTrigger(*sample);
-                    }
-                }
-                ScopeState::WaitingForTrigger(old) => {
-                    let trigger_level = 0.0;
-                    if old < trigger_level && *sample > trigger_level {
-                        self.horiz = 0.0; // TODO: linear interp
-                        let x = self.horiz * (self.width as f32);
-                        let y = y0 - yscale * sample;
-                        self.xylast = Some((x, y));
-                        self.state = ScopeState::Scanning;
-                    } else {
-                        self.state = ScopeState::WaitingForTrigger(*sample);
-                    }
-                }
-            }
+    // Fill in the image with SIMD, if the CPU supports it, returning false if
+    // not.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn as_rgba_body_simd(&self, im: &mut [u8]) -> bool {
+        if is_x86_feature_detected!("avx") {
+            unsafe { self.as_rgba_body_avx(im); }
+            true
+        } else {
+            false
         }
     }
 
-    fn render_grid_lines(&self, im: &mut [u8]) {
-        let x2 = self.width / 2;
-        let y2 = self.height / 2;
-        let grid_sp = 60;
-        let tick_sp = 12;
-        let tick_len = 6;
-        self.render_hline(0, self.width, y2, im);
-        self.render_vline(x2, 0, self.height, im);
-        for i in 1..((y2 + grid_sp - 1) / grid_sp) {
-            self.render_hline(0, self.width, y2 + i * grid_sp, im);
-            self.render_hline(0, self.width, y2 - i * grid_sp, im);
-        }
-        for i in 1..((x2 + grid_sp - 1) / grid_sp) {
-            self.render_vline(x2 + i * grid_sp, 0, self.height, im);
-            self.render_vline(x2 - i * grid_sp, 0, self.height, im);
-        }
-        for i in 1..((y2 + tick_sp - 1) / tick_sp) {
-            self.render_hline(x2 - tick_len, x2 + tick_len, y2 - i * tick_sp, im);
-            self.render_hline(x2 - tick_len, x2 + tick_len, y2 + i * tick_sp, im);
-        }
-        for i in 1..((x2 + tick_sp - 1) / tick_sp) {
-            self.render_vline(x2 + i * tick_sp, y2 - tick_len, y2 + tick_len, im);
-            self.render_vline(x2 - i * tick_sp, y2 - tick_len, y2 + tick_len, im);
+    #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+    fn as_rgba_body_simd(&self, im: &mut [u8]) -> bool {
+        if ::std::arch::is_aarch64_feature_detected!("neon") {
+            unsafe { self.as_rgba_body_neon(im); }
+            true
+        } else {
+            false
         }
     }
 
-    fn render_hline(&self, x0: usize, x1: usize, y: usize, im: &mut [u8]) {
-        for i in (y * self.width + x0)..(y * self.width + x1) {
-            im[i * 4 + 0] >>= 1;
-            im[i * 4 + 1] >>= 1;
-            im[i * 4 + 2] >>= 1;
-        }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64",
+        all(target_arch = "aarch64", feature = "neon"))))]
+    fn as_rgba_body_simd(&self, _im: &mut [u8]) -> bool {
+        false
+    }
+
+    // The color of a glow value, from the lookup table. Each channel's slope
+    // against sqrt(glow) is at most its scale, so this is within 1 of
+    // `glow_color`, for one sqrt rather than three.
+    fn lut_color(&self, x: f32) -> [u8; 3] {
+        let ix = (x.max(0.0).sqrt() * LUT_SCALE + 0.5) as usize;
+        self.lut[ix.min(self.lut.len() - 1)]
     }
 
-    fn render_vline(&self, x: usize, y0: usize, y1: usize, im: &mut [u8]) {
-        for j in y0..y1 {
-            let i = j * self.width + x;
-            im[i * 4 + 0] >>= 1;
-            im[i * 4 + 1] >>= 1;
-            im[i * 4 + 2] >>= 1;
+    fn fade(&mut self, factor: f32) {
+        for x in &mut self.glow {
+            *x *= factor;
         }
     }
 }
 
+// Halve the brightness of the pixel's color, in proportion to `coverage`.
+fn darken(pixel: &mut [u8], coverage: f32) {
+    let k = 1.0 - 0.5 * coverage;
+    for c in pixel {
+        *c = (*c as f32 * k) as u8;
+    }
+}
+
+// The color of a glow value.
+fn glow_color(x: f32, tint: &Tint) -> [u8; 3] {
+    let channel = |(offset, scale): (f32, f32)| ((x + offset).sqrt() * scale).min(255.0) as u8;
+    [channel(tint[0]), channel(tint[1]), channel(tint[2])]
+}
+
+fn color_lut(tint: &Tint) -> Vec<[u8; 3]> {
+    let max = tint.iter().map(|&(_, scale)| 255.0 / scale).fold(0.0, f32::max);
+    (0..=(max * LUT_SCALE).ceil() as usize)
+        .map(|i| glow_color((i as f32 / LUT_SCALE).powi(2), tint))
+        .collect()
+}
+
 // Approximate exp(-x*x) in a SIMD-friendly way; approx 3.2e-3 error.
 pub fn gauss_approx(x: f32) -> f32 {
     let xx = x * x;
@@ -282,14 +685,302 @@ pub fn erf_approx(x: f32) -> f32 {
     x / (1.0 + x * x).sqrt()
 }
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
 fn avx_sqrt_pos11<S: SimdF32>(x: S) -> S {
     x * x.rsqrt11()
 }
 
 #[cfg(test)]
 mod tests {
+    use super::{glow_color, Phosphor, Scope, ScopeState, TriggerMode};
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    // Sample indices (mod period) at which sweeps start, for a waveform with
+    // three rising zero crossings per period.
+    fn trigger_phases(holdoff: usize) -> Vec<usize> {
+        let period = 400;
+        let mut scope = Scope::new(64, 64);
+        scope.sweep = 0.01;
+        scope.set_holdoff(holdoff);
+        let mut phases = Vec::new();
+        for i in 0..20 * period {
+            let th = (i as f32 + 0.5) * (2.0 * ::std::f32::consts::PI) / period as f32;
+            let y = 0.4 * (th.sin() + 1.5 * (3.0 * th).sin());
+            let was_waiting = matches!(scope.traces[0].state, ScopeState::WaitingForTrigger(_));
+            scope.provide_samples(&[y]);
+            if was_waiting && matches!(scope.traces[0].state, ScopeState::Scanning) {
+                phases.push(i % period);
+            }
+        }
+        phases.sort();
+        phases.dedup();
+        phases
+    }
+
+    #[test]
+    fn holdoff_stabilizes_trigger() {
+        assert!(trigger_phases(0).len() > 1);
+        assert_eq!(trigger_phases(380).len(), 1);
+    }
+
+    #[test]
+    fn xy_quadrature_draws_circle() {
+        let mut scope = Scope::new(64, 64);
+        let period = 100;
+        let th = |i: usize| i as f32 * (2.0 * ::std::f32::consts::PI) / period as f32;
+        let xs: Vec<f32> = (0..4 * period).map(|i| 0.5 * th(i).cos()).collect();
+        let ys: Vec<f32> = (0..4 * period).map(|i| 0.5 * th(i).sin()).collect();
+        scope.provide_xy(&xs, &ys);
+        // radius 16 pixels around the center
+        let glow = |x: f32, y: f32| scope.traces[0].glow[(y.round() as usize) * 64 + x.round() as usize];
+        let ring: Vec<f32> = (0..16).map(|k| k as f32 * ::std::f32::consts::PI / 8.0)
+            .map(|a| glow(32.0 + 16.0 * a.cos(), 32.0 - 16.0 * a.sin()))
+            .collect();
+        let min_ring = ring.iter().fold(f32::MAX, |m, &x| m.min(x));
+        assert!(min_ring > 0.1, "ring {:?}", ring);
+        assert!(glow(32.0, 32.0) < 1e-3 * min_ring);
+        assert!(glow(32.0, 32.0 - 8.0) < 1e-3 * min_ring);
+        assert!(glow(32.0 + 24.0, 32.0) < 1e-3 * min_ring);
+        // Back to sweeping, with no line joining the last point.
+        scope.provide_samples(&[0.0]);
+        assert!(matches!(scope.traces[0].state, ScopeState::WaitingForTrigger(_)));
+    }
+
+    // Sample indices (mod period) at which sweeps of a DC-offset sine start.
+    fn offset_sine_phases(level: f32, rising: bool) -> Vec<usize> {
+        let period = 360;
+        let mut scope = Scope::new(64, 64);
+        scope.sweep = 0.01;
+        scope.set_trigger(level, rising);
+        let mut phases = Vec::new();
+        for i in 0..10 * period {
+            let th = (i as f32 + 0.5) * (2.0 * ::std::f32::consts::PI) / period as f32;
+            let was_waiting = matches!(scope.traces[0].state, ScopeState::WaitingForTrigger(_));
+            scope.provide_samples(&[0.3 + 0.5 * th.sin()]);
+            // skipping the first period, which can trigger on the first sample
+            if was_waiting && matches!(scope.traces[0].state, ScopeState::Scanning) && i >= period {
+                phases.push(i % period);
+            }
+        }
+        phases.sort();
+        phases.dedup();
+        phases
+    }
+
+    #[test]
+    fn trigger_level_and_slope() {
+        // 0.3 + 0.5 sin crosses 0.55 going up at 30 degrees, down at 150.
+        assert_eq!(offset_sine_phases(0.55, true), vec![30]);
+        assert_eq!(offset_sine_phases(0.55, false), vec![150]);
+        // The default level of 0 is crossed going up at about 323 degrees.
+        assert_eq!(offset_sine_phases(0.0, true), vec![323]);
+    }
+
+    #[test]
+    fn trigger_modes() {
+        let sweeps = |scope: &mut Scope, samples: &[f32]| {
+            let mut n = 0;
+            for &x in samples {
+                let was_waiting = matches!(scope.traces[0].state, ScopeState::WaitingForTrigger(_));
+                scope.provide_samples(&[x]);
+                n += (was_waiting && matches!(scope.traces[0].state, ScopeState::Scanning)) as usize;
+            }
+            n
+        };
+        let flat = vec![0.5; 1000];
+        let ramps: Vec<f32> = (0..1000).map(|i| (i % 100) as f32 * 0.01 - 0.505).collect();
+        let mut scope = Scope::new(64, 64);
+        scope.sweep = 0.01;
+        scope.provide_samples(&[-1.0]);
+        assert_eq!(sweeps(&mut scope, &flat), 1);
+        scope.set_trigger_mode(TriggerMode::Auto);
+        assert_eq!(sweeps(&mut scope, &flat), 5);
+
+        let mut scope = Scope::new(64, 64);
+        scope.sweep = 0.01;
+        scope.set_trigger_mode(TriggerMode::Single);
+        assert_eq!(sweeps(&mut scope, &ramps), 1);
+        assert!(matches!(scope.traces[0].state, ScopeState::Stopped));
+        let frozen = scope.traces[0].glow.clone();
+        sweeps(&mut scope, &ramps);
+        assert_eq!(scope.traces[0].glow, frozen);
+        scope.arm();
+        assert_eq!(sweeps(&mut scope, &ramps), 1);
+    }
+
+    #[test]
+    fn color_lut_matches_direct() {
+        let mut scope = Scope::new(8, 8);
+        for &phosphor in &[Phosphor::Green, Phosphor::Amber, Phosphor::White, Phosphor::Blue] {
+            scope.set_phosphor(phosphor);
+            for i in 0..40_000 {
+                let x = (i as f32 * 1e-3).powi(2) * 0.05;
+                let direct = glow_color(x, &phosphor.tint());
+                let lut = scope.traces[0].lut_color(x);
+                for c in 0..3 {
+                    assert!((direct[c] as i32 - lut[c] as i32).abs() <= 1,
+                        "glow {} direct {:?} lut {:?}", x, direct, lut);
+                }
+            }
+        }
+    }
+
+    #[cfg(all(target_arch = "aarch64", feature = "neon"))]
+    #[test]
+    fn neon_matches_scalar() {
+        // 9 by 9, so that the last pixel is left to the scalar tail.
+        let mut scope = Scope::new(9, 9);
+        for (i, x) in scope.traces[0].glow.iter_mut().enumerate() {
+            *x = (i as f32 * 0.03).powi(2);
+        }
+        let mut neon = vec![0; 9 * 9 * 4];
+        let mut scalar = neon.clone();
+        unsafe { scope.traces[0].as_rgba_body_neon(&mut neon); }
+        scope.traces[0].as_rgba_body_scalar(&mut scalar, 0);
+        for (i, (&a, &b)) in neon.iter().zip(&scalar).enumerate() {
+            if i % 4 != 3 {
+                assert!((a as i32 - b as i32).abs() <= 1, "byte {}: {} vs {}", i, a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn amber_phosphor_is_red() {
+        let mut scope = Scope::new(64, 64);
+        scope.traces[0].glow[64 + 1] = 0.3;
+        let pixel = |scope: &Scope| scope.as_rgba()[(64 + 1) * 4..(64 + 1) * 4 + 3].to_vec();
+        let green = pixel(&scope);
+        assert!(green[1] > green[0] && green[1] > green[2], "{:?}", green);
+        scope.set_phosphor(Phosphor::Amber);
+        let amber = pixel(&scope);
+        assert!(amber[0] > amber[1] && amber[1] > amber[2], "{:?}", amber);
+    }
+
+    #[test]
+    fn two_channels_keep_their_own_glow() {
+        let mut scope = Scope::new(64, 64);
+        scope.sweep = 0.01;
+        let period = 100;
+        let wave = |offset: f32| -> Vec<f32> {
+            (0..4 * period).map(|i| {
+                let th = (i as f32 + 0.5) * (2.0 * ::std::f32::consts::PI) / period as f32;
+                offset + 0.25 * th.sin()
+            }).collect()
+        };
+        // Neither crosses the trigger level.
+        scope.set_trigger_mode(TriggerMode::Auto);
+        scope.provide_samples_ch(0, &wave(0.5));
+        let first = scope.traces[0].glow.clone();
+        scope.provide_samples_ch(1, &wave(-0.5));
+        assert_eq!(scope.traces.len(), 2);
+        // Drawing the second channel doesn't touch the first's glow.
+        assert_eq!(scope.traces[0].glow, first);
+        // The upper half has the first trace, the lower half the second.
+        let sum = |glow: &[f32], rows: ::std::ops::Range<usize>| -> f32 {
+            glow[rows.start * 64..rows.end * 64].iter().sum()
+        };
+        let (upper, lower) = (0..32, 32..64);
+        assert!(sum(&scope.traces[0].glow, upper.clone()) > 1.0);
+        assert!(sum(&scope.traces[0].glow, lower.clone()) < 1e-3);
+        assert!(sum(&scope.traces[1].glow, lower) > 1.0);
+        assert!(sum(&scope.traces[1].glow, upper) < 1e-3);
+        // Both show in the image, in their own colors.
+        let im = scope.as_rgba();
+        let brightest = |rows: ::std::ops::Range<usize>| {
+            (rows.start * 64..rows.end * 64)
+                .max_by_key(|&i| im[i * 4] as u32 + im[i * 4 + 1] as u32)
+                .map(|i| [im[i * 4], im[i * 4 + 1], im[i * 4 + 2]]).unwrap()
+        };
+        let green = brightest(0..32);
+        let amber = brightest(32..64);
+        assert!(green[1] > green[0], "{:?}", green);
+        assert!(amber[0] > amber[2] && amber[0] > 150, "{:?}", amber);
+        scope.traces[1].glow.iter_mut().for_each(|x| *x = 0.0);
+        assert!(scope.as_rgba()[32 * 64 * 4..].iter().step_by(4).all(|&r| r < 8));
+    }
+
+    #[test]
+    fn persistence() {
+        // The total glow left, 100 chunks after a sweep.
+        let remaining = |secs: f32| {
+            let mut scope = Scope::new(64, 64);
+            scope.sweep = 0.01;
+            scope.set_persistence(secs, 44_100.0);
+            let sweep: Vec<f32> = (0..150).map(|i| (i as f32 * 0.1).sin() * 0.5).collect();
+            scope.provide_samples(&sweep);
+            let drawn: f32 = scope.traces[0].glow.iter().sum();
+            assert!(drawn > 1.0);
+            for _ in 0..100 {
+                scope.provide_samples(&[0.0; 64]);
+            }
+            scope.traces[0].glow.iter().sum::<f32>() / drawn
+        };
+        assert!(remaining(10.0) > 0.9);
+        assert!(remaining(0.01) < 1e-4);
+    }
+
+    #[test]
+    fn timebase_sets_sweep_length() {
+        let mut scope = Scope::new(64, 64);
+        scope.set_timebase(5000.0);
+        scope.provide_samples(&[-0.5, 0.5]);
+        assert!(matches!(scope.traces[0].state, ScopeState::Scanning));
+        let mut n = 0;
+        while matches!(scope.traces[0].state, ScopeState::Scanning) {
+            scope.provide_samples(&[0.5]);
+            n += 1;
+        }
+        assert!((n - 5000i32).abs() <= 1, "{} samples", n);
+    }
+
+    #[test]
+    fn line_row_bounds_match_full_rect() {
+        let lines = [(3.0, 4.0, 60.0, 61.0), (60.5, 2.2, 10.0, 50.0), (5.0, 30.0, 58.0, 33.0),
+            (30.0, 2.0, 31.0, 62.0), (10.0, 10.0, 40.0, 10.0)];
+        for &(x0, y0, x1, y1) in &lines {
+            let mut clipped = Scope::new(64, 64);
+            let mut full = Scope::new(64, 64);
+            clipped.add_line(x0, y0, x1, y1, 1.5, 1.0);
+            full.add_line_full_rect(x0, y0, x1, y1, 1.5, 1.0);
+            for (a, b) in clipped.traces[0].glow.iter().zip(&full.traces[0].glow) {
+                assert!((a - b).abs() < 1e-3, "{} vs {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn render_into_matches_as_rgba() {
+        let mut scope = Scope::new(64, 48);
+        scope.add_line(3.0, 40.0, 60.0, 5.0, 1.0, 2.0);
+        scope.provide_samples_ch(1, &[0.2; 10]);
+        scope.traces[1].glow[100] = 0.5;
+        // stale contents, as from the previous frame
+        let mut im = vec![7; 64 * 48 * 4];
+        scope.render_into(&mut im);
+        assert_eq!(im, scope.as_rgba());
+        scope.traces[0].as_rgba_body_scalar(&mut im, 0);
+        assert!(im.iter().skip(3).step_by(4).all(|&a| a == 0xff));
+    }
+
+    #[test]
+    fn fractional_grid_line_blends() {
+        let scope = Scope::new(16, 16);
+        let mut im = vec![255; 16 * 16 * 4];
+        scope.render_hline(0, 16, 5.25, &mut im);
+        scope.render_vline(8.0, 0, 16, &mut im);
+        let red = |i: usize, j: usize| im[(j * 16 + i) * 4];
+        // three quarters of the line in row 5, a quarter in row 6
+        assert_eq!(red(2, 4), 255);
+        assert_eq!(red(2, 5), (255.0 * (1.0 - 0.5 * 0.75)) as u8);
+        assert_eq!(red(2, 6), (255.0 * (1.0 - 0.5 * 0.25)) as u8);
+        assert_eq!(red(2, 7), 255);
+        // A whole-pixel line halves a single column, as before.
+        assert_eq!(red(8, 2), 127);
+        assert_eq!(red(7, 2), 255);
+        assert_eq!(red(9, 2), 255);
+    }
 }
@@ -13,6 +13,14 @@
 // limitations under the License.
 
 //! A little sketch to make spectrograms of sigmoid functions applied to sines.
+//!
+//! ```text
+//! sigmoid FILE_BASE [--colormap NAMES]
+//! ```
+//!
+//! Writes `FILE_BASE.wav` and the spectrogram `FILE_BASE.png`. `NAMES` is a
+//! comma-separated list of colormaps (`inferno`, `viridis`, `magma`,
+//! `grayscale`), or `all`; with more than one, the images for comparison are `FILE_BASE-NAME.png`.
 
 extern crate hound;
 extern crate png;
@@ -25,7 +33,7 @@ use std::path::Path;
 
 use png::HasParameters;
 
-use synthesizer_io_spect::Spect;
+use synthesizer_io_spect::{Colormap, Spect};
 
 /// Approximate erf(x * sqrt(pi) / 2)
 #[allow(unused)]
@@ -50,8 +58,34 @@ fn gen_audio(len: usize) -> Vec<f32> {
     }).collect()
 }
 
+fn parse_colormaps(names: &str) -> Vec<Colormap> {
+    if names == "all" {
+        return Colormap::ALL.to_vec();
+    }
+    names.split(',').map(|name| Colormap::from_name(name).unwrap_or_else(|| {
+        let known: Vec<_> = Colormap::ALL.iter().map(|c| c.name()).collect();
+        panic!("unknown colormap {}, expected one of {} or all", name, known.join(", "))
+    })).collect()
+}
+
+fn write_png(path: &Path, img: &[u8], width: usize, height: usize) {
+    let f = File::create(path).unwrap();
+    let w = BufWriter::new(f);
+
+    let mut encoder = png::Encoder::new(w, width as u32, height as u32);
+    encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_image_data(img).unwrap();
+}
+
 fn main() {
-    let file_base = env::args().skip(1).next().expect("need filename base");
+    let mut args = env::args().skip(1);
+    let file_base = args.next().expect("need filename base");
+    let colormaps = match (args.next(), args.next()) {
+        (None, _) => vec![Colormap::Inferno],
+        (Some(ref flag), Some(ref names)) if flag == "--colormap" => parse_colormaps(names),
+        _ => panic!("usage: sigmoid FILE_BASE [--colormap NAMES]"),
+    };
 
     let audio = gen_audio(441_000);
 
@@ -72,15 +106,16 @@ fn main() {
 
     let mut spect = Spect::new(1024);
     let (width, height) = spect.image_dims(audio.len());
-    let img = spect.generate(&audio);
-
-    // Write spectrogram image as PNG
-    let path = Path::new(&file_base).with_extension("png");
-    let f = File::create(path).unwrap();
-    let w = BufWriter::new(f);
 
-    let mut encoder = png::Encoder::new(w, width as u32, height as u32);
-    encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
-    let mut writer = encoder.write_header().unwrap();
-    writer.write_image_data(&img).unwrap();
+    // Write spectrogram images as PNG
+    for &colormap in &colormaps {
+        spect.set_colormap(colormap);
+        let img = spect.generate(&audio);
+        let path = if colormaps.len() == 1 {
+            Path::new(&file_base).with_extension("png")
+        } else {
+            Path::new(&format!("{}-{}", file_base, colormap.name())).with_extension("png")
+        };
+        write_png(&path, &img, width, height);
+    }
 }
@@ -276,6 +276,28 @@ const INFERNO_DATA: [[f32; 3]; 256] = [
     [0.988362, 0.998364, 0.644924],
 ];
 
+// Viridis and magma are sixth-order polynomial fits of the matplotlib data, the
+// coefficients of each power in turn, within about 3% of the tables.
+const VIRIDIS_FIT: [[f32; 3]; 7] = [
+    [0.27772734, 0.0054073445, 0.3340998],
+    [0.10509304, 1.4046135, 1.3845901],
+    [-0.33086184, 0.21484756, 0.095095165],
+    [-4.6342306, -5.799101, -19.332441],
+    [6.22827, 14.179934, 56.69055],
+    [4.776385, -13.745146, -65.353035],
+    [-5.435456, 4.6458526, 26.312435],
+];
+
+const MAGMA_FIT: [[f32; 3]; 7] = [
+    [-0.002136485, -0.00074965507, -0.0053861276],
+    [0.25166056, 0.67752326, 2.4940267],
+    [8.353717, -3.5777194, 0.3144679],
+    [-27.668734, 14.26473, -13.649213],
+    [52.17614, -27.943605, 12.944169],
+    [-50.768524, 29.046583, 4.234153],
+    [18.655704, -11.489774, -5.6019616],
+];
+
 fn scale_u8(x: f32) -> u8 {
     (x * 255.0) as u8
 }
@@ -286,4 +308,66 @@ pub fn map_inferno(y: f32) -> (u8, u8, u8) {
     // TODO: could extend the top range (blowing out to white)
     let rgb = INFERNO_DATA[y];
     (scale_u8(rgb[0]), scale_u8(rgb[1]), scale_u8(rgb[2]))
-}
\ No newline at end of file
+}
+// Evaluate a polynomial fit at a level scaled to 0..255.
+fn map_fit(fit: &[[f32; 3]; 7], y: f32) -> (u8, u8, u8) {
+    let t = y.clamp(0.0, 255.0) / 255.0;
+    let channel = |c: usize| {
+        let x = fit.iter().rev().fold(0.0, |acc, coeffs| acc * t + coeffs[c]);
+        scale_u8(x.clamp(0.0, 1.0))
+    };
+    (channel(0), channel(1), channel(2))
+}
+
+pub fn map_viridis(y: f32) -> (u8, u8, u8) {
+    map_fit(&VIRIDIS_FIT, y)
+}
+
+pub fn map_magma(y: f32) -> (u8, u8, u8) {
+    map_fit(&MAGMA_FIT, y)
+}
+
+pub fn map_grayscale(y: f32) -> (u8, u8, u8) {
+    let v = y.clamp(0.0, 255.0) as u8;
+    (v, v, v)
+}
+
+/// A mapping from level to color, for the spectrogram.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Colormap {
+    Inferno,
+    Viridis,
+    Magma,
+    Grayscale,
+}
+
+impl Colormap {
+    /// All the available colormaps.
+    pub const ALL: &'static [Colormap] = &[Colormap::Inferno, Colormap::Viridis,
+        Colormap::Magma, Colormap::Grayscale];
+
+    /// A short lowercase name, for command lines and file names.
+    pub fn name(self) -> &'static str {
+        match self {
+            Colormap::Inferno => "inferno",
+            Colormap::Viridis => "viridis",
+            Colormap::Magma => "magma",
+            Colormap::Grayscale => "grayscale",
+        }
+    }
+
+    /// Look up a colormap by its `name`.
+    pub fn from_name(name: &str) -> Option<Colormap> {
+        Colormap::ALL.iter().cloned().find(|c| c.name() == name)
+    }
+
+    /// Map a level, scaled to 0..255, to RGB.
+    pub fn map(self, y: f32) -> (u8, u8, u8) {
+        match self {
+            Colormap::Inferno => map_inferno(y),
+            Colormap::Viridis => map_viridis(y),
+            Colormap::Magma => map_magma(y),
+            Colormap::Grayscale => map_grayscale(y),
+        }
+    }
+}
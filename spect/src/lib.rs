@@ -17,17 +17,45 @@
 extern crate rustfft;
 mod colormap;
 
+use std::mem;
 use std::sync::Arc;
 use std::f32::consts::PI;
 
 use rustfft::{FFT, FFTplanner};
 use rustfft::num_complex::Complex;
 
+pub use colormap::Colormap;
+
+/// How frequency maps to the rows of the spectrogram.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FreqScale {
+    /// Evenly spaced bins, as the FFT computes them.
+    Linear,
+    /// Evenly spaced octaves, from the first bin above DC to the top.
+    Log,
+    /// The mel scale, close to linear below 700Hz and logarithmic above.
+    Mel,
+}
+
 pub struct Spect {
     window: Vec<f32>,
     ibuf: Vec<Complex<f32>>,
     obuf: Vec<Complex<f32>>,
     fft: Arc<FFT<f32>>,
+    // per-bin gain in dB, from the spectral tilt
+    tilt: Vec<f32>,
+    colormap: Colormap,
+    // level shown at the top of the colormap, and the range below it, in dB
+    max_db: f32,
+    range_db: f32,
+    // samples between the starts of successive windows
+    hop: usize,
+    freq_scale: FreqScale,
+    sample_rate: f32,
+    // fractional bin shown in each row, from the bottom
+    row_bins: Vec<f32>,
+    // input to `process_frame` not yet consumed by a hop
+    pending: Vec<f32>,
 }
 
 impl Spect {
@@ -37,12 +65,90 @@ impl Spect {
         let window = Self::mk_window(width);
         let ibuf = vec![Default::default(); width];
         let obuf = vec![Default::default(); width];
-        Spect { window, ibuf, obuf, fft }
+        let tilt = vec![0.0; width / 2];
+        let colormap = Colormap::Inferno;
+        let max_db = 40.0;
+        let range_db = 120.0;
+        let hop = width / 2;
+        let freq_scale = FreqScale::Linear;
+        let sample_rate = 44_100.0;
+        let row_bins = (0..width / 2).map(|bin| bin as f32).collect();
+        let pending = Vec::new();
+        Spect { window, ibuf, obuf, fft, tilt, colormap, max_db, range_db, hop, freq_scale,
+            sample_rate, row_bins, pending }
+    }
+
+    /// Select the colormap used by `generate`. The default is inferno.
+    pub fn set_colormap(&mut self, colormap: Colormap) {
+        self.colormap = colormap;
+    }
+
+    /// Set the levels shown: `max_db` maps to the top of the colormap, and
+    /// `range_db` below that to the bottom. The default is 40 dB with a range
+    /// of 120 dB; quiet signals show better with a lower maximum.
+    pub fn set_db_range(&mut self, max_db: f32, range_db: f32) {
+        self.max_db = max_db;
+        self.range_db = range_db.max(1e-3);
+    }
+
+    /// Set the fraction by which successive windows overlap, which is 0.5 by
+    /// default. More overlap gives more columns for the same input, for finer
+    /// time resolution.
+    pub fn set_overlap(&mut self, overlap: f32) {
+        let window_len = self.window.len() as f32;
+        self.hop = ((1.0 - overlap.clamp(0.0, 1.0)) * window_len).round().max(1.0) as usize;
+    }
+
+    /// Set the frequency axis. The default is linear.
+    pub fn set_freq_scale(&mut self, freq_scale: FreqScale) {
+        self.freq_scale = freq_scale;
+        self.update_row_bins();
+    }
+
+    /// Set the sample rate of the input, which places the mel scale. The
+    /// default is 44.1kHz.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.update_row_bins();
+    }
+
+    fn update_row_bins(&mut self) {
+        let window_len = self.window.len();
+        let height = window_len / 2;
+        let top = (height - 1) as f32;
+        // position of each row, from 0 at the bottom to 1 at the top
+        let rows = (0..height).map(|row| row as f32 / top);
+        self.row_bins = match self.freq_scale {
+            FreqScale::Linear => rows.map(|t| t * top).collect(),
+            FreqScale::Log => rows.map(|t| top.powf(t)).collect(),
+            FreqScale::Mel => {
+                let bin_hz = self.sample_rate / window_len as f32;
+                let mel_top = hz_to_mel(top * bin_hz);
+                rows.map(|t| mel_to_hz(t * mel_top) / bin_hz).collect()
+            }
+        };
+    }
+
+    /// Apply a spectral tilt to the display, in dB per octave of bin
+    /// frequency. A tilt of 3 dB/octave shows pink noise as flat. The tilt
+    /// pivots around the geometric middle of the bins, so the overall
+    /// brightness is roughly preserved.
+    pub fn set_tilt(&mut self, db_per_octave: f32) {
+        let height = self.tilt.len() as f32;
+        let pivot = 0.5 * height.log2();
+        for (i, gain) in self.tilt.iter_mut().enumerate() {
+            *gain = db_per_octave * ((i.max(1) as f32).log2() - pivot);
+        }
     }
 
     pub fn image_dims(&self, n_samples: usize) -> (usize, usize) {
-        let height = self.window.len() / 2;
-        let width = n_samples / height - 1;
+        let window_len = self.window.len();
+        let height = window_len / 2;
+        let width = if n_samples < window_len {
+            0
+        } else {
+            (n_samples - window_len) / self.hop + 1
+        };
         (width, height)
     }
 
@@ -51,7 +157,7 @@ impl Spect {
         let (width, height) = self.image_dims(input.len());
         let mut img = vec![255; 4 * width * height];
         let window_len = self.window.len();
-        let step = window_len / 2;
+        let step = self.hop;
         let mut ix = 0;
         for x in 0..width {
             self.compute_one_window(&input[ix..ix + window_len]);
@@ -61,6 +167,51 @@ impl Spect {
         img
     }
 
+    /// Add `samples` to the input of a live spectrogram, returning the columns
+    /// of pixels, each top row first, for every window that is now complete.
+    /// Frames can be any size; all the columns together are the same as
+    /// `generate` gives for all the input at once, and less than a window of
+    /// input is kept buffered between calls.
+    pub fn process_frame(&mut self, samples: &[f32]) -> Vec<Vec<(u8, u8, u8)>> {
+        self.pending.extend_from_slice(samples);
+        let window_len = self.window.len();
+        let pending = mem::take(&mut self.pending);
+        let mut columns = Vec::new();
+        let mut start = 0;
+        while start + window_len <= pending.len() {
+            self.compute_one_window(&pending[start..start + window_len]);
+            columns.push(self.row_bins.iter().rev().map(|&bin| self.color(bin)).collect());
+            start += self.hop;
+        }
+        self.pending = pending;
+        self.pending.drain(..start);
+        columns
+    }
+
+    /// The magnitude of each bin, up to half the window, of the spectrum of
+    /// one window of `data`, which must be the size of the window. A sine on a
+    /// bin, of amplitude 1, has a magnitude of a quarter of the window size.
+    pub fn compute_spectrum(&mut self, data: &[f32]) -> Vec<f32> {
+        assert_eq!(data.len(), self.window.len());
+        self.compute_one_window(data);
+        self.obuf[..self.tilt.len()].iter().map(|c| c.norm()).collect()
+    }
+
+    /// The frequency, in Hz at the sample rate, of the strongest tone in one
+    /// window of `data`, more precisely than the bin spacing: a parabola is
+    /// fitted through the levels in dB of the peak bin and its neighbors.
+    pub fn estimate_peak(&mut self, data: &[f32]) -> f32 {
+        let spectrum = self.compute_spectrum(data);
+        let peak = (1..spectrum.len() - 1)
+            .max_by(|&a, &b| spectrum[a].partial_cmp(&spectrum[b]).unwrap())
+            .unwrap();
+        let db = |bin: usize| 20.0 * (spectrum[bin] + 1e-9).log10();
+        let (a, b, c) = (db(peak - 1), db(peak), db(peak + 1));
+        let curvature = a - 2.0 * b + c;
+        let offset = if curvature < 0.0 { 0.5 * (a - c) / curvature } else { 0.0 };
+        (peak as f32 + offset) * self.sample_rate / self.window.len() as f32
+    }
+
     // Compute one slice worth of spectrum. On input, `data` is the same size as the window.
     fn compute_one_window(&mut self, data: &[f32]) {
         for ((i, w), o) in data.iter().zip(self.window.iter()).zip(self.ibuf.iter_mut()) {
@@ -70,30 +221,264 @@ impl Spect {
     }
 
     fn fill_column(&self, img: &mut [u8], x: usize, width: usize) {
-        // TODO: make scaling parameters tunable in constructor
-        let max_amp = 40.0;  // dB
-        let min_amp = max_amp - 120.0;
-
-        let y_scale = 255.0 * 10.0 / 10f32.ln() / (max_amp - min_amp);
-        let y0 = 255.0 - y_scale * max_amp * 10f32.ln() / 10.0;
         let height = self.window.len() / 2;
         let stride = width * 4;
         let mut ix = x * 4 + height * stride;
-        for z in &self.obuf[0..height] {
+        for &bin in &self.row_bins {
             ix -= stride;
-            let y = (z.norm_sqr() + 1e-12).ln();
-            let scaled_y = y0 + y * y_scale;
-            //println!("z = {:?}, y {}, sc_y = {}", z, y, scaled_y);
-            let (r, g, b) = colormap::map_inferno(scaled_y);
+            let (r, g, b) = self.color(bin);
             img[ix] = r;
             img[ix + 1] = g;
             img[ix + 2] = b;
         }
     }
 
+    // The color of a fractional bin of the current spectrum.
+    fn color(&self, bin: f32) -> (u8, u8, u8) {
+        let min_amp = self.max_db - self.range_db;
+        let y_scale = 255.0 / self.range_db;
+        self.colormap.map((self.interp_db(bin) - min_amp) * y_scale)
+    }
+
+    // Level of one bin of the current spectrum, in dB, including the tilt.
+    fn bin_db(&self, bin: usize) -> f32 {
+        let db = 10.0 * (self.obuf[bin].norm_sqr() + 1e-12).log10();
+        db + self.tilt[bin]
+    }
+
+    // Level at a fractional bin, interpolating in dB between bins.
+    fn interp_db(&self, bin: f32) -> f32 {
+        let i = (bin.max(0.0) as usize).min(self.tilt.len() - 1);
+        let frac = bin - i as f32;
+        if frac <= 0.0 || i + 1 == self.tilt.len() {
+            self.bin_db(i)
+        } else {
+            self.bin_db(i) + frac * (self.bin_db(i + 1) - self.bin_db(i))
+        }
+    }
+
     // Create a Hann window of the specified width.
     fn mk_window(width: usize) -> Vec<f32> {
         let d = 2.0 * PI / (width as f32);
         (0..width).map(|i| 0.5 - 0.5 * (i as f32 * d).cos()).collect()
     }
 }
+
+fn hz_to_mel(hz: f32) -> f32 {
+    2595.0 * (1.0 + hz / 700.0).log10()
+}
+
+fn mel_to_hz(mel: f32) -> f32 {
+    700.0 * (10f32.powf(mel / 2595.0) - 1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::{Colormap, FreqScale, Spect};
+
+    // Level in dB of the mean power in each octave band of bins, for a signal
+    // with a 1/f power spectrum.
+    fn octave_levels(spect: &mut Spect) -> Vec<f32> {
+        let width = 1024;
+        let mut rng = 1u32;
+        let mut signal = vec![0.0; width];
+        for k in 1..width / 2 {
+            rng = rng.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let phase = rng as f32 * (2.0 * PI / 4_294_967_296.0);
+            let amp = (k as f32).powf(-0.5);
+            for (i, x) in signal.iter_mut().enumerate() {
+                *x += amp * (2.0 * PI * (k * i) as f32 / width as f32 + phase).sin();
+            }
+        }
+        spect.compute_one_window(&signal);
+        (3..9).map(|octave| {
+            let bins = (1 << octave)..(2 << octave);
+            let n = bins.len() as f32;
+            let power = bins.map(|bin| 10f32.powf(0.1 * spect.bin_db(bin))).sum::<f32>();
+            10.0 * (power / n).log10()
+        }).collect()
+    }
+
+    fn spread(levels: &[f32]) -> f32 {
+        let max = levels.iter().cloned().fold(f32::MIN, f32::max);
+        let min = levels.iter().cloned().fold(f32::MAX, f32::min);
+        max - min
+    }
+
+    #[test]
+    fn tilt_flattens_pink_spectrum() {
+        let mut spect = Spect::new(1024);
+        let untilted = octave_levels(&mut spect);
+        assert!(spread(&untilted) > 12.0, "{:?}", untilted);
+        spect.set_tilt(10.0 * 2f32.log10());
+        let tilted = octave_levels(&mut spect);
+        assert!(spread(&tilted) < 3.5, "{:?}", tilted);
+    }
+
+    #[test]
+    fn db_range_scales_brightness() {
+        // A quiet sine, centered on bin 64, at 20 log10(256 amp) dB in that
+        // bin with the Hann window.
+        let amp = 1e-5;
+        let signal: Vec<f32> = (0..1536)
+            .map(|i| amp * (2.0 * PI * 64.0 * i as f32 / 1024.0).sin())
+            .collect();
+        let db = 20.0 * (256.0 * amp).log10();
+        let mut spect = Spect::new(1024);
+        spect.set_colormap(Colormap::Grayscale);
+        let brightness = |spect: &mut Spect, bin: usize| {
+            let img = spect.generate(&signal);
+            // two columns; bins go up from the bottom row
+            img[(511 - bin) * 2 * 4] as f32
+        };
+        let expected = |max_db: f32, range_db: f32| (db - (max_db - range_db)) * 255.0 / range_db;
+        let default = brightness(&mut spect, 64);
+        assert!((default - expected(40.0, 120.0)).abs() <= 1.0, "{}", default);
+        spect.set_db_range(-40.0, 20.0);
+        let narrowed = brightness(&mut spect, 64);
+        assert!((narrowed - expected(-40.0, 20.0)).abs() <= 1.0, "{}", narrowed);
+        assert!(narrowed > default + 30.0);
+        assert_eq!(brightness(&mut spect, 200), 0.0);
+    }
+
+    #[test]
+    fn grayscale_is_gray() {
+        let signal: Vec<f32> = (0..8192).map(|i| (i as f32 * 0.003 * (i as f32).sqrt()).sin())
+            .collect();
+        let mut spect = Spect::new(1024);
+        spect.set_colormap(Colormap::Grayscale);
+        let img = spect.generate(&signal);
+        assert!(img.chunks(4).all(|p| p[0] == p[1] && p[1] == p[2]));
+        assert!(img.chunks(4).any(|p| p[0] > 0) && img.chunks(4).any(|p| p[0] < 255));
+        spect.set_colormap(Colormap::Viridis);
+        let img = spect.generate(&signal);
+        assert!(img.chunks(4).any(|p| p[0] != p[1]));
+    }
+
+    #[test]
+    fn overlap_sets_hop() {
+        let signal = vec![0.0; 10_000];
+        let mut spect = Spect::new(1024);
+        // (10000 - 1024) / 512 + 1 hops, as before
+        assert_eq!(spect.image_dims(signal.len()), (18, 512));
+        for &(overlap, hop) in &[(0.75, 256), (0.875, 128), (0.0, 1024)] {
+            spect.set_overlap(overlap);
+            let width = (signal.len() - 1024) / hop + 1;
+            assert_eq!(spect.image_dims(signal.len()), (width, 512));
+            assert_eq!(spect.generate(&signal).len(), width * 512 * 4);
+        }
+        assert_eq!(spect.image_dims(1000), (0, 512));
+    }
+
+    #[test]
+    fn log_scale_spaces_octaves_evenly() {
+        // sines centered on the bins of octaves from 4 to 256
+        let bins = [4, 8, 16, 32, 64, 128, 256];
+        let signal: Vec<f32> = (0..1024).map(|i| bins.iter()
+            .map(|&bin| 0.1 * (2.0 * PI * (bin * i) as f32 / 1024.0).sin())
+            .sum()).collect();
+        let mut spect = Spect::new(1024);
+        spect.set_colormap(Colormap::Grayscale);
+        spect.set_freq_scale(FreqScale::Log);
+        let img = spect.generate(&signal);
+        // rows from the bottom of the single column
+        let level = |row: usize| img[(511 - row) * 4];
+        // the brightest row of each run of bright rows
+        let mut peaks: Vec<usize> = Vec::new();
+        for row in 0..512 {
+            if level(row) > 128 {
+                match peaks.last_mut() {
+                    Some(peak) if level(row - 1) > 128 => if level(row) > level(*peak) {
+                        *peak = row;
+                    },
+                    _ => peaks.push(row),
+                }
+            }
+        }
+        assert_eq!(peaks.len(), bins.len(), "{:?}", peaks);
+        let octave = 511.0 * 2f32.ln() / 511f32.ln();
+        for pair in peaks.windows(2) {
+            assert!(((pair[1] - pair[0]) as f32 - octave).abs() <= 1.0, "{:?}", peaks);
+        }
+        // Linear, the octaves double in height.
+        spect.set_freq_scale(FreqScale::Linear);
+        let img = spect.generate(&signal);
+        assert!(bins.iter().all(|&bin| img[(511 - bin) * 4] > 128));
+        spect.set_freq_scale(FreqScale::Mel);
+        assert!((spect.row_bins[511] - 511.0).abs() < 0.01);
+        assert!(spect.row_bins.windows(2).all(|w| w[1] > w[0]));
+    }
+
+    #[test]
+    fn streaming_matches_batch() {
+        let signal: Vec<f32> = (0..6000).map(|i| (i as f32 * 1e-5 * i as f32).sin()).collect();
+        let mut spect = Spect::new(1024);
+        spect.set_overlap(0.75);
+        let img = spect.generate(&signal);
+        let (width, height) = spect.image_dims(signal.len());
+        assert!(spect.process_frame(&signal[..1000]).is_empty());
+        let mut columns = spect.process_frame(&signal[1000..1024]);
+        assert_eq!(columns.len(), 1);
+        for frame in signal[1024..].chunks(256) {
+            columns.extend(spect.process_frame(frame));
+        }
+        // Window-sized frames give several columns at a time.
+        let mut spect = Spect::new(1024);
+        spect.set_overlap(0.75);
+        let mut windowed = Vec::new();
+        for frame in signal.chunks(1024) {
+            windowed.extend(spect.process_frame(frame));
+            assert!(spect.pending.len() < 1024);
+        }
+        assert_eq!(windowed, columns);
+        assert_eq!(columns.len(), width);
+        for (x, column) in columns.iter().enumerate() {
+            assert_eq!(column.len(), height);
+            for (y, &(r, g, b)) in column.iter().enumerate() {
+                let ix = (y * width + x) * 4;
+                assert_eq!([r, g, b], img[ix..ix + 3]);
+            }
+        }
+    }
+
+    #[test]
+    fn spectrum_of_sine() {
+        let signal: Vec<f32> = (0..1024)
+            .map(|i| 0.5 * (2.0 * PI * (100 * i) as f32 / 1024.0).cos())
+            .collect();
+        let mut spect = Spect::new(1024);
+        let spectrum = spect.compute_spectrum(&signal);
+        assert_eq!(spectrum.len(), 512);
+        let peak = (0..512).max_by(|&a, &b| spectrum[a].partial_cmp(&spectrum[b]).unwrap());
+        assert_eq!(peak, Some(100));
+        assert!((spectrum[100] - 0.5 * 256.0).abs() < 0.01, "{}", spectrum[100]);
+        // The Hann window spreads it to the neighboring bins only.
+        assert!((spectrum[99] - 0.5 * 128.0).abs() < 0.01);
+        assert!(spectrum[102] < 1e-3);
+    }
+
+    #[test]
+    fn peak_between_bins() {
+        let mut spect = Spect::new(1024);
+        spect.set_sample_rate(48_000.0);
+        for &bin in &[20.37, 100.5, 311.81] {
+            let freq = bin * 48_000.0 / 1024.0;
+            let signal: Vec<f32> = (0..1024)
+                .map(|i| (2.0 * PI * freq * i as f32 / 48_000.0).sin())
+                .collect();
+            let estimate = spect.estimate_peak(&signal);
+            let cents = 1200.0 * (estimate / freq).log2();
+            assert!(cents.abs() < 3.0, "{} Hz for {} Hz", estimate, freq);
+        }
+    }
+
+    #[test]
+    fn colormaps_by_name() {
+        for &colormap in Colormap::ALL {
+            assert_eq!(Colormap::from_name(colormap.name()), Some(colormap));
+        }
+        assert_eq!(Colormap::from_name("rainbow"), None);
+    }
+}
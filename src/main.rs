@@ -131,7 +131,7 @@ fn main() {
     worker.handle_node(Node::create(module, 3, [], []));
     let module = Box::new(modules::Sin::new(44_100.0));
     worker.handle_node(Node::create(module, 4, [], [(3, 0)]));
-    let module = Box::new(modules::Sum);
+    let module = Box::new(modules::Sum::new());
     worker.handle_node(Node::create(module, 0, [(2, 0), (4, 0)], []));
     */
 
@@ -141,7 +141,7 @@ fn main() {
     worker.handle_node(Node::create(module, 3, [], []));
     let module = Box::new(modules::SmoothCtrl::new(0.5));
     worker.handle_node(Node::create(module, 4, [], []));
-    let module = Box::new(modules::NotePitch::new());
+    let module = Box::new(modules::NotePitch::new(44_100.0));
     worker.handle_node(Node::create(module, 5, [], []));
     let module = Box::new(modules::Biquad::new(44_100.0));
     worker.handle_node(Node::create(module, 6, [(1, 0)], [(3, 0), (4, 0)]));
@@ -209,21 +209,8 @@ fn run_cpal(mut worker: Worker, tx: Sender<Message>) {
             StreamData::Output {
                 buffer: UnknownTypeOutputBuffer::F32(mut buf),
             } => {
-                let mut buf_slice = buf.deref_mut();
-                let mut i = 0;
-                let mut timestamp = time::precise_time_ns();
-                while i < buf_slice.len() {
-                    // should let the graph generate stereo
-                    let buf = worker.work(timestamp)[0].get();
-                    for j in 0..N_SAMPLES_PER_CHUNK {
-                        buf_slice[i + j * 2] = buf[j];
-                        buf_slice[i + j * 2 + 1] = buf[j];
-                    }
-
-                    // TODO: calculate properly, magic value is 64 * 1e9 / 44_100
-                    timestamp += 1451247 * (N_SAMPLES_PER_CHUNK as u64) / 64;
-                    i += N_SAMPLES_PER_CHUNK * 2;
-                }
+                let buf_slice = buf.deref_mut();
+                worker.work_var(buf_slice, 2, time::precise_time_ns());
             }
             _ => panic!("Can't handle output buffer format"),
         }
@@ -292,12 +279,11 @@ fn run_audio_unit(mut worker: Worker) -> Result<AudioUnit, coreaudio::Error> {
         let mut i = 0;
         let mut timestamp = time::precise_time_ns();
         while i < num_frames {
-            // should let the graph generate stereo
-            let buf = worker.work(timestamp)[0].get();
-            for j in 0..N_SAMPLES_PER_CHUNK {
-                for channel in data.channels_mut() {
-                    channel[i + j] = buf[j];
-                }
+            // a mono root is played on all channels
+            let bufs = worker.work(timestamp);
+            for (c, channel) in data.channels_mut().enumerate() {
+                let buf = bufs[c.min(bufs.len() - 1)].get();
+                channel[i..i + N_SAMPLES_PER_CHUNK].copy_from_slice(buf);
             }
             // TODO: calculate properly, magic value is 64 * 1e9 / 44_100
             timestamp += 1451247 * (N_SAMPLES_PER_CHUNK as u64) / 64;
@@ -25,6 +25,9 @@ mod bench {
     use synthesizer_io_core::module::{Module, Buffer};
     use synthesizer_io_core::modules::Sin;
     use synthesizer_io_core::modules::Biquad;
+    use synthesizer_io_core::modules;
+    use synthesizer_io_core::graph::{Graph, Message, Node};
+    use synthesizer_io_core::queue::Item;
 
     #[bench]
     fn sin(b: &mut Bencher) {
@@ -84,6 +87,77 @@ mod bench {
         })
     }
 
+    // A large patch: 64 voices of saw into a filter, mixed by a tree of sums.
+    // Compare graph_serial with graph_parallel on a machine with 4+ cores.
+    fn big_graph(n_threads: usize) -> Graph {
+        let mut graph = Graph::new(256);
+        graph.set_threads(n_threads);
+        {
+            let mut add = |node: Node| {
+                let ix = node.ix;
+                graph.replace(ix, Some(Item::make_item(Message::Node(node))));
+            };
+            add(Node::create(Box::new(modules::ConstCtrl::new(0.5)), 1, [], []));
+            let mut sums = Vec::new();
+            for i in 0..8 {
+                let mut inputs = Vec::new();
+                for j in 0..8 {
+                    let ix = 16 + 3 * (8 * i + j);
+                    let pitch = 110f32.log2() + (8 * i + j) as f32 / 12.0;
+                    add(Node::create(Box::new(modules::ConstCtrl::new(pitch)), ix, [], []));
+                    add(Node::create(Box::new(modules::Saw::new(44_100.0)), ix + 1, [],
+                        [(ix, 0)]));
+                    add(Node::create(Box::new(Biquad::new(44_100.0)), ix + 2,
+                        [(ix + 1, 0)], [(ix, 0), (1, 0)]));
+                    inputs.push((ix + 2, 0));
+                }
+                add(Node::create(Box::new(modules::Sum::new()), 2 + i, inputs, []));
+                sums.push((2 + i, 0));
+            }
+            add(Node::create(Box::new(modules::Sum::new()), 0, sums, []));
+        }
+        graph
+    }
+
+    #[bench]
+    fn graph_serial(b: &mut Bencher) {
+        let mut graph = big_graph(1);
+        b.iter(|| graph.run_graph(0, 0))
+    }
+
+    #[bench]
+    fn graph_parallel(b: &mut Bencher) {
+        let mut graph = big_graph(4);
+        b.iter(|| graph.run_graph(0, 0))
+    }
+
+    // A tree of 50 cheap nodes, to measure the cost of sorting the graph.
+    fn sort_graph() -> Graph {
+        let n = 50;
+        let mut graph = Graph::new(n);
+        for ix in 0..n {
+            let inputs: Vec<_> = (2 * ix + 1..n.min(2 * ix + 3)).map(|i| (i, 0)).collect();
+            let node = Node::create(Box::new(modules::ConstCtrl::new(0.0)), ix, [], inputs);
+            graph.replace(ix, Some(Item::make_item(Message::Node(node))));
+        }
+        graph
+    }
+
+    #[bench]
+    fn graph_sort_cached(b: &mut Bencher) {
+        let mut graph = sort_graph();
+        b.iter(|| graph.run_graph(0, 0))
+    }
+
+    #[bench]
+    fn graph_sort_uncached(b: &mut Bencher) {
+        let mut graph = sort_graph();
+        b.iter(|| {
+            graph.invalidate();
+            graph.run_graph(0, 0)
+        })
+    }
+
     #[bench]
     fn tan(b: &mut Bencher) {
         b.iter(|| {
@@ -0,0 +1,105 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Coalescing of parameter changes on their way to the worker.
+
+use graph::{Message, SetParam};
+use queue::Sender;
+
+/// A wrapper around the sender to the worker that holds back parameter
+/// changes until `flush`, keeping only the latest for each parameter. A dense
+/// stream of MIDI controller values then costs one message per flush rather
+/// than one per value. Flushing once per chunk loses nothing audible, as
+/// parameters only change at chunk boundaries anyway.
+///
+/// Other messages flush the held changes before being sent, so the worker
+/// sees everything in order.
+pub struct ParamCoalescer {
+    tx: Sender<Message>,
+    pending: Vec<SetParam>,
+}
+
+impl ParamCoalescer {
+    pub fn new(tx: Sender<Message>) -> ParamCoalescer {
+        ParamCoalescer { tx, pending: Vec::new() }
+    }
+
+    /// Queue a parameter change, replacing any pending one for the same
+    /// parameter.
+    pub fn set_param(&mut self, param: SetParam) {
+        if let Some(pos) = self.pending.iter()
+            .position(|p| p.ix == param.ix && p.param_ix == param.param_ix)
+        {
+            self.pending.remove(pos);
+        }
+        self.pending.push(param);
+    }
+
+    /// Send a message, after any pending parameter changes.
+    pub fn send(&mut self, msg: Message) {
+        self.flush();
+        self.tx.send(msg);
+    }
+
+    /// Send the pending parameter changes.
+    pub fn flush(&mut self) {
+        for param in self.pending.drain(..) {
+            self.tx.send(Message::SetParam(param));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::{Node, SetParam};
+    use module::{Buffer, Module};
+    use worker::Worker;
+    use super::ParamCoalescer;
+
+    // Outputs the number of param changes, and the last value.
+    struct ParamCount(usize, f32);
+
+    impl Module for ParamCount {
+        fn n_bufs_out(&self) -> usize { 1 }
+
+        fn set_param(&mut self, _param_ix: usize, val: f32, _timestamp: u64) {
+            self.0 += 1;
+            self.1 = val;
+        }
+
+        fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
+            _buf_in: &[&Buffer], buf_out: &mut [Buffer])
+        {
+            let out = buf_out[0].get_mut();
+            out[0] = self.0 as f32;
+            out[1] = self.1;
+        }
+    }
+
+    #[test]
+    fn only_latest_value_applied() {
+        let (mut worker, tx, _rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(ParamCount(0, 0.0)), 0, [], []));
+        let mut coalescer = ParamCoalescer::new(tx);
+        worker.work(0);
+        for i in 0..1000 {
+            let param = SetParam { ix: 0, param_ix: 0, val: i as f32, timestamp: 0 };
+            coalescer.set_param(param);
+        }
+        coalescer.flush();
+        let out = worker.work(0)[0].get();
+        assert_eq!(out[0], 1.0);
+        assert_eq!(out[1], 999.0);
+    }
+}
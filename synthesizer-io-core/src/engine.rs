@@ -14,13 +14,19 @@
 
 //! Interface for the audio engine.
 
+use std::collections::{BTreeMap, HashMap};
+use std::mem;
+
 use time;
 
+use coalesce::ParamCoalescer;
 use id_allocator::IdAllocator;
 use graph::{IntoBoxedSlice, Message, Node, Note, SetParam};
-use module::Module;
+use json::Value;
+use module::{Module, ParamDesc, ParamKind, N_SAMPLES_PER_CHUNK};
 use modules;
 use queue::{Receiver, Sender};
+use worker::Worker;
 
 /// The interface from the application to the audio engine.
 ///
@@ -40,20 +46,60 @@ pub type NodeId = usize;
 
 /// The type of a module to be instantiated. It's not clear this should be
 /// an enum, but it should do for now.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ModuleType {
     Sin,
     Saw,
+    Biquad,
 }
 
+/// Polyphonic aftertouch, in octaves of filter cutoff.
+const PRESSURE: ParamDesc = ParamDesc {
+    name: "pressure",
+    min: 0.0,
+    max: 2.0,
+    default: 0.0,
+    kind: ParamKind::Linear,
+};
+
 /// The core owns the connection to the real-time worker.
 struct Core {
     sample_rate: f32,
     rx: Receiver<Message>,
-    tx: Sender<Message>,
+    tx: ParamCoalescer,
+    // whether parameter changes are held back until flushed
+    coalesce: bool,
 
     id_alloc: IdAllocator,
 
     monitor_queues: Option<MonitorQueues>,
+
+    // node number of the fader controlling the master gain
+    master_fader: Option<usize>,
+
+    // node numbers of the ext bus attenuator and its gain control
+    ext_atten: Option<(usize, usize)>,
+
+    auto_wah: Option<AutoWah>,
+
+    // every node created, for saving presets
+    patch: BTreeMap<usize, NodeRecord>,
+}
+
+/// What's needed to rebuild a node: the module's type tag and param values
+/// (if it can be saved), kept up to date as params are set, and its wiring.
+struct NodeRecord {
+    tag: Option<String>,
+    values: Vec<f32>,
+    bufs: Vec<(usize, usize)>,
+    ctrls: Vec<(usize, usize)>,
+}
+
+/// Control nodes of the auto-wah effect.
+struct AutoWah {
+    sensitivity: usize,
+    range: usize,
+    reso: usize,
 }
 
 #[derive(Clone)]
@@ -66,6 +112,62 @@ pub struct NoteEvent {
 struct Midi {
     control_map: ControlMap,
     cur_note: Option<u8>,
+
+    // notes held down on the monosynth, with their velocities, most recent
+    // last, so releasing one falls back to the one before
+    held: Vec<(u8, u8)>,
+
+    // release control value, for estimating when a voice has gone quiet
+    release: f32,
+
+    // pitch bend range in semitones, and the current bend in octaves
+    bend_range: f32,
+    bend: f32,
+
+    // velocity sensitivity of the envelope; see `Engine::set_velocity_sensitivity`
+    velocity_amount: f32,
+
+    // whether overlapping notes on the monosynth are played legato
+    legato: bool,
+
+    // the channel listened to, counting from 0, or None for omni
+    channel: Option<u8>,
+
+    // status byte of the last channel message, for running status
+    running_status: Option<u8>,
+
+    // whether the sustain pedal is down, and the notes released under it
+    sustain: bool,
+    sustained: Vec<u8>,
+
+    poly: Option<Poly>,
+}
+
+/// State for dynamic allocation of polysynth voices.
+struct Poly {
+    max_voices: usize,
+
+    // node number of the sum of active voice outputs
+    voice_bus: usize,
+
+    // parallel to `ControlMap::voices`
+    voices: Vec<VoiceState>,
+
+    // amount of pan by note number; see `Engine::set_key_pan`
+    key_pan: f32,
+}
+
+struct VoiceState {
+    note: Option<u8>,
+
+    // timestamp of the last note-on, for stealing the oldest note
+    started_at: u64,
+
+    // timestamp of the last note-off
+    released_at: u64,
+
+    // whether the voice is connected to the voice bus, and thus running
+    active: bool,
 }
 
 struct ControlMap {
@@ -80,12 +182,39 @@ struct ControlMap {
     // node number of node that can be replaced to inject more audio
     ext: usize,
 
+    voices: Vec<VoiceMap>,
+
+    // MIDI controller number to the node it sets, and the range it maps to
+    cc: HashMap<u8, (usize, f32, f32)>,
+
+    // the node set by channel pressure, and its range, if mapped
+    pressure: Option<(usize, f32, f32)>,
+}
+
+/// Nodes that are controlled independently for each voice.
+struct VoiceMap {
     note_receivers: Vec<usize>,
+
+    // node number of the NotePitch, which applies pitch bend
+    pitch: usize,
+
+    // node number of the Adsr
+    adsr: usize,
+
+    // node number of the polyphonic aftertouch control
+    pressure: usize,
+
+    // node number of the pan position control, for stereo voices
+    pan: Option<usize>,
+
+    // node number of the voice's audio output
+    out: usize,
 }
 
 struct MonitorQueues {
     rx: Receiver<Vec<f32>>,
     tx: Sender<Vec<f32>>,
+    channels: usize,
 }
 
 impl Engine {
@@ -100,7 +229,91 @@ impl Engine {
     /// Initialize the engine with a simple mono synth.
     pub fn init_monosynth(&mut self) {
         let control_map = self.core.init_monosynth();
-        self.midi = Some(Midi::new(control_map));
+        self.midi = Some(Midi::new(control_map, None));
+    }
+
+    /// Initialize the engine with a polyphonic synth. Voices are allocated
+    /// as notes are played, up to `max_voices`, and disconnected from the
+    /// graph when they go idle so they don't use any CPU. Beyond that, a new
+    /// note steals the voice of the oldest one. The output is stereo, each
+    /// voice panned according to `set_key_pan`.
+    pub fn init_polysynth(&mut self, max_voices: usize) {
+        let (control_map, voice_bus) = self.core.init_polysynth();
+        let poly = Poly {
+            max_voices,
+            voice_bus,
+            voices: Vec::new(),
+            key_pan: 0.0,
+        };
+        self.midi = Some(Midi::new(control_map, Some(poly)));
+    }
+
+    /// Pan each voice of the polysynth by its note number, like the strings of
+    /// a piano heard from the player's seat. With an amount of 1, the lowest
+    /// key of a piano keyboard is panned hard left and the highest hard right;
+    /// negative amounts reverse that. Applies from the next note played.
+    pub fn set_key_pan(&mut self, amount: f32) {
+        if let Some(Midi { poly: Some(ref mut poly), .. }) = self.midi {
+            poly.key_pan = amount;
+        }
+    }
+
+    /// Map a MIDI controller to set the node `node`, with controller values
+    /// from 0 to 127 mapped linearly to the range `lo` to `hi`. This replaces
+    /// any existing mapping of the controller, including the default ones
+    /// (1 and 2 for cutoff and resonance, 5 to 8 for the envelope). Messages
+    /// from unmapped controllers are ignored.
+    pub fn map_cc(&mut self, cc: u8, node: usize, lo: f32, hi: f32) {
+        if let Some(ref mut midi) = self.midi {
+            midi.control_map.cc.insert(cc, (node, lo, hi));
+        }
+    }
+
+    /// Map channel pressure to set the node `node`, with pressure from 0 to
+    /// 127 mapped linearly to the range `lo` to `hi`, for example to the
+    /// cutoff or the ext bus gain. Unmapped, channel pressure acts like
+    /// polyphonic aftertouch on every note being played, opening its filter.
+    pub fn map_pressure(&mut self, node: usize, lo: f32, hi: f32) {
+        if let Some(ref mut midi) = self.midi {
+            midi.control_map.pressure = Some((node, lo, hi));
+        }
+    }
+
+    /// Listen only to the given MIDI channel, numbered 1 to 16, or to all
+    /// of them (omni, the default) if `None`.
+    pub fn set_midi_channel(&mut self, channel: Option<u8>) {
+        if let Some(ref mut midi) = self.midi {
+            midi.channel = channel.map(|c| c.clamp(1, 16) - 1);
+        }
+    }
+
+    /// Set the range of the pitch bend wheel, in semitones each way. The
+    /// default is 2. Applies from the next bend message.
+    pub fn set_bend_range(&mut self, semitones: f32) {
+        if let Some(ref mut midi) = self.midi {
+            midi.bend_range = semitones;
+        }
+    }
+
+    /// Set how much note velocity affects the level, from 0 (the default,
+    /// every note at full level) to 1 (level proportional to velocity).
+    pub fn set_velocity_sensitivity(&mut self, amount: f32) {
+        if let Some(ref mut midi) = self.midi {
+            midi.velocity_amount = amount;
+            for voice in &midi.control_map.voices {
+                let param = SetParam { ix: voice.adsr, param_ix: 1, val: amount, timestamp: 0 };
+                self.core.send_param(param);
+            }
+        }
+    }
+
+    /// Set legato mode on the monosynth. When on, a note played while
+    /// another is held changes the pitch (gliding, if the NotePitch has a
+    /// glide time) without retriggering the envelope. Off by default.
+    pub fn set_legato(&mut self, legato: bool) {
+        if let Some(ref mut midi) = self.midi {
+            midi.legato = legato;
+        }
     }
 
     /// Handle a MIDI event.
@@ -117,12 +330,141 @@ impl Engine {
         }
     }
 
+    /// Set parameter `param_ix` of node `ix` directly, as of `timestamp`.
+    pub fn set_param(&mut self, ix: usize, param_ix: usize, val: f32, timestamp: u64) {
+        self.core.send_param(SetParam { ix, param_ix, val, timestamp });
+    }
+
+    /// Fade the output to silence over the given duration, in seconds,
+    /// starting at `ts`.
+    pub fn fade_out(&mut self, duration: f32, ts: u64) {
+        self.core.fade(0, duration, ts);
+    }
+
+    /// Fade the output up to full level over the given duration, in seconds,
+    /// starting at `ts`.
+    pub fn fade_in(&mut self, duration: f32, ts: u64) {
+        self.core.fade(1, duration, ts);
+    }
+
+    /// Stop all sound immediately, resetting the state of every module, so
+    /// that envelopes go quiet and filter and delay tails are flushed. The
+    /// graph itself is kept.
+    pub fn panic_reset(&mut self) {
+        self.core.send(Message::ResetAll);
+        if let Some(ref mut midi) = self.midi {
+            midi.cur_note = None;
+            midi.held.clear();
+            midi.sustained.clear();
+            if let Some(ref mut poly) = midi.poly {
+                for voice in &mut poly.voices {
+                    voice.note = None;
+                    voice.released_at = 0;
+                }
+            }
+        }
+    }
+
+    /// Insert an auto-wah on the ext bus, for processing injected audio. This
+    /// is a lowpass filter whose cutoff opens up following the amplitude
+    /// envelope of the input.
+    pub fn enable_auto_wah(&mut self) {
+        if let Some(ref midi) = self.midi {
+            self.core.enable_auto_wah(midi.control_map.ext);
+        }
+    }
+
+    /// Set the auto-wah parameters: sensitivity is log2 of gain applied to the
+    /// envelope, range is how many octaves the cutoff opens at full envelope,
+    /// and resonance is in [0, 1). The change is made at `ts`.
+    pub fn set_auto_wah(&mut self, sensitivity: f32, range: f32, resonance: f32, ts: u64) {
+        let ixs = self.core.auto_wah.as_ref()
+            .map(|wah| [wah.sensitivity, wah.range, wah.reso]);
+        if let Some(ixs) = ixs {
+            for (&ix, &val) in ixs.iter().zip([sensitivity, range, resonance].iter()) {
+                self.core.set_param(ix, val, ts);
+            }
+        }
+    }
+
+    /// Coalesce parameter changes, so that only the latest value of each is
+    /// sent to the worker on `flush`. This keeps dense streams of controller
+    /// changes from flooding the queue.
+    pub fn set_coalesce_params(&mut self, coalesce: bool) {
+        self.core.coalesce = coalesce;
+        if !coalesce {
+            self.core.tx.flush();
+        }
+    }
+
+    /// Send the parameter changes held back by coalescing. Call this once per
+    /// chunk, for example just before running the worker. Polling the return
+    /// queue also does this.
+    pub fn flush(&mut self) {
+        self.core.tx.flush();
+    }
+
     /// Poll the return queue. Right now this just returns the number of items
     /// retrieved.
     pub fn poll_rx(&mut self) -> usize {
+        self.poll_rx_at(time::precise_time_ns())
+    }
+
+    /// Poll the return queue, where the current time is `ts`. This is for
+    /// offline rendering, where time is counted in samples rather than read
+    /// from the clock.
+    pub fn poll_rx_at(&mut self, ts: u64) -> usize {
+        self.core.tx.flush();
+        if let Some(ref mut midi) = self.midi {
+            midi.reclaim_idle(&mut self.core, ts);
+        }
         self.core.poll_rx()
     }
 
+    /// Render `n_samples` of audio with no audio device, stepping `worker`,
+    /// which must be the one connected to this engine, starting from time 0.
+    /// Each message in `events` is sent just before the chunk containing its
+    /// time, in ns; they must be in time order. Stereo output is mixed down
+    /// to mono. The result depends only on the graph and the events, so this
+    /// is good for testing and bouncing.
+    pub fn render_offline(&mut self, worker: &mut Worker, events: Vec<(u64, Message)>,
+        n_samples: usize) -> Vec<f32>
+    {
+        let sample_rate = self.core.sample_rate;
+        worker.set_sample_rate(sample_rate);
+        let chunk_ns = |chunk: usize| {
+            (chunk as f64 * N_SAMPLES_PER_CHUNK as f64 * 1e9 / sample_rate as f64) as u64
+        };
+        let mut events = events.into_iter().peekable();
+        let mut out = Vec::with_capacity(n_samples + N_SAMPLES_PER_CHUNK);
+        let mut chunk = 0;
+        while out.len() < n_samples {
+            let (ts, chunk_end) = (chunk_ns(chunk), chunk_ns(chunk + 1));
+            while let Some((_, msg)) = events.next_if(|&(t, _)| t < chunk_end) {
+                self.core.send(msg);
+            }
+            self.core.tx.flush();
+            {
+                let bufs = worker.work(ts);
+                let channels = bufs.len().min(2);
+                for i in 0..N_SAMPLES_PER_CHUNK {
+                    let sum: f32 = bufs[..channels].iter().map(|buf| buf.get()[i]).sum();
+                    out.push(sum / channels.max(1) as f32);
+                }
+            }
+            self.poll_rx_at(ts);
+            chunk += 1;
+        }
+        out.truncate(n_samples);
+        out
+    }
+
+    /// The number of messages waiting on the return queue, for monitoring.
+    /// It doesn't dequeue them; `poll_rx` does that.
+    pub fn rx_depth(&self) -> usize {
+        self.core.rx.approx_len()
+    }
+
     /// Poll the monitor queue, retrieving audio data.
     pub fn poll_monitor(&mut self) -> Vec<f32> {
         self.core.poll_monitor()
@@ -138,13 +480,121 @@ impl Engine {
         self.core.instantiate_module(node_id, ty)
     }
 
+    /// Remove a low-level graph node, freeing its id for reuse. Nothing still
+    /// in use should be wired to it.
+    pub fn remove_node(&mut self, id: usize) {
+        self.core.remove_node(id);
+    }
+
+    /// Wire the audio inputs of a module made by `instantiate_module` to the
+    /// first output of each of the given nodes, replacing its old wiring.
+    /// Nodes whose modules can't be rebuilt from a type tag are left alone.
+    pub fn set_module_inputs(&mut self, ll_id: usize, inputs: &[usize]) {
+        self.core.set_module_inputs(ll_id, inputs);
+    }
+
+    /// The nodes wired to the audio inputs of a node, or `None` if there's
+    /// no node at that id.
+    pub fn module_inputs(&self, ll_id: usize) -> Option<Vec<usize>> {
+        self.core.patch.get(&ll_id).map(|node| node.bufs.iter().map(|&(n, _)| n).collect())
+    }
+
+    /// Remove a module made by `instantiate_module`, along with the control
+    /// nodes that were made to drive it.
+    pub fn remove_module(&mut self, ll_id: usize) {
+        self.core.remove_module(ll_id);
+    }
+
+    /// Save the patch as JSON: the nodes of the graph, with their param
+    /// values and wiring, and how MIDI drives them. The notes being played
+    /// aren't saved. Fails if a node's module doesn't report a type tag, so
+    /// it couldn't be loaded again, or if a param value isn't finite.
+    pub fn save_preset(&self) -> Result<String, String> {
+        let core = &self.core;
+        let nodes = core.patch.iter()
+            .map(|(&id, node)| {
+                let tag = node.tag.as_ref()
+                    .ok_or_else(|| format!("node {} has a module that can't be saved", id))?;
+                Ok(Value::object(vec![
+                    ("id", id.into()),
+                    ("type", tag.as_str().into()),
+                    ("params", node.values.clone().into()),
+                    ("bufs", wiring_to_json(&node.bufs)),
+                    ("ctrls", wiring_to_json(&node.ctrls)),
+                ]))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let auto_wah = core.auto_wah.as_ref().map(|w| vec![w.sensitivity, w.range, w.reso]);
+        Value::object(vec![
+            ("nodes", nodes.into()),
+            ("master_fader", core.master_fader.into()),
+            ("ext_atten", core.ext_atten.map(|(atten, gain)| vec![atten, gain]).into()),
+            ("auto_wah", auto_wah.into()),
+            ("midi", self.midi.as_ref().map(Midi::to_json).into()),
+        ]).to_json()
+    }
+
+    /// Load a patch saved by `save_preset`, replacing the current one. The
+    /// modules are built for this engine's sample rate. On error, the engine
+    /// is left unchanged.
+    pub fn load_preset(&mut self, preset: &str) -> Result<(), String> {
+        let value = Value::parse(preset)?;
+        let mut nodes = BTreeMap::new();
+        for node in value.field("nodes")?.as_array()? {
+            let id = node.field("id")?.as_usize()?;
+            let tag = node.field("type")?.as_str()?;
+            let values = node.field("params")?.as_array()?.iter()
+                .map(Value::as_f32)
+                .collect::<Result<Vec<_>, _>>()?;
+            if tag != "monitor" && modules::from_preset(tag, &values, 1.0).is_none() {
+                return Err(format!("unknown module type {}", tag));
+            }
+            let record = NodeRecord {
+                tag: Some(tag.to_string()),
+                values,
+                bufs: wiring_from_json(node.field("bufs")?)?,
+                ctrls: wiring_from_json(node.field("ctrls")?)?,
+            };
+            if nodes.insert(id, record).is_some() {
+                return Err(format!("node {} appears twice", id));
+            }
+        }
+        for (id, node) in &nodes {
+            if let Some(&(from, _)) = node.bufs.iter().chain(&node.ctrls)
+                .find(|&&(from, _)| !nodes.contains_key(&from))
+            {
+                return Err(format!("node {} is wired to missing node {}", id, from));
+            }
+        }
+        let master_fader = value.field("master_fader")?.as_option()
+            .map(Value::as_usize).transpose()?;
+        let ext_atten = node_ids(&value, "ext_atten", 2)?;
+        let auto_wah = node_ids(&value, "auto_wah", 3)?;
+        let midi = value.field("midi")?.as_option().map(Midi::from_json).transpose()?;
+
+        self.core.load_nodes(nodes);
+        self.core.master_fader = master_fader;
+        self.core.ext_atten = ext_atten.map(|ids| (ids[0], ids[1]));
+        self.core.auto_wah = auto_wah.map(|ids| AutoWah {
+            sensitivity: ids[0],
+            range: ids[1],
+            reso: ids[2],
+        });
+        if let Some(Midi { poly: Some(ref poly), ref control_map, .. }) = midi {
+            // Voices start out idle.
+            poly.update_bus(&mut self.core, control_map);
+        }
+        self.midi = midi;
+        Ok(())
+    }
+
     /// Set the output bus.
     pub fn set_outputs(&mut self, outputs: &[usize]) {
         let sum_node = match self.midi {
             Some(Midi { control_map: ControlMap{ ext, .. }, .. }) => ext,
             _ => 0,
         };
-        self.core.update_sum_node(sum_node, outputs);
+        self.core.update_sum_node(sum_node, outputs, 1);
     }
 }
 
@@ -153,7 +603,16 @@ impl Core {
         let mut id_alloc = IdAllocator::new();
         id_alloc.reserve(0);
         let monitor_queues = None;
-        Core { sample_rate, rx, tx, id_alloc, monitor_queues }
+        let master_fader = None;
+        let ext_atten = None;
+        let auto_wah = None;
+        let tx = ParamCoalescer::new(tx);
+        let coalesce = false;
+        let patch = BTreeMap::new();
+        Core {
+            sample_rate, rx, tx, coalesce, id_alloc, monitor_queues, master_fader, ext_atten,
+            auto_wah, patch,
+        }
     }
 
     pub fn create_node<B1: IntoBoxedSlice<(usize, usize)>,
@@ -162,39 +621,94 @@ impl Core {
         (&mut self, module: M, in_buf_wiring: B1, in_ctrl_wiring: B2) -> usize
     {
         let id = self.id_alloc.alloc();
-        self.send_node(Node::create(Box::new(module), id, in_buf_wiring, in_ctrl_wiring));
+        self.put_node(id, Box::new(module), in_buf_wiring.into_box(), in_ctrl_wiring.into_box());
         id
     }
 
-    fn init_monosynth(&mut self) -> ControlMap {
-        let sample_rate = self.sample_rate;
-        let note_pitch = self.create_node(modules::NotePitch::new(), [], []);
-        let saw = self.create_node(modules::Saw::new(sample_rate), [], [(note_pitch, 0)]);
-        let cutoff = self.create_node(modules::SmoothCtrl::new(880.0f32.log2()), [], []);
-        let reso = self.create_node(modules::SmoothCtrl::new(0.5), [], []);
-        let filter_out = self.create_node(modules::Biquad::new(sample_rate),
-            [(saw, 0)], [(cutoff, 0), (reso, 0)]);
+    // Send a node to the worker, creating or replacing the one at `id`, and
+    // record it in the patch.
+    fn put_node(&mut self, id: usize, module: Box<dyn Module>, bufs: Box<[(usize, usize)]>,
+        ctrls: Box<[(usize, usize)]>)
+    {
+        let record = NodeRecord {
+            tag: module.type_tag().map(String::from),
+            values: module.param_values(),
+            bufs: bufs.to_vec(),
+            ctrls: ctrls.to_vec(),
+        };
+        self.patch.insert(id, record);
+        self.send_node(Node::create(module, id, bufs, ctrls));
+    }
 
-        let attack = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
-        let decay = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
-        let sustain = self.create_node(modules::SmoothCtrl::new(4.0), [], []);
-        let release = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
-        let adsr = self.create_node(modules::Adsr::new(), [],
-            vec![(attack, 0), (decay, 0), (sustain, 0), (release, 0)]);
-        let env_out = self.create_node(modules::Gain::new(), [(filter_out, 0)], [(adsr, 0)]);
+    /// Remove a node and free its id. The worker sends the old node back, so
+    /// it's dropped when the return queue is polled.
+    fn remove_node(&mut self, id: usize) {
+        self.patch.remove(&id);
+        self.send(Message::Remove(id));
+        // The id can be reused right away: the worker holds this removal, and
+        // whatever is sent after it, behind any params or notes still pending
+        // for the old node.
+        self.id_alloc.free(id);
+    }
 
-        let ext = self.create_node(modules::Sum::new(), [], []);
-        let ext_gain = self.create_node(modules::ConstCtrl::new(-2.0), [], []);
-        let ext_atten = self.create_node(modules::Gain::new(), [(ext, 0)], [(ext_gain, 0)]);
+    fn set_module_inputs(&mut self, ll_id: usize, inputs: &[usize]) {
+        let bufs: Vec<_> = inputs.iter().map(|&n| (n, 0)).collect();
+        let sample_rate = self.sample_rate;
+        let rebuilt = match self.patch.get(&ll_id) {
+            Some(node) if node.bufs != bufs => node.tag.as_ref()
+                .and_then(|tag| modules::from_preset(tag, &node.values, sample_rate))
+                .map(|module| (module, node.ctrls.clone())),
+            _ => None,
+        };
+        let (module, ctrls) = match rebuilt {
+            Some(rebuilt) => rebuilt,
+            None => return,
+        };
+        // The new node takes over the old one's state in `migrate`, which the
+        // modules made by `instantiate_module` implement.
+        self.put_node(ll_id, module, bufs.into_boxed_slice(), ctrls.into_boxed_slice());
+    }
 
-        let monitor_in = self.create_node(modules::Sum::new(), [(env_out, 0), (ext_atten, 0)], []);
+    fn remove_module(&mut self, ll_id: usize) {
+        let ctrls = self.patch.get(&ll_id).map(|node| node.ctrls.clone()).unwrap_or_default();
+        self.remove_node(ll_id);
+        for (ctrl_id, _) in ctrls {
+            self.remove_node(ctrl_id);
+        }
+    }
 
-        let (monitor, tx, rx) = modules::Monitor::new();
-        self.monitor_queues = Some(MonitorQueues { tx, rx });
-        let monitor = self.create_node(monitor, [(monitor_in, 0)], []);
+    fn init_monosynth(&mut self) -> ControlMap {
+        let mut control_map = self.create_controls();
+        let voice = self.create_voice(&control_map);
+        self.create_output(&control_map, voice.out, 1);
+        control_map.voices.push(voice);
+        control_map
+    }
 
-        self.update_sum_node(0, &[monitor]);
+    // Returns the control map (with no voices) and the voice bus.
+    fn init_polysynth(&mut self) -> (ControlMap, usize) {
+        let control_map = self.create_controls();
+        let voice_bus = self.create_node(modules::Sum::new_channels(2), [], []);
+        self.create_output(&control_map, voice_bus, 2);
+        (control_map, voice_bus)
+    }
 
+    // Create the controls shared by all voices.
+    fn create_controls(&mut self) -> ControlMap {
+        let filter = modules::Biquad::PARAMS;
+        let env = modules::Adsr::PARAMS;
+        let cutoff = self.create_node(modules::SmoothCtrl::with_desc(filter[0]), [], []);
+        let reso = self.create_node(modules::SmoothCtrl::with_desc(filter[1]), [], []);
+        let attack = self.create_node(modules::SmoothCtrl::with_desc(env[0]), [], []);
+        let decay = self.create_node(modules::SmoothCtrl::with_desc(env[1]), [], []);
+        let sustain = self.create_node(modules::SmoothCtrl::with_desc(env[2]), [], []);
+        let release = self.create_node(modules::SmoothCtrl::with_desc(env[3]), [], []);
+        let ext = self.create_node(modules::Sum::new(), [], []);
+        let cc = [(1, cutoff, filter[0]), (2, reso, filter[1]), (5, attack, env[0]),
+            (6, decay, env[1]), (7, sustain, env[2]), (8, release, env[3])]
+            .iter()
+            .map(|&(cc, node, desc)| (cc, (node, desc.min, desc.max)))
+            .collect();
         ControlMap {
             cutoff,
             reso,
@@ -203,27 +717,148 @@ impl Core {
             sustain,
             release,
             ext,
+            voices: Vec::new(),
+            cc,
+            pressure: None,
+        }
+    }
+
+    fn create_voice(&mut self, control_map: &ControlMap) -> VoiceMap {
+        let sample_rate = self.sample_rate;
+        let note_pitch = self.create_node(modules::NotePitch::new(sample_rate), [], []);
+        let saw = self.create_node(modules::Saw::new(sample_rate), [], [(note_pitch, 0)]);
+        let pressure = self.create_node(modules::SmoothCtrl::with_desc(PRESSURE), [], []);
+        let voice_cutoff = self.create_node(modules::CtrlSum::new(), [],
+            [(control_map.cutoff, 0), (pressure, 0)]);
+        let filter_out = self.create_node(modules::Biquad::new(sample_rate),
+            [(saw, 0)], [(voice_cutoff, 0), (control_map.reso, 0)]);
+
+        let adsr = self.create_node(modules::Adsr::new(), [],
+            vec![(control_map.attack, 0), (control_map.decay, 0), (control_map.sustain, 0),
+                (control_map.release, 0)]);
+        let env_out = self.create_node(modules::Gain::new(), [(filter_out, 0)], [(adsr, 0)]);
+        VoiceMap {
             note_receivers: vec![note_pitch, adsr],
+            pitch: note_pitch,
+            adsr,
+            pressure,
+            pan: None,
+            out: env_out,
         }
     }
 
-    fn send(&self, msg: Message) {
+    // Make the voice stereo, through a panner.
+    fn pan_voice(&mut self, voice: &mut VoiceMap) {
+        let pan = self.create_node(modules::ConstCtrl::new(0.0), [], []);
+        voice.out = self.create_node(modules::Pan::new(), [(voice.out, 0)], [(pan, 0)]);
+        voice.pan = Some(pan);
+    }
+
+    // Create the output stage, mixing the given voice output with the ext bus,
+    // and set it as the root of the graph. The voice output and the stages
+    // after the mix have the given number of channels; the ext bus is mono.
+    fn create_output(&mut self, control_map: &ControlMap, voices_out: usize, channels: usize) {
+        let sample_rate = self.sample_rate;
+        let ext_gain = self.create_node(modules::ConstCtrl::new(-2.0), [], []);
+        let ext_atten = self.create_node(modules::Gain::new(), [(control_map.ext, 0)],
+            [(ext_gain, 0)]);
+        self.ext_atten = Some((ext_atten, ext_gain));
+
+        let mix_in: Vec<_> = (0..channels).map(|c| (voices_out, c))
+            .chain((0..channels).map(|_| (ext_atten, 0)))
+            .collect();
+        let mix = self.create_node(modules::Sum::new_channels(channels), mix_in, []);
+        let master_fader = self.create_node(modules::Fader::new(sample_rate), [], []);
+        self.master_fader = Some(master_fader);
+        let monitor_in: Vec<_> = (0..channels)
+            .map(|c| (self.create_node(modules::Gain::new(), [(mix, c)], [(master_fader, 0)]), 0))
+            .collect();
+
+        let (monitor, tx, rx) = if channels == 2 {
+            modules::Monitor::new_stereo()
+        } else {
+            modules::Monitor::new()
+        };
+        self.monitor_queues = Some(MonitorQueues { tx, rx, channels });
+        let monitor = self.create_node(monitor, monitor_in, []);
+
+        self.update_sum_node(0, &[monitor], channels);
+    }
+
+    // Route the ext bus through an auto-wah on its way to the output.
+    fn enable_auto_wah(&mut self, ext: usize) {
+        let (ext_atten, ext_gain) = match self.ext_atten {
+            Some(ixs) => ixs,
+            None => return,
+        };
+        let sample_rate = self.sample_rate;
+        let attack = self.create_node(modules::ConstCtrl::new(5.0), [], []);
+        let release = self.create_node(modules::ConstCtrl::new(100.0), [], []);
+        let sensitivity = self.create_node(modules::SmoothCtrl::new(0.0), [], []);
+        let range = self.create_node(modules::SmoothCtrl::new(5.0), [], []);
+        let reso = self.create_node(modules::SmoothCtrl::new(0.7), [], []);
+        let follower = self.create_node(modules::Follower::new(sample_rate), [(ext, 0)],
+            vec![(attack, 0), (release, 0), (sensitivity, 0), (range, 0)]);
+        let base = self.create_node(modules::ConstCtrl::new(220f32.log2()), [], []);
+        let cutoff = self.create_node(modules::CtrlSum::new(), [],
+            [(base, 0), (follower, 0)]);
+        let filter = self.create_node(modules::Biquad::new(sample_rate), [(ext, 0)],
+            [(cutoff, 0), (reso, 0)]);
+        self.put_node(ext_atten, Box::new(modules::Gain::new()), [(filter, 0)].into_box(),
+            [(ext_gain, 0)].into_box());
+        self.auto_wah = Some(AutoWah { sensitivity, range, reso });
+    }
+
+    fn set_param(&mut self, ix: usize, val: f32, timestamp: u64) {
+        self.send_param(SetParam { ix, param_ix: 0, val, timestamp });
+    }
+
+    fn send(&mut self, msg: Message) {
         self.tx.send(msg);
     }
 
+    fn send_param(&mut self, param: SetParam) {
+        // Track the param in the patch. This runs for every param message, so
+        // it stores the value in place rather than allocating.
+        if let Some(record) = self.patch.get_mut(&param.ix) {
+            if record.tag.as_ref().is_some_and(|tag| modules::values_are_params(tag)) {
+                if let Some(value) = record.values.get_mut(param.param_ix) {
+                    *value = param.val;
+                }
+            }
+        }
+        if self.coalesce {
+            self.tx.set_param(param);
+        } else {
+            self.tx.send(Message::SetParam(param));
+        }
+    }
+
     fn send_node(&mut self, node: Node) {
         self.send(Message::Node(node));
     }
 
+    fn fade(&mut self, param_ix: usize, duration: f32, timestamp: u64) {
+        if let Some(ix) = self.master_fader {
+            self.send_param(SetParam { ix, param_ix, val: duration, timestamp });
+        }
+    }
+
     fn poll_rx(&mut self) -> usize {
         self.rx.recv().count()
     }
 
+    // Stereo is mixed down to mono.
     fn poll_monitor(&self) -> Vec<f32> {
         let mut result = Vec::new();
         if let Some(ref qs) = self.monitor_queues {
             for mut item in qs.rx.recv_items() {
-                result.extend_from_slice(&item);
+                if qs.channels == 1 {
+                    result.extend_from_slice(&item);
+                } else {
+                    result.extend(item.chunks(qs.channels)
+                        .map(|frame| frame.iter().sum::<f32>() / qs.channels as f32));
+                }
                 item.clear();
                 qs.tx.send_item(item);
             }
@@ -231,10 +866,47 @@ impl Core {
         result
     }
 
-    fn update_sum_node(&mut self, sum_node: usize, outputs: &[usize]) {
-        let module = Box::new(modules::Sum::new());
-        let buf_wiring: Vec<_> = outputs.iter().map(|n| (*n, 0)).collect();
-        self.send_node(Node::create(module, sum_node, buf_wiring, []));
+    // Replace `sum_node` with a sum of the outputs, each having the given
+    // number of channels.
+    fn update_sum_node(&mut self, sum_node: usize, outputs: &[usize], channels: usize) {
+        let module = Box::new(modules::Sum::new_channels(channels));
+        let buf_wiring: Vec<_> = outputs.iter()
+            .flat_map(|&n| (0..channels).map(move |c| (n, c)))
+            .collect();
+        self.put_node(sum_node, module, buf_wiring.into_box(), [].into_box());
+    }
+
+    // Replace the graph with the given nodes, which must all have tags known
+    // to `modules::from_preset`, keeping their ids.
+    fn load_nodes(&mut self, nodes: BTreeMap<usize, NodeRecord>) {
+        // Unwire the root first, so the worker never runs it with an input
+        // that has already been removed. A new node 0 replaces this one.
+        self.update_sum_node(0, &[], 1);
+        let old: Vec<usize> = self.patch.keys().cloned().filter(|&id| id != 0).collect();
+        for id in old {
+            self.remove_node(id);
+        }
+        self.monitor_queues = None;
+        for (id, node) in nodes {
+            if id != 0 {
+                self.id_alloc.reserve(id);
+            }
+            let tag = node.tag.unwrap();
+            let module: Box<dyn Module> = if tag == "monitor" {
+                let channels = node.values.first().map_or(1, |&c| c as usize);
+                let (monitor, tx, rx) = if channels == 2 {
+                    modules::Monitor::new_stereo()
+                } else {
+                    modules::Monitor::new()
+                };
+                self.monitor_queues = Some(MonitorQueues { tx, rx, channels });
+                Box::new(monitor)
+            } else {
+                modules::from_preset(&tag, &node.values, self.sample_rate).unwrap()
+            };
+            self.put_node(id, module, node.bufs.into_boxed_slice(),
+                node.ctrls.into_boxed_slice());
+        }
     }
 
     fn instantiate_module(&mut self, _node_id: NodeId, ty: ModuleType) -> usize {
@@ -249,30 +921,221 @@ impl Core {
                 let sample_rate = self.sample_rate;
                 self.create_node(modules::Saw::new(sample_rate), [], [(pitch, 0)])
             }
+            ModuleType::Biquad => {
+                let filter = modules::Biquad::PARAMS;
+                let cutoff = self.create_node(modules::SmoothCtrl::with_desc(filter[0]), [], []);
+                let reso = self.create_node(modules::SmoothCtrl::with_desc(filter[1]), [], []);
+                let sample_rate = self.sample_rate;
+                self.create_node(modules::Biquad::new(sample_rate), [], [(cutoff, 0), (reso, 0)])
+            }
         };
         ll_id
     }
 }
 
 impl Midi {
-    fn new(control_map: ControlMap) -> Midi {
+    fn new(control_map: ControlMap, poly: Option<Poly>) -> Midi {
         Midi {
             control_map,
             cur_note: None,
+            held: Vec::new(),
+            release: modules::Adsr::PARAMS[3].default,
+            bend_range: 2.0,
+            bend: 0.0,
+            velocity_amount: 0.0,
+            legato: false,
+            channel: None,
+            running_status: None,
+            sustain: false,
+            sustained: Vec::new(),
+            poly,
         }
     }
 
-    fn set_ctrl_const(&mut self, core: &mut Core, value: u8, lo: f32, hi: f32, ix: usize,
-        ts: u64)
-    {
-        let value = lo + value as f32 * (1.0/127.0) * (hi - lo);
+    // Set a control from a MIDI value, mapped to the range in `desc`.
+    fn set_ctrl(&mut self, core: &mut Core, value: u8, desc: &ParamDesc, ix: usize, ts: u64) {
+        let value = desc.from_unit(value as f32 * (1.0 / 127.0));
         let param = SetParam {
             ix: ix,
             param_ix: 0,
             val: value,
             timestamp: ts,
         };
-        core.send(Message::SetParam(param));
+        core.send_param(param);
+    }
+
+    /// Find the voice currently sounding the given note.
+    fn voice_for_note(&self, midi_num: u8) -> Option<&VoiceMap> {
+        match self.poly {
+            Some(ref poly) => poly.voices.iter().position(|v| v.note == Some(midi_num))
+                .map(|ix| &self.control_map.voices[ix]),
+            None if self.cur_note == Some(midi_num) => self.control_map.voices.first(),
+            None => None,
+        }
+    }
+
+    // Upper bound on how long a voice takes to go quiet after note-off, based
+    // on the Adsr taking 2^-release chunks per unit (log2) of decay, and a
+    // range of 24.
+    fn release_ns(&self, core: &Core) -> u64 {
+        let chunks = 24.0 * self.release.exp2();
+        (chunks as f64 * N_SAMPLES_PER_CHUNK as f64 * 1e9 / core.sample_rate as f64) as u64
+    }
+
+    /// Disconnect voices that have finished their release.
+    fn reclaim_idle(&mut self, core: &mut Core, ts: u64) {
+        let release_ns = self.release_ns(core);
+        if let Some(ref mut poly) = self.poly {
+            let mut changed = false;
+            for voice in &mut poly.voices {
+                if voice.active && voice.note.is_none()
+                    && ts >= voice.released_at.saturating_add(release_ns)
+                {
+                    voice.active = false;
+                    changed = true;
+                }
+            }
+            if changed {
+                poly.update_bus(core, &self.control_map);
+            }
+        }
+    }
+
+    // Find a voice for a new note, allocating one if needed and the pool isn't
+    // at its maximum, and otherwise stealing one. The voice is made active.
+    fn alloc_voice(&mut self, core: &mut Core, midi_num: u8) -> usize {
+        let poly = self.poly.as_mut().unwrap();
+        // retrigger of a held note
+        if let Some(ix) = poly.voices.iter().position(|v| v.note == Some(midi_num)) {
+            return ix;
+        }
+        let ix = if let Some(ix) = poly.voices.iter().position(|v| !v.active) {
+            ix
+        } else if poly.voices.len() < poly.max_voices {
+            let mut voice = core.create_voice(&self.control_map);
+            core.pan_voice(&mut voice);
+            if self.bend != 0.0 {
+                let param = SetParam { ix: voice.pitch, param_ix: 1, val: self.bend, timestamp: 0 };
+                core.send_param(param);
+            }
+            if self.velocity_amount != 0.0 {
+                let val = self.velocity_amount;
+                core.send_param(SetParam { ix: voice.adsr, param_ix: 1, val, timestamp: 0 });
+            }
+            self.control_map.voices.push(voice);
+            poly.voices.push(VoiceState {
+                note: None,
+                started_at: 0,
+                released_at: 0,
+                active: false,
+            });
+            poly.voices.len() - 1
+        } else {
+            // All voices are sounding, so take the one furthest into its
+            // release, or failing that, the oldest held note.
+            let released = poly.voices.iter().enumerate()
+                .filter(|&(_, v)| v.note.is_none())
+                .min_by_key(|&(_, v)| v.released_at);
+            let held = poly.voices.iter().enumerate().min_by_key(|&(_, v)| v.started_at);
+            return released.or(held).unwrap().0;
+        };
+        poly.voices[ix].active = true;
+        poly.update_bus(core, &self.control_map);
+        ix
+    }
+
+    // Bend the pitch of all voices, in octaves; NotePitch smooths it.
+    fn set_bend(&mut self, core: &mut Core, bend: f32, ts: u64) {
+        self.bend = bend;
+        for voice in &self.control_map.voices {
+            let param = SetParam { ix: voice.pitch, param_ix: 1, val: bend, timestamp: ts };
+            core.send_param(param);
+        }
+    }
+
+    fn poly_note(&mut self, core: &mut Core, midi_num: u8, velocity: u8, on: bool, ts: u64) {
+        if on {
+            self.reclaim_idle(core, ts);
+            let ix = self.alloc_voice(core, midi_num);
+            let key_pan = {
+                let poly = self.poly.as_mut().unwrap();
+                poly.voices[ix].note = Some(midi_num);
+                poly.voices[ix].started_at = ts;
+                poly.key_pan
+            };
+            if let Some(pan) = self.control_map.voices[ix].pan {
+                // centered on the 88-key range, A0 to C8
+                let val = (key_pan * (midi_num as f32 - 64.5) / 43.5).clamp(-1.0, 1.0);
+                let param = SetParam { ix: pan, param_ix: 0, val, timestamp: ts };
+                core.send_param(param);
+            }
+            let targets = self.control_map.voices[ix].note_receivers.clone();
+            self.send_note(core, targets, midi_num as f32, velocity as f32, true, ts);
+            let pressure = self.control_map.voices[ix].pressure;
+            self.set_ctrl(core, 0, &PRESSURE, pressure, ts);
+        } else {
+            let ix = self.poly.as_ref().unwrap().voices.iter()
+                .position(|v| v.note == Some(midi_num));
+            if let Some(ix) = ix {
+                {
+                    let voice = &mut self.poly.as_mut().unwrap().voices[ix];
+                    voice.note = None;
+                    voice.released_at = ts;
+                }
+                let targets = self.control_map.voices[ix].note_receivers.clone();
+                self.send_note(core, targets, midi_num as f32, velocity as f32, false, ts);
+            }
+        }
+    }
+
+    fn note(&mut self, core: &mut Core, midi_num: u8, velocity: u8, on: bool, ts: u64) {
+        if self.poly.is_some() {
+            self.poly_note(core, midi_num, velocity, on, ts);
+            return;
+        }
+        self.held.retain(|&(n, _)| n != midi_num);
+        let legato = self.legato && !self.held.is_empty();
+        if on {
+            self.held.push((midi_num, velocity));
+            self.mono_note(core, midi_num, velocity, true, legato, ts);
+        } else if self.cur_note == Some(midi_num) {
+            // Go back to the most recent note still held, if any.
+            match self.held.last().cloned() {
+                Some((prev, prev_velocity)) => {
+                    self.mono_note(core, prev, prev_velocity, true, legato, ts);
+                }
+                None => self.mono_note(core, midi_num, velocity, false, false, ts),
+            }
+        }
+    }
+
+    // A legato note only goes to the NotePitch, so the envelope carries on.
+    fn mono_note(&mut self, core: &mut Core, midi_num: u8, velocity: u8, on: bool, legato: bool,
+        ts: u64)
+    {
+        let targets = if legato {
+            vec![self.control_map.voices[0].pitch]
+        } else {
+            self.control_map.voices[0].note_receivers.clone()
+        };
+        self.send_note(core, targets, midi_num as f32, velocity as f32, on, ts);
+        self.cur_note = if on { Some(midi_num) } else { None };
+        if on {
+            // A new note starts with no aftertouch.
+            let pressure = self.control_map.voices[0].pressure;
+            self.set_ctrl(core, 0, &PRESSURE, pressure, ts);
+        }
+    }
+
+    // While the sustain pedal is down, note-offs are held back, and released
+    // when it comes up.
+    fn set_sustain(&mut self, core: &mut Core, down: bool, ts: u64) {
+        self.sustain = down;
+        if !down {
+            for midi_num in mem::take(&mut self.sustained) {
+                self.note(core, midi_num, 0, false, ts);
+            }
+        }
     }
 
     fn send_note(&mut self, core: &mut Core, ixs: Vec<usize>, midi_num: f32, velocity: f32,
@@ -291,59 +1154,892 @@ impl Midi {
     fn dispatch_midi(&mut self, core: &mut Core, data: &[u8], ts: u64) {
         let mut i = 0;
         while i < data.len() {
-            if data[i] == 0xb0 {
-                let controller = data[i + 1];
-                let value = data[i + 2];
-                match controller {
-                    1 => {
-                        let cutoff = self.control_map.cutoff;
-                        self.set_ctrl_const(core, value, 0.0, 22_000f32.log2(), cutoff, ts);
-                    }
-                    2 => {
-                        let reso = self.control_map.reso;
-                        self.set_ctrl_const(core, value, 0.0, 0.995, reso, ts);
-                    }
+            let byte = data[i];
+            if byte >= 0xf8 {
+                // real time messages can appear anywhere, even mid-message
+                i += 1;
+                continue;
+            }
+            if byte >= 0xf0 {
+                // System common messages, SysEx included, aren't handled, and
+                // cancel running status. Their data bytes are skipped, up to
+                // the end of a SysEx.
+                self.running_status = None;
+                i += 1;
+                while i < data.len() && data[i] < 0x80 {
+                    i += 1;
+                }
+                continue;
+            }
+            // A data byte where a status byte is expected repeats the last
+            // status, as hardware often sends.
+            let status = if byte >= 0x80 {
+                i += 1;
+                self.running_status = Some(byte);
+                byte
+            } else if let Some(status) = self.running_status {
+                status
+            } else {
+                i += 1;
+                continue;
+            };
+            let n_data = match status & 0xf0 {
+                0xc0 | 0xd0 => 1,
+                _ => 2,
+            };
+            let mut msg = [status, 0, 0];
+            let mut n = 0;
+            while n < n_data && i < data.len() {
+                let byte = data[i];
+                if (0x80..0xf8).contains(&byte) {
+                    // a status byte cuts the message short
+                    break;
+                }
+                i += 1;
+                if byte < 0x80 {
+                    n += 1;
+                    msg[n] = byte;
+                }
+            }
+            if n < n_data {
+                if i == data.len() {
+                    break;
+                }
+                continue;
+            }
+            if self.channel.is_none_or(|c| status & 0x0f == c) {
+                self.dispatch_message(core, &msg[..=n_data], ts);
+            }
+        }
+    }
 
-                    5 => {
-                        let attack = self.control_map.attack;
-                        self.set_ctrl_const(core, value, 0.0, 10.0, attack, ts);
-                    }
-                    6 => {
-                        let decay = self.control_map.decay;
-                        self.set_ctrl_const(core, value, 0.0, 10.0, decay, ts);
+    // Handle one channel message, of the length its status byte calls for.
+    fn dispatch_message(&mut self, core: &mut Core, msg: &[u8], ts: u64) {
+        match msg[0] & 0xf0 {
+            0xb0 => {
+                let controller = msg[1];
+                let value = msg[2];
+                if let Some(&(ix, lo, hi)) = self.control_map.cc.get(&controller) {
+                    let val = map_linear(value, lo, hi);
+                    core.send_param(SetParam { ix, param_ix: 0, val, timestamp: ts });
+                    if ix == self.control_map.release {
+                        self.release = val;
                     }
-                    7 => {
-                        let sustain = self.control_map.sustain;
-                        self.set_ctrl_const(core, value, 0.0, 6.0, sustain, ts);
-                    }
-                    8 => {
-                        let release = self.control_map.release;
-                        self.set_ctrl_const(core, value, 0.0, 10.0, release, ts);
+                } else if controller == 64 {
+                    self.set_sustain(core, value >= 64, ts);
+                }
+            }
+            status @ 0x80 | status @ 0x90 => {
+                let midi_num = msg[1];
+                let velocity = msg[2];
+                let on = status == 0x90 && velocity > 0;
+                if on {
+                    self.sustained.retain(|&n| n != midi_num);
+                } else if self.sustain {
+                    if !self.sustained.contains(&midi_num) {
+                        self.sustained.push(midi_num);
                     }
-                    _ => println!("don't have handler for controller {}", controller),
+                    return;
                 }
-                i += 3;
-            } else if data[i] == 0x90 || data[i] == 0x80 {
-                let midi_num = data[i + 1];
-                let velocity = data[i + 2];
-                let on = data[i] == 0x90 && velocity > 0;
-                if on || self.cur_note == Some(midi_num) {
-                    let targets = self.control_map.note_receivers.clone();
-                    self.send_note(core, targets, midi_num as f32, velocity as f32, on, ts);
-                    self.cur_note = if on { Some(midi_num) } else { None }
+                self.note(core, midi_num, velocity, on, ts);
+            }
+            0xa0 => {
+                // Polyphonic aftertouch opens up the filter of that note's voice,
+                // by up to two octaves.
+                let midi_num = msg[1];
+                let value = msg[2];
+                if let Some(pressure) = self.voice_for_note(midi_num).map(|v| v.pressure) {
+                    self.set_ctrl(core, value, &PRESSURE, pressure, ts);
                 }
-                i += 3;
-            } else {
-                break;
             }
+            0xd0 => {
+                let value = msg[1];
+                if let Some((ix, lo, hi)) = self.control_map.pressure {
+                    let val = map_linear(value, lo, hi);
+                    core.send_param(SetParam { ix, param_ix: 0, val, timestamp: ts });
+                } else {
+                    let pressures: Vec<usize> = match self.poly {
+                        Some(ref poly) => poly.voices.iter().zip(&self.control_map.voices)
+                            .filter(|&(state, _)| state.note.is_some())
+                            .map(|(_, voice)| voice.pressure)
+                            .collect(),
+                        None => self.cur_note.and(self.control_map.voices.first())
+                            .map(|voice| voice.pressure)
+                            .into_iter()
+                            .collect(),
+                    };
+                    for pressure in pressures {
+                        self.set_ctrl(core, value, &PRESSURE, pressure, ts);
+                    }
+                }
+            }
+            0xe0 => {
+                // 14 bits, least significant first, centered on 0x2000
+                let value = (msg[1] as i32 | (msg[2] as i32) << 7) - 0x2000;
+                let bend = value as f32 * (1.0 / 0x2000 as f32) * self.bend_range / 12.0;
+                self.set_bend(core, bend, ts);
+            }
+            _ => (),
         }
     }
 
     fn dispatch_note_event(&mut self, core: &mut Core, note_event: &NoteEvent) {
         let mut data = [0u8; 3];
-        data[0] = if note_event.down { 0x90 } else { 0x80 };
+        let status = if note_event.down { 0x90 } else { 0x80 };
+        data[0] = status | self.channel.unwrap_or(0);
         data[1] = note_event.note;
         data[2] = note_event.velocity;
         self.dispatch_midi(core, &data, time::precise_time_ns());
     }
+
+    // The node numbers and settings, for saving in a preset.
+    fn to_json(&self) -> Value {
+        let control_map = &self.control_map;
+        let voices: Vec<_> = control_map.voices.iter().map(|voice| Value::object(vec![
+            ("note_receivers", voice.note_receivers.clone().into()),
+            ("pitch", voice.pitch.into()),
+            ("adsr", voice.adsr.into()),
+            ("pressure", voice.pressure.into()),
+            ("pan", voice.pan.into()),
+            ("out", voice.out.into()),
+        ])).collect();
+        let mut cc: Vec<_> = control_map.cc.iter().collect();
+        cc.sort_by_key(|&(&cc, _)| cc);
+        let cc: Vec<_> = cc.into_iter()
+            .map(|(&cc, &(node, lo, hi))| Value::Array(vec![
+                (cc as usize).into(), node.into(), lo.into(), hi.into()
+            ]))
+            .collect();
+        let poly = self.poly.as_ref().map(|poly| Value::object(vec![
+            ("max_voices", poly.max_voices.into()),
+            ("voice_bus", poly.voice_bus.into()),
+            ("key_pan", poly.key_pan.into()),
+        ]));
+        Value::object(vec![
+            ("cutoff", control_map.cutoff.into()),
+            ("reso", control_map.reso.into()),
+            ("attack", control_map.attack.into()),
+            ("decay", control_map.decay.into()),
+            ("sustain", control_map.sustain.into()),
+            ("release", control_map.release.into()),
+            ("ext", control_map.ext.into()),
+            ("voices", voices.into()),
+            ("cc", cc.into()),
+            ("pressure", control_map.pressure
+                .map(|(node, lo, hi)| Value::Array(vec![node.into(), lo.into(), hi.into()]))
+                .into()),
+            ("poly", poly.into()),
+            ("release_time", self.release.into()),
+            ("bend_range", self.bend_range.into()),
+            ("bend", self.bend.into()),
+            ("velocity_amount", self.velocity_amount.into()),
+            ("legato", Value::Bool(self.legato)),
+            ("channel", self.channel.map(|c| c as usize).into()),
+        ])
+    }
+
+    // The inverse of `to_json`, with no notes playing.
+    fn from_json(value: &Value) -> Result<Midi, String> {
+        let id = |v: &Value, key: &str| v.field(key).and_then(Value::as_usize);
+        let num = |key: &str| value.field(key).and_then(Value::as_f32);
+        let mut voices = Vec::new();
+        for voice in value.field("voices")?.as_array()? {
+            voices.push(VoiceMap {
+                note_receivers: voice.field("note_receivers")?.as_array()?.iter()
+                    .map(Value::as_usize)
+                    .collect::<Result<_, _>>()?,
+                pitch: id(voice, "pitch")?,
+                adsr: id(voice, "adsr")?,
+                pressure: id(voice, "pressure")?,
+                pan: voice.field("pan")?.as_option().map(Value::as_usize).transpose()?,
+                out: id(voice, "out")?,
+            });
+        }
+        let mut cc = HashMap::new();
+        for entry in value.field("cc")?.as_array()? {
+            match *entry.as_array()? {
+                [ref cc_num, ref node, ref lo, ref hi] => {
+                    cc.insert(cc_num.as_usize()? as u8,
+                        (node.as_usize()?, lo.as_f32()?, hi.as_f32()?));
+                }
+                _ => return Err(format!("bad controller mapping {}", entry)),
+            }
+        }
+        let pressure = match value.field("pressure")?.as_option() {
+            Some(mapping) => match *mapping.as_array()? {
+                [ref node, ref lo, ref hi] => Some((node.as_usize()?, lo.as_f32()?, hi.as_f32()?)),
+                _ => return Err(format!("bad pressure mapping {}", mapping)),
+            },
+            None => None,
+        };
+        let poly = match value.field("poly")?.as_option() {
+            Some(poly) => Some(Poly {
+                max_voices: id(poly, "max_voices")?,
+                voice_bus: id(poly, "voice_bus")?,
+                voices: voices.iter().map(|_| VoiceState {
+                    note: None,
+                    started_at: 0,
+                    released_at: 0,
+                    active: false,
+                }).collect(),
+                key_pan: poly.field("key_pan")?.as_f32()?,
+            }),
+            None if voices.is_empty() => return Err("monosynth has no voice".into()),
+            None => None,
+        };
+        let control_map = ControlMap {
+            cutoff: id(value, "cutoff")?,
+            reso: id(value, "reso")?,
+            attack: id(value, "attack")?,
+            decay: id(value, "decay")?,
+            sustain: id(value, "sustain")?,
+            release: id(value, "release")?,
+            ext: id(value, "ext")?,
+            voices,
+            cc,
+            pressure,
+        };
+        let mut midi = Midi::new(control_map, poly);
+        midi.release = num("release_time")?;
+        midi.bend_range = num("bend_range")?;
+        midi.bend = num("bend")?;
+        midi.velocity_amount = num("velocity_amount")?;
+        midi.legato = value.field("legato")?.as_bool()?;
+        midi.channel = value.field("channel")?.as_option()
+            .map(|c| c.as_usize().map(|c| c as u8)).transpose()?;
+        Ok(midi)
+    }
+}
+
+impl Poly {
+    // Connect the active voices to the voice bus.
+    fn update_bus(&self, core: &mut Core, control_map: &ControlMap) {
+        let outputs: Vec<_> = self.voices.iter().zip(control_map.voices.iter())
+            .filter(|&(state, _)| state.active)
+            .map(|(_, voice)| voice.out)
+            .collect();
+        core.update_sum_node(self.voice_bus, &outputs, 2);
+    }
+}
+
+// Map a MIDI value from 0 to 127 linearly to the range `lo` to `hi`.
+fn map_linear(value: u8, lo: f32, hi: f32) -> f32 {
+    lo + value as f32 * (1.0 / 127.0) * (hi - lo)
+}
+
+fn wiring_to_json(wiring: &[(usize, usize)]) -> Value {
+    wiring.iter().map(|&(node, ix)| vec![node, ix]).collect::<Vec<_>>().into()
+}
+
+fn wiring_from_json(value: &Value) -> Result<Vec<(usize, usize)>, String> {
+    value.as_array()?.iter()
+        .map(|input| match *input.as_array()? {
+            [ref node, ref ix] => Ok((node.as_usize()?, ix.as_usize()?)),
+            _ => Err(format!("bad wiring {}", input)),
+        })
+        .collect()
+}
+
+// Node numbers saved as a list of length `n`, or null.
+fn node_ids(value: &Value, key: &str, n: usize) -> Result<Option<Vec<usize>>, String> {
+    match value.field(key)?.as_option() {
+        Some(list) => {
+            let ids = list.as_array()?.iter().map(Value::as_usize)
+                .collect::<Result<Vec<_>, _>>()?;
+            if ids.len() != n {
+                return Err(format!("expected {} node numbers for {}", n, key));
+            }
+            Ok(Some(ids))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::{Message, Node, Note};
+    use module::{Module, N_SAMPLES_PER_CHUNK};
+    use modules;
+    use queue::{Queue, Receiver};
+    use test_util::magnitude;
+    use worker::Worker;
+    use super::{Engine, ModuleType};
+
+    fn monosynth() -> (Engine, Receiver<Message>) {
+        let (tx, worker_rx) = Queue::new();
+        let (_worker_tx, rx) = Queue::new();
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        engine.init_monosynth();
+        worker_rx.recv().count();
+        (engine, worker_rx)
+    }
+
+    fn polysynth(max_voices: usize) -> (Engine, Receiver<Message>) {
+        let (tx, worker_rx) = Queue::new();
+        let (_worker_tx, rx) = Queue::new();
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        engine.init_polysynth(max_voices);
+        (engine, worker_rx)
+    }
+
+    // (allocated, active) voice counts
+    fn voice_counts(engine: &Engine) -> (usize, usize) {
+        let poly = engine.midi.as_ref().unwrap().poly.as_ref().unwrap();
+        (poly.voices.len(), poly.voices.iter().filter(|v| v.active).count())
+    }
+
+    #[test]
+    fn voices_allocated_and_reclaimed() {
+        let (mut engine, _worker_rx) = polysynth(4);
+        assert_eq!(voice_counts(&engine), (0, 0));
+        engine.dispatch_midi(&[0x90, 60, 100, 0x90, 64, 100], 0);
+        assert_eq!(voice_counts(&engine), (2, 2));
+
+        // A voice that's still releasing isn't reused.
+        engine.dispatch_midi(&[0x80, 60, 0], 1_000_000);
+        engine.dispatch_midi(&[0x90, 67, 100], 2_000_000);
+        assert_eq!(voice_counts(&engine), (3, 3));
+
+        // Long after its release, it's reclaimed and reused.
+        engine.dispatch_midi(&[0x90, 72, 100], 10_000_000_000);
+        assert_eq!(voice_counts(&engine), (3, 3));
+
+        // The pool grows to the maximum, and no further; 76 steals from 64.
+        engine.dispatch_midi(&[0x90, 74, 100, 0x90, 76, 100], 10_000_000_000);
+        assert_eq!(voice_counts(&engine), (4, 4));
+
+        // Idle voices are disconnected.
+        engine.dispatch_midi(&[0x80, 76, 0, 0x80, 67, 0], 11_000_000_000);
+        {
+            let Engine { ref mut core, ref mut midi } = engine;
+            midi.as_mut().unwrap().reclaim_idle(core, 20_000_000_000);
+        }
+        assert_eq!(voice_counts(&engine), (4, 2));
+    }
+
+    // The note receivers and note number of each note-on sent to the worker.
+    fn note_ons(worker_rx: &Receiver<Message>) -> Vec<(Vec<usize>, f32)> {
+        worker_rx.recv().filter_map(|msg| match msg {
+            Message::Note(ref note) if note.on => Some((note.ixs.to_vec(), note.midi_num)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn chord_gets_distinct_voices_then_steals_oldest() {
+        let (mut engine, worker_rx) = polysynth(4);
+        engine.dispatch_midi(&[0x90, 60, 100, 0x90, 64, 100], 0);
+        engine.dispatch_midi(&[0x90, 67, 100, 0x90, 71, 100], 1_000);
+        let chord = note_ons(&worker_rx);
+        assert_eq!(chord.iter().map(|n| n.1).collect::<Vec<_>>(), vec![60.0, 64.0, 67.0, 71.0]);
+        for (i, a) in chord.iter().enumerate() {
+            for b in &chord[i + 1..] {
+                assert!(a.0.iter().all(|ix| !b.0.contains(ix)), "voices share a node");
+            }
+        }
+
+        // Full, so the oldest held note gives up its voice.
+        engine.dispatch_midi(&[0x90, 74, 100], 2_000);
+        assert_eq!(note_ons(&worker_rx), vec![(chord[0].0.clone(), 74.0)]);
+        assert_eq!(voice_counts(&engine), (4, 4));
+
+        // A released voice is stolen before any held one.
+        engine.dispatch_midi(&[0x80, 67, 0], 3_000);
+        engine.dispatch_midi(&[0x90, 76, 100], 4_000);
+        assert_eq!(note_ons(&worker_rx), vec![(chord[2].0.clone(), 76.0)]);
+    }
+
+    #[test]
+    fn pitch_bend_shifts_note_pitch() {
+        let (mut engine, worker_rx) = monosynth();
+        let pitch = engine.midi.as_ref().unwrap().control_map.voices[0].pitch;
+        engine.set_bend_range(12.0);
+        // half way down, 0x1000, is half an octave
+        engine.dispatch_midi(&[0xe0, 0x00, 0x20], 0);
+        let msgs: Vec<_> = worker_rx.recv().collect();
+        assert_eq!(msgs.len(), 1);
+        let bend = match msgs[0] {
+            Message::SetParam(ref param) => {
+                assert_eq!((param.ix, param.param_ix), (pitch, 1));
+                param.val
+            }
+            _ => panic!("expected SetParam"),
+        };
+        let mut note_pitch = modules::NotePitch::new(44_100.0);
+        note_pitch.handle_note(69.0, 100.0, true);
+        note_pitch.set_param(1, bend, 0);
+        let mut out = [0.0];
+        for _ in 0..100 {
+            note_pitch.process(&[], &mut out, &[], &mut []);
+        }
+        assert!((out[0] - (440f32.log2() - 0.5)).abs() < 1e-4, "pitch {}", out[0]);
+    }
+
+    #[test]
+    fn remapped_cc_sets_configured_range() {
+        let (mut engine, worker_rx) = monosynth();
+        let gain = engine.core.ext_atten.unwrap().1;
+        engine.map_cc(7, gain, -4.0, 0.0);
+        let params = |engine: &mut Engine, data: &[u8]| {
+            engine.dispatch_midi(data, 0);
+            engine.flush();
+            worker_rx.recv().map(|msg| match msg {
+                Message::SetParam(param) => (param.ix, param.val),
+                _ => panic!("expected SetParam"),
+            }).collect::<Vec<_>>()
+        };
+        assert_eq!(params(&mut engine, &[0xb0, 7, 127]), vec![(gain, 0.0)]);
+        assert_eq!(params(&mut engine, &[0xb0, 7, 0]), vec![(gain, -4.0)]);
+        // unmapped controllers do nothing
+        assert_eq!(params(&mut engine, &[0xb0, 20, 64]), vec![]);
+    }
+
+    // The note number and on/off of each note message sent to the worker.
+    fn notes(worker_rx: &Receiver<Message>) -> Vec<(u8, bool)> {
+        worker_rx.recv().filter_map(|msg| match msg {
+            Message::Note(ref note) => Some((note.midi_num as u8, note.on)),
+            _ => None,
+        }).collect()
+    }
+
+    #[test]
+    fn mono_overlapping_notes_resolve() {
+        let (mut engine, worker_rx) = monosynth();
+        // staccato
+        engine.dispatch_midi(&[0x90, 60, 100, 0x80, 60, 0, 0x90, 62, 100, 0x80, 62, 0], 0);
+        assert_eq!(notes(&worker_rx), vec![(60, true), (60, false), (62, true), (62, false)]);
+        // legato: the first note's release is ignored, as it's not sounding
+        engine.dispatch_midi(&[0x90, 60, 100, 0x90, 62, 100, 0x80, 60, 0, 0x80, 62, 0], 0);
+        assert_eq!(notes(&worker_rx), vec![(60, true), (62, true), (62, false)]);
+        // interleaved: releasing the top note falls back to the one held
+        engine.dispatch_midi(&[0x90, 60, 100, 0x90, 62, 100, 0x90, 64, 100], 0);
+        engine.dispatch_midi(&[0x80, 64, 0, 0x80, 60, 0, 0x90, 65, 100], 0);
+        engine.dispatch_midi(&[0x80, 65, 0, 0x80, 62, 0], 0);
+        assert_eq!(notes(&worker_rx), vec![(60, true), (62, true), (64, true), (62, true),
+            (65, true), (62, true), (62, false)]);
+        // a repeated note-off, say from a lost message, is harmless
+        engine.dispatch_midi(&[0x80, 62, 0, 0x90, 67, 100, 0x80, 62, 0], 0);
+        assert_eq!(notes(&worker_rx), vec![(67, true)]);
+        assert_eq!(engine.midi.as_ref().unwrap().cur_note, Some(67));
+    }
+
+    #[test]
+    fn running_status() {
+        let (mut engine, worker_rx) = monosynth();
+        engine.dispatch_midi(&[0x90, 60, 100, 62, 100], 0);
+        assert_eq!(notes(&worker_rx), vec![(60, true), (62, true)]);
+        // it carries over between calls, and past real time messages
+        engine.dispatch_midi(&[62, 0, 0xf8, 60, 0], 0);
+        assert_eq!(notes(&worker_rx), vec![(60, true), (60, false)]);
+    }
+
+    #[test]
+    fn status_bytes_inside_a_message() {
+        let (mut engine, worker_rx) = monosynth();
+        engine.dispatch_midi(&[0x90, 60, 0xf8, 100], 0);
+        let velocities: Vec<_> = worker_rx.recv().filter_map(|msg| match msg {
+            Message::Note(ref note) => Some((note.midi_num, note.velocity)),
+            _ => None,
+        }).collect();
+        assert_eq!(velocities, vec![(60.0, 100.0)]);
+        // a note-on cut short by a note-off is dropped
+        engine.dispatch_midi(&[0x90, 62, 0x80, 60, 0], 0);
+        assert_eq!(notes(&worker_rx), vec![(60, false)]);
+    }
+
+    #[test]
+    fn system_messages_are_skipped() {
+        let (mut engine, worker_rx) = monosynth();
+        engine.dispatch_midi(&[0xf0, 0x7e, 0x7f, 0x09, 0x01, 0xf7, 0x90, 60, 100], 0);
+        assert_eq!(notes(&worker_rx), vec![(60, true)]);
+        // song position, then data that running status no longer applies to
+        engine.dispatch_midi(&[0xf2, 0x10, 0x20, 60, 0, 0x80, 60, 0], 0);
+        assert_eq!(notes(&worker_rx), vec![(60, false)]);
+    }
+
+    #[test]
+    fn channel_filter() {
+        let (mut engine, worker_rx) = polysynth(4);
+        engine.set_midi_channel(Some(2));
+        engine.dispatch_midi(&[0x90, 60, 100, 0x91, 64, 100, 0xc0, 5, 0x92, 67, 100], 0);
+        assert_eq!(note_ons(&worker_rx).iter().map(|n| n.1).collect::<Vec<_>>(), vec![64.0]);
+        engine.set_midi_channel(None);
+        engine.dispatch_midi(&[0x90, 60, 100, 0x9f, 67, 100], 0);
+        assert_eq!(note_ons(&worker_rx).iter().map(|n| n.1).collect::<Vec<_>>(),
+            vec![60.0, 67.0]);
+    }
+
+    #[test]
+    fn poly_aftertouch_targets_held_note() {
+        let (mut engine, worker_rx) = monosynth();
+        let pressure = engine.midi.as_ref().unwrap().control_map.voices[0].pressure;
+        engine.dispatch_midi(&[0x90, 60, 100], 0);
+        worker_rx.recv().count();
+
+        // Aftertouch on a note that isn't held is ignored.
+        engine.dispatch_midi(&[0xa0, 62, 127], 0);
+        assert_eq!(worker_rx.recv().count(), 0);
+
+        engine.dispatch_midi(&[0xa0, 60, 127], 0);
+        let msgs: Vec<_> = worker_rx.recv().collect();
+        assert_eq!(msgs.len(), 1);
+        match msgs[0] {
+            Message::SetParam(ref param) => {
+                assert_eq!(param.ix, pressure);
+                assert_eq!(param.val, 2.0);
+            }
+            _ => panic!("expected SetParam"),
+        }
+    }
+
+    #[test]
+    fn channel_pressure() {
+        let (mut engine, worker_rx) = monosynth();
+        let (cutoff, pressure) = {
+            let control_map = &engine.midi.as_ref().unwrap().control_map;
+            (control_map.cutoff, control_map.voices[0].pressure)
+        };
+        let params = |engine: &mut Engine, data: &[u8]| {
+            engine.dispatch_midi(data, 0);
+            worker_rx.recv().filter_map(|msg| match msg {
+                Message::SetParam(param) => Some((param.ix, param.val)),
+                _ => None,
+            }).collect::<Vec<_>>()
+        };
+        // By default, it's aftertouch on the note being played, if any.
+        assert_eq!(params(&mut engine, &[0xd0, 127]), vec![]);
+        params(&mut engine, &[0x90, 60, 100]);
+        assert_eq!(params(&mut engine, &[0xd0, 127]), vec![(pressure, 2.0)]);
+
+        engine.map_pressure(cutoff, 8.0, 12.0);
+        assert_eq!(params(&mut engine, &[0xd0, 127]), vec![(cutoff, 12.0)]);
+        // running status, with one data byte per message
+        assert_eq!(params(&mut engine, &[0xd0, 0, 0x7f]), vec![(cutoff, 8.0), (cutoff, 12.0)]);
+    }
+
+    // Ratio of 8th harmonic to fundamental of a saw through the auto-wah,
+    // with the saw at the given gain (log2), and the given range.
+    fn auto_wah_brightness(gain: f32, range: f32) -> f32 {
+        let sample_rate = 44_100.0;
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(sample_rate, rx, tx);
+        engine.init_monosynth();
+        engine.enable_auto_wah();
+        engine.set_auto_wah(0.0, range, 0.7, 1_000_000);
+        let source = {
+            let core = &mut engine.core;
+            let pitch = core.create_node(modules::ConstCtrl::new(110f32.log2()), [], []);
+            let saw = core.create_node(modules::Saw::new(sample_rate), [], [(pitch, 0)]);
+            let gain = core.create_node(modules::ConstCtrl::new(gain), [], []);
+            core.create_node(modules::Gain::new(), [(saw, 0)], [(gain, 0)])
+        };
+        engine.set_outputs(&[source]);
+        let mut out = Vec::new();
+        for i in 0..1024 {
+            let buf = worker.work((i * N_SAMPLES_PER_CHUNK) as u64 * 1_000_000_000 / 44_100);
+            if i * N_SAMPLES_PER_CHUNK >= 16384 {
+                out.extend_from_slice(buf[0].get());
+            }
+        }
+        magnitude(&out, 880.0, sample_rate) / magnitude(&out, 110.0, sample_rate)
+    }
+
+    #[test]
+    fn auto_wah_opens_with_level() {
+        let loud = auto_wah_brightness(0.0, 5.0);
+        let quiet = auto_wah_brightness(-5.0, 5.0);
+        // A saw's 8th harmonic is 1/8 of the fundamental; at full level the
+        // cutoff is well above it, but quiet input barely opens the filter.
+        assert!(loud > 0.08, "loud {}", loud);
+        assert!(loud > 4.0 * quiet, "loud {} quiet {}", loud, quiet);
+        // with no range, even loud input leaves it closed
+        let closed = auto_wah_brightness(0.0, 0.0);
+        assert!(loud > 4.0 * closed, "loud {} closed {}", loud, closed);
+    }
+
+    #[test]
+    fn removed_ids_are_reused() {
+        let (mut worker, tx, rx) = Worker::create(64);
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0, [], []));
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        let mut first_ids = None;
+        for i in 0..100 {
+            let ids: Vec<_> = (0..3)
+                .map(|_| engine.core.create_node(modules::ConstCtrl::new(0.0), [], []))
+                .collect();
+            worker.work(i * 1000);
+            assert_eq!(worker.node_count(), 4);
+            for &id in ids.iter().rev() {
+                engine.remove_node(id);
+            }
+            worker.work(i * 1000 + 500);
+            assert_eq!(worker.node_count(), 1);
+            engine.poll_rx();
+            assert_eq!(*first_ids.get_or_insert(ids.clone()), ids);
+        }
+    }
+
+    #[test]
+    fn remove_module_takes_its_controls() {
+        let (mut worker, tx, rx) = Worker::create(64);
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0, [], []));
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        let ll_id = engine.instantiate_module(0, ModuleType::Saw);
+        worker.work(0);
+        assert_eq!(worker.node_count(), 3);
+        engine.remove_module(ll_id);
+        worker.work(1000);
+        assert_eq!(worker.node_count(), 1);
+    }
+
+    #[test]
+    fn module_inputs_are_rewired() {
+        let (mut worker, tx, rx) = Worker::create(64);
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        let saw = engine.instantiate_module(0, ModuleType::Saw);
+        let filter = engine.instantiate_module(0, ModuleType::Biquad);
+        engine.set_outputs(&[filter]);
+        let mut peak = |ts| worker.work(ts)[0].get().iter().fold(0.0f32, |m, x| m.max(x.abs()));
+        peak(0);
+        assert_eq!(peak(1_000_000), 0.0);
+        engine.set_module_inputs(filter, &[saw]);
+        peak(2_000_000);
+        assert!(peak(3_000_000) > 0.1);
+        assert_eq!(engine.module_inputs(filter), Some(vec![saw]));
+        engine.set_module_inputs(filter, &[]);
+        assert_eq!(engine.module_inputs(filter), Some(vec![]));
+        // a module with no type tag can't be rewired
+        let shaper = engine.core.create_node(modules::Shaper::new(), [(saw, 0)], []);
+        engine.set_module_inputs(shaper, &[]);
+        assert_eq!(engine.module_inputs(shaper), Some(vec![saw]));
+    }
+
+    // Ratio of right to left level of a note played on the polysynth.
+    fn key_pan_ratio(amount: f32, midi_num: u8) -> f32 {
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        engine.init_polysynth(4);
+        engine.set_key_pan(amount);
+        engine.dispatch_midi(&[0x90, midi_num, 100], 0);
+        let (mut l, mut r) = (0.0, 0.0);
+        for i in 0..100 {
+            let out = worker.work(i * 1000);
+            if i >= 2 {
+                l += out[0].get().iter().map(|x| x * x).sum::<f32>();
+                r += out[1].get().iter().map(|x| x * x).sum::<f32>();
+            }
+        }
+        assert!(l + r > 0.0);
+        (r / l).sqrt()
+    }
+
+    #[test]
+    fn key_pan_spreads_notes() {
+        // At half spread, the ends of the keyboard are half way to each side.
+        let expected = (0.125 * ::std::f32::consts::PI).tan();
+        let low = key_pan_ratio(0.5, 21);
+        let high = key_pan_ratio(0.5, 108);
+        assert!((low - expected).abs() < 1e-3, "low {}", low);
+        assert!((high - 1.0 / expected).abs() < 1e-2, "high {}", high);
+        assert!((key_pan_ratio(-0.5, 21) - 1.0 / expected).abs() < 1e-2);
+    }
+
+    #[test]
+    fn sustain_pedal_holds_note_off() {
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        engine.init_monosynth();
+        let mut chunk = 0;
+        let mut peak = |worker: &mut Worker, n: u64| {
+            let mut peak = 0.0f32;
+            for _ in 0..n {
+                for &x in worker.work(chunk * 1000)[0].get() {
+                    peak = peak.max(x.abs());
+                }
+                chunk += 1;
+            }
+            peak
+        };
+        engine.dispatch_midi(&[0x90, 60, 100, 0xb0, 64, 127], 0);
+        peak(&mut worker, 200);
+        let held = peak(&mut worker, 10);
+        engine.dispatch_midi(&[0x80, 60, 0], 0);
+        peak(&mut worker, 200);
+        let sustained = peak(&mut worker, 10);
+        assert!((sustained - held).abs() < 0.1 * held, "held {} sustained {}", held, sustained);
+
+        engine.dispatch_midi(&[0xb0, 64, 0], 0);
+        peak(&mut worker, 200);
+        let released = peak(&mut worker, 10);
+        assert!(released < 0.1 * held, "held {} released {}", held, released);
+    }
+
+    #[test]
+    fn panic_reset_silences_held_note() {
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        engine.init_monosynth();
+        engine.dispatch_midi(&[0x90, 60, 100], 0);
+        let peak = |worker: &mut Worker, start: u64| {
+            let mut peak = 0.0f32;
+            for i in start..start + 100 {
+                for &x in worker.work(i * 1000)[0].get() {
+                    peak = peak.max(x.abs());
+                }
+            }
+            peak
+        };
+        assert!(peak(&mut worker, 0) > 0.01);
+        engine.panic_reset();
+        worker.work(100_000);
+        assert!(peak(&mut worker, 101) < 1e-5);
+    }
+
+    // The monosynth's output over a phrase of notes, a bend and controller
+    // moves, at a chunk per ms.
+    fn play_phrase(engine: &mut Engine, worker: &mut Worker) -> Vec<f32> {
+        let mut out = Vec::new();
+        for i in 0..600 {
+            let ts = i * 1_000_000;
+            let data: &[u8] = match i {
+                0 => &[0x90, 60, 100],
+                100 => &[0x90, 64, 40, 0xb0, 20, 100],
+                200 => &[0xe0, 0x00, 0x30, 0xb0, 1, 90],
+                300 => &[0x80, 64, 0],
+                400 => &[0x80, 60, 0],
+                _ => &[],
+            };
+            engine.dispatch_midi(data, ts);
+            out.extend_from_slice(worker.work(ts)[0].get());
+        }
+        out
+    }
+
+    #[test]
+    fn fades_on_sample_clock() {
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        engine.init_monosynth();
+        let mut peaks = Vec::new();
+        for i in 0..1000 {
+            let ts = i * N_SAMPLES_PER_CHUNK as u64 * 1_000_000_000 / 44_100;
+            match i {
+                0 => engine.dispatch_midi(&[0x90, 60, 100], ts),
+                200 => engine.fade_out(0.01, ts),
+                500 => engine.fade_in(0.01, ts),
+                _ => (),
+            }
+            peaks.push(worker.work(ts)[0].get().iter().fold(0.0f32, |m, x| m.max(x.abs())));
+            engine.poll_rx_at(ts);
+        }
+        let max = |chunks: &[f32]| chunks.iter().fold(0.0f32, |m, &x| m.max(x));
+        assert!(max(&peaks[150..200]) > 0.1);
+        assert!(max(&peaks[300..500]) < 1e-4);
+        assert!(max(&peaks[700..]) > 0.1);
+    }
+
+    #[test]
+    fn preset_round_trip() {
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        engine.init_monosynth();
+        engine.set_velocity_sensitivity(0.5);
+        engine.set_bend_range(7.0);
+        let reso = engine.midi.as_ref().unwrap().control_map.reso;
+        engine.map_cc(20, reso, 0.2, 0.9);
+        let preset = engine.save_preset().unwrap();
+
+        let (mut loaded_worker, tx, rx) = Worker::create(1024);
+        let mut loaded = Engine::new(44_100.0, rx, tx);
+        loaded.load_preset(&preset).unwrap();
+        assert_eq!(loaded.save_preset(), Ok(preset.clone()));
+        let out = play_phrase(&mut engine, &mut worker);
+        assert!(out.iter().any(|&x| x.abs() > 0.01));
+        assert_eq!(play_phrase(&mut loaded, &mut loaded_worker), out);
+
+        // Loading over a patch that's playing replaces it cleanly.
+        engine.load_preset(&preset).unwrap();
+        worker.work(600_000_000);
+        assert_eq!(play_phrase(&mut engine, &mut worker).len(), out.len());
+
+        let played = loaded.save_preset();
+        assert!(loaded.load_preset("{\"nodes\": [{\"id\": 1}]}").is_err());
+        assert_eq!(loaded.save_preset(), played);
+
+        // A module with no type tag can't be saved.
+        loaded.core.create_node(modules::Shaper::new(), [], []);
+        assert!(loaded.save_preset().is_err());
+    }
+
+    #[test]
+    fn render_offline_saw() {
+        let sample_rate = 44_100.0;
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(sample_rate, rx, tx);
+        engine.init_monosynth();
+        let ixs = engine.midi.as_ref().unwrap().control_map.voices[0].note_receivers.clone();
+        let note = |on: bool, timestamp: u64| Message::Note(Note {
+            ixs: ixs.clone().into_boxed_slice(),
+            midi_num: 69.0,
+            velocity: 127.0,
+            on,
+            timestamp,
+        });
+        let events = vec![(0, note(true, 0)), (900_000_000, note(false, 900_000_000))];
+        let out = engine.render_offline(&mut worker, events, 44_100);
+        assert_eq!(out.len(), 44_100);
+        // The strongest frequency over the held part of the note, to 1Hz.
+        let held = &out[4410..39690];
+        let peak = (300..600).map(|f| f as f32)
+            .max_by(|&a, &b| magnitude(held, a, sample_rate)
+                .partial_cmp(&magnitude(held, b, sample_rate)).unwrap())
+            .unwrap();
+        assert_eq!(peak, 440.0);
+        assert!(magnitude(held, 440.0, sample_rate) > 0.05);
+    }
+
+    // Peak level of the monosynth just before and just after a second note
+    // overlapping the first.
+    fn overlap_levels(legato: bool) -> (f32, f32) {
+        let (mut worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        engine.init_monosynth();
+        engine.set_legato(legato);
+        let mut peaks = (0.0f32, 0.0f32);
+        for i in 0..600 {
+            let ts = i * 1_000_000;
+            match i {
+                0 => engine.dispatch_midi(&[0x90, 60, 100], ts),
+                400 => engine.dispatch_midi(&[0x90, 64, 100], ts),
+                _ => (),
+            }
+            let peak = worker.work(ts)[0].get().iter().fold(0.0f32, |m, x| m.max(x.abs()));
+            match i {
+                350..=399 => peaks.0 = peaks.0.max(peak),
+                400..=449 => peaks.1 = peaks.1.max(peak),
+                _ => (),
+            }
+        }
+        peaks
+    }
+
+    #[test]
+    fn legato_skips_envelope_retrigger() {
+        let (mut engine, worker_rx) = monosynth();
+        engine.set_legato(true);
+        let voice = engine.midi.as_ref().unwrap().control_map.voices[0].note_receivers.clone();
+        engine.dispatch_midi(&[0x90, 60, 100, 0x90, 64, 100, 0x80, 64, 0], 0);
+        assert_eq!(note_ons(&worker_rx), vec![(voice.clone(), 60.0), (vec![voice[0]], 64.0),
+            (vec![voice[0]], 60.0)]);
+        // Once the notes are apart, the next one retriggers.
+        engine.dispatch_midi(&[0x80, 60, 0, 0x90, 62, 100], 0);
+        assert_eq!(note_ons(&worker_rx), vec![(voice, 62.0)]);
+
+        // Rendered, the envelope stays at the sustain level, where a
+        // retrigger would attack up to full level again.
+        let (before, after) = overlap_levels(true);
+        assert!(after < 1.05 * before, "before {} after {}", before, after);
+        let (before, after) = overlap_levels(false);
+        assert!(after > 1.5 * before, "before {} after {}", before, after);
+    }
 }
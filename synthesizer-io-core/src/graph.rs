@@ -14,9 +14,17 @@
 
 //! A graph runner that avoids all blocking operations, suitable for realtime threads.
 
+use std::cell::UnsafeCell;
+use std::cmp::max;
+use std::hint;
 use std::ops::DerefMut;
+use std::panic::{self, AssertUnwindSafe};
 use std::ptr;
 use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::vec;
 
 use queue::Item;
 use module::{Module, Buffer};
@@ -28,24 +36,49 @@ const MAX_CTRL: usize = 16;
 // maximum number of buffer inputs
 const MAX_BUF: usize = 16;
 
-const SENTINEL: usize = !0;
+// number of times to poll before yielding (or for pool threads, parking)
+const SPIN_LIMIT: usize = 1 << 10;
+
+// what a node wired to its own output buffer reads there
+static SILENCE: Buffer = Buffer::SILENCE;
 
 pub struct Graph {
     nodes: Box<[Option<Item<Message>>]>,
 
-    // state for topo sort; all have same len
+    // state for topo sort, indexed by node
     visited: Box<[VisitedState]>,
-    // TODO: there's no compelling reason for the graph sorting
-    // to use linked lists, we could just use one vector for the
-    // stack and another for the result.
-    link: Box<[usize]>,
+    // depth-first search stack of nodes and index of the next input to visit
+    stack: Vec<(usize, usize)>,
+    // result of the topo sort
+    order: Vec<usize>,
+    // feedback edges found by the topo sort, as (from, to) pairs
+    feedback: Vec<(usize, usize)>,
+    // if false, `order` (and the parallel schedule) is valid for `sorted_roots`
+    dirty: bool,
+    sorted_roots: Vec<usize>,
+
+    // if true, panics in module processing are caught and the module disabled
+    catch_panics: bool,
+    // nodes that panicked and haven't been reported yet
+    panicked: Vec<usize>,
+
+    // state for parallel scheduling; all have same len
+    level: Box<[usize]>,
+    // reachable nodes, grouped by level
+    schedule: Box<[usize]>,
+    // end of each level's range in `schedule`; one more than len of the others
+    level_end: Box<[usize]>,
+    // number of levels in use
+    n_levels: usize,
+    // helper threads, present when running in parallel
+    pool: Option<Pool>,
 }
 
 #[derive(Copy, Clone, PartialEq)]
 enum VisitedState {
     NotVisited,
-    Pushed,
-    Scanned,
+    InProgress,  // on the search stack
+    Done,  // in the sorted order
 }
 
 use self::VisitedState::*;
@@ -65,6 +98,23 @@ pub enum Message {
 
     /// A request to shut down in an orderly way. Currently does nothing.
     Quit,
+
+    /// A request to reset the module at this node to its initial state; see
+    /// `Module::reset`.
+    Reset(usize),
+
+    /// A request to reset every module in the graph, keeping its structure.
+    ResetAll,
+
+    /// A request to remove the node at this id, leaving the slot empty. The
+    /// old node is sent back on the return queue. Nothing reachable from the
+    /// root should still be wired to it.
+    Remove(usize),
+
+    /// Sent from the worker when the module at this node panicked during
+    /// processing and has been disabled. Only happens when panic catching
+    /// is enabled.
+    Panicked(usize),
 }
 
 impl Message {
@@ -86,6 +136,8 @@ pub struct Node {
     in_ctrl_wiring: Box<[(usize, usize)]>,
     out_bufs: Box<[Buffer]>,
     out_ctrl: Box<[f32]>,
+    // set when the module has panicked; its outputs are then held at zero
+    disabled: bool,
 }
 
 /// A struct that contains the data for setting a parameter
@@ -156,6 +208,7 @@ impl Node {
             in_ctrl_wiring: in_ctrl_wiring.into_box(),
             out_bufs: out_bufs,
             out_ctrl: out_ctrl,
+            disabled: false,
         }
     }
 }
@@ -170,10 +223,59 @@ impl Graph {
         Graph {
             nodes: nodes.into_boxed_slice(),
             visited: vec![NotVisited; max_size].into_boxed_slice(),
-            link: vec![0; max_size].into_boxed_slice(),
+            stack: Vec::with_capacity(max_size),
+            order: Vec::with_capacity(max_size),
+            feedback: Vec::with_capacity(max_size),
+            dirty: true,
+            sorted_roots: Vec::with_capacity(max_size),
+            catch_panics: false,
+            panicked: Vec::with_capacity(max_size),
+            level: vec![0; max_size].into_boxed_slice(),
+            schedule: vec![0; max_size].into_boxed_slice(),
+            level_end: vec![0; max_size + 1].into_boxed_slice(),
+            n_levels: 0,
+            pool: None,
         }
     }
 
+    /// Set the number of threads used to run the graph, including the calling
+    /// thread. With more than one, nodes that don't depend on each other are
+    /// processed in parallel by a pool of helper threads. The output is
+    /// identical to running on one thread.
+    ///
+    /// Helper threads spin briefly between jobs and park otherwise; waking
+    /// them is the only system call made while running. This only pays off
+    /// for large graphs, and with no more threads than idle cores. Spawns
+    /// threads and allocates, so call this before starting audio.
+    pub fn set_threads(&mut self, n_threads: usize) {
+        // dropping the old pool joins its threads
+        self.pool = None;
+        self.dirty = true;
+        if n_threads > 1 {
+            self.pool = Some(Pool::new(n_threads - 1, self.nodes.len()));
+        }
+    }
+
+    /// Enable or disable catching panics in module processing. When enabled,
+    /// a module that panics has its outputs zeroed and is disabled rather than
+    /// taking down the audio thread. This is intended as a development aid;
+    /// unwinding isn't free of allocation, so it's off by default.
+    pub fn set_catch_panics(&mut self, catch_panics: bool) {
+        self.catch_panics = catch_panics;
+    }
+
+    /// Retrieve the nodes that have panicked since the last call.
+    pub fn drain_panicked(&mut self) -> vec::Drain<'_, usize> {
+        self.panicked.drain(..)
+    }
+
+    /// The feedback edges found when the graph was last sorted, as (from, to)
+    /// pairs of node indices. Each is broken by `to` reading the output of
+    /// `from` from the previous chunk.
+    pub fn feedback_edges(&self) -> &[(usize, usize)] {
+        &self.feedback
+    }
+
     /// Get the output buffers for the specified graph node. Panics if the
     /// index is not a valid, populated node. Lock-free.
     pub fn get_out_bufs(&self, ix: usize) -> &[Buffer] {
@@ -195,9 +297,19 @@ impl Graph {
         self.get_node_mut(ix).unwrap().module.deref_mut()
     }
 
+    /// Reset the state of all modules; see `Module::reset`.
+    pub fn reset_all(&mut self) {
+        for ix in 0..self.nodes.len() {
+            if let Some(node) = self.get_node_mut(ix) {
+                node.module.reset();
+            }
+        }
+    }
+
     /// Replace a graph node with a new item, returning the old value.
     /// Lock-free.
     pub fn replace(&mut self, ix: usize, item: Option<Item<Message>>) -> Option<Item<Message>> {
+        self.dirty = true;
         let mut old_item = mem::replace(&mut self.nodes[ix], item);
         if let Some(ref mut old) = old_item {
             if let Message::Node(ref mut old_node) = *old.deref_mut() {
@@ -207,83 +319,553 @@ impl Graph {
         old_item
     }
 
+    /// Remove a graph node, returning it. Lock-free.
+    pub fn remove(&mut self, ix: usize) -> Option<Item<Message>> {
+        self.dirty = true;
+        self.nodes[ix].take()
+    }
+
+    /// The number of populated nodes in the graph.
+    pub fn node_count(&self) -> usize {
+        (0..self.nodes.len()).filter(|&ix| self.get_node(ix).is_some()).count()
+    }
+
+    /// Force the next run to sort the graph again. This is never needed for
+    /// correctness, as changing the graph does it; it's for benchmarking.
+    pub fn invalidate(&mut self) {
+        self.dirty = true;
+    }
+
     fn run_one_module(&mut self, module_ix: usize, ctrl: &mut [f32; MAX_CTRL],
         bufs: &mut [*const Buffer; MAX_BUF], timestamp: u64)
     {
-        {
-            let this = self.get_node(module_ix).unwrap();
-            for (i, &(mod_ix, buf_ix)) in this.in_buf_wiring.iter().enumerate() {
-                // otherwise the transmute would cause aliasing
-                assert!(module_ix != mod_ix);
-                bufs[i] = &self.get_out_bufs(mod_ix)[buf_ix];
-            }
-            for (i, &(mod_ix, ctrl_ix)) in this.in_ctrl_wiring.iter().enumerate() {
-                ctrl[i] = self.get_node(mod_ix).unwrap().out_ctrl[ctrl_ix];
-            }
+        let catch_panics = self.catch_panics;
+        let nodes = self.nodes.as_mut_ptr();
+        if unsafe { run_node(nodes, module_ix, ctrl, bufs, timestamp, catch_panics) } {
+            self.panicked.push(module_ix);
         }
-        let this = self.get_node_mut(module_ix).unwrap();
-        let buf_in = unsafe { mem::transmute(&bufs[..this.in_buf_wiring.len()]) };
-        let ctrl_in = &ctrl[..this.in_ctrl_wiring.len()];
-        this.module.process_ts(ctrl_in, &mut this.out_ctrl, buf_in, &mut this.out_bufs,
-            timestamp);
     }
 
-    fn topo_sort(&mut self, root: usize) -> usize {
-        // initially the result linked list is empty
-        let mut head = SENTINEL;
-        let mut tail = SENTINEL;
-
-        // initially the stack just contains root
-        self.link[root] = SENTINEL;
-        let mut stack = root;
-        self.visited[root] = Pushed;
-
-        while stack != SENTINEL {
-            if self.visited[stack] == Pushed {
-                self.visited[stack] = Scanned;
-                let node = self.nodes[stack].as_ref().and_then(|item| item.get_node()).unwrap();
-                for &(ix, _) in node.in_buf_wiring.iter().chain(node.in_ctrl_wiring.iter()) {
-                    if self.visited[ix] == NotVisited {
-                        self.visited[ix] = Pushed;
-                        // push ix on stack
-                        self.link[ix] = stack;
-                        stack = ix;
-                    }
-                }
+    // Sort the nodes reachable from `roots` into `order`, inputs before the
+    // nodes that read them. An input reached again while still in progress
+    // closes a cycle; that edge is left out of the ordering, so its reader sees
+    // the previous chunk's output, a delay of one chunk, which is how feedback
+    // is allowed. A node can't read its own output buffers, though, as it's
+    // writing them; it reads silence there instead, and that isn't feedback.
+    fn topo_sort(&mut self, roots: &[usize]) {
+        self.order.clear();
+        self.feedback.clear();
+        for &root in roots {
+            if self.visited[root] == NotVisited {
+                self.visited[root] = InProgress;
+                self.stack.push((root, 0));
+                self.search();
             }
-            if self.visited[stack] == Scanned {
-                let next = self.link[stack];
+        }
+    }
 
-                // add `stack` to end of result linked list
-                self.link[stack] = SENTINEL;
-                if head == SENTINEL {
-                    head = stack;
+    // Continue the depth-first search until the stack is empty.
+    fn search(&mut self) {
+        while let Some(&mut (ix, ref mut next)) = self.stack.last_mut() {
+            let node = self.nodes[ix].as_ref().and_then(|item| item.get_node()).unwrap();
+            let n_bufs = node.in_buf_wiring.len();
+            let input = if *next < n_bufs {
+                node.in_buf_wiring[*next].0
+            } else if *next < n_bufs + node.in_ctrl_wiring.len() {
+                node.in_ctrl_wiring[*next - n_bufs].0
+            } else {
+                self.stack.pop();
+                self.visited[ix] = Done;
+                self.order.push(ix);
+                continue;
+            };
+            let own_buf = *next < n_bufs && input == ix;
+            *next += 1;
+            if own_buf {
+                continue;
+            }
+            match self.visited[input] {
+                NotVisited => {
+                    self.visited[input] = InProgress;
+                    self.stack.push((input, 0));
                 }
-                if tail != SENTINEL {
-                    self.link[tail] = stack;
+                InProgress => {
+                    // Note: non-allocation depends on this not overflowing.
+                    if self.feedback.len() < self.feedback.capacity() {
+                        self.feedback.push((input, ix));
+                    }
                 }
-                tail = stack;
-
-                // pop stack
-                stack = next;
+                Done => (),
             }
         }
-        head
     }
 
     /// Run the graph. On return, the buffer for the given root node will be
     /// filled. Designed to be lock-free.
     pub fn run_graph(&mut self, root: usize, timestamp: u64) {
+        self.run_graph_roots(&[root], timestamp);
+    }
+
+    /// Run the graph from several roots, filling the buffers of each. Nodes
+    /// shared between roots are run once. Designed to be lock-free, as long
+    /// as there are no more roots than the maximum number of nodes.
+    pub fn run_graph_roots(&mut self, roots: &[usize], timestamp: u64) {
         // scratch space, here to amortize the initialization costs
         let mut ctrl = [0.0f32; MAX_CTRL];
         let mut bufs = [ptr::null(); MAX_BUF];
 
-        // TODO: don't do topo sort every time, reuse if graph hasn't changed
-        let mut ix = self.topo_sort(root);
-        while ix != SENTINEL {
+        if self.dirty || roots != &self.sorted_roots[..] {
+            self.topo_sort(roots);
+            if self.pool.is_some() {
+                self.schedule_levels();
+            } else {
+                for &ix in &self.order {
+                    self.visited[ix] = NotVisited;  // reset state for next topo sort
+                }
+            }
+            self.dirty = false;
+            self.sorted_roots.clear();
+            self.sorted_roots.extend_from_slice(roots);
+        }
+        if self.pool.is_some() {
+            self.run_parallel(&mut ctrl, &mut bufs, timestamp);
+            return;
+        }
+        for i in 0..self.order.len() {
+            let ix = self.order[i];
             self.run_one_module(ix, &mut ctrl, &mut bufs, timestamp);
-            self.visited[ix] = NotVisited;  // reset state for next topo sort
-            ix = self.link[ix];
+        }
+    }
+
+    // Group the nodes in `order` by level into `schedule`. A node's level is
+    // one more than that of its deepest input, so the nodes within a level
+    // are independent and can run concurrently. Called right after the topo
+    // sort, while `visited` is still valid; resets it for the next sort.
+    fn schedule_levels(&mut self) {
+        // Inputs precede their consumers in topo order, so one pass suffices.
+        // Inputs not yet reached (still `Done`) are feedback edges, and don't
+        // count.
+        let mut n_levels = 0;
+        for &ix in &self.order {
+            let level = {
+                let node = self.get_node(ix).unwrap();
+                node.in_buf_wiring.iter().chain(node.in_ctrl_wiring.iter())
+                    .filter(|&&(i, _)| self.visited[i] != Done)
+                    .map(|&(i, _)| self.level[i] + 1)
+                    .max()
+                    .unwrap_or(0)
+            };
+            self.level[ix] = level;
+            n_levels = max(n_levels, level + 1);
+            self.visited[ix] = NotVisited;
+        }
+        self.n_levels = n_levels;
+
+        // Counting sort by level into `schedule`.
+        for end in self.level_end[..n_levels].iter_mut() {
+            *end = 0;
+        }
+        for &ix in &self.order {
+            self.level_end[self.level[ix]] += 1;
+        }
+        let mut start = 0;
+        for end in self.level_end[..n_levels].iter_mut() {
+            let count = *end;
+            *end = start;
+            start += count;
+        }
+        for &ix in &self.order {
+            let pos = &mut self.level_end[self.level[ix]];
+            self.schedule[*pos] = ix;
+            *pos += 1;
+        }
+    }
+
+    // Run the nodes in `schedule` a level at a time.
+    fn run_parallel(&mut self, ctrl: &mut [f32; MAX_CTRL],
+        bufs: &mut [*const Buffer; MAX_BUF], timestamp: u64)
+    {
+        let mut start = 0;
+        for level in 0..self.n_levels {
+            let end = self.level_end[level];
+            if end - start == 1 {
+                // not worth waking the pool
+                let ix = self.schedule[start];
+                self.run_one_module(ix, ctrl, bufs, timestamp);
+            } else {
+                let job = Job {
+                    nodes: self.nodes.as_mut_ptr(),
+                    schedule: self.schedule[start..].as_ptr(),
+                    len: end - start,
+                    timestamp,
+                    catch_panics: self.catch_panics,
+                };
+                let pool = self.pool.as_ref().unwrap();
+                pool.run(job, ctrl, bufs);
+                for &ix in &self.schedule[start..end] {
+                    if pool.shared.panicked[ix].swap(false, Ordering::Relaxed) {
+                        self.panicked.push(ix);
+                    }
+                }
+            }
+            start = end;
+        }
+    }
+}
+
+unsafe fn node_ref<'a>(nodes: *const Option<Item<Message>>, ix: usize) -> &'a Node {
+    (*nodes.add(ix)).as_ref().and_then(|item| item.get_node()).unwrap()
+}
+
+unsafe fn node_mut<'a>(nodes: *mut Option<Item<Message>>, ix: usize) -> &'a mut Node {
+    match (*nodes.add(ix)).as_deref_mut() {
+        Some(Message::Node(ref mut n)) => n,
+        _ => panic!("no node at {}", ix),
+    }
+}
+
+// Run one module, returning true if it panicked (and was disabled).
+//
+// Safety: `nodes` must point to the graph's nodes, with the node at
+// `module_ix` and all its inputs populated. No other thread may access that
+// node or write to its inputs for the duration of the call.
+unsafe fn run_node(nodes: *mut Option<Item<Message>>, module_ix: usize,
+    ctrl: &mut [f32; MAX_CTRL], bufs: &mut [*const Buffer; MAX_BUF], timestamp: u64,
+    catch_panics: bool) -> bool
+{
+    {
+        let this = node_ref(nodes, module_ix);
+        for (i, &(mod_ix, buf_ix)) in this.in_buf_wiring.iter().enumerate() {
+            // otherwise the transmute would cause aliasing
+            bufs[i] = if mod_ix == module_ix {
+                &SILENCE
+            } else {
+                &node_ref(nodes, mod_ix).out_bufs[buf_ix]
+            };
+        }
+        for (i, &(mod_ix, ctrl_ix)) in this.in_ctrl_wiring.iter().enumerate() {
+            ctrl[i] = node_ref(nodes, mod_ix).out_ctrl[ctrl_ix];
+        }
+    }
+    let this = node_mut(nodes, module_ix);
+    if this.disabled {
+        return false;
+    }
+    let buf_in = mem::transmute::<&[*const Buffer], &[&Buffer]>(
+        &bufs[..this.in_buf_wiring.len()]);
+    let ctrl_in = &ctrl[..this.in_ctrl_wiring.len()];
+    if !catch_panics {
+        this.module.process_ts(ctrl_in, &mut this.out_ctrl, buf_in, &mut this.out_bufs,
+            timestamp);
+        return false;
+    }
+    let result = {
+        let module = &mut this.module;
+        let out_ctrl = &mut this.out_ctrl;
+        let out_bufs = &mut this.out_bufs;
+        panic::catch_unwind(AssertUnwindSafe(||
+            module.process_ts(ctrl_in, out_ctrl, buf_in, out_bufs, timestamp)
+        ))
+    };
+    if result.is_err() {
+        for buf in this.out_bufs.iter_mut() {
+            buf.set_zero();
+        }
+        for ctrl in this.out_ctrl.iter_mut() {
+            *ctrl = 0.0;
+        }
+        this.disabled = true;
+        return true;
+    }
+    false
+}
+
+// One level's worth of nodes to run in parallel.
+#[derive(Clone, Copy)]
+struct Job {
+    nodes: *mut Option<Item<Message>>,
+    schedule: *const usize,
+    len: usize,
+    timestamp: u64,
+    catch_panics: bool,
+}
+
+struct Pool {
+    shared: Arc<Shared>,
+    threads: Vec<thread::JoinHandle<()>>,
+}
+
+// State shared between the graph and pool threads. This is all lock-free; the
+// job is only written while no pool threads are working on it.
+struct Shared {
+    // incremented to start a job
+    generation: AtomicUsize,
+    // next index within the job's schedule to be claimed
+    next: AtomicUsize,
+    // number of pool threads that have finished the current job
+    done: AtomicUsize,
+    quit: AtomicBool,
+    // set if a module panicked on a pool thread and it wasn't caught
+    poisoned: AtomicBool,
+    // set for nodes that panicked and were disabled, indexed by node
+    panicked: Box<[AtomicBool]>,
+    job: UnsafeCell<Job>,
+}
+
+unsafe impl Send for Shared {}
+unsafe impl Sync for Shared {}
+
+impl Pool {
+    fn new(n_threads: usize, max_size: usize) -> Pool {
+        let shared = Arc::new(Shared {
+            generation: AtomicUsize::new(0),
+            next: AtomicUsize::new(0),
+            done: AtomicUsize::new(0),
+            quit: AtomicBool::new(false),
+            poisoned: AtomicBool::new(false),
+            panicked: (0..max_size).map(|_| AtomicBool::new(false)).collect(),
+            job: UnsafeCell::new(Job {
+                nodes: ptr::null_mut(),
+                schedule: ptr::null(),
+                len: 0,
+                timestamp: 0,
+                catch_panics: false,
+            }),
+        });
+        let threads = (0..n_threads).map(|_| {
+            let shared = shared.clone();
+            thread::spawn(move || shared.pool_thread())
+        }).collect();
+        Pool { shared, threads }
+    }
+
+    // Run the job to completion, with the calling thread participating.
+    fn run(&self, job: Job, ctrl: &mut [f32; MAX_CTRL], bufs: &mut [*const Buffer; MAX_BUF]) {
+        let shared = &self.shared;
+        unsafe { *shared.job.get() = job; }
+        shared.next.store(0, Ordering::Relaxed);
+        shared.done.store(0, Ordering::Relaxed);
+        shared.generation.fetch_add(1, Ordering::Release);
+        for thread in &self.threads {
+            thread.thread().unpark();
+        }
+        unsafe { shared.work(ctrl, bufs); }
+        let mut spins = 0;
+        while shared.done.load(Ordering::Acquire) < self.threads.len() {
+            // Yield eventually, in case pool threads are contending for the core.
+            if spins < SPIN_LIMIT {
+                spins += 1;
+                hint::spin_loop();
+            } else {
+                thread::yield_now();
+            }
+        }
+        if shared.poisoned.load(Ordering::Relaxed) {
+            panic!("module panicked on graph pool thread");
+        }
+    }
+}
+
+impl Drop for Pool {
+    fn drop(&mut self) {
+        self.shared.quit.store(true, Ordering::Release);
+        for thread in self.threads.drain(..) {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Shared {
+    fn pool_thread(&self) {
+        let mut ctrl = [0.0f32; MAX_CTRL];
+        let mut bufs = [ptr::null(); MAX_BUF];
+        let mut generation = 0;
+        loop {
+            let mut spins = 0;
+            while self.generation.load(Ordering::Acquire) == generation {
+                if self.quit.load(Ordering::Acquire) {
+                    return;
+                }
+                if spins < SPIN_LIMIT {
+                    spins += 1;
+                    hint::spin_loop();
+                } else {
+                    thread::park();
+                }
+            }
+            // The graph waits for every thread between jobs, so we can't miss one.
+            generation = generation.wrapping_add(1);
+            let result = panic::catch_unwind(AssertUnwindSafe(||
+                unsafe { self.work(&mut ctrl, &mut bufs) }
+            ));
+            if result.is_err() {
+                // Propagated to the graph thread, as it would be when serial.
+                self.poisoned.store(true, Ordering::Relaxed);
+            }
+            self.done.fetch_add(1, Ordering::Release);
+        }
+    }
+
+    // Claim and run nodes from the current job until none are left.
+    unsafe fn work(&self, ctrl: &mut [f32; MAX_CTRL], bufs: &mut [*const Buffer; MAX_BUF]) {
+        let job = *self.job.get();
+        loop {
+            let i = self.next.fetch_add(1, Ordering::Relaxed);
+            if i >= job.len {
+                break;
+            }
+            let ix = *job.schedule.add(i);
+            if run_node(job.nodes, ix, ctrl, bufs, job.timestamp, job.catch_panics) {
+                self.panicked[ix].store(true, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use queue::Item;
+    use module::{Buffer, Module};
+    use modules;
+    use super::{Graph, Message, Node};
+
+    // Many oscillators through filters, mixed by a two-level tree of sums.
+    fn build(graph: &mut Graph) {
+        let mut add = |node: Node| {
+            let ix = node.ix;
+            graph.replace(ix, Some(Item::make_item(Message::Node(node))));
+        };
+        add(Node::create(Box::new(modules::ConstCtrl::new(0.5)), 1, [], []));
+        let mut sums = Vec::new();
+        for i in 0..4 {
+            let mut inputs = Vec::new();
+            for j in 0..8 {
+                let ix = 10 + 3 * (8 * i + j);
+                let pitch = 220f32.log2() + (8 * i + j) as f32 / 12.0;
+                add(Node::create(Box::new(modules::ConstCtrl::new(pitch)), ix, [], []));
+                add(Node::create(Box::new(modules::Saw::new(44_100.0)), ix + 1, [],
+                    [(ix, 0)]));
+                add(Node::create(Box::new(modules::Biquad::new(44_100.0)), ix + 2,
+                    [(ix + 1, 0)], [(ix, 0), (1, 0)]));
+                inputs.push((ix + 2, 0));
+            }
+            add(Node::create(Box::new(modules::Sum::new()), 2 + i, inputs, []));
+            sums.push((2 + i, 0));
+        }
+        add(Node::create(Box::new(modules::Sum::new()), 0, sums, []));
+    }
+
+    // Produces a single impulse at the start of the first chunk.
+    struct Impulse(bool);
+
+    impl Module for Impulse {
+        fn n_bufs_out(&self) -> usize { 1 }
+
+        fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
+            _buf_in: &[&Buffer], buf_out: &mut [Buffer])
+        {
+            buf_out[0].set_zero();
+            if !self.0 {
+                buf_out[0].get_mut()[0] = 1.0;
+                self.0 = true;
+            }
+        }
+    }
+
+    #[test]
+    fn feedback_loop_delays_by_a_chunk() {
+        let mut graph = Graph::new(16);
+        {
+            let mut add = |node: Node| {
+                let ix = node.ix;
+                graph.replace(ix, Some(Item::make_item(Message::Node(node))));
+            };
+            add(Node::create(Box::new(Impulse(false)), 2, [], []));
+            add(Node::create(Box::new(modules::ConstCtrl::new(-1.0)), 3, [], []));
+            // 0 mixes the impulse with its own output, through 1, at half gain
+            add(Node::create(Box::new(modules::Sum::new()), 0, [(2, 0), (1, 0)], []));
+            add(Node::create(Box::new(modules::Gain::new()), 1, [(0, 0)], [(3, 0)]));
+        }
+        let mut expected = 1.0;
+        for _ in 0..10 {
+            graph.run_graph(0, 0);
+            assert_eq!(graph.feedback_edges(), &[(0, 1)]);
+            let out = graph.get_out_bufs(0)[0].get();
+            assert!((out[0] - expected).abs() < 1e-6, "got {}, expected {}", out[0], expected);
+            assert!(out[1..].iter().all(|&y| y == 0.0));
+            expected *= 0.5;
+        }
+    }
+
+    #[test]
+    fn own_output_reads_as_silence() {
+        for &threads in &[0, 2] {
+            let mut graph = Graph::new(16);
+            if threads > 0 {
+                graph.set_threads(threads);
+            }
+            graph.replace(1, Some(Item::make_item(Message::Node(
+                Node::create(Box::new(Impulse(false)), 1, [], [])))));
+            graph.replace(0, Some(Item::make_item(Message::Node(
+                Node::create(Box::new(modules::Sum::new()), 0, [(1, 0), (0, 0)], [])))));
+            graph.run_graph(0, 0);
+            assert!(graph.feedback_edges().is_empty());
+            assert_eq!(graph.get_out_bufs(0)[0].get()[0], 1.0);
+            graph.run_graph(0, 0);
+            assert!(graph.get_out_bufs(0)[0].get().iter().all(|&y| y == 0.0));
+        }
+    }
+
+    #[test]
+    fn cached_order_tracks_mutations() {
+        let mut graph = Graph::new(128);
+        build(&mut graph);
+        let check = |graph: &mut Graph| {
+            graph.run_graph(0, 0);
+            let cached = graph.order.clone();
+            graph.run_graph(0, 0);
+            assert_eq!(graph.order, cached);
+            graph.invalidate();
+            graph.run_graph(0, 0);
+            assert_eq!(graph.order, cached);
+            cached
+        };
+        assert_eq!(check(&mut graph).len(), 1 + 4 + 32 * 3 + 1);
+
+        // rewire a sum to read a new oscillator
+        let saw = Node::create(Box::new(modules::Saw::new(44_100.0)), 120, [], [(10, 0)]);
+        graph.replace(120, Some(Item::make_item(Message::Node(saw))));
+        let sum = Node::create(Box::new(modules::Sum::new()), 2, [(120, 0)], []);
+        graph.replace(2, Some(Item::make_item(Message::Node(sum))));
+        let order = check(&mut graph);
+        assert!(order.contains(&120));
+        assert!(!order.contains(&12));
+
+        // disconnect and remove another voice
+        let sum = Node::create(Box::new(modules::Sum::new()), 3, [], []);
+        graph.replace(3, Some(Item::make_item(Message::Node(sum))));
+        graph.remove(34 + 2);
+        let order = check(&mut graph);
+        assert!(!order.contains(&36));
+        // root, sums, shared cutoff, new saw and its pitch, two sums of voices
+        assert_eq!(order.len(), 1 + 4 + 1 + 2 + 2 * 8 * 3);
+    }
+
+    #[test]
+    fn parallel_matches_serial() {
+        let mut serial = Graph::new(128);
+        let mut parallel = Graph::new(128);
+        build(&mut serial);
+        build(&mut parallel);
+        parallel.set_threads(4);
+        for i in 0..50 {
+            serial.run_graph(0, i);
+            parallel.run_graph(0, i);
+            let a = serial.get_out_bufs(0)[0].get();
+            let b = parallel.get_out_bufs(0)[0].get();
+            assert!(a.iter().any(|&x| x != 0.0));
+            assert_eq!(a, b);
         }
     }
 }
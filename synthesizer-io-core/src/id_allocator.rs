@@ -55,8 +55,9 @@ impl IdAllocator {
 
     /// Reserve an id, preventing it from being issued.
     pub fn reserve(&mut self, id: usize) {
-        if id == self.highwater {
-            self.highwater += 1;
+        if id >= self.highwater {
+            self.free.extend(self.highwater..id);
+            self.highwater = id + 1;
         } else {
             if let Some(pos) = self.free.iter().position(|x| *x == id) {
                 self.free.remove(pos);
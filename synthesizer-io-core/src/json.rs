@@ -0,0 +1,379 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Just enough JSON to save and load presets.
+
+use std::fmt;
+
+// Deeper documents are rejected rather than risk overflowing the stack.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    // keys in order of appearance
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Parse a complete JSON document.
+    pub fn parse(text: &str) -> Result<Value, String> {
+        let mut parser = Parser { text: text.as_bytes(), pos: 0, depth: 0 };
+        let value = parser.value()?;
+        parser.skip_ws();
+        if parser.pos < parser.text.len() {
+            return Err(parser.err("trailing characters"));
+        }
+        Ok(value)
+    }
+
+    /// Write the value as a JSON document. Fails on a number that isn't
+    /// finite, which JSON can't represent.
+    pub fn to_json(&self) -> Result<String, String> {
+        self.check_finite()?;
+        Ok(self.to_string())
+    }
+
+    fn check_finite(&self) -> Result<(), String> {
+        match *self {
+            Value::Number(x) if !x.is_finite() => Err(format!("can't write {} as JSON", x)),
+            Value::Array(ref items) => items.iter().try_for_each(Value::check_finite),
+            Value::Object(ref fields) => fields.iter().try_for_each(|f| f.1.check_finite()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Build an object from its fields.
+    pub fn object(fields: Vec<(&str, Value)>) -> Value {
+        Value::Object(fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+    }
+
+    /// Look up a field of an object.
+    pub fn field(&self, key: &str) -> Result<&Value, String> {
+        match *self {
+            Value::Object(ref fields) => fields.iter().find(|f| f.0 == key).map(|f| &f.1)
+                .ok_or_else(|| format!("missing field {}", key)),
+            _ => Err(format!("expected an object with field {}", key)),
+        }
+    }
+
+    pub fn as_f32(&self) -> Result<f32, String> {
+        match *self {
+            Value::Number(x) => Ok(x as f32),
+            _ => Err(format!("expected a number, got {}", self)),
+        }
+    }
+
+    pub fn as_usize(&self) -> Result<usize, String> {
+        match *self {
+            Value::Number(x) if x >= 0.0 && x.fract() == 0.0 => Ok(x as usize),
+            _ => Err(format!("expected a non-negative integer, got {}", self)),
+        }
+    }
+
+    pub fn as_bool(&self) -> Result<bool, String> {
+        match *self {
+            Value::Bool(b) => Ok(b),
+            _ => Err(format!("expected true or false, got {}", self)),
+        }
+    }
+
+    pub fn as_str(&self) -> Result<&str, String> {
+        match *self {
+            Value::String(ref s) => Ok(s),
+            _ => Err(format!("expected a string, got {}", self)),
+        }
+    }
+
+    pub fn as_array(&self) -> Result<&[Value], String> {
+        match *self {
+            Value::Array(ref items) => Ok(items),
+            _ => Err(format!("expected an array, got {}", self)),
+        }
+    }
+
+    /// The value, or `None` if it's null.
+    pub fn as_option(&self) -> Option<&Value> {
+        match *self {
+            Value::Null => None,
+            _ => Some(self),
+        }
+    }
+}
+
+impl From<f32> for Value {
+    fn from(x: f32) -> Value {
+        // Going through the shortest decimal form of the f32 keeps the text
+        // tidy, and parses back to the same f32.
+        Value::Number(x.to_string().parse().unwrap())
+    }
+}
+
+impl From<usize> for Value {
+    fn from(x: usize) -> Value {
+        Value::Number(x as f64)
+    }
+}
+
+impl<'a> From<&'a str> for Value {
+    fn from(s: &'a str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Value {
+        Value::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(x: Option<T>) -> Value {
+        x.map_or(Value::Null, Into::into)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{}", b),
+            Value::Number(x) => write!(f, "{}", x),
+            Value::String(ref s) => write_str(f, s),
+            Value::Array(ref items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { ", " }, item)?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(ref fields) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    write!(f, "{}", if i == 0 { "" } else { ", " })?;
+                    write_str(f, key)?;
+                    write!(f, ": {}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_str(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn err(&self, what: &str) -> String {
+        format!("JSON error at byte {}: {}", self.pos, what)
+    }
+
+    fn skip_ws(&mut self) {
+        while self.pos < self.text.len() && b" \t\r\n".contains(&self.text[self.pos]) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_ws();
+        self.text.get(self.pos).cloned()
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.err(&format!("expected '{}'", c as char)))
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Value) -> Result<Value, String> {
+        if self.text[self.pos..].starts_with(word.as_bytes()) {
+            self.pos += word.len();
+            Ok(value)
+        } else {
+            Err(self.err("unexpected word"))
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.err("nested too deeply"));
+        }
+        self.depth += 1;
+        let value = self.nested_value();
+        self.depth -= 1;
+        value
+    }
+
+    fn nested_value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some(b'n') => self.literal("null", Value::Null),
+            Some(b't') => self.literal("true", Value::Bool(true)),
+            Some(b'f') => self.literal("false", Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.peek() == Some(b',') {
+                        self.pos += 1;
+                    } else {
+                        self.expect(b']')?;
+                        return Ok(Value::Array(items));
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut fields = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(fields));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return Err(self.err("expected a key"));
+                    }
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    if self.peek() == Some(b',') {
+                        self.pos += 1;
+                    } else {
+                        self.expect(b'}')?;
+                        return Ok(Value::Object(fields));
+                    }
+                }
+            }
+            Some(_) => self.number(),
+            None => Err(self.err("unexpected end")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while self.pos < self.text.len() && b"+-.eE0123456789".contains(&self.text[self.pos]) {
+            self.pos += 1;
+        }
+        // only ASCII was consumed, so this is a char boundary
+        let s = ::std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        s.parse().map(Value::Number).map_err(|_| {
+            self.pos = start;
+            self.err("expected a value")
+        })
+    }
+
+    // Parse a string, the parser being at its opening quote.
+    fn string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let c = match self.text.get(self.pos) {
+                Some(&c) => c,
+                None => return Err(self.err("unterminated string")),
+            };
+            self.pos += 1;
+            match c {
+                b'"' => break,
+                b'\\' => {
+                    let esc = self.text.get(self.pos).cloned();
+                    self.pos += 1;
+                    let c = match esc {
+                        Some(b'n') => '\n',
+                        Some(b't') => '\t',
+                        Some(b'r') => '\r',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'u') => {
+                            let hex = self.text.get(self.pos..self.pos + 4)
+                                .and_then(|h| ::std::str::from_utf8(h).ok())
+                                .and_then(|h| u32::from_str_radix(h, 16).ok())
+                                .and_then(::std::char::from_u32);
+                            self.pos += 4;
+                            hex.ok_or_else(|| self.err("bad \\u escape"))?
+                        }
+                        Some(c @ b'"') | Some(c @ b'\\') | Some(c @ b'/') => c as char,
+                        _ => return Err(self.err("bad escape")),
+                    };
+                    let mut buf = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                }
+                c => bytes.push(c),
+            }
+        }
+        String::from_utf8(bytes).map_err(|_| self.err("invalid UTF-8"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Value;
+
+    #[test]
+    fn round_trip() {
+        let value = Value::object(vec![
+            ("name", "a \"quoted\"\nname".into()),
+            ("values", vec![0.1f32, -24.0, 1e-7].into()),
+            ("id", Value::from(17usize)),
+            ("none", Value::from(None::<usize>)),
+            ("flags", Value::Array(vec![Value::Bool(true), Value::Bool(false)])),
+            ("empty", Value::object(vec![])),
+        ]);
+        let text = value.to_json().unwrap();
+        assert_eq!(Value::parse(&text), Ok(value.clone()));
+        let spaced = Value::parse(" { \"id\" : 17 , \"values\" : [ ] } ").unwrap();
+        assert_eq!(spaced.field("id").and_then(Value::as_usize), Ok(17));
+        assert_eq!(value.field("values").unwrap().as_array().unwrap()[0].as_f32(), Ok(0.1));
+        assert!(Value::parse("[1, 2").is_err());
+        assert!(Value::parse("{\"a\": 1} x").is_err());
+        assert!(Value::parse("\"\\u00e9\"").unwrap().as_str() == Ok("\u{e9}"));
+    }
+
+    #[test]
+    fn rejects_what_json_cannot_hold() {
+        assert!(Value::from(vec![1.0, f32::NAN]).to_json().is_err());
+        assert!(Value::object(vec![("x", f32::INFINITY.into())]).to_json().is_err());
+        let nested = |depth| "[".repeat(depth) + &"]".repeat(depth);
+        assert!(Value::parse(&nested(64)).is_ok());
+        assert!(Value::parse(&nested(100_000)).is_err());
+    }
+}
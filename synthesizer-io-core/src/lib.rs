@@ -17,12 +17,19 @@
 #[macro_use]
 extern crate lazy_static;
 
+extern crate rustfft;
 extern crate time;
 
+pub mod coalesce;
 pub mod engine;
 pub mod graph;
 pub mod id_allocator;
+mod json;
 pub mod module;
 pub mod modules;
 pub mod queue;
+pub mod resample;
 pub mod worker;
+
+#[cfg(test)]
+mod test_util;
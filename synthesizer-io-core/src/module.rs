@@ -18,6 +18,45 @@ use std::any::Any;
 
 pub const N_SAMPLES_PER_CHUNK: usize = 32;
 
+/// How a parameter's value is interpreted.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ParamKind {
+    /// The value is used directly.
+    Linear,
+    /// The value is log2 of a quantity such as frequency, gain, or rate.
+    Log,
+    /// The value selects one of a set of integer choices.
+    Switch,
+}
+
+/// A description of a parameter, for building UIs and mapping controllers.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ParamDesc {
+    pub name: &'static str,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub kind: ParamKind,
+}
+
+impl ParamDesc {
+    /// Map a value in [0, 1] (for example, from a MIDI controller) to the
+    /// parameter's range. Switches are rounded to a whole choice.
+    pub fn from_unit(&self, x: f32) -> f32 {
+        let val = self.min + x * (self.max - self.min);
+        match self.kind {
+            ParamKind::Switch => val.round(),
+            _ => val,
+        }
+    }
+
+    /// Map a value in the parameter's range to [0, 1]; the inverse of
+    /// `from_unit`.
+    pub fn to_unit(&self, val: f32) -> f32 {
+        ((val - self.min) / (self.max - self.min)).clamp(0.0, 1.0)
+    }
+}
+
 pub struct Buffer {
     // TODO: simd alignment
     buf: [f32; N_SAMPLES_PER_CHUNK],
@@ -25,6 +64,9 @@ pub struct Buffer {
 }
 
 impl Buffer {
+    /// A buffer of zeros.
+    pub const SILENCE: Buffer = Buffer { buf: [0.0; N_SAMPLES_PER_CHUNK] };
+
     pub fn set_zero(&mut self) {
         *self = Default::default();
     }
@@ -46,6 +88,14 @@ impl Default for Buffer {
     }
 }
 
+/// A unit of audio processing, run as a node of the graph.
+///
+/// Multi-channel audio is carried as separate mono buffers: a stereo module
+/// has `n_bufs_out() == 2`, left first, and a stereo input takes two
+/// consecutive buffer wiring slots. Modules like `Sum` and `Monitor` can be
+/// configured to handle more than one channel. If the root of the graph has
+/// two output buffers, they are played as left and right; otherwise its
+/// first buffer is played on both channels.
 pub trait Module: ToAny + Send {
     /// Report the number of buffers this module is expected to generate.
     fn n_bufs_out(&self) -> usize { 0 }
@@ -79,6 +129,26 @@ pub trait Module: ToAny + Send {
     /// Handle a note on or off message.
     #[allow(unused)]
     fn handle_note(&mut self, midi_num: f32, velocity: f32, on: bool) {}
+
+    /// Describe the module's parameters. For most modules these are the
+    /// control inputs, in order; for control sources such as `SmoothCtrl`,
+    /// it's the value set by `set_param`.
+    fn params(&self) -> &[ParamDesc] { &[] }
+
+    /// A name for the module's type, used when saving presets. Modules that
+    /// can't be saved return `None`; see `modules::from_preset`.
+    fn type_tag(&self) -> Option<&'static str> { None }
+
+    /// The current values of the module's settings: params set by
+    /// `set_param` and configuration such as the number of channels, enough
+    /// to rebuild it (without its running state) from the type tag.
+    fn param_values(&self) -> Vec<f32> { Vec::new() }
+
+    /// Return the module to its initial state, for example clearing
+    /// oscillator phase and filter state, so a reused voice sounds the same
+    /// as a fresh one. Parameters are kept. Implementations are expected to
+    /// be lock-free.
+    fn reset(&mut self) {}
 }
 
 pub trait ToAny {
@@ -88,3 +158,28 @@ pub trait ToAny {
 impl<T: Sized + 'static> ToAny for T {
     fn to_any(&mut self) -> &mut dyn Any { self }
 }
+
+#[cfg(test)]
+mod tests {
+    use modules::{Adsr, Biquad, SmoothCtrl};
+    use super::{Module, ParamDesc};
+
+    #[test]
+    fn param_ranges_round_trip() {
+        let modules: Vec<Box<dyn Module>> = vec![
+            Box::new(Biquad::new(44_100.0)),
+            Box::new(Adsr::new()),
+            Box::new(SmoothCtrl::with_desc(Biquad::PARAMS[0])),
+        ];
+        let params: Vec<ParamDesc> = modules.iter().flat_map(|m| m.params().to_vec()).collect();
+        assert_eq!(params.len(), Biquad::PARAMS.len() + Adsr::PARAMS.len() + 1);
+        for p in params {
+            assert!(p.min < p.max && p.min <= p.default && p.default <= p.max, "{}", p.name);
+            for &val in &[p.min, p.default, p.max] {
+                assert!((p.from_unit(p.to_unit(val)) - val).abs() < 1e-5, "{}", p.name);
+            }
+            assert_eq!(p.from_unit(0.0), p.min);
+            assert_eq!(p.from_unit(1.0), p.max);
+        }
+    }
+}
@@ -13,12 +13,47 @@
 // limitations under the License.
 
 //! Attack, decay, sustain, release.
+//!
+//! The output is a log2 level. Control inputs are attack, decay, and release
+//! rates and a sustain level, all log2 (see `PARAMS`), and an optional curve.
+//! The attack takes 2^attack chunks to reach full level, wherever it starts
+//! from. Decay falls by a unit of log2 level every 2^decay chunks, and release
+//! likewise every 2^release chunks, down to silence at -24.
+//!
+//! The curve, from 0 to 1, blends the shape of each segment between linear in
+//! amplitude and exponential: an RC-style charge for the attack, and constant
+//! dB per second for decay and release. Each segment takes the same time
+//! whatever its shape. Without the curve input, the attack is linear and decay
+//! and release are exponential.
+//!
+//! Param 0 sets a minimum retrigger interval, in seconds. A note-on arriving
+//! sooner than this after the previous one doesn't restart the attack, so fast
+//! repeats blend smoothly rather than sounding machine-gunned.
+//!
+//! Param 1 sets the velocity sensitivity, from 0 (the default, every note at
+//! full level) to 1, where the level of the whole envelope is proportional to
+//! the note's velocity.
 
-use module::{Module, Buffer};
+use module::{Module, Buffer, ParamDesc, ParamKind};
+
+// Steepness of the exponential attack; it's 1 - e^(-k t), normalized.
+const ATTACK_K: f32 = 3.0;
 
 pub struct Adsr {
     value: f32,
     state: State,
+    // level (log2) at the start of the current segment, and how far through
+    // the segment it is, from 0 to 1
+    start: f32,
+    pos: f32,
+    // minimum interval between attacks, in ns
+    retrigger_ns: u64,
+    // timestamp of the most recently processed chunk
+    now: u64,
+    last_note_on: Option<u64>,
+    velocity_amount: f32,
+    // level offset (log2) from the velocity of the current note
+    velocity_offset: f32,
 }
 
 enum State {
@@ -32,43 +67,142 @@ enum State {
 use self::State::*;
 
 impl Adsr {
+    /// Descriptions of the control inputs. Attack, decay, and release are
+    /// log2 rates, and sustain is a log2 level.
+    pub const PARAMS: [ParamDesc; 4] = [
+        ParamDesc { name: "attack", min: 0.0, max: 10.0, default: 5.0, kind: ParamKind::Log },
+        ParamDesc { name: "decay", min: 0.0, max: 10.0, default: 5.0, kind: ParamKind::Log },
+        ParamDesc { name: "sustain", min: 0.0, max: 6.0, default: 4.0, kind: ParamKind::Log },
+        ParamDesc { name: "release", min: 0.0, max: 10.0, default: 5.0, kind: ParamKind::Log },
+    ];
+
     pub fn new() -> Adsr {
         Adsr {
             value: -24.0,
             state: Quiet,
+            start: -24.0,
+            pos: 0.0,
+            retrigger_ns: 0,
+            now: 0,
+            last_note_on: None,
+            velocity_amount: 0.0,
+            velocity_offset: 0.0,
         }
     }
 }
 
+impl Adsr {
+    fn start_segment(&mut self, state: State) {
+        self.state = state;
+        self.start = self.value;
+        self.pos = 0.0;
+    }
+}
+
+// Fraction of the way up the attack at position `pos`.
+fn attack_shape(pos: f32, curve: f32) -> f32 {
+    let exp = (1.0 - (-ATTACK_K * pos).exp()) / (1.0 - (-ATTACK_K).exp());
+    pos + curve * (exp - pos)
+}
+
+// Level (log2) at position `pos` of a segment falling from `from` to `to`,
+// both log2.
+fn fall(from: f32, to: f32, pos: f32, curve: f32) -> f32 {
+    let exp = (from + (to - from) * pos).exp2();
+    let (l0, l1) = (from.exp2(), to.exp2());
+    let lin = l0 + (l1 - l0) * pos;
+    (lin + curve * (exp - lin)).max(1e-30).log2()
+}
+
 impl Module for Adsr {
     fn n_ctrl_out(&self) -> usize { 1 }
 
-    fn handle_note(&mut self, _midi_num: f32, _velocity: f32, on: bool) {
+    fn type_tag(&self) -> Option<&'static str> { Some("adsr") }
+
+    fn param_values(&self) -> Vec<f32> {
+        vec![(self.retrigger_ns as f64 * 1e-9) as f32, self.velocity_amount]
+    }
+
+    fn params(&self) -> &[ParamDesc] { &Adsr::PARAMS }
+
+    fn reset(&mut self) {
+        self.value = -24.0;
+        self.state = Quiet;
+        self.last_note_on = None;
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        match param_ix {
+            0 => self.retrigger_ns = (val.max(0.0) as f64 * 1e9) as u64,
+            1 => self.velocity_amount = val.clamp(0.0, 1.0),
+            _ => (),
+        }
+    }
+
+    fn handle_note(&mut self, _midi_num: f32, velocity: f32, on: bool) {
         if on {
-            self.state = Attack;
+            let v = (velocity * (1.0 / 127.0)).clamp(1.0 / 127.0, 1.0);
+            self.velocity_offset = self.velocity_amount * v.log2();
+            let fast_repeat = match self.last_note_on {
+                Some(t) => self.now.saturating_sub(t) < self.retrigger_ns,
+                None => false,
+            };
+            self.last_note_on = Some(self.now);
+            if !fast_repeat {
+                self.start_segment(Attack);
+            } else if let Release = self.state {
+                // Return to the sustain level without a new attack peak.
+                self.start_segment(Decay);
+            }
         } else {
-            self.state = Release;
+            self.start_segment(Release);
         }
     }
 
+    fn process_ts(&mut self, control_in: &[f32], control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer], timestamp: u64)
+    {
+        self.now = timestamp;
+        self.process(control_in, control_out, buf_in, buf_out);
+    }
+
     fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
         _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
     {
+        let (attack_curve, fall_curve) = match control_in.get(4) {
+            Some(c) => (c.clamp(0.0, 1.0), c.clamp(0.0, 1.0)),
+            None => (0.0, 1.0),
+        };
         match self.state {
             Quiet => (),
             Attack => {
-                let mut l = self.value.exp2();
-                l += (-control_in[0]).exp2();
-                if l >= 1.0 {
-                    l = 1.0;
-                    self.state = Decay;
-                }
+                self.pos = (self.pos + (-control_in[0]).exp2()).min(1.0);
+                let l0 = self.start.exp2();
+                let l = l0 + (1.0 - l0) * attack_shape(self.pos, attack_curve);
                 self.value = l.log2();
+                if self.pos >= 1.0 {
+                    self.value = 0.0;
+                    self.start_segment(Decay);
+                }
             }
             Decay => {
                 let sustain = control_in[2] - 6.0;
-                self.value -= (-control_in[1]).exp2();
                 if self.value < sustain {
+                    // Only reached on a fast repeat from release; rise at the
+                    // attack rate.
+                    let l = self.value.exp2() + (-control_in[0]).exp2();
+                    self.value = l.log2();
+                    if self.value >= sustain {
+                        self.value = sustain;
+                        self.state = Sustain;
+                    }
+                    control_out[0] = self.value + self.velocity_offset;
+                    return;
+                }
+                let span = (self.start - sustain).max(1e-6);
+                self.pos = (self.pos + (-control_in[1]).exp2() / span).min(1.0);
+                self.value = fall(self.start, sustain, self.pos, fall_curve);
+                if self.pos >= 1.0 {
                     self.value = sustain;
                     self.state = Sustain;
                 }
@@ -78,13 +212,93 @@ impl Module for Adsr {
                 self.value = sustain;
             }
             Release => {
-                self.value -= (-control_in[3]).exp2();
-                if self.value < -24.0 {
+                let span = (self.start + 24.0).max(1e-6);
+                self.pos = (self.pos + (-control_in[3]).exp2() / span).min(1.0);
+                self.value = fall(self.start, -24.0, self.pos, fall_curve);
+                if self.pos >= 1.0 {
                     self.value = -24.0;
                     self.state = Quiet;
                 }
             }
         }
-        control_out[0] = self.value;
+        control_out[0] = self.value + self.velocity_offset;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::Module;
+    use super::Adsr;
+
+    // attack, decay, sustain (-2 in log2), release
+    const CTRL: [f32; 4] = [5.0, 5.0, 4.0, 5.0];
+    const CHUNK_NS: u64 = 725_624;
+
+    // Run for n chunks, returning the peak value.
+    fn run(adsr: &mut Adsr, ts: &mut u64, n: usize) -> f32 {
+        let mut out = [0.0];
+        let mut peak = -24.0f32;
+        for _ in 0..n {
+            adsr.process_ts(&CTRL, &mut out, &[], &mut [], *ts);
+            *ts += CHUNK_NS;
+            peak = peak.max(out[0]);
+        }
+        peak
+    }
+
+    #[test]
+    fn fast_repeat_does_not_retrigger() {
+        let mut adsr = Adsr::new();
+        adsr.set_param(0, 0.1, 0);
+        let mut ts = 0;
+        adsr.handle_note(60.0, 100.0, true);
+        assert_eq!(run(&mut adsr, &mut ts, 200), 0.0);
+        adsr.handle_note(60.0, 0.0, false);
+        run(&mut adsr, &mut ts, 4);
+        // About 0.15s after the first note-on, so this one attacks.
+        adsr.handle_note(60.0, 100.0, true);
+        assert_eq!(run(&mut adsr, &mut ts, 100), 0.0);
+        adsr.handle_note(60.0, 0.0, false);
+        run(&mut adsr, &mut ts, 4);
+        // About 0.075s later: only returns to the sustain level, with no
+        // attack peak.
+        adsr.handle_note(60.0, 100.0, true);
+        assert_eq!(run(&mut adsr, &mut ts, 100), -2.0);
+    }
+
+    #[test]
+    fn velocity_scales_level() {
+        let peak = |velocity: f32| {
+            let mut adsr = Adsr::new();
+            adsr.set_param(1, 1.0, 0);
+            adsr.handle_note(60.0, velocity, true);
+            run(&mut adsr, &mut 0, 200)
+        };
+        assert!((peak(120.0) - peak(40.0) - 3f32.log2()).abs() < 1e-5);
+        assert!(peak(127.0).abs() < 1e-5);
+    }
+
+    // Number of chunks for the attack to reach 90%, and then full level.
+    fn attack_chunks(curve: f32) -> (usize, usize) {
+        let mut adsr = Adsr::new();
+        adsr.handle_note(60.0, 100.0, true);
+        let ctrl = [5.0, 5.0, 4.0, 5.0, curve];
+        let mut out = [0.0];
+        let levels: Vec<_> = (0..64).map(|_| {
+            adsr.process(&ctrl, &mut out, &[], &mut []);
+            out[0].exp2()
+        }).collect();
+        let reach = |l: f32| levels.iter().position(|&x| x >= l - 1e-6).unwrap() + 1;
+        (reach(0.9), reach(1.0))
+    }
+
+    #[test]
+    fn attack_time_matches_curve() {
+        // An attack of 5 is 32 chunks.
+        assert_eq!(attack_chunks(0.0), (29, 32));
+        // 1 - e^(-3t) = 0.9 (1 - e^-3) at t = 0.644, or 20.6 chunks
+        assert_eq!(attack_chunks(1.0), (21, 32));
+        let (ninety, full) = attack_chunks(0.5);
+        assert!(ninety > 21 && ninety < 29 && full == 32);
     }
 }
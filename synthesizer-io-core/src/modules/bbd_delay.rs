@@ -0,0 +1,192 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An emulation of an analog bucket-brigade (BBD) delay.
+//!
+//! Unlike the clean `Delay`, the signal going into the line is lowpass
+//! filtered, as by a BBD's anti-aliasing filter, so each repeat is darker
+//! than the one before. It's also companded, 2:1 going in and 1:2 coming out,
+//! with low-level clock noise added in between, so the noise rises and falls
+//! with the signal rather than hissing through the gaps. The expander uses the
+//! compressor's own gain, delayed along with the signal, so the two always
+//! track and the repeats decay by the feedback amount.
+//!
+//! Control inputs are delay time (seconds), feedback (0 to 0.99), tone (the
+//! cutoff of the loop filter, log2 Hz), and mix (0 dry to 1 wet).
+
+use std::f32::consts;
+
+use module::{Module, Buffer};
+
+// time constant of the compander's envelope followers, in seconds
+const COMPANDER_TC: f32 = 0.01;
+
+// level of the clock noise added in the line
+const NOISE_LEVEL: f32 = 1e-4;
+
+pub struct BbdDelay {
+    sample_rate: f32,
+    // ring buffers of the compressed signal, and the gain that compressed it
+    buf: Vec<f32>,
+    gains: Vec<f32>,
+    // index of the next sample to be written
+    ix: usize,
+    // delay in samples at the end of the last chunk, for smoothing
+    last_delay: Option<f32>,
+    // state of the two one-pole stages of the loop filter
+    lp: [f32; 2],
+    // envelope of the compressor input
+    env: f32,
+    env_coef: f32,
+    rng: u32,
+}
+
+impl BbdDelay {
+    /// Create a new delay. The buffer is allocated here, so `max_delay_secs`
+    /// bounds the delay time for the lifetime of the module.
+    pub fn new(sample_rate: f32, max_delay_secs: f32) -> BbdDelay {
+        let len = (max_delay_secs * sample_rate).ceil() as usize + 2;
+        BbdDelay {
+            sample_rate,
+            buf: vec![0.0; len],
+            gains: vec![0.0; len],
+            ix: 0,
+            last_delay: None,
+            lp: [0.0; 2],
+            env: 0.0,
+            env_coef: 1.0 - (-1.0 / (COMPANDER_TC * sample_rate)).exp(),
+            rng: 1,
+        }
+    }
+
+    // Read the sample of `buf` written `delay` samples in the past, with
+    // linear interpolation. Requires 1 <= delay <= len - 2.
+    fn read(&self, buf: &[f32], delay: f32) -> f32 {
+        let len = buf.len();
+        let d_int = delay as usize;
+        let frac = delay - d_int as f32;
+        let i0 = (self.ix + len - d_int) % len;
+        let i1 = (i0 + len - 1) % len;
+        let y0 = buf[i0];
+        let y1 = buf[i1];
+        y0 + (y1 - y0) * frac
+    }
+
+    // White noise in [-1, 1).
+    fn noise(&mut self) -> f32 {
+        self.rng = self.rng.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (self.rng as i32) as f32 * (1.0 / 2_147_483_648.0)
+    }
+}
+
+impl Module for BbdDelay {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        for x in self.buf.iter_mut().chain(self.gains.iter_mut()) {
+            *x = 0.0;
+        }
+        self.ix = 0;
+        self.last_delay = None;
+        self.lp = [0.0; 2];
+        self.env = 0.0;
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let max_delay = (self.buf.len() - 2) as f32;
+        let delay = (control_in[0] * self.sample_rate).clamp(1.0, max_delay);
+        let feedback = control_in[1].clamp(0.0, 0.99);
+        let cutoff = control_in[2].exp2().min(0.45 * self.sample_rate);
+        let lp_coef = 1.0 - (-2.0 * consts::PI * cutoff / self.sample_rate).exp();
+        let mix = control_in[3];
+        let inb = buf_in[0].get();
+        let out = buf_out[0].get_mut();
+        // Ramp the delay time linearly across the chunk to avoid zipper noise.
+        let mut d = self.last_delay.unwrap_or(delay);
+        let dd = (delay - d) * (1.0 / out.len() as f32);
+        self.last_delay = Some(delay);
+        let len = self.buf.len();
+        let k = self.env_coef;
+        for (x, y) in inb.iter().zip(out.iter_mut()) {
+            d += dd;
+            // expand what comes out of the line
+            let wet = self.read(&self.buf, d) * self.read(&self.gains, d);
+
+            let mut v = x + feedback * wet;
+            for lp in self.lp.iter_mut() {
+                *lp += lp_coef * (v - *lp);
+                v = *lp;
+            }
+            // compress what goes in
+            self.env += k * (v.abs() - self.env);
+            let gain = self.env.max(1e-6).sqrt();
+            self.buf[self.ix] = v / gain + NOISE_LEVEL * self.noise();
+            self.gains[self.ix] = gain;
+            self.ix = (self.ix + 1) % len;
+            *y = x + mix * (wet - x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use modules::Delay;
+    use test_util::{magnitude, sine_buf};
+    use super::BbdDelay;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    // Brightness (ratio of 4kHz to 500Hz) of each of the first few echoes of
+    // a burst containing both.
+    fn echo_brightness(module: &mut dyn Module, ctrl: &[f32]) -> Vec<f32> {
+        let n_burst = 16;
+        let mut out = Vec::new();
+        let mut bufs = [Buffer::default()];
+        for i in 0..n_burst * 16 {
+            let start = i * N_SAMPLES_PER_CHUNK;
+            let mut inb = Buffer::default();
+            if i < n_burst {
+                let lo = sine_buf(500.0, SAMPLE_RATE, start);
+                let hi = sine_buf(4000.0, SAMPLE_RATE, start);
+                for (x, (a, b)) in inb.get_mut().iter_mut().zip(lo.get().iter().zip(hi.get())) {
+                    *x = 0.5 * (a + b);
+                }
+            }
+            module.process(ctrl, &mut [], &[&inb], &mut bufs);
+            out.extend_from_slice(bufs[0].get());
+        }
+        // echoes every 4 bursts' length, measured away from their edges
+        let n = n_burst * N_SAMPLES_PER_CHUNK;
+        (1..4).map(|echo| {
+            let window = &out[echo * 4 * n + n / 4..echo * 4 * n + 3 * n / 4];
+            magnitude(window, 4000.0, SAMPLE_RATE) / magnitude(window, 500.0, SAMPLE_RATE)
+        }).collect()
+    }
+
+    #[test]
+    fn repeats_get_darker() {
+        let time = 4.0 * 16.0 * N_SAMPLES_PER_CHUNK as f32 / SAMPLE_RATE;
+        let clean = echo_brightness(&mut Delay::new(SAMPLE_RATE, 1.0), &[time, 0.7, 1.0]);
+        let mut bbd = BbdDelay::new(SAMPLE_RATE, 1.0);
+        let dark = echo_brightness(&mut bbd, &[time, 0.7, 3000f32.log2(), 1.0]);
+        for b in &clean {
+            assert!((b - 1.0).abs() < 0.05, "clean {:?}", clean);
+        }
+        assert!(dark[0] < 0.9, "bbd {:?}", dark);
+        assert!(dark[1] < 0.8 * dark[0] && dark[2] < 0.8 * dark[1], "bbd {:?}", dark);
+    }
+}
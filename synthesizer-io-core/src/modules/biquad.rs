@@ -13,24 +13,70 @@
 // limitations under the License.
 
 //! An implementation of biquad filters.
-
+//!
+//! Control inputs are cutoff (log2 Hz) and resonance. An optional third
+//! control input engages saturation, with drive as log2 of the gain applied
+//! to the input before soft clipping. The filter state is also soft clipped,
+//! so high resonance self-limits rather than blowing up. A drive of -24 or
+//! less bypasses saturation.
+//!
+//! Optional fourth and fifth control inputs select the filter type (0=LP,
+//! 1=HP, 2=BP, 3=peak, 4=low-shelf, 5=high-shelf) and the gain in dB for the
+//! peak and shelf types. Types other than lowpass use the RBJ cookbook
+//! formulas, with Q derived from resonance the same way as the lowpass.
+//!
+//! A stereo biquad filters two input buffers identically, with independent
+//! state for each channel.
 
 use std::f32::consts;
 
-use module::{Module, Buffer};
+use module::{Module, Buffer, ParamDesc, ParamKind};
+use super::shaper::tanh5;
+
+// Level at which the filter state saturates when drive is engaged.
+const STATE_LIMIT: f32 = 4.0;
 
 pub struct Biquad {
     sr_offset: f32,
-    state: [f32; 2],
+    channels: usize,
+    // per channel
+    state: [[f32; 2]; 2],
     matrix: [f32; 16],
+    // cutoff, resonance, type, gain used to compute the current matrix
+    params: Option<[f32; 4]>,
 }
 
 impl Biquad {
+    /// Descriptions of the control inputs.
+    pub const PARAMS: [ParamDesc; 5] = [
+        ParamDesc { name: "cutoff", min: 0.0, max: 14.425_216, default: 9.781_36,
+            kind: ParamKind::Log },
+        ParamDesc { name: "resonance", min: 0.0, max: 0.995, default: 0.5,
+            kind: ParamKind::Linear },
+        ParamDesc { name: "drive", min: -24.0, max: 4.0, default: -24.0,
+            kind: ParamKind::Log },
+        ParamDesc { name: "type", min: 0.0, max: 5.0, default: 0.0,
+            kind: ParamKind::Switch },
+        ParamDesc { name: "gain", min: -24.0, max: 24.0, default: 0.0,
+            kind: ParamKind::Linear },
+    ];
+
     pub fn new(sample_rate: f32) -> Biquad {
+        Biquad::with_channels(sample_rate, 1)
+    }
+
+    /// Create a biquad processing a stereo pair of buffers.
+    pub fn new_stereo(sample_rate: f32) -> Biquad {
+        Biquad::with_channels(sample_rate, 2)
+    }
+
+    fn with_channels(sample_rate: f32, channels: usize) -> Biquad {
         Biquad {
             sr_offset: consts::PI.log2() - sample_rate.log2(),
-            state: [0.0; 2],
+            channels,
+            state: [[0.0; 2]; 2],
             matrix: [0.0; 16],
+            params: None,
         }
     }
 }
@@ -42,6 +88,15 @@ struct StateParams {
     d: f32,
 }
 
+/// Parameters of a state variable filter, with output taps for each mode
+/// sharing the same state update.
+pub struct SvfParams {
+    pub a: [f32; 4],
+    pub b: [f32; 2],
+    /// (c, d) for lowpass, bandpass, highpass, and notch, in that order.
+    pub taps: [([f32; 2], f32); 4],
+}
+
 // `log_f` is log2 of frequency relative to sampling rate, e.g.
 // -1.0 is the Nyquist frequency.
 fn calc_g(log_f: f32) -> f32 {
@@ -50,9 +105,9 @@ fn calc_g(log_f: f32) -> f32 {
     f.tan()
 }
 
-// Compute parameters for low-pass state variable filter.
+// Compute parameters for state variable filter, all modes.
 // `res` ranges from 0 (no resonance) to 1 (self-oscillating)
-fn svf_lp(log_f: f32, res: f32) -> StateParams {
+pub fn svf(log_f: f32, res: f32) -> SvfParams {
     let g = calc_g(log_f);
     let k = 2.0 - 2.0 * res;
     let a1 = 2.0 / (1.0 + g * (g + k));
@@ -60,58 +115,323 @@ fn svf_lp(log_f: f32, res: f32) -> StateParams {
     let a3 = g * a2;
     let a = [a1 - 1.0, a2, -a2, 1.0 - a3];
     let b = [a2, a3];
-    let c = [0.5 * a2, 1.0 - 0.5 * a3];
-    let d = 0.5 * a3;
+    let lp = ([0.5 * a2, 1.0 - 0.5 * a3], 0.5 * a3);
+    let bp = ([0.5 * a1, -0.5 * a2], 0.5 * a2);
+    // highpass is x - k * bp - lp, notch is x - k * bp
+    let hp = ([-k * bp.0[0] - lp.0[0], -k * bp.0[1] - lp.0[1]], 1.0 - k * bp.1 - lp.1);
+    let notch = ([-k * bp.0[0], -k * bp.0[1]], 1.0 - k * bp.1);
+    SvfParams { a, b, taps: [lp, bp, hp, notch] }
+}
+
+// Compute parameters for low-pass state variable filter.
+fn svf_lp(log_f: f32, res: f32) -> StateParams {
+    let SvfParams { a, b, taps } = svf(log_f, res);
+    let (c, d) = taps[0];
     StateParams { a: a, b: b, c: c, d: d }
 }
 
+// Compute parameters for RBJ cookbook filters, realized as transposed direct
+// form II. `log_f` is as for `calc_g`, and `gain_db` only applies to the peak
+// and shelf types.
+fn rbj(ty: i32, log_f: f32, res: f32, gain_db: f32) -> StateParams {
+    let w0 = 2.0 * log_f.exp2();
+    let (sin, cos) = w0.sin_cos();
+    let q = 1.0 / (2.0 - 2.0 * res.min(0.999));
+    let alpha = sin / (2.0 * q);
+    let amp = 10f32.powf(gain_db * (1.0 / 40.0));
+    let sq = 2.0 * amp.sqrt() * alpha;
+    let (b0, b1, b2, a0, a1, a2) = match ty {
+        1 => (0.5 * (1.0 + cos), -(1.0 + cos), 0.5 * (1.0 + cos),
+            1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+        2 => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+        3 => (1.0 + alpha * amp, -2.0 * cos, 1.0 - alpha * amp,
+            1.0 + alpha / amp, -2.0 * cos, 1.0 - alpha / amp),
+        4 => (amp * ((amp + 1.0) - (amp - 1.0) * cos + sq),
+            2.0 * amp * ((amp - 1.0) - (amp + 1.0) * cos),
+            amp * ((amp + 1.0) - (amp - 1.0) * cos - sq),
+            (amp + 1.0) + (amp - 1.0) * cos + sq,
+            -2.0 * ((amp - 1.0) + (amp + 1.0) * cos),
+            (amp + 1.0) + (amp - 1.0) * cos - sq),
+        _ => (amp * ((amp + 1.0) + (amp - 1.0) * cos + sq),
+            -2.0 * amp * ((amp - 1.0) + (amp + 1.0) * cos),
+            amp * ((amp + 1.0) + (amp - 1.0) * cos - sq),
+            (amp + 1.0) - (amp - 1.0) * cos + sq,
+            2.0 * ((amp - 1.0) - (amp + 1.0) * cos),
+            (amp + 1.0) - (amp - 1.0) * cos - sq),
+    };
+    let norm = 1.0 / a0;
+    let (b0, b1, b2, a1, a2) = (b0 * norm, b1 * norm, b2 * norm, a1 * norm, a2 * norm);
+    StateParams {
+        a: [-a1, -a2, 1.0, 0.0],
+        b: [b1 - a1 * b0, b2 - a2 * b0],
+        c: [1.0, 0.0],
+        d: b0,
+    }
+}
+
+// The state update for two samples at once, as rows of a matrix applied to
+// (x0, x1, state0, state1).
+pub fn raise_state(a: [f32; 4], b: [f32; 2]) -> [[f32; 4]; 2] {
+    [[a[0] * b[0] + a[2] * b[1], b[0],
+      a[0] * a[0] + a[2] * a[1], a[0] * a[2] + a[2] * a[3]],
+     [a[1] * b[0] + a[3] * b[1], b[1],
+      a[1] * a[0] + a[3] * a[1], a[1] * a[2] + a[3] * a[3]]]
+}
+
+// The outputs for two samples at once, given an output tap vector, as rows of
+// a matrix applied to (x0, x1, state0, state1).
+pub fn raise_output(a: [f32; 4], b: [f32; 2], c: [f32; 2], d: f32) -> [[f32; 4]; 2] {
+    [[d, 0.0, c[0], c[1]],
+     [c[0] * b[0] + c[1] * b[1], d,
+      c[0] * a[0] + c[1] * a[1], c[0] * a[2] + c[1] * a[3]]]
+}
+
 // See https://github.com/google/music-synthesizer-for-android/blob/master/lab/Second%20order%20sections%20in%20matrix%20form.ipynb
 fn raise_matrix(params: StateParams) -> [f32; 16] {
     let StateParams { a, b, c, d } = params;
-    [d, c[0] * b[0] + c[1] * b[1],
-     a[0] * b[0] + a[2] * b[1], a[1] * b[0] + a[3] * b[1],
-
-     0.0, d, b[0], b[1],
-
-     c[0], c[0] * a[0] + c[1] * a[1],
-     a[0] * a[0] + a[2] * a[1], a[1] * a[0] + a[3] * a[1],
+    let out = raise_output(a, b, c, d);
+    let state = raise_state(a, b);
+    // column-major
+    let mut m = [0.0; 16];
+    for col in 0..4 {
+        m[col * 4] = out[0][col];
+        m[col * 4 + 1] = out[1][col];
+        m[col * 4 + 2] = state[0][col];
+        m[col * 4 + 3] = state[1][col];
+    }
+    m
+}
 
-     c[1], c[0] * a[2] + c[1] * a[3],
-     a[0] * a[2] + a[2] * a[3], a[1] * a[2] + a[3] * a[3],
-    ]
+// Run one channel through the filter, two samples at a time.
+fn filter(m: &[f32; 16], drive: Option<f32>, inb: &[f32], out: &mut [f32],
+    state: &mut [f32; 2])
+{
+    let mut i = 0;
+    let mut state0 = state[0];
+    let mut state1 = state[1];
+    while i < out.len() {
+        let (x0, x1) = match drive {
+            Some(g) => (tanh5(g * inb[i]), tanh5(g * inb[i + 1])),
+            None => (inb[i], inb[i + 1]),
+        };
+        let y0 = m[0] * x0 + m[4] * x1 + m[8] * state0 + m[12] * state1;
+        let y1 = m[1] * x0 + m[5] * x1 + m[9] * state0 + m[13] * state1;
+        let y2 = m[2] * x0 + m[6] * x1 + m[10] * state0 + m[14] * state1;
+        let y3 = m[3] * x0 + m[7] * x1 + m[11] * state0 + m[15] * state1;
+        out[i] = y0;
+        out[i + 1] = y1;
+        if drive.is_some() {
+            state0 = STATE_LIMIT * tanh5(y2 * (1.0 / STATE_LIMIT));
+            state1 = STATE_LIMIT * tanh5(y3 * (1.0 / STATE_LIMIT));
+        } else {
+            state0 = y2;
+            state1 = y3;
+        }
+        i += 2;
+    }
+    state[0] = state0;
+    state[1] = state1;
 }
 
 impl Module for Biquad {
-    fn n_bufs_out(&self) -> usize { 1 }
+    fn n_bufs_out(&self) -> usize { self.channels }
+
+    fn type_tag(&self) -> Option<&'static str> { Some("biquad") }
+
+    fn param_values(&self) -> Vec<f32> { vec![self.channels as f32] }
+
+    fn params(&self) -> &[ParamDesc] { &Biquad::PARAMS }
+
+    // Carry on filtering without a click when the node is rewired.
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old) = old.to_any().downcast_ref::<Biquad>() {
+            self.state = old.state;
+            self.matrix = old.matrix;
+            self.params = old.params;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = [[0.0; 2]; 2];
+    }
 
     fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
         buf_in: &[&Buffer], buf_out: &mut [Buffer])
     {
         let log_f = control_in[0];
         let res = control_in[1];
-        // TODO: maybe avoid recomputing matrix if params haven't changed
-        let params = svf_lp(log_f + self.sr_offset, res);
-        self.matrix = raise_matrix(params);
-        let inb = buf_in[0].get();
-        let out = buf_out[0].get_mut();
-        let m = &self.matrix;
-        let mut i = 0;
-        let mut state0 = self.state[0];
-        let mut state1 = self.state[1];
-        while i < out.len() {
-            let x0 = inb[i];
-            let x1 = inb[i + 1];
-            let y0 = m[0] * x0 + m[4] * x1 + m[8] * state0 + m[12] * state1;
-            let y1 = m[1] * x0 + m[5] * x1 + m[9] * state0 + m[13] * state1;
-            let y2 = m[2] * x0 + m[6] * x1 + m[10] * state0 + m[14] * state1;
-            let y3 = m[3] * x0 + m[7] * x1 + m[11] * state0 + m[15] * state1;
-            out[i] = y0;
-            out[i + 1] = y1;
-            state0 = y2;
-            state1 = y3;
-            i += 2;
+        let ty = control_in.get(3).map(|t| t.round()).unwrap_or(0.0);
+        let gain_db = control_in.get(4).cloned().unwrap_or(0.0);
+        let params = [log_f, res, ty, gain_db];
+        if self.params != Some(params) {
+            let state_params = match ty as i32 {
+                1..=5 => rbj(ty as i32, log_f + self.sr_offset, res, gain_db),
+                _ => svf_lp(log_f + self.sr_offset, res),
+            };
+            self.matrix = raise_matrix(state_params);
+            self.params = Some(params);
+        }
+        let drive = control_in.get(2).filter(|&&d| d > -24.0).map(|d| d.exp2());
+        for (c, (inb, out)) in buf_in.iter().zip(buf_out.iter_mut()).enumerate() {
+            filter(&self.matrix, drive, inb.get(), out.get_mut(), &mut self.state[c]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use test_util::{magnitude, sine_buf};
+    use super::Biquad;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    fn run(ctrl: &[f32], input: &dyn Fn(usize) -> Buffer, n: usize) -> Vec<f32> {
+        let mut biquad = Biquad::new(SAMPLE_RATE);
+        let mut out = [Buffer::default()];
+        let mut result = Vec::new();
+        for i in 0..n / N_SAMPLES_PER_CHUNK {
+            let inb = input(i * N_SAMPLES_PER_CHUNK);
+            biquad.process(ctrl, &mut [], &[&inb], &mut out);
+            result.extend_from_slice(out[0].get());
+        }
+        result
+    }
+
+    // Measure gain at DC, Nyquist, and `freq`, in the steady state.
+    fn response(ctrl: &[f32], freq: f32) -> [f32; 3] {
+        let n = 8192;
+        let dc = run(ctrl, &|_| {
+            let mut buf = Buffer::default();
+            for x in buf.get_mut().iter_mut() { *x = 1.0; }
+            buf
+        }, n);
+        let nyquist = run(ctrl, &|_| {
+            let mut buf = Buffer::default();
+            for (i, x) in buf.get_mut().iter_mut().enumerate() {
+                *x = if i % 2 == 0 { 1.0 } else { -1.0 };
+            }
+            buf
+        }, n);
+        let sine = run(ctrl, &|start| sine_buf(freq, SAMPLE_RATE, start), n);
+        let half = n / 2;
+        let dc_gain = dc[half..].iter().sum::<f32>() / half as f32;
+        let ny_gain = nyquist[half..].iter().enumerate()
+            .map(|(i, y)| if i % 2 == 0 { *y } else { -y })
+            .sum::<f32>() / half as f32;
+        [dc_gain.abs(), ny_gain.abs(), magnitude(&sine[half..], freq, SAMPLE_RATE)]
+    }
+
+    #[test]
+    fn mode_responses() {
+        // bin-centered for the 4096 samples measured
+        let fc = 186.0 * SAMPLE_RATE / 4096.0;
+        let gain_db = 12.0;
+        let a2 = 10f32.powf(gain_db / 20.0);
+        let a = a2.sqrt();
+        // With zero resonance, Q is 0.5.
+        let expected = [
+            [1.0, 0.0, 0.5],  // LP
+            [0.0, 1.0, 0.5],  // HP
+            [0.0, 0.0, 1.0],  // BP
+            [1.0, 1.0, a2],  // peak
+            [a2, 1.0, a],  // low shelf
+            [1.0, a2, a],  // high shelf
+        ];
+        for (ty, exp) in expected.iter().enumerate() {
+            let ctrl = [fc.log2(), 0.0, -24.0, ty as f32, gain_db];
+            let got = response(&ctrl, fc);
+            for j in 0..3 {
+                assert!((got[j] - exp[j]).abs() < 0.01 * exp[j].max(1.0),
+                    "type {}: got {:?}, expected {:?}", ty, got, exp);
+            }
+        }
+    }
+
+    #[test]
+    fn drive_adds_harmonics() {
+        let n = 8192;
+        let freq = 40.0 * SAMPLE_RATE / n as f32;
+        let sine = |start| sine_buf(freq, SAMPLE_RATE, start);
+        let third = |drive: f32| {
+            let out = run(&[10_000f32.log2(), 0.0, drive], &sine, n);
+            magnitude(&out, 3.0 * freq, SAMPLE_RATE) / magnitude(&out, freq, SAMPLE_RATE)
+        };
+        let clean = run(&[10_000f32.log2(), 0.0], &sine, n);
+        let h_clean = magnitude(&clean, 3.0 * freq, SAMPLE_RATE);
+        assert!(h_clean < 1e-3);
+        assert!(third(-2.0) > 10.0 * h_clean);
+        assert!(third(3.0) > 4.0 * third(-2.0));
+    }
+
+    #[test]
+    fn driven_resonance_is_stable() {
+        let noise = |start: usize| {
+            let mut buf = Buffer::default();
+            for (i, x) in buf.get_mut().iter_mut().enumerate() {
+                let h = ((start + i) as u32).wrapping_mul(2_654_435_761);
+                *x = h as f32 * (2.0 / 4_294_967_296.0) - 1.0;
+            }
+            buf
+        };
+        let out = run(&[1000f32.log2(), 1.0, 3.0], &noise, 44_100);
+        assert!(out.iter().all(|y| y.is_finite() && y.abs() < 10.0));
+    }
+
+    #[test]
+    fn reset_matches_fresh() {
+        let ctrl = [1000f32.log2(), 0.5];
+        let mut used = Biquad::new(SAMPLE_RATE);
+        let mut out = [Buffer::default()];
+        for i in 0..10 {
+            let inb = sine_buf(440.0, SAMPLE_RATE, i * N_SAMPLES_PER_CHUNK);
+            used.process(&ctrl, &mut [], &[&inb], &mut out);
+        }
+        assert!(used.state[0].iter().any(|&s| s != 0.0));
+        used.reset();
+        let mut fresh = Biquad::new(SAMPLE_RATE);
+        let mut fresh_out = [Buffer::default()];
+        let mut inb = Buffer::default();
+        inb.get_mut()[0] = 1.0;
+        used.process(&ctrl, &mut [], &[&inb], &mut out);
+        fresh.process(&ctrl, &mut [], &[&inb], &mut fresh_out);
+        assert_eq!(out[0].get()[0], fresh_out[0].get()[0]);
+        assert_eq!(out[0].get(), fresh_out[0].get());
+    }
+
+    #[test]
+    fn migrate_continues_filtering() {
+        let ctrl = [1000f32.log2(), 0.5];
+        let mut old = Biquad::new(SAMPLE_RATE);
+        let mut out = [Buffer::default()];
+        for i in 0..10 {
+            let inb = sine_buf(440.0, SAMPLE_RATE, i * N_SAMPLES_PER_CHUNK);
+            old.process(&ctrl, &mut [], &[&inb], &mut out);
+        }
+        let mut new = Biquad::new(SAMPLE_RATE);
+        new.migrate(&mut old);
+        let mut new_out = [Buffer::default()];
+        let inb = sine_buf(440.0, SAMPLE_RATE, 10 * N_SAMPLES_PER_CHUNK);
+        old.process(&ctrl, &mut [], &[&inb], &mut out);
+        new.process(&ctrl, &mut [], &[&inb], &mut new_out);
+        assert_eq!(out[0].get(), new_out[0].get());
+    }
+
+    #[test]
+    fn stereo_channels_independent() {
+        let ctrl = [1000f32.log2(), 0.8];
+        let mut mono = Biquad::new(SAMPLE_RATE);
+        let mut stereo = Biquad::new_stereo(SAMPLE_RATE);
+        let silence = Buffer::default();
+        let mut mono_out = [Buffer::default()];
+        let mut out = [Buffer::default(), Buffer::default()];
+        for i in 0..20 {
+            let inb = sine_buf(440.0, SAMPLE_RATE, i * N_SAMPLES_PER_CHUNK);
+            mono.process(&ctrl, &mut [], &[&inb], &mut mono_out);
+            // sine on the right only
+            stereo.process(&ctrl, &mut [], &[&silence, &inb], &mut out);
+            assert!(out[0].get().iter().all(|&y| y == 0.0));
+            assert_eq!(out[1].get(), mono_out[0].get());
         }
-        self.state[0] = state0;
-        self.state[1] = state1;
     }
 }
@@ -0,0 +1,205 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A feedforward compressor.
+//!
+//! Control inputs are threshold (dB), ratio, attack (ms), and release (ms).
+//! An optional fifth control input selects limiter mode when it's 1, which
+//! uses an infinite ratio and very fast attack, ignoring those inputs.
+//!
+//! An optional sixth control input, a slow release (ms), makes the release
+//! program-dependent. A second envelope charges slowly, so it only builds up
+//! on sustained material, and releases with the slow time; the gain follows
+//! whichever envelope is higher. Brief transients then recover with the fast
+//! release, while sustained material is held steady instead of pumping. Zero
+//! or absent, the default, is a single release.
+
+use module::{Module, Buffer};
+
+// Attack time in limiter mode, in ms.
+const LIMITER_ATTACK: f32 = 0.05;
+
+// Attack time of the slow envelope, in ms.
+const SLOW_ATTACK: f32 = 20.0;
+
+pub struct Compressor {
+    sample_rate: f32,
+    // peak envelope, linear amplitude
+    env: f32,
+    // slow envelope, for program-dependent release
+    slow_env: f32,
+}
+
+impl Compressor {
+    pub fn new(sample_rate: f32) -> Compressor {
+        Compressor {
+            sample_rate,
+            env: 0.0,
+            slow_env: 0.0,
+        }
+    }
+
+    // Per-sample coefficient of a one-pole lowpass with time constant in ms.
+    fn coef(&self, ms: f32) -> f32 {
+        (-1000.0 / (ms.max(1e-3) * self.sample_rate)).exp()
+    }
+}
+
+impl Module for Compressor {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        self.env = 0.0;
+        self.slow_env = 0.0;
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let threshold = control_in[0];
+        let limiter = control_in.get(4).map(|m| m.round() as i32 == 1).unwrap_or(false);
+        let (slope, attack) = if limiter {
+            (1.0, self.coef(LIMITER_ATTACK))
+        } else {
+            (1.0 - 1.0 / control_in[1].max(1.0), self.coef(control_in[2]))
+        };
+        let release = self.coef(control_in[3]);
+        let slow_release = control_in.get(5).cloned().unwrap_or(0.0);
+        let dual = slow_release > 0.0;
+        let (slow_attack, slow_release) = (self.coef(SLOW_ATTACK), self.coef(slow_release));
+        let inb = buf_in[0].get();
+        let out = buf_out[0].get_mut();
+        let mut env = self.env;
+        let mut slow_env = self.slow_env;
+        for (x, y) in inb.iter().zip(out.iter_mut()) {
+            let a = x.abs();
+            let coef = if a > env { attack } else { release };
+            env = a + (env - a) * coef;
+            let mut level = env;
+            if dual {
+                let coef = if a > slow_env { slow_attack } else { slow_release };
+                slow_env = a + (slow_env - a) * coef;
+                level = level.max(slow_env);
+            }
+            let over = 20.0 * level.max(1e-6).log10() - threshold;
+            let gain = if over > 0.0 {
+                10f32.powf(-over * slope * (1.0 / 20.0))
+            } else {
+                1.0
+            };
+            *y = x * gain;
+        }
+        self.env = env;
+        self.slow_env = slow_env;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use test_util::sine_buf;
+    use std::f32::consts::PI;
+
+    use super::Compressor;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    fn peak_after_settling(ctrl: &[f32]) -> f32 {
+        let sample_rate = 44_100.0;
+        let mut comp = Compressor::new(sample_rate);
+        let mut out = [Buffer::default()];
+        let mut peak = 0.0f32;
+        // 0.5s, measuring the last 0.1s
+        let n_chunks = 22_050 / N_SAMPLES_PER_CHUNK;
+        for i in 0..n_chunks {
+            let inb = sine_buf(440.0, sample_rate, i * N_SAMPLES_PER_CHUNK);
+            comp.process(ctrl, &mut [], &[&inb], &mut out);
+            if i * N_SAMPLES_PER_CHUNK > 17_640 {
+                for y in out[0].get() {
+                    peak = peak.max(y.abs());
+                }
+            }
+        }
+        20.0 * peak.log10()
+    }
+
+    #[test]
+    fn settles_near_threshold() {
+        // threshold -12 dB, 20:1, 5ms attack, 100ms release
+        let peak = peak_after_settling(&[-12.0, 20.0, 5.0, 100.0]);
+        let expected = -12.0 + 12.0 / 20.0;
+        assert!((peak - expected).abs() < 1.0, "peak {} dB", peak);
+
+        let peak = peak_after_settling(&[-12.0, 20.0, 5.0, 100.0, 1.0]);
+        assert!((peak + 12.0).abs() < 0.5, "limiter peak {} dB", peak);
+    }
+
+    // Limit a signal, one sample per call of `input`, returning the output.
+    fn limit(ctrl: &[f32], input: &dyn Fn(usize) -> f32, n: usize) -> Vec<f32> {
+        let mut comp = Compressor::new(SAMPLE_RATE);
+        let mut out = [Buffer::default()];
+        let mut result = Vec::new();
+        for i in 0..n / N_SAMPLES_PER_CHUNK {
+            let mut inb = Buffer::default();
+            for (j, x) in inb.get_mut().iter_mut().enumerate() {
+                *x = input(i * N_SAMPLES_PER_CHUNK + j);
+            }
+            comp.process(ctrl, &mut [], &[&inb], &mut out);
+            result.extend_from_slice(out[0].get());
+        }
+        result
+    }
+
+    #[test]
+    fn program_dependent_release() {
+        let sine = |freq: f32, amp: f32, i: usize| {
+            amp * (i as f32 * (2.0 * PI * freq / SAMPLE_RATE)).sin()
+        };
+        // threshold -12 dB, limiter, with fast and slow releases
+        let single = [-12.0, 1.0, 1.0, 20.0, 1.0];
+        let slow = [-12.0, 1.0, 1.0, 1000.0, 1.0];
+        let dual = [-12.0, 1.0, 1.0, 20.0, 1.0, 1000.0];
+
+        // A 2ms full scale click, then a quiet tone below threshold: time (ms)
+        // until the gain is back within 1 dB.
+        let burst = 88;
+        let click = |i| sine(1000.0, if i < burst { 1.0 } else { 0.1 }, i);
+        let recovery = |ctrl: &[f32]| {
+            let out = limit(ctrl, &click, 44_100);
+            let target = 0.1 * 10f32.powf(-1.0 / 20.0);
+            // peak of each millisecond
+            let ms = out[burst..].chunks(44).position(|c| {
+                c.iter().fold(0.0f32, |m, y| m.max(y.abs())) > target
+            });
+            ms.unwrap_or(1000)
+        };
+        let (fast, adaptive) = (recovery(&single), recovery(&dual));
+        assert!(adaptive < 50 && adaptive <= fast + 5, "recovery {} ms vs {}", adaptive, fast);
+        assert!(recovery(&slow) > 500);
+
+        // A sustained loud bass note: ripple (dB) of the gain once settled.
+        let bass = |i| sine(50.0, 1.0, i);
+        let ripple = |ctrl: &[f32]| {
+            let out = limit(ctrl, &bass, 44_100);
+            let gains: Vec<_> = (22_050..44_100).filter(|&i| bass(i).abs() > 0.2)
+                .map(|i| 20.0 * (out[i] / bass(i)).log10())
+                .collect();
+            let max = gains.iter().fold(f32::MIN, |m, &g| m.max(g));
+            let min = gains.iter().fold(f32::MAX, |m, &g| m.min(g));
+            max - min
+        };
+        let (fast, adaptive) = (ripple(&single), ripple(&dual));
+        assert!(adaptive < 0.5 * fast, "ripple {} dB vs {}", adaptive, fast);
+    }
+}
@@ -12,7 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! A simple module that just sets a constant control parameter.
+//! A simple module that just sets a constant control parameter. Param 0
+//! changes the value, with no smoothing.
 
 use module::{Module, Buffer};
 
@@ -29,6 +30,14 @@ impl ConstCtrl {
 impl Module for ConstCtrl {
     fn n_ctrl_out(&self) -> usize { 1 }
 
+    fn type_tag(&self) -> Option<&'static str> { Some("const_ctrl") }
+
+    fn param_values(&self) -> Vec<f32> { vec![self.value] }
+
+    fn set_param(&mut self, _param_ix: usize, val: f32, _timestamp: u64) {
+        self.value = val;
+    }
+
     fn process(&mut self, _control_in: &[f32], control_out: &mut [f32],
         _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
     {
@@ -0,0 +1,169 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Convolution reverb, with an impulse response supplied at creation.
+//!
+//! The impulse response is cut into chunk sized partitions, and each chunk of
+//! input is convolved with all of them in the frequency domain, the results
+//! being overlapped and added. The output isn't delayed beyond the chunk it
+//! comes from, but the cost per chunk grows with the length of the response.
+
+use std::sync::Arc;
+
+use rustfft::{FFT, FFTplanner};
+use rustfft::num_complex::Complex;
+
+use module::{Module, Buffer, N_SAMPLES_PER_CHUNK};
+
+const FFT_LEN: usize = 2 * N_SAMPLES_PER_CHUNK;
+
+pub struct ConvReverb {
+    fft: Arc<dyn FFT<f32>>,
+    ifft: Arc<dyn FFT<f32>>,
+    // spectrum of each partition of the impulse response, scaled for the ifft
+    ir: Vec<Vec<Complex<f32>>>,
+    // spectra of the most recent chunks of input, a ring buffer
+    history: Vec<Vec<Complex<f32>>>,
+    // index in `history` of the latest chunk
+    pos: usize,
+    // the tail of the last chunk's convolution, to be added to the next
+    overlap: [f32; N_SAMPLES_PER_CHUNK],
+    time: Vec<Complex<f32>>,
+    freq: Vec<Complex<f32>>,
+}
+
+impl ConvReverb {
+    /// Create a reverb from an impulse response. An empty one gives silence.
+    pub fn new(ir: &[f32]) -> ConvReverb {
+        let fft = FFTplanner::new(false).plan_fft(FFT_LEN);
+        let ifft = FFTplanner::new(true).plan_fft(FFT_LEN);
+        let scale = 1.0 / FFT_LEN as f32;
+        let mut time = vec![Complex::from(0.0); FFT_LEN];
+        let ir: Vec<_> = ir.chunks(N_SAMPLES_PER_CHUNK)
+            .map(|part| {
+                for (i, z) in time.iter_mut().enumerate() {
+                    *z = Complex::from(part.get(i).map_or(0.0, |&x| x * scale));
+                }
+                let mut spectrum = vec![Complex::from(0.0); FFT_LEN];
+                fft.process(&mut time, &mut spectrum);
+                spectrum
+            })
+            .collect();
+        let history = vec![vec![Complex::from(0.0); FFT_LEN]; ir.len()];
+        let freq = vec![Complex::from(0.0); FFT_LEN];
+        ConvReverb {
+            fft,
+            ifft,
+            ir,
+            history,
+            pos: 0,
+            overlap: [0.0; N_SAMPLES_PER_CHUNK],
+            time,
+            freq,
+        }
+    }
+}
+
+impl Module for ConvReverb {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        for spectrum in &mut self.history {
+            for z in spectrum.iter_mut() {
+                *z = Complex::from(0.0);
+            }
+        }
+        self.overlap = [0.0; N_SAMPLES_PER_CHUNK];
+    }
+
+    fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let n = self.history.len();
+        if n == 0 {
+            buf_out[0].set_zero();
+            return;
+        }
+        self.pos = (self.pos + 1) % n;
+        let inb = buf_in[0].get();
+        for (i, z) in self.time.iter_mut().enumerate() {
+            *z = Complex::from(inb.get(i).cloned().unwrap_or(0.0));
+        }
+        self.fft.process(&mut self.time, &mut self.history[self.pos]);
+        // Partition k of the response applies to the input from k chunks ago.
+        for z in self.freq.iter_mut() {
+            *z = Complex::from(0.0);
+        }
+        for (k, part) in self.ir.iter().enumerate() {
+            let input = &self.history[(self.pos + n - k) % n];
+            for ((y, x), h) in self.freq.iter_mut().zip(input).zip(part) {
+                *y += x * h;
+            }
+        }
+        self.ifft.process(&mut self.freq, &mut self.time);
+        let out = buf_out[0].get_mut();
+        let (head, tail) = self.time.split_at(N_SAMPLES_PER_CHUNK);
+        for (((y, z), o), t) in out.iter_mut().zip(head).zip(self.overlap.iter_mut()).zip(tail) {
+            *y = z.re + *o;
+            *o = t.re;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use super::ConvReverb;
+
+    // Run the reverb on `input`, padded with silence to `len` samples.
+    fn convolve(reverb: &mut ConvReverb, input: &[f32], len: usize) -> Vec<f32> {
+        let mut out = Vec::new();
+        let mut bufs = [Buffer::default()];
+        while out.len() < len {
+            let mut inb = Buffer::default();
+            for (i, x) in inb.get_mut().iter_mut().enumerate() {
+                *x = input.get(out.len() + i).cloned().unwrap_or(0.0);
+            }
+            reverb.process(&[], &mut [], &[&inb], &mut bufs);
+            out.extend_from_slice(bufs[0].get());
+        }
+        out
+    }
+
+    #[test]
+    fn impulse_gives_ir() {
+        // longer than a chunk, so it takes several partitions
+        let ir: Vec<f32> = (0..100).map(|i| (0.3 * i as f32).sin() * 0.97f32.powi(i)).collect();
+        let mut reverb = ConvReverb::new(&ir);
+        let out = convolve(&mut reverb, &[1.0], 4 * N_SAMPLES_PER_CHUNK);
+        for (i, &y) in out.iter().enumerate() {
+            let expected = ir.get(i).cloned().unwrap_or(0.0);
+            assert!((y - expected).abs() < 1e-5, "at {}: {}, expected {}", i, y, expected);
+        }
+    }
+
+    #[test]
+    fn delta_delays() {
+        let delay = 75;
+        let mut ir = vec![0.0; delay + 1];
+        ir[delay] = 1.0;
+        let mut reverb = ConvReverb::new(&ir);
+        let input: Vec<f32> = (0..200).map(|i| (0.05 * i as f32).sin()).collect();
+        let out = convolve(&mut reverb, &input, 300);
+        for (i, &y) in out.iter().enumerate() {
+            let expected = i.checked_sub(delay).and_then(|j| input.get(j)).cloned().unwrap_or(0.0);
+            assert!((y - expected).abs() < 1e-5, "at {}: {}, expected {}", i, y, expected);
+        }
+    }
+}
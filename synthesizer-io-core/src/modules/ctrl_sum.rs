@@ -0,0 +1,44 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A simple module that sums its control inputs, useful for applying a
+//! modulation offset to a log-domain parameter.
+
+use module::{Module, Buffer};
+
+pub struct CtrlSum;
+
+impl CtrlSum {
+    pub fn new() -> CtrlSum {
+        CtrlSum
+    }
+}
+
+impl Default for CtrlSum {
+    fn default() -> CtrlSum {
+        CtrlSum::new()
+    }
+}
+
+impl Module for CtrlSum {
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn type_tag(&self) -> Option<&'static str> { Some("ctrl_sum") }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        control_out[0] = control_in.iter().sum();
+    }
+}
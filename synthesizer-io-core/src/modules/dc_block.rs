@@ -0,0 +1,88 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A DC blocker: a one-pole, one-zero highpass with a cutoff around 20Hz.
+
+use std::f32::consts::PI;
+
+use module::{Module, Buffer};
+
+const CUTOFF_HZ: f32 = 20.0;
+
+pub struct DcBlock {
+    // pole radius
+    r: f32,
+    last_in: f32,
+    last_out: f32,
+}
+
+impl DcBlock {
+    pub fn new(sample_rate: f32) -> DcBlock {
+        DcBlock {
+            r: (-2.0 * PI * CUTOFF_HZ / sample_rate).exp(),
+            last_in: 0.0,
+            last_out: 0.0,
+        }
+    }
+}
+
+impl Module for DcBlock {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        self.last_in = 0.0;
+        self.last_out = 0.0;
+    }
+
+    fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let inb = buf_in[0].get();
+        let out = buf_out[0].get_mut();
+        for (&x, y) in inb.iter().zip(out.iter_mut()) {
+            *y = x - self.last_in + self.r * self.last_out;
+            self.last_in = x;
+            self.last_out = *y;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module};
+    use test_util::{magnitude, sine_buf};
+    use super::DcBlock;
+
+    #[test]
+    fn removes_offset_keeps_tone() {
+        let sample_rate = 44_100.0;
+        let mut dc_block = DcBlock::new(sample_rate);
+        let mut out = Vec::new();
+        let mut bufs = [Buffer::default()];
+        while out.len() < 2 * 44_100 {
+            let mut inb = sine_buf(1000.0, sample_rate, out.len());
+            for x in inb.get_mut() {
+                *x = 0.5 + 0.3 * *x;
+            }
+            dc_block.process(&[], &mut [], &[&inb], &mut bufs);
+            out.extend_from_slice(bufs[0].get());
+        }
+        // 0.1s is a whole number of cycles of the tone.
+        let tail = &out[out.len() - 4410..];
+        let mean = tail.iter().sum::<f32>() / tail.len() as f32;
+        assert!(mean.abs() < 1e-3, "mean {}", mean);
+        let level = magnitude(tail, 1000.0, sample_rate);
+        assert!((level - 0.3).abs() < 0.003, "level {}", level);
+    }
+}
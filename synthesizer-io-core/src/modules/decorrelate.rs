@@ -0,0 +1,181 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Stereo widening of a mono input by decorrelation.
+//!
+//! Each channel has its own chain of short allpass filters, with different
+//! delays, producing copies of the input with the same spectrum but
+//! differently scrambled phase. Unlike a Haas delay, or a side signal added
+//! to one channel and subtracted from the other, neither channel is comb
+//! filtered at full width.
+//!
+//! Control 0 is the width, from 0 (mono) to 1 (uncorrelated channels),
+//! crossfading from the dry input to the allpassed copies. It's smoothed
+//! linearly across each chunk.
+
+use module::{Module, Buffer};
+
+// delays of the allpass stages of the left and right channels, in seconds;
+// all mutually prime in samples at 44.1kHz and 48kHz, so the echoes don't
+// line up
+const DELAYS: [[f32; 4]; 2] = [
+    [0.0011, 0.0021, 0.0031, 0.0056],
+    [0.0010, 0.0016, 0.0026, 0.0041],
+];
+
+const ALLPASS_GAIN: f32 = 0.6;
+
+// A Schroeder allpass filter.
+struct Allpass {
+    buf: Vec<f32>,
+    ix: usize,
+}
+
+impl Allpass {
+    fn new(len: usize) -> Allpass {
+        Allpass { buf: vec![0.0; len.max(1)], ix: 0 }
+    }
+
+    fn reset(&mut self) {
+        for x in self.buf.iter_mut() {
+            *x = 0.0;
+        }
+        self.ix = 0;
+    }
+
+    fn process(&mut self, x: f32) -> f32 {
+        let delayed = self.buf[self.ix];
+        let v = x + ALLPASS_GAIN * delayed;
+        self.buf[self.ix] = v;
+        self.ix = (self.ix + 1) % self.buf.len();
+        delayed - ALLPASS_GAIN * v
+    }
+}
+
+pub struct Decorrelate {
+    // allpass chain for each channel
+    stages: [Vec<Allpass>; 2],
+    last_width: Option<f32>,
+}
+
+impl Decorrelate {
+    pub fn new(sample_rate: f32) -> Decorrelate {
+        let chain = |delays: &[f32]| delays.iter()
+            .map(|&t| Allpass::new((t * sample_rate).round() as usize))
+            .collect();
+        Decorrelate { stages: [chain(&DELAYS[0]), chain(&DELAYS[1])], last_width: None }
+    }
+}
+
+impl Module for Decorrelate {
+    fn n_bufs_out(&self) -> usize { 2 }
+
+    fn reset(&mut self) {
+        for stage in self.stages.iter_mut().flat_map(|chain| chain.iter_mut()) {
+            stage.reset();
+        }
+        self.last_width = None;
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let width = control_in[0].clamp(0.0, 1.0);
+        let w = self.last_width.unwrap_or(width);
+        let inb = buf_in[0].get();
+        let dw = (width - w) * (1.0 / inb.len() as f32);
+        self.last_width = Some(width);
+        for (chain, out) in self.stages.iter_mut().zip(buf_out.iter_mut()) {
+            let mut w = w;
+            for (&x, y) in inb.iter().zip(out.get_mut().iter_mut()) {
+                w += dw;
+                let wet = chain.iter_mut().fold(x, |y, stage| stage.process(y));
+                *y = x + w * (wet - x);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use test_util::magnitude;
+    use super::Decorrelate;
+
+    #[test]
+    fn delays_are_coprime() {
+        let gcd = |mut a: usize, mut b: usize| {
+            while b != 0 {
+                let r = a % b;
+                a = b;
+                b = r;
+            }
+            a
+        };
+        for &sample_rate in &[44_100.0, 48_000.0] {
+            let module = Decorrelate::new(sample_rate);
+            let lens: Vec<_> = module.stages.iter().flat_map(|chain| chain.iter())
+                .map(|stage| stage.buf.len())
+                .collect();
+            for (i, &a) in lens.iter().enumerate() {
+                for &b in &lens[i + 1..] {
+                    assert_eq!(gcd(a, b), 1, "{} and {} samples at {}", a, b, sample_rate);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn wide_and_uncolored() {
+        let sample_rate = 44_100.0;
+        let mut module = Decorrelate::new(sample_rate);
+        let mut rng = 1u32;
+        let mut inb = Buffer::default();
+        let mut out = [Buffer::default(), Buffer::default()];
+        let (mut l, mut r) = (Vec::new(), Vec::new());
+        for _ in 0..32768 / N_SAMPLES_PER_CHUNK {
+            for x in inb.get_mut().iter_mut() {
+                rng = rng.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                *x = rng as f32 * (2.0 / 4_294_967_296.0) - 1.0;
+            }
+            module.process(&[1.0], &mut [], &[&inb], &mut out);
+            l.extend_from_slice(out[0].get());
+            r.extend_from_slice(out[1].get());
+        }
+        let dot = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(x, y)| x * y).sum::<f32>();
+        let correlation = dot(&l, &r) / (dot(&l, &l) * dot(&r, &r)).sqrt();
+        assert!(correlation.abs() < 0.1, "correlation {}", correlation);
+
+        // Each channel on its own should have a flat response, which for an
+        // impulse of 1 is a magnitude of 2 / n.
+        let mut module = Decorrelate::new(sample_rate);
+        let n = 16384;
+        let mut responses = [Vec::new(), Vec::new()];
+        inb.set_zero();
+        inb.get_mut()[0] = 1.0;
+        while responses[0].len() < n {
+            module.process(&[1.0], &mut [], &[&inb], &mut out);
+            inb.set_zero();
+            for (response, buf) in responses.iter_mut().zip(&out) {
+                response.extend_from_slice(buf.get());
+            }
+        }
+        for response in &responses {
+            for &f in &[100.0, 500.0, 1234.0, 2000.0, 8000.0] {
+                let gain = magnitude(response, f, sample_rate) * (0.5 * n as f32);
+                assert!((gain - 1.0).abs() < 0.01, "gain {} at {}Hz", gain, f);
+            }
+        }
+    }
+}
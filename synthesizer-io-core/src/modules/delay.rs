@@ -0,0 +1,123 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A delay line with feedback.
+
+use module::{Module, Buffer};
+
+pub struct Delay {
+    sample_rate: f32,
+    // ring buffer of past input (including feedback)
+    buf: Vec<f32>,
+    // index of the next sample to be written
+    ix: usize,
+    // delay in samples at the end of the last chunk, for smoothing
+    last_delay: Option<f32>,
+}
+
+impl Delay {
+    /// Create a new delay. The buffer is allocated here, so `max_delay_secs`
+    /// bounds the delay time for the lifetime of the module.
+    pub fn new(sample_rate: f32, max_delay_secs: f32) -> Delay {
+        let len = (max_delay_secs * sample_rate).ceil() as usize + 2;
+        Delay {
+            sample_rate,
+            buf: vec![0.0; len],
+            ix: 0,
+            last_delay: None,
+        }
+    }
+
+    // Read the sample `delay` samples in the past, with linear interpolation.
+    // Requires 1 <= delay <= len - 2.
+    fn read(&self, delay: f32) -> f32 {
+        let len = self.buf.len();
+        let d_int = delay as usize;
+        let frac = delay - d_int as f32;
+        let i0 = (self.ix + len - d_int) % len;
+        let i1 = (i0 + len - 1) % len;
+        let y0 = self.buf[i0];
+        let y1 = self.buf[i1];
+        y0 + (y1 - y0) * frac
+    }
+}
+
+impl Module for Delay {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        for x in self.buf.iter_mut() {
+            *x = 0.0;
+        }
+        self.ix = 0;
+        self.last_delay = None;
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let max_delay = (self.buf.len() - 2) as f32;
+        let delay = (control_in[0] * self.sample_rate).clamp(1.0, max_delay);
+        let feedback = control_in[1].clamp(0.0, 0.99);
+        let mix = control_in[2];
+        let inb = buf_in[0].get();
+        let out = buf_out[0].get_mut();
+        // Ramp the delay time linearly across the chunk to avoid zipper noise.
+        let mut d = self.last_delay.unwrap_or(delay);
+        let dd = (delay - d) * (1.0 / out.len() as f32);
+        self.last_delay = Some(delay);
+        let len = self.buf.len();
+        for (x, y) in inb.iter().zip(out.iter_mut()) {
+            d += dd;
+            let wet = self.read(d);
+            self.buf[self.ix] = x + feedback * wet;
+            self.ix = (self.ix + 1) % len;
+            *y = x + mix * (wet - x);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use super::Delay;
+
+    #[test]
+    fn impulse_echoes() {
+        let sample_rate = 44_100.0;
+        let delay_samples = 100;
+        let mut delay = Delay::new(sample_rate, 2.0);
+        let ctrl = [delay_samples as f32 / sample_rate, 0.5, 1.0];
+        let mut out = Vec::new();
+        let mut inb = Buffer::default();
+        inb.get_mut()[0] = 1.0;
+        let mut bufs = [Buffer::default()];
+        for _ in 0..20 {
+            delay.process(&ctrl, &mut [], &[&inb], &mut bufs);
+            out.extend_from_slice(bufs[0].get());
+            inb.set_zero();
+        }
+        assert!(out.len() > 4 * delay_samples + N_SAMPLES_PER_CHUNK);
+        let mut expected = 1.0;
+        for (i, &y) in out.iter().enumerate() {
+            if i > 0 && i % delay_samples == 0 && i <= 4 * delay_samples {
+                assert!((y - expected).abs() < 1e-3, "echo at {}: {}, expected {}",
+                    i, y, expected);
+                expected *= 0.5;
+            } else if i <= 4 * delay_samples {
+                assert!(y.abs() < 1e-3, "unexpected output {} at {}", y, i);
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A control that ramps between silence and full level, for driving a
+//! `Gain`. The ramp is linear in amplitude; output is log2 of the level,
+//! with -24 standing in for silence.
+//!
+//! Param 0 starts a fade out, and param 1 a fade in, with the value giving
+//! the duration in seconds.
+
+use module::{Module, Buffer, N_SAMPLES_PER_CHUNK};
+
+pub struct Fader {
+    chunks_per_sec: f32,
+    level: f32,  // linear, in [0, 1]
+    step: f32,  // change in level per chunk
+}
+
+impl Fader {
+    pub fn new(sample_rate: f32) -> Fader {
+        Fader {
+            chunks_per_sec: sample_rate / N_SAMPLES_PER_CHUNK as f32,
+            level: 1.0,
+            step: 0.0,
+        }
+    }
+}
+
+impl Module for Fader {
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn type_tag(&self) -> Option<&'static str> { Some("fader") }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        let target = if param_ix == 0 { 0.0 } else { 1.0 };
+        let n_chunks = (val * self.chunks_per_sec).max(1.0);
+        self.step = (target - self.level) / n_chunks;
+    }
+
+    fn process(&mut self, _control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        self.level += self.step;
+        if self.level <= 0.0 || self.level >= 1.0 {
+            self.level = self.level.clamp(0.0, 1.0);
+            self.step = 0.0;
+        }
+        control_out[0] = self.level.log2().max(-24.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::Module;
+    use super::Fader;
+
+    #[test]
+    fn fade_out_reaches_silence() {
+        let sample_rate = 44_100.0;
+        let mut fader = Fader::new(sample_rate);
+        fader.set_param(0, 0.1, 0);
+        let n_chunks = (0.1 * sample_rate / 32.0f32).ceil() as usize;
+        let mut out = [0.0];
+        let mut levels = Vec::new();
+        for _ in 0..n_chunks {
+            fader.process(&[], &mut out, &[], &mut []);
+            levels.push(out[0].exp2());
+        }
+        assert_eq!(out[0], -24.0);
+        // smooth: a constant step in amplitude
+        let step = 1.0 - levels[0];
+        for w in levels[..n_chunks - 1].windows(2) {
+            assert!((w[0] - w[1] - step).abs() < 1e-4, "{:?}", w);
+        }
+    }
+}
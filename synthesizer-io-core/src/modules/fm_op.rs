@@ -0,0 +1,181 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A self-contained two-operator FM (strictly, phase modulation) oscillator.
+//!
+//! Controls are carrier pitch (log2 Hz), modulator frequency as a ratio of the
+//! carrier, and modulation index (peak phase deviation in radians). The index
+//! is smoothed linearly across each chunk.
+//!
+//! FM spreads energy far above the carrier, so high pitches and indices alias.
+//! Param 0, if positive, limits the index so that the highest significant
+//! sideband (by Carson's rule, carrier + (index + 1) * modulator) stays below
+//! that fraction of Nyquist; 0 (the default) leaves the index alone. The limit
+//! follows the sample rate the operator was created with, so inside an
+//! `Oversample` wrapper it allows the extra bandwidth the wrapper provides.
+
+use std::f32::consts;
+use std::ops::Deref;
+
+use module::{Module, Buffer};
+use super::sin::{LG_N_SAMPLES, N_SAMPLES, SINTAB};
+
+pub struct FmOp {
+    sr_offset: f32,
+    // phases are in units of table samples
+    carrier_phase: f32,
+    mod_phase: f32,
+    last_index: Option<f32>,
+    // fraction of Nyquist, or 0 for no limiting
+    index_limit: f32,
+}
+
+impl FmOp {
+    pub fn new(sample_rate: f32) -> FmOp {
+        // make initialization happen here so it doesn't happen in process
+        let _ = SINTAB.deref();
+        FmOp {
+            sr_offset: LG_N_SAMPLES as f32 - sample_rate.log2(),
+            carrier_phase: 0.0,
+            mod_phase: 0.0,
+            last_index: None,
+            index_limit: 0.0,
+        }
+    }
+
+    // The largest index, in radians, keeping sidebands below the limit. Both
+    // frequencies are in table samples per sample, so Nyquist is N_SAMPLES / 2.
+    fn max_index(&self, freq: f32, mod_freq: f32) -> f32 {
+        let ceiling = self.index_limit * (N_SAMPLES / 2) as f32;
+        ((ceiling - freq) / mod_freq.abs() - 1.0).max(0.0)
+    }
+}
+
+// Phase may be negative or beyond one cycle.
+fn lookup(tab: &[f32; N_SAMPLES + 1], phase: f32) -> f32 {
+    let phasefloor = phase.floor();
+    let tab_ix = (phasefloor as i32 & (N_SAMPLES as i32 - 1)) as usize;
+    let y0 = tab[tab_ix];
+    let y1 = tab[tab_ix + 1];
+    y0 + (y1 - y0) * (phase - phasefloor)
+}
+
+fn wrap(phase: f32) -> f32 {
+    let phaseint = phase as i32;
+    phase - (phaseint & -(N_SAMPLES as i32)) as f32
+}
+
+impl Module for FmOp {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        self.carrier_phase = 0.0;
+        self.mod_phase = 0.0;
+        self.last_index = None;
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        if param_ix == 0 {
+            self.index_limit = val.max(0.0);
+        }
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        _buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let freq = (control_in[0] + self.sr_offset).exp2();
+        let mod_freq = freq * control_in[1];
+        let mut index = control_in[2];
+        if self.index_limit > 0.0 {
+            index = index.min(self.max_index(freq, mod_freq));
+        }
+        // convert index from radians to table samples
+        let index = index * (N_SAMPLES as f32 / (2.0 * consts::PI));
+        let tab = SINTAB.deref();
+        let out = buf_out[0].get_mut();
+        let mut i = self.last_index.unwrap_or(index);
+        let di = (index - i) * (1.0 / out.len() as f32);
+        self.last_index = Some(index);
+        let mut carrier_phase = self.carrier_phase;
+        let mut mod_phase = self.mod_phase;
+        for y in out.iter_mut() {
+            i += di;
+            let m = lookup(tab, mod_phase);
+            *y = lookup(tab, carrier_phase + i * m);
+            carrier_phase += freq;
+            mod_phase += mod_freq;
+        }
+        self.carrier_phase = wrap(carrier_phase);
+        self.mod_phase = wrap(mod_phase);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use test_util::magnitude;
+    use super::FmOp;
+
+    fn render(fm: &mut FmOp, ctrl: &[f32], n: usize) -> Vec<f32> {
+        let mut out = [Buffer::default()];
+        let mut samples = Vec::new();
+        for _ in 0..n / N_SAMPLES_PER_CHUNK {
+            fm.process(ctrl, &mut [], &[], &mut out);
+            samples.extend_from_slice(out[0].get());
+        }
+        samples
+    }
+
+    #[test]
+    fn bessel_sidebands() {
+        let sample_rate = 44_100.0;
+        let n = 8192;
+        let bin = sample_rate / n as f32;
+        let carrier = 400.0 * bin;
+        let mut fm = FmOp::new(sample_rate);
+        let samples = render(&mut fm, &[carrier.log2(), 0.25, 1.0], n);
+        // J0(1) = 0.7652, J1(1) = 0.4401
+        let c = magnitude(&samples, carrier, sample_rate);
+        let upper = magnitude(&samples, carrier + 100.0 * bin, sample_rate);
+        let lower = magnitude(&samples, carrier - 100.0 * bin, sample_rate);
+        assert!((c - 0.7652).abs() < 0.01, "carrier {}", c);
+        assert!((upper / c - 0.5751).abs() < 0.01, "upper ratio {}", upper / c);
+        assert!((lower / c - 0.5751).abs() < 0.01, "lower ratio {}", lower / c);
+    }
+
+    #[test]
+    fn index_limit_reduces_aliasing() {
+        let sample_rate = 44_100.0;
+        let n = 8192;
+        let bin = sample_rate / n as f32;
+        // Harmonics of the carrier up to 3600 bins are genuine; everything
+        // else in the signal has folded back from above Nyquist.
+        let carrier = 1200.0 * bin;
+        let ctrl = [carrier.log2(), 1.0, 8.0];
+        let aliased_fraction = |samples: &[f32]| {
+            let total = samples.iter().map(|x| x * x).sum::<f32>() / n as f32;
+            let dc = samples.iter().sum::<f32>() / n as f32;
+            let harmonics = (1..4)
+                .map(|k| magnitude(samples, k as f32 * carrier, sample_rate).powi(2) / 2.0)
+                .sum::<f32>();
+            (total - dc * dc - harmonics) / total
+        };
+        let naive = aliased_fraction(&render(&mut FmOp::new(sample_rate), &ctrl, n));
+        let mut fm = FmOp::new(sample_rate);
+        fm.set_param(0, 1.0, 0);
+        let limited = aliased_fraction(&render(&mut fm, &ctrl, n));
+        assert!(naive > 0.3, "naive {}", naive);
+        assert!(limited < 0.05, "limited {}", limited);
+    }
+}
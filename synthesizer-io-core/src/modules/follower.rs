@@ -0,0 +1,96 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! An envelope follower, tracking the peak amplitude of its audio input as a
+//! control output, for example to modulate a filter cutoff.
+//!
+//! Control inputs are attack and release times in ms, and optionally
+//! sensitivity (log2 of gain applied to the envelope) and range. The output is
+//! `range * min(env * 2^sensitivity, 1)`, so with the defaults of 0 and 1 it's
+//! the envelope clamped to [0, 1].
+
+use module::{Module, Buffer};
+
+pub struct Follower {
+    sample_rate: f32,
+    // peak envelope, linear amplitude
+    env: f32,
+}
+
+impl Follower {
+    pub fn new(sample_rate: f32) -> Follower {
+        Follower {
+            sample_rate,
+            env: 0.0,
+        }
+    }
+
+    // Per-sample coefficient of a one-pole lowpass with time constant in ms.
+    fn coef(&self, ms: f32) -> f32 {
+        (-1000.0 / (ms.max(1e-3) * self.sample_rate)).exp()
+    }
+}
+
+impl Module for Follower {
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn type_tag(&self) -> Option<&'static str> { Some("follower") }
+
+    fn reset(&mut self) {
+        self.env = 0.0;
+    }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        let attack = self.coef(control_in[0]);
+        let release = self.coef(control_in[1]);
+        let sensitivity = control_in.get(2).cloned().unwrap_or(0.0);
+        let range = control_in.get(3).cloned().unwrap_or(1.0);
+        let mut env = self.env;
+        for x in buf_in[0].get() {
+            let a = x.abs();
+            let coef = if a > env { attack } else { release };
+            env = a + (env - a) * coef;
+        }
+        self.env = env;
+        control_out[0] = range * (env * sensitivity.exp2()).min(1.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use super::Follower;
+
+    #[test]
+    fn tracks_amplitude() {
+        let sample_rate = 44_100.0;
+        for &amp in &[0.1, 0.5] {
+            let mut follower = Follower::new(sample_rate);
+            let mut out = [0.0];
+            for i in 0..100 {
+                let mut inb = Buffer::default();
+                for (j, x) in inb.get_mut().iter_mut().enumerate() {
+                    let t = (i * N_SAMPLES_PER_CHUNK + j) as f32 / sample_rate;
+                    *x = amp * (t * 440.0 * 2.0 * ::std::f32::consts::PI).sin();
+                }
+                // 1ms attack, 100ms release; sensitivity +1, range 4
+                follower.process(&[1.0, 100.0, 1.0, 4.0], &mut out, &[&inb], &mut []);
+            }
+            let expected = 4.0 * (2.0 * amp).min(1.0);
+            assert!((out[0] / expected - 1.0).abs() < 0.05, "{} for amp {}", out[0], amp);
+        }
+    }
+}
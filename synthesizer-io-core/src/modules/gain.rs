@@ -12,19 +12,43 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! A simple module that applies gain to the input. Gain is interpreted
-//! as log2 of absolute gain. Linear smoothing applied.
+//! A simple module that applies gain to the input. By default gain is
+//! interpreted as log2 of absolute gain. Linear smoothing applied.
+//!
+//! Param 0 selects the curve mapping control to gain: 0 is exponential (the
+//! default), 1 is linear, for VCA-style modulation, and 2 is a power taper,
+//! `ctrl^k` for non-negative control, with the exponent `k` set by param 1
+//! (default 2).
 
 use module::{Module, Buffer};
 
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Curve {
+    Exp,
+    Linear,
+    Power,
+}
+
 pub struct Gain {
     last_g: f32,
+    curve: Curve,
+    exponent: f32,
 }
 
 impl Gain {
     pub fn new() -> Gain {
         Gain {
             last_g: 0.0,
+            curve: Curve::Exp,
+            exponent: 2.0,
+        }
+    }
+
+    fn gain(&self, ctrl: f32) -> f32 {
+        match self.curve {
+            Curve::Exp => ctrl.exp2(),
+            Curve::Linear => ctrl,
+            Curve::Power => ctrl.max(0.0).powf(self.exponent),
         }
     }
 }
@@ -32,11 +56,28 @@ impl Gain {
 impl Module for Gain {
     fn n_bufs_out(&self) -> usize { 1 }
 
+    fn type_tag(&self) -> Option<&'static str> { Some("gain") }
+
+    fn param_values(&self) -> Vec<f32> { vec![self.curve as i32 as f32, self.exponent] }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        match param_ix {
+            0 => {
+                self.curve = match val.round() as i32 {
+                    1 => Curve::Linear,
+                    2 => Curve::Power,
+                    _ => Curve::Exp,
+                }
+            }
+            1 => self.exponent = val,
+            _ => (),
+        }
+    }
+
     fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
         buf_in: &[&Buffer], buf_out: &mut [Buffer])
     {
-        let ctrl = control_in[0];
-        let g = ctrl.exp2();
+        let g = self.gain(control_in[0]);
         let out = buf_out[0].get_mut();
         let dg = (g - self.last_g) * (1.0 / out.len() as f32);
         let mut y = self.last_g + dg;
@@ -48,3 +89,33 @@ impl Module for Gain {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module};
+    use super::Gain;
+
+    #[test]
+    fn curve_modes() {
+        let mut inb = Buffer::default();
+        for x in inb.get_mut().iter_mut() {
+            *x = 1.0;
+        }
+        // (curve, exponent, control, expected gain)
+        let cases = [(0.0, 2.0, -1.0, 0.5), (1.0, 2.0, 0.25, 0.25), (2.0, 2.0, 0.5, 0.25),
+            (2.0, 3.0, 0.5, 0.125)];
+        for &(curve, exponent, ctrl, expected) in &cases {
+            let mut gain = Gain::new();
+            gain.set_param(0, curve, 0);
+            gain.set_param(1, exponent, 0);
+            let mut out = [Buffer::default()];
+            // the second chunk is past the smoothing ramp
+            for _ in 0..2 {
+                gain.process(&[ctrl], &mut [], &[&inb], &mut out);
+            }
+            for &y in out[0].get() {
+                assert!((y - expected).abs() < 1e-6, "curve {}: {}", curve, y);
+            }
+        }
+    }
+}
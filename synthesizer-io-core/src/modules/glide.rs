@@ -0,0 +1,81 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Portamento. Control inputs are the target pitch and the glide time
+//! constant, in seconds; the output slews exponentially toward the target.
+
+use module::{Module, Buffer, N_SAMPLES_PER_CHUNK};
+
+pub struct Glide {
+    chunk_secs: f32,
+    out: Option<f32>,
+}
+
+impl Glide {
+    pub fn new(sample_rate: f32) -> Glide {
+        Glide {
+            chunk_secs: N_SAMPLES_PER_CHUNK as f32 / sample_rate,
+            out: None,
+        }
+    }
+}
+
+impl Module for Glide {
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        self.out = None;
+    }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        let target = control_in[0];
+        let tc = control_in[1];
+        // Analytic solution of a 1-pole lowpass under the step invariant
+        // assumption, as in SmoothCtrl.
+        let out = match self.out {
+            Some(out) if tc > 0.0 => {
+                let e = (-self.chunk_secs / tc).exp();
+                target + (out - target) * e
+            }
+            _ => target,
+        };
+        self.out = Some(out);
+        control_out[0] = out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, N_SAMPLES_PER_CHUNK};
+    use super::Glide;
+
+    #[test]
+    fn one_time_constant() {
+        let sample_rate = 44_100.0;
+        let n_chunks = 100;
+        let tc = (n_chunks * N_SAMPLES_PER_CHUNK) as f32 / sample_rate;
+        let mut glide = Glide::new(sample_rate);
+        let mut out = [0.0];
+        let a4 = 440f32.log2();
+        glide.process(&[a4, tc], &mut out, &[], &mut []);
+        assert_eq!(out[0], a4);
+        for _ in 0..n_chunks {
+            glide.process(&[a4 + 1.0, tc], &mut out, &[], &mut []);
+        }
+        let fraction = out[0] - a4;
+        assert!((fraction - (1.0 - (-1.0f32).exp())).abs() < 1e-4, "{}", fraction);
+    }
+}
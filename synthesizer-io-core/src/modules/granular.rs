@@ -0,0 +1,192 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Granular synthesis, playing overlapping short grains from a sample.
+//!
+//! Control inputs are grain size (s), density (grains/s), position (0 to 1,
+//! as a fraction of the sample), and pitch (log2 of playback rate, 0 is the
+//! original pitch). Each grain has a Hann window. The interval between grain
+//! onsets is randomized around the mean given by density, which avoids the
+//! comb filtering of strictly periodic grains. When all the voice slots are
+//! busy, a new grain is skipped.
+
+use module::{Module, Buffer};
+
+/// Maximum number of grains sounding at once.
+const MAX_GRAINS: usize = 64;
+
+#[derive(Clone, Copy, Default)]
+struct Grain {
+    // read position in the sample, in samples
+    pos: f32,
+    // playback rate, in samples per output sample
+    step: f32,
+    // progress through the window, in [0, 1), and its increment
+    phase: f32,
+    dphase: f32,
+    active: bool,
+}
+
+pub struct Granular {
+    sample_rate: f32,
+    sample: Vec<f32>,
+    grains: [Grain; MAX_GRAINS],
+    // output samples until the next grain starts
+    until_next: f32,
+    rng: u32,
+}
+
+impl Granular {
+    /// Create a granulator playing from the given sample. The sample is
+    /// treated as a loop, so grains can cross the end.
+    pub fn new(sample_rate: f32, sample: Vec<f32>) -> Granular {
+        assert!(!sample.is_empty(), "need a non-empty sample");
+        Granular {
+            sample_rate,
+            sample,
+            grains: [Grain::default(); MAX_GRAINS],
+            until_next: 0.0,
+            rng: 1,
+        }
+    }
+
+    // Uniform random number in [0, 1).
+    fn random(&mut self) -> f32 {
+        self.rng = self.rng.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        (self.rng >> 8) as f32 * (1.0 / (1 << 24) as f32)
+    }
+
+    fn start_grain(&mut self, size: f32, position: f32, step: f32) {
+        let len = self.sample.len() as f32;
+        let n_samples = (size * self.sample_rate).max(1.0);
+        if let Some(grain) = self.grains.iter_mut().find(|g| !g.active) {
+            *grain = Grain {
+                // a position of 1 is the end, which wraps to the start
+                pos: (position.clamp(0.0, 1.0) * len).rem_euclid(len),
+                step,
+                phase: 0.0,
+                dphase: 1.0 / n_samples,
+                active: true,
+            };
+        }
+    }
+}
+
+// Linearly interpolated read from a looped buffer; `pos` is in [0, len).
+fn read(sample: &[f32], pos: f32) -> f32 {
+    let i = pos as usize;
+    let frac = pos - i as f32;
+    let y0 = sample[i];
+    let y1 = sample[(i + 1) % sample.len()];
+    y0 + (y1 - y0) * frac
+}
+
+impl Module for Granular {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        for grain in self.grains.iter_mut() {
+            grain.active = false;
+        }
+        self.until_next = 0.0;
+        self.rng = 1;
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        _buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let size = control_in[0].max(0.0);
+        let interval = self.sample_rate / control_in[1].max(1e-3);
+        let position = control_in[2];
+        let step = control_in[3].exp2();
+        let len = self.sample.len() as f32;
+        for y in buf_out[0].get_mut().iter_mut() {
+            if self.until_next <= 0.0 {
+                self.start_grain(size, position, step);
+                self.until_next += interval * (0.5 + self.random());
+            }
+            self.until_next -= 1.0;
+            let mut sum = 0.0;
+            for grain in self.grains.iter_mut().filter(|g| g.active) {
+                let w = 0.5 - 0.5 * (grain.phase * (2.0 * ::std::f32::consts::PI)).cos();
+                sum += w * read(&self.sample, grain.pos);
+                // The step can be more than the length of a short sample.
+                grain.pos = (grain.pos + grain.step).rem_euclid(len);
+                grain.phase += grain.dphase;
+                if grain.phase >= 1.0 {
+                    grain.active = false;
+                }
+            }
+            *y = sum;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use test_util::{magnitude, sine_buf};
+    use super::Granular;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    // Render half a second of grains from a 440Hz sine.
+    fn render(ctrl: &[f32]) -> Vec<f32> {
+        let mut sample = Vec::new();
+        for i in 0..SAMPLE_RATE as usize / N_SAMPLES_PER_CHUNK {
+            sample.extend_from_slice(sine_buf(440.0, SAMPLE_RATE, i * N_SAMPLES_PER_CHUNK).get());
+        }
+        let mut granular = Granular::new(SAMPLE_RATE, sample);
+        let mut out = [Buffer::default()];
+        let mut result = Vec::new();
+        for _ in 0..SAMPLE_RATE as usize / 2 / N_SAMPLES_PER_CHUNK {
+            granular.process(ctrl, &mut [], &[], &mut out);
+            result.extend_from_slice(out[0].get());
+        }
+        result
+    }
+
+    #[test]
+    fn density_increases_energy() {
+        let energy = |density: f32| {
+            render(&[0.05, density, 0.5, 0.0]).iter().map(|x| x * x).sum::<f32>()
+        };
+        let sparse = energy(10.0);
+        let dense = energy(100.0);
+        assert!(sparse > 0.0);
+        assert!(dense > 4.0 * sparse, "sparse {} dense {}", sparse, dense);
+    }
+
+    #[test]
+    fn pitch_shifts_frequency() {
+        let out = render(&[0.05, 100.0, 0.5, 1.0]);
+        let octave_up = magnitude(&out, 880.0, SAMPLE_RATE);
+        let original = magnitude(&out, 440.0, SAMPLE_RATE);
+        assert!(octave_up > 10.0 * original, "880: {} 440: {}", octave_up, original);
+    }
+
+    #[test]
+    fn wraps_short_sample() {
+        let sample: Vec<f32> = (0..10).map(|i| i as f32 * 0.1).collect();
+        // Position at the very end, and a step of 32 samples.
+        for ctrl in &[[0.01, 1000.0, 1.0, 0.0], [0.01, 1000.0, 0.5, 5.0]] {
+            let mut granular = Granular::new(SAMPLE_RATE, sample.clone());
+            let mut out = [Buffer::default()];
+            for _ in 0..100 {
+                granular.process(ctrl, &mut [], &[], &mut out);
+                assert!(out[0].get().iter().all(|y| y.is_finite()));
+            }
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A low-frequency oscillator, producing a control value.
+//!
+//! The waveform is sampled once per chunk, and that value is smoothed by a
+//! one-pole lowpass, following the timestamps, so that the edges of the
+//! square and saw don't step whatever they modulate from chunk to chunk.
+
+use std::f32::consts;
+
+use module::{Module, Buffer, N_SAMPLES_PER_CHUNK};
+
+// time constant of the output smoothing, in ms
+const SMOOTH_TC: f32 = 10.0;
+
+pub struct Lfo {
+    sr_offset: f32,
+    phase: f32,  // in cycles, always in [0, 1)
+    out: Option<(f32, u64)>,  // smoothed output, and when it was computed
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Waveform {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+}
+
+impl Waveform {
+    /// Interpret a control value as a waveform selection, rounding to the
+    /// nearest integer.
+    pub fn from_ctrl(ctrl: f32) -> Waveform {
+        match ctrl.round() as i32 {
+            1 => Waveform::Triangle,
+            2 => Waveform::Saw,
+            3 => Waveform::Square,
+            _ => Waveform::Sine,
+        }
+    }
+
+    /// Evaluate the waveform at the given phase (in cycles). All waveforms
+    /// start at 0 and rise, like a sine, and lie within [-1, 1].
+    pub fn eval(self, phase: f32) -> f32 {
+        match self {
+            Waveform::Sine => (phase * (2.0 * consts::PI)).sin(),
+            Waveform::Triangle => {
+                let p = (phase + 0.25).fract();
+                1.0 - 4.0 * (p - 0.5).abs()
+            }
+            Waveform::Saw => 2.0 * (phase + 0.5).fract() - 1.0,
+            Waveform::Square => if phase < 0.5 { 1.0 } else { -1.0 },
+        }
+    }
+}
+
+impl Lfo {
+    pub fn new(sample_rate: f32) -> Lfo {
+        Lfo {
+            sr_offset: (N_SAMPLES_PER_CHUNK as f32).log2() - sample_rate.log2(),
+            phase: 0.0,
+            out: None,
+        }
+    }
+}
+
+impl Module for Lfo {
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.out = None;
+    }
+
+    // Retrigger; only happens if the engine routes notes to this module, otherwise
+    // the LFO is free-running.
+    fn handle_note(&mut self, _midi_num: f32, _velocity: f32, on: bool) {
+        if on {
+            self.phase = 0.0;
+        }
+    }
+
+    fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
+        _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+    }
+
+    fn process_ts(&mut self, control_in: &[f32], control_out: &mut [f32],
+        _buf_in: &[&Buffer], _buf_out: &mut [Buffer], timestamp: u64)
+    {
+        // phase increment per chunk, in cycles
+        let dphase = (control_in[0] + self.sr_offset).exp2();
+        let waveform = Waveform::from_ctrl(control_in[1]);
+        // Sample at the middle of the chunk, so the value is centered on the
+        // interval it's held for.
+        let mid = (self.phase + 0.5 * dphase).fract();
+        let value = waveform.eval(mid);
+        self.phase = (self.phase + dphase).fract();
+        let out = match self.out {
+            Some((out, t)) => {
+                let dt = timestamp.saturating_sub(t) as f32 * 1e-6;  // in ms
+                value + (out - value) * (-dt / SMOOTH_TC).exp()
+            }
+            None => value,
+        };
+        self.out = Some((out, timestamp));
+        control_out[0] = out;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, N_SAMPLES_PER_CHUNK};
+    use super::Lfo;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    // Run on a sample clock.
+    fn run(lfo: &mut Lfo, freq: f32, waveform: f32, n_chunks: usize) -> Vec<f32> {
+        let ctrl = [freq.log2(), waveform];
+        let mut out = [0.0];
+        (0..n_chunks).map(|i| {
+            let ts = (i * N_SAMPLES_PER_CHUNK) as u64 * 1_000_000_000 / SAMPLE_RATE as u64;
+            lfo.process_ts(&ctrl, &mut out, &[], &mut [], ts);
+            out[0]
+        }).collect()
+    }
+
+    #[test]
+    fn output_in_range() {
+        for waveform in 0..4 {
+            let mut lfo = Lfo::new(SAMPLE_RATE);
+            for y in run(&mut lfo, 7.3, waveform as f32, 10_000) {
+                assert!((-1.0..=1.0).contains(&y), "waveform {} produced {}", waveform, y);
+            }
+        }
+    }
+
+    #[test]
+    fn rate_tracks_control() {
+        let secs = 10.0;
+        let n_chunks = (secs * SAMPLE_RATE / N_SAMPLES_PER_CHUNK as f32) as usize;
+        for &freq in &[0.5, 2.0, 8.0] {
+            let mut lfo = Lfo::new(SAMPLE_RATE);
+            let out = run(&mut lfo, freq, 2.0, n_chunks);
+            // count the falling edges of the saw
+            let n_cycles = out.windows(2).filter(|w| w[0] >= 0.0 && w[1] < 0.0).count();
+            let expected = freq * secs;
+            assert!((n_cycles as f32 - expected).abs() <= 1.0,
+                "{} Hz: got {} cycles, expected {}", freq, n_cycles, expected);
+        }
+    }
+
+    #[test]
+    fn square_is_smoothed() {
+        let mut lfo = Lfo::new(SAMPLE_RATE);
+        let out = run(&mut lfo, 4.0, 3.0, 2000);
+        let max_step = out.windows(2).map(|w| (w[1] - w[0]).abs()).fold(0.0f32, f32::max);
+        assert!(max_step < 0.3, "step of {}", max_step);
+        // it still swings most of the way between the levels
+        assert!(out.iter().any(|&y| y > 0.95) && out.iter().any(|&y| y < -0.95));
+    }
+}
@@ -14,6 +14,8 @@
 
 //! A collection of audio processing modules.
 
+use module::Module;
+
 mod sum;
 mod buzz;
 mod sin;
@@ -25,6 +27,28 @@ mod note_pitch;
 mod adsr;
 mod gain;
 mod monitor;
+mod lfo;
+mod delay;
+mod shaper;
+mod ctrl_sum;
+mod ring_mod;
+mod fm_op;
+mod svf;
+mod pan;
+mod fader;
+mod compressor;
+mod sample_hold;
+mod glide;
+mod tz_flanger;
+mod wavetable;
+mod follower;
+mod granular;
+mod decorrelate;
+mod tremolo;
+mod bbd_delay;
+mod conv_reverb;
+mod oversample;
+mod dc_block;
 
 pub use self::sum::Sum;
 pub use self::buzz::Buzz;
@@ -37,3 +61,65 @@ pub use self::note_pitch::NotePitch;
 pub use self::adsr::Adsr;
 pub use self::gain::Gain;
 pub use self::monitor::Monitor;
+pub use self::lfo::Lfo;
+pub use self::delay::Delay;
+pub use self::shaper::Shaper;
+pub use self::ctrl_sum::CtrlSum;
+pub use self::ring_mod::RingMod;
+pub use self::fm_op::FmOp;
+pub use self::svf::Svf;
+pub use self::pan::Pan;
+pub use self::fader::Fader;
+pub use self::compressor::Compressor;
+pub use self::sample_hold::SampleHold;
+pub use self::glide::Glide;
+pub use self::tz_flanger::TzFlanger;
+pub use self::wavetable::Wavetable;
+pub use self::follower::Follower;
+pub use self::granular::Granular;
+pub use self::decorrelate::Decorrelate;
+pub use self::tremolo::Tremolo;
+pub use self::bbd_delay::BbdDelay;
+pub use self::conv_reverb::ConvReverb;
+pub use self::oversample::Oversample;
+pub use self::dc_block::DcBlock;
+
+/// Rebuild a module from its type tag and param values, as reported by
+/// `Module::type_tag` and `Module::param_values`, for loading presets.
+/// Returns `None` if the tag is unknown. A `Monitor` isn't built here, as
+/// its queues have to go to the caller.
+pub fn from_preset(tag: &str, values: &[f32], sample_rate: f32) -> Option<Box<dyn Module>> {
+    let value = |i: usize, default: f32| values.get(i).cloned().unwrap_or(default);
+    let channels = value(0, 1.0) as usize;
+    let mut module: Box<dyn Module> = match tag {
+        "sum" => Box::new(Sum::new_channels(channels)),
+        "ctrl_sum" => Box::new(CtrlSum::new()),
+        "saw" => Box::new(Saw::new(sample_rate)),
+        "sin" => Box::new(Sin::new(sample_rate)),
+        "pan" => Box::new(Pan::new()),
+        "follower" => Box::new(Follower::new(sample_rate)),
+        "fader" => Box::new(Fader::new(sample_rate)),
+        "biquad" if channels == 2 => Box::new(Biquad::new_stereo(sample_rate)),
+        "biquad" => Box::new(Biquad::new(sample_rate)),
+        "smooth_ctrl" => Box::new(SmoothCtrl::new(value(0, 0.0))),
+        // The rest have param values in the order of `set_param`.
+        "const_ctrl" => Box::new(ConstCtrl::new(0.0)),
+        "note_pitch" => Box::new(NotePitch::new(sample_rate)),
+        "adsr" => Box::new(Adsr::new()),
+        "gain" => Box::new(Gain::new()),
+        _ => return None,
+    };
+    if let "const_ctrl" | "note_pitch" | "adsr" | "gain" = tag {
+        for (i, &val) in values.iter().enumerate() {
+            module.set_param(i, val, 0);
+        }
+    }
+    Some(module)
+}
+
+/// Whether the param values of modules with this tag are their params, in
+/// the order of `set_param`, so that setting a param can update them in
+/// place.
+pub fn values_are_params(tag: &str) -> bool {
+    matches!(tag, "smooth_ctrl" | "const_ctrl" | "note_pitch" | "adsr" | "gain")
+}
@@ -13,14 +13,18 @@
 // limitations under the License.
 
 //! A module for monitoring an audio signal.
+//!
+//! A stereo monitor takes two input buffers and sends the samples
+//! interleaved, left then right.
 
-use module::{Module, Buffer};
+use module::{Module, Buffer, N_SAMPLES_PER_CHUNK};
 use queue::{Item, Queue, Receiver, Sender};
 
 pub struct Monitor {
     buf_pool: Vec<Item<Vec<f32>>>,
     to_monitor: Receiver<Vec<f32>>,
     from_monitor: Sender<Vec<f32>>,
+    channels: usize,
 }
 
 const POOL_SIZE: usize = 256;
@@ -29,19 +33,31 @@ const BUF_SIZE: usize = 256;
 
 impl Monitor {
     pub fn new() -> (Monitor, Sender<Vec<f32>>, Receiver<Vec<f32>>) {
+        Monitor::with_channels(1)
+    }
+
+    pub fn new_stereo() -> (Monitor, Sender<Vec<f32>>, Receiver<Vec<f32>>) {
+        Monitor::with_channels(2)
+    }
+
+    fn with_channels(channels: usize) -> (Monitor, Sender<Vec<f32>>, Receiver<Vec<f32>>) {
         let (tx, to_monitor) = Queue::new();
         let (from_monitor, rx) = Queue::new();
         let mut buf_pool = Vec::with_capacity(POOL_SIZE);
         for _ in 0..POOL_SIZE {
             buf_pool.push(Item::make_item(Vec::with_capacity(BUF_SIZE)));
         }
-        let monitor = Monitor { buf_pool, to_monitor, from_monitor };
+        let monitor = Monitor { buf_pool, to_monitor, from_monitor, channels };
         (monitor, tx, rx)
     }
 }
 
 impl Module for Monitor {
-    fn n_bufs_out(&self) -> usize { 1 }
+    fn n_bufs_out(&self) -> usize { self.channels }
+
+    fn type_tag(&self) -> Option<&'static str> { Some("monitor") }
+
+    fn param_values(&self) -> Vec<f32> { vec![self.channels as f32] }
 
     fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
         buf_in: &[&Buffer], buf_out: &mut [Buffer])
@@ -51,15 +67,25 @@ impl Module for Monitor {
         // Note: non-allocation depends on this not overflowing.
         self.buf_pool.extend(self.to_monitor.recv_items());
 
-        let buf = buf_in[0].get();
         // Copy input to output. This is so node can participate in graph
         // topological sort, but maybe there's a better approach, like
         // having an explicit list of roots.
-        buf_out[0].get_mut().copy_from_slice(buf);
+        for (out, buf) in buf_out.iter_mut().zip(buf_in.iter()) {
+            out.get_mut().copy_from_slice(buf.get());
+        }
 
         if let Some(mut cur_buf) = cur_buf {
-            cur_buf.extend_from_slice(buf);
-            if cur_buf.len() + buf.len() > cur_buf.capacity () {
+            if self.channels == 1 {
+                cur_buf.extend_from_slice(buf_in[0].get());
+            } else {
+                let (l, r) = (buf_in[0].get(), buf_in[1].get());
+                for i in 0..N_SAMPLES_PER_CHUNK {
+                    cur_buf.push(l[i]);
+                    cur_buf.push(r[i]);
+                }
+            }
+            let chunk_len = self.channels * N_SAMPLES_PER_CHUNK;
+            if cur_buf.len() + chunk_len > cur_buf.capacity () {
                 self.from_monitor.send_item(cur_buf);
             } else {
                 self.buf_pool.push(cur_buf);
@@ -67,3 +93,34 @@ impl Module for Monitor {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module};
+    use super::Monitor;
+
+    #[test]
+    fn stereo_interleaved() {
+        let (mut monitor, _tx, rx) = Monitor::new_stereo();
+        let mut l = Buffer::default();
+        let mut r = Buffer::default();
+        for (i, (x, y)) in l.get_mut().iter_mut().zip(r.get_mut().iter_mut()).enumerate() {
+            *x = i as f32;
+            *y = -(i as f32);
+        }
+        let mut out = [Buffer::default(), Buffer::default()];
+        let mut samples = Vec::new();
+        for _ in 0..8 {
+            monitor.process(&[], &mut [], &[&l, &r], &mut out);
+            for item in rx.recv_items() {
+                samples.extend_from_slice(&item);
+            }
+        }
+        assert!(!samples.is_empty());
+        for (i, frame) in samples.chunks(2).enumerate() {
+            let expected = (i % l.get().len()) as f32;
+            assert_eq!(frame, [expected, -expected]);
+        }
+        assert_eq!(out[1].get(), r.get());
+    }
+}
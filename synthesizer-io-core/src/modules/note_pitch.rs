@@ -12,32 +12,129 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-//! A simple module that just holds a note at a constant pitch.
+//! The pitch of a note, combining all the sources of pitch modulation, as
+//! log2 Hz.
+//!
+//! The output is the sum of the note's base pitch, pitch bend, and an optional
+//! vibrato control input (in octaves). The base pitch glides toward each new
+//! note with the time constant (s) set by param 0; zero, the default, jumps.
+//! Param 1 sets the bend, in octaves, which is smoothed so that coarse
+//! controller steps don't produce audible clicks. Vibrato is assumed to be
+//! smooth already and is applied as is.
 
-use module::{Module, Buffer};
+use module::{Module, Buffer, N_SAMPLES_PER_CHUNK};
+
+// time constant of bend smoothing, in seconds
+const BEND_TC: f32 = 0.005;
 
 pub struct NotePitch {
-    value: f32,
+    chunk_secs: f32,
+    // pitch of the most recent note, and the gliding pitch following it
+    target: Option<f32>,
+    base: f32,
+    glide_tc: f32,
+    bend_target: f32,
+    bend: f32,
 }
 
 impl NotePitch {
-    pub fn new() -> NotePitch {
-        NotePitch { value: 0.0 }
+    pub fn new(sample_rate: f32) -> NotePitch {
+        NotePitch {
+            chunk_secs: N_SAMPLES_PER_CHUNK as f32 / sample_rate,
+            target: None,
+            base: 0.0,
+            glide_tc: 0.0,
+            bend_target: 0.0,
+            bend: 0.0,
+        }
+    }
+}
+
+// Advance a 1-pole lowpass by `dt`, under the step invariant assumption.
+fn slew(value: f32, target: f32, dt: f32, tc: f32) -> f32 {
+    if tc > 0.0 {
+        target + (value - target) * (-dt / tc).exp()
+    } else {
+        target
     }
 }
 
 impl Module for NotePitch {
     fn n_ctrl_out(&self) -> usize { 1 }
 
+    fn type_tag(&self) -> Option<&'static str> { Some("note_pitch") }
+
+    fn param_values(&self) -> Vec<f32> { vec![self.glide_tc, self.bend_target] }
+
+    fn reset(&mut self) {
+        self.target = None;
+        self.base = 0.0;
+        self.bend = self.bend_target;
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, _timestamp: u64) {
+        match param_ix {
+            0 => self.glide_tc = val.max(0.0),
+            1 => self.bend_target = val,
+            _ => (),
+        }
+    }
+
     fn handle_note(&mut self, midi_num: f32, _velocity: f32, on: bool) {
         if on {
-            self.value = midi_num * (1.0 / 12.0) + (440f32.log2() - 69.0 / 12.0);
+            let pitch = midi_num * (1.0 / 12.0) + (440f32.log2() - 69.0 / 12.0);
+            if self.target.is_none() {
+                self.base = pitch;
+            }
+            self.target = Some(pitch);
         }
     }
 
-    fn process(&mut self, _control_in: &[f32], control_out: &mut [f32],
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
         _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
     {
-        control_out[0] = self.value;
+        let dt = self.chunk_secs;
+        if let Some(target) = self.target {
+            self.base = slew(self.base, target, dt, self.glide_tc);
+        }
+        self.bend = slew(self.bend, self.bend_target, dt, BEND_TC);
+        let vibrato = control_in.first().cloned().unwrap_or(0.0);
+        control_out[0] = self.base + self.bend + vibrato;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Module, N_SAMPLES_PER_CHUNK};
+    use super::{NotePitch, BEND_TC};
+
+    #[test]
+    fn glide_bend_and_vibrato_combine() {
+        let sample_rate = 44_100.0;
+        let dt = N_SAMPLES_PER_CHUNK as f32 / sample_rate;
+        let (a4, a5) = (440f32.log2(), 880f32.log2());
+        let mut pitch = NotePitch::new(sample_rate);
+        let mut out = [0.0];
+        pitch.handle_note(69.0, 100.0, true);
+        pitch.process(&[0.0], &mut out, &[], &mut []);
+        assert!((out[0] - a4).abs() < 1e-5);
+
+        let glide_tc = 0.05;
+        let bend = 1.0 / 12.0;
+        pitch.set_param(0, glide_tc, 0);
+        pitch.set_param(1, bend, 0);
+        pitch.handle_note(81.0, 100.0, true);
+        let mut last = out[0];
+        for k in 1..200 {
+            let vibrato = 0.01 * (k as f32 * 0.1).sin();
+            pitch.process(&[vibrato], &mut out, &[], &mut []);
+            let t = k as f32 * dt;
+            let expected = a5 + (a4 - a5) * (-t / glide_tc).exp()
+                + bend * (1.0 - (-t / BEND_TC).exp()) + vibrato;
+            assert!((out[0] - expected).abs() < 1e-4, "chunk {}: {} vs {}", k, out[0], expected);
+            // no steps bigger than the initial glide and bend rates
+            assert!((out[0] - last).abs() < 0.1, "step at chunk {}", k);
+            last = out[0];
+        }
     }
 }
@@ -0,0 +1,209 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Oversampling, to run a nonlinear module at a multiple of the sample rate
+//! so that less of the distortion it adds aliases.
+//!
+//! The input is interpolated with a polyphase windowed sinc filter, and the
+//! same filter cuts everything above the original Nyquist frequency before
+//! the inner module's output is decimated. Together they delay the signal by
+//! just under 32 samples.
+
+use std::f32::consts::PI;
+
+use module::{Module, Buffer, ParamDesc, N_SAMPLES_PER_CHUNK};
+
+const TAPS_PER_PHASE: usize = 32;
+
+pub struct Oversample<M: Module + 'static> {
+    inner: M,
+    factor: usize,
+    // lowpass filter at the oversampled rate
+    taps: Vec<f32>,
+    // the last TAPS_PER_PHASE - 1 samples of input, then the current chunk
+    input: Vec<f32>,
+    // the last taps.len() - 1 samples of inner output, then the new ones
+    inner_out: Vec<f32>,
+    up: Buffer,
+    down: [Buffer; 1],
+}
+
+impl<M: Module + 'static> Oversample<M> {
+    /// Wrap a module with at most one input and one output buffer, running it
+    /// at `factor` times the sample rate. The inner module should be created
+    /// with the oversampled rate.
+    pub fn new(inner: M, factor: usize) -> Oversample<M> {
+        assert!(factor >= 1, "oversampling factor must be at least 1");
+        let n = TAPS_PER_PHASE * factor;
+        let cutoff = 0.5 / factor as f32;
+        let center = 0.5 * (n - 1) as f32;
+        let mut taps: Vec<f32> = (0..n)
+            .map(|i| {
+                let t = i as f32 - center;
+                let sinc = if t == 0.0 {
+                    2.0 * cutoff
+                } else {
+                    (2.0 * PI * cutoff * t).sin() / (PI * t)
+                };
+                let phase = 2.0 * PI * i as f32 / (n - 1) as f32;
+                sinc * (0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos())
+            })
+            .collect();
+        let sum: f32 = taps.iter().sum();
+        for tap in &mut taps {
+            *tap /= sum;
+        }
+        let input = vec![0.0; TAPS_PER_PHASE - 1 + N_SAMPLES_PER_CHUNK];
+        let inner_out = vec![0.0; n - 1 + N_SAMPLES_PER_CHUNK * factor];
+        Oversample {
+            inner,
+            factor,
+            taps,
+            input,
+            inner_out,
+            up: Buffer::default(),
+            down: [Buffer::default()],
+        }
+    }
+}
+
+impl<M: Module + 'static> Module for Oversample<M> {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn n_ctrl_out(&self) -> usize { self.inner.n_ctrl_out() }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        for x in self.input.iter_mut().chain(self.inner_out.iter_mut()) {
+            *x = 0.0;
+        }
+    }
+
+    fn set_param(&mut self, param_ix: usize, val: f32, timestamp: u64) {
+        self.inner.set_param(param_ix, val, timestamp);
+    }
+
+    fn handle_note(&mut self, midi_num: f32, velocity: f32, on: bool) {
+        self.inner.handle_note(midi_num, velocity, on);
+    }
+
+    fn params(&self) -> &[ParamDesc] { self.inner.params() }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let factor = self.factor;
+        let hist = TAPS_PER_PHASE - 1;
+        // A source, such as an oscillator, has no input to interpolate.
+        let has_input = !buf_in.is_empty();
+        if has_input {
+            self.input[hist..].copy_from_slice(buf_in[0].get());
+        }
+        let out_hist = self.taps.len() - 1;
+        for sub in 0..factor {
+            // Interpolate a chunk at the oversampled rate. Oversampled sample
+            // n * factor + p is phase p of the filter applied to the input up
+            // to sample n, with the gain made up for the zeros stuffed between.
+            if has_input {
+                for (i, y) in self.up.get_mut().iter_mut().enumerate() {
+                    let m = sub * N_SAMPLES_PER_CHUNK + i;
+                    let (n, p) = (m / factor, m % factor);
+                    let x = &self.input[n..n + TAPS_PER_PHASE];
+                    let taps = self.taps[p..].iter().step_by(factor);
+                    *y = factor as f32
+                        * x.iter().rev().zip(taps).map(|(x, h)| x * h).sum::<f32>();
+                }
+            }
+            let up = [&self.up];
+            let inner_in: &[&Buffer] = if has_input { &up } else { &[] };
+            self.inner.process(control_in, control_out, inner_in, &mut self.down);
+            let start = out_hist + sub * N_SAMPLES_PER_CHUNK;
+            self.inner_out[start..start + N_SAMPLES_PER_CHUNK]
+                .copy_from_slice(self.down[0].get());
+        }
+        // Filter and decimate.
+        for (n, y) in buf_out[0].get_mut().iter_mut().enumerate() {
+            let v = &self.inner_out[n * factor..n * factor + self.taps.len()];
+            *y = v.iter().rev().zip(&self.taps).map(|(v, h)| v * h).sum();
+        }
+        if has_input {
+            let len = self.input.len();
+            self.input.copy_within(len - hist.., 0);
+        }
+        let len = self.inner_out.len();
+        self.inner_out.copy_within(len - out_hist.., 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use modules::{FmOp, Shaper};
+    use test_util::{magnitude, sine_buf};
+    use super::Oversample;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+    const N: usize = 4096;
+
+    // Drive a sine, on an exact bin, into a hard clipper. Returns the level of
+    // the fundamental, and the energy in bins above half the Nyquist frequency
+    // that aren't harmonics.
+    fn aliasing<M: Module>(clipper: &mut M) -> (f32, f32) {
+        let bin = 465;
+        let freq = bin as f32 * SAMPLE_RATE / N as f32;
+        // 12dB of drive into the clipper
+        let ctrl = [2.0, 0.0, 0.0];
+        let mut out = Vec::new();
+        let mut bufs = [Buffer::default()];
+        let warmup = 8 * N_SAMPLES_PER_CHUNK;
+        while out.len() < warmup + N {
+            let inb = sine_buf(freq, SAMPLE_RATE, out.len());
+            clipper.process(&ctrl, &mut [], &[&inb], &mut bufs);
+            out.extend_from_slice(bufs[0].get());
+        }
+        let samples = &out[warmup..];
+        let harmonics: Vec<_> = (1..N / bin).step_by(2).map(|h| h * bin).collect();
+        let energy = (N / 4..N / 2)
+            .filter(|k| !harmonics.contains(k))
+            .map(|k| magnitude(samples, k as f32 * SAMPLE_RATE / N as f32, SAMPLE_RATE).powi(2))
+            .sum();
+        (magnitude(samples, freq, SAMPLE_RATE), energy)
+    }
+
+    #[test]
+    fn oversampling_reduces_aliasing() {
+        let (level, plain) = aliasing(&mut Shaper::new());
+        for &(factor, max_ratio) in &[(2, 0.05), (4, 0.01)] {
+            let (over_level, over) = aliasing(&mut Oversample::new(Shaper::new(), factor));
+            assert!((over_level - level).abs() < 0.01 * level, "{}x level {}", factor, over_level);
+            assert!(over < max_ratio * plain, "plain {} {}x {}", plain, factor, over);
+        }
+    }
+
+    #[test]
+    fn wraps_a_source() {
+        let freq = 93.0 * SAMPLE_RATE / N as f32;
+        let mut osc = Oversample::new(FmOp::new(2.0 * SAMPLE_RATE), 2);
+        let ctrl = [freq.log2(), 1.0, 0.0];
+        let mut out = Vec::new();
+        let mut bufs = [Buffer::default()];
+        let warmup = 8 * N_SAMPLES_PER_CHUNK;
+        while out.len() < warmup + N {
+            osc.process(&ctrl, &mut [], &[], &mut bufs);
+            out.extend_from_slice(bufs[0].get());
+        }
+        let level = magnitude(&out[warmup..], freq, SAMPLE_RATE);
+        assert!((level - 1.0).abs() < 0.01, "level {}", level);
+    }
+}
@@ -0,0 +1,91 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A stereo panner, producing left and right buffers from a mono input.
+//! Pan position ranges from -1 (left) to +1 (right), with an equal-power
+//! law, and is smoothed linearly across each chunk.
+
+use std::f32::consts;
+
+use module::{Module, Buffer};
+
+pub struct Pan {
+    last_pan: Option<f32>,
+}
+
+impl Pan {
+    pub fn new() -> Pan {
+        Pan { last_pan: None }
+    }
+}
+
+impl Default for Pan {
+    fn default() -> Pan {
+        Pan::new()
+    }
+}
+
+impl Module for Pan {
+    fn n_bufs_out(&self) -> usize { 2 }
+
+    fn type_tag(&self) -> Option<&'static str> { Some("pan") }
+
+    fn reset(&mut self) {
+        self.last_pan = None;
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let pan = control_in[0].clamp(-1.0, 1.0);
+        let mut p = self.last_pan.unwrap_or(pan);
+        let inb = buf_in[0].get();
+        let dp = (pan - p) * (1.0 / inb.len() as f32);
+        self.last_pan = Some(pan);
+        let (l, r) = buf_out.split_at_mut(1);
+        let (l, r) = (l[0].get_mut(), r[0].get_mut());
+        for i in 0..inb.len() {
+            p += dp;
+            let (sin, cos) = ((p + 1.0) * (0.25 * consts::PI)).sin_cos();
+            l[i] = inb[i] * cos;
+            r[i] = inb[i] * sin;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module};
+    use super::Pan;
+
+    fn run(pan: f32) -> (f32, f32) {
+        let mut inb = Buffer::default();
+        for x in inb.get_mut().iter_mut() {
+            *x = 1.0;
+        }
+        let mut module = Pan::new();
+        let mut out = [Buffer::default(), Buffer::default()];
+        module.process(&[pan], &mut [], &[&inb], &mut out);
+        (out[0].get()[0], out[1].get()[0])
+    }
+
+    #[test]
+    fn pan_law() {
+        let (l, r) = run(0.0);
+        let minus_3db = 0.5f32.sqrt();
+        assert!((l - minus_3db).abs() < 1e-6 && (r - minus_3db).abs() < 1e-6);
+        let (l, r) = run(-1.0);
+        assert!((l - 1.0).abs() < 1e-6 && r.abs() < 1e-6);
+    }
+}
@@ -0,0 +1,94 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A ring modulator. Takes two input buffers, the carrier and the modulator.
+//!
+//! Control 0 selects the mode: 0 is ring modulation (bipolar multiply), 1 is
+//! amplitude modulation, where the modulator is offset so it's unipolar and
+//! the carrier passes through at half amplitude when the modulator is silent.
+
+use module::{Module, Buffer};
+
+pub struct RingMod;
+
+impl RingMod {
+    pub fn new() -> RingMod {
+        RingMod
+    }
+}
+
+impl Default for RingMod {
+    fn default() -> RingMod {
+        RingMod::new()
+    }
+}
+
+impl Module for RingMod {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let carrier = buf_in[0].get();
+        let modulator = buf_in[1].get();
+        let out = buf_out[0].get_mut();
+        if control_in[0].round() as i32 == 1 {
+            for ((y, c), m) in out.iter_mut().zip(carrier.iter()).zip(modulator.iter()) {
+                *y = c * (0.5 + 0.5 * m);
+            }
+        } else {
+            for ((y, c), m) in out.iter_mut().zip(carrier.iter()).zip(modulator.iter()) {
+                *y = c * m;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use test_util::{magnitude, sine_buf};
+    use super::RingMod;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    fn run(mode: f32, f1: f32, f2: f32) -> Vec<f32> {
+        let mut ring_mod = RingMod::new();
+        let mut out = [Buffer::default()];
+        let mut result = Vec::new();
+        for i in 0..4096 / N_SAMPLES_PER_CHUNK {
+            let start = i * N_SAMPLES_PER_CHUNK;
+            let carrier = sine_buf(f1, SAMPLE_RATE, start);
+            let modulator = sine_buf(f2, SAMPLE_RATE, start);
+            ring_mod.process(&[mode], &mut [], &[&carrier, &modulator], &mut out);
+            result.extend_from_slice(out[0].get());
+        }
+        result
+    }
+
+    #[test]
+    fn ring_mode_sidebands() {
+        // bin-centered frequencies for 4096 samples
+        let bin = SAMPLE_RATE / 4096.0;
+        let (f1, f2) = (100.0 * bin, 23.0 * bin);
+        let out = run(0.0, f1, f2);
+        assert!((magnitude(&out, f1 + f2, SAMPLE_RATE) - 0.5).abs() < 0.01);
+        assert!((magnitude(&out, f1 - f2, SAMPLE_RATE) - 0.5).abs() < 0.01);
+        assert!(magnitude(&out, f1, SAMPLE_RATE) < 0.01);
+        assert!(magnitude(&out, f2, SAMPLE_RATE) < 0.01);
+
+        let out = run(1.0, f1, f2);
+        assert!((magnitude(&out, f1, SAMPLE_RATE) - 0.5).abs() < 0.01);
+    }
+}
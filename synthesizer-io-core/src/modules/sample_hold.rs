@@ -0,0 +1,83 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sample and hold. Latches the input signal when the trigger control rises
+//! above the threshold (an optional second control input, defaulting to 0),
+//! and outputs the held value as a control.
+
+use module::{Module, Buffer};
+
+pub struct SampleHold {
+    value: f32,
+    last_trigger: f32,
+}
+
+impl SampleHold {
+    pub fn new() -> SampleHold {
+        SampleHold {
+            value: 0.0,
+            last_trigger: 0.0,
+        }
+    }
+}
+
+impl Default for SampleHold {
+    fn default() -> SampleHold {
+        SampleHold::new()
+    }
+}
+
+impl Module for SampleHold {
+    fn n_ctrl_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        self.value = 0.0;
+        self.last_trigger = 0.0;
+    }
+
+    fn process(&mut self, control_in: &[f32], control_out: &mut [f32],
+        buf_in: &[&Buffer], _buf_out: &mut [Buffer])
+    {
+        let trigger = control_in[0];
+        let threshold = control_in.get(1).cloned().unwrap_or(0.0);
+        if self.last_trigger <= threshold && trigger > threshold {
+            // Controls are per-chunk, so sample at the start of the chunk.
+            self.value = buf_in[0].get()[0];
+        }
+        self.last_trigger = trigger;
+        control_out[0] = self.value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module};
+    use super::SampleHold;
+
+    #[test]
+    fn ramp_becomes_staircase() {
+        let mut sh = SampleHold::new();
+        let mut out = [0.0];
+        let mut inb = Buffer::default();
+        for i in 0..16 {
+            for x in inb.get_mut().iter_mut() {
+                *x = i as f32;
+            }
+            // square wave trigger with a period of 4 chunks
+            let trigger = if i % 4 < 2 { 1.0 } else { -1.0 };
+            sh.process(&[trigger], &mut out, &[&inb], &mut []);
+            assert_eq!(out[0], (i - i % 4) as f32);
+        }
+    }
+}
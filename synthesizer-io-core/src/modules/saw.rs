@@ -96,6 +96,19 @@ fn compute(tab_ix: usize, phasefrac: f32) -> f32 {
 impl Module for Saw {
     fn n_bufs_out(&self) -> usize { 1 }
 
+    fn type_tag(&self) -> Option<&'static str> { Some("saw") }
+
+    // Keep the phase continuous when the node is rewired.
+    fn migrate(&mut self, old: &mut dyn Module) {
+        if let Some(old_saw) = old.to_any().downcast_ref::<Saw>() {
+            self.phase = old_saw.phase;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+
     fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
         _buf_in: &[&Buffer], buf_out: &mut [Buffer])
     {
@@ -0,0 +1,124 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A waveshaper for soft (or hard) clipping. Drive and makeup gain are
+//! interpreted as log2 of absolute gain, with linear smoothing.
+
+use module::{Module, Buffer};
+
+/// Approximation of tanh, max error 2e-4. See benches/sigmoid.rs.
+pub fn tanh5(x: f32) -> f32 {
+    let xx = x * x;
+    let x = x + (0.16489087 + 0.00985468 * xx) * (x * xx);
+    x / (1.0 + x * x).sqrt()
+}
+
+/// Approximation of erf, scaled for a slope of 1 at the origin, ie it computes
+/// erf(x * sqrt(pi) / 2). See benches/sigmoid.rs.
+pub fn erf7(x: f32) -> f32 {
+    let xx = x * x;
+    let x = x + (0.24295 + (0.03395 + 0.0104 * xx) * xx) * (x * xx);
+    x / (1.0 + x * x).sqrt()
+}
+
+pub struct Shaper {
+    last_drive: f32,
+    last_makeup: f32,
+}
+
+impl Shaper {
+    pub fn new() -> Shaper {
+        Shaper {
+            last_drive: 1.0,
+            last_makeup: 1.0,
+        }
+    }
+}
+
+impl Default for Shaper {
+    fn default() -> Shaper {
+        Shaper::new()
+    }
+}
+
+fn shape<F: Fn(f32) -> f32>(f: F, buf: &[f32], out: &mut [f32], mut drive: f32, ddrive: f32,
+    mut makeup: f32, dmakeup: f32)
+{
+    for (x, y) in buf.iter().zip(out.iter_mut()) {
+        drive += ddrive;
+        makeup += dmakeup;
+        *y = makeup * f(drive * x);
+    }
+}
+
+impl Module for Shaper {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let drive = control_in[0].exp2();
+        let makeup = control_in[1].exp2();
+        let out = buf_out[0].get_mut();
+        let scale = 1.0 / out.len() as f32;
+        let ddrive = (drive - self.last_drive) * scale;
+        let dmakeup = (makeup - self.last_makeup) * scale;
+        let (d0, m0) = (self.last_drive, self.last_makeup);
+        self.last_drive = drive;
+        self.last_makeup = makeup;
+        let buf = buf_in[0].get();
+        // Select the curve outside the inner loop.
+        match control_in[2].round() as i32 {
+            0 => shape(|x: f32| x.clamp(-1.0, 1.0), buf, out, d0, ddrive, m0, dmakeup),
+            2 => shape(erf7, buf, out, d0, ddrive, m0, dmakeup),
+            _ => shape(tanh5, buf, out, d0, ddrive, m0, dmakeup),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module};
+    use super::{tanh5, Shaper};
+
+    #[test]
+    fn tanh5_accuracy() {
+        let max_err = (-5000..=5000)
+            .map(|i| {
+                let x = i as f32 * 1e-3;
+                (tanh5(x) - x.tanh()).abs()
+            })
+            .fold(0.0, f32::max);
+        assert!(max_err < 2e-4, "max error {}", max_err);
+    }
+
+    #[test]
+    fn output_bounded() {
+        let mut inb = Buffer::default();
+        for (i, x) in inb.get_mut().iter_mut().enumerate() {
+            *x = i as f32 - 16.0;
+        }
+        for curve in 0..3 {
+            let mut shaper = Shaper::new();
+            let mut out = [Buffer::default()];
+            // two chunks so the drive smoothing has settled
+            for _ in 0..2 {
+                shaper.process(&[3.0, 0.0, curve as f32], &mut [], &[&inb], &mut out);
+            }
+            for &y in out[0].get() {
+                assert!((-1.0..=1.0).contains(&y), "curve {} produced {}", curve, y);
+            }
+        }
+    }
+}
@@ -19,11 +19,12 @@ use std::ops::Deref;
 
 use module::{Module, Buffer};
 
-const LG_N_SAMPLES: usize = 10;
-const N_SAMPLES: usize = (1 << LG_N_SAMPLES);
+pub const LG_N_SAMPLES: usize = 10;
+pub const N_SAMPLES: usize = (1 << LG_N_SAMPLES);
 
 lazy_static! {
-    static ref SINTAB: [f32; N_SAMPLES + 1] = {
+    /// One cycle of a sine wave, with a guard sample for interpolation.
+    pub static ref SINTAB: [f32; N_SAMPLES + 1] = {
         let mut t = [0.0; N_SAMPLES + 1];
         let dth = 2.0 * consts::PI / (N_SAMPLES as f32);
         for i in 0..N_SAMPLES/2 {
@@ -56,6 +57,8 @@ impl Sin {
 impl Module for Sin {
     fn n_bufs_out(&self) -> usize { 1 }
 
+    fn type_tag(&self) -> Option<&'static str> { Some("sin") }
+
     // Example of migration, although replacing one Sin module with another
     // isn't going to have much use unless the sample rate is changing. But
     // if so, at least the phase will be continuous now.
@@ -65,6 +68,10 @@ impl Module for Sin {
         }
     }
 
+    fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+
     fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
         _buf_in: &[&Buffer], buf_out: &mut [Buffer])
     {
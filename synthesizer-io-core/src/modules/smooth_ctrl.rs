@@ -14,7 +14,7 @@
 
 //! A module that smooths parameters (optimized for midi controllers).
 
-use module::{Module, Buffer};
+use module::{Module, Buffer, ParamDesc};
 
 pub struct SmoothCtrl {
     rate: f32,  // smoothed rate (units of updates per ms)
@@ -24,6 +24,7 @@ pub struct SmoothCtrl {
     inp: f32,  // raw, unsmoothed value
     mid: f32,  // result of 1 pole of lowpass filtering
     out: f32,  // result of 2 poles of lowpass filtering
+    desc: Option<ParamDesc>,  // description of the value, if known
 }
 
 impl SmoothCtrl {
@@ -36,6 +37,15 @@ impl SmoothCtrl {
             inp: value,
             mid: value,
             out: value,
+            desc: None,
+        }
+    }
+
+    /// Create a control described by `desc`, starting at its default.
+    pub fn with_desc(desc: ParamDesc) -> SmoothCtrl {
+        SmoothCtrl {
+            desc: Some(desc),
+            ..SmoothCtrl::new(desc.default)
         }
     }
 }
@@ -43,6 +53,14 @@ impl SmoothCtrl {
 impl Module for SmoothCtrl {
     fn n_ctrl_out(&self) -> usize { 1 }
 
+    fn type_tag(&self) -> Option<&'static str> { Some("smooth_ctrl") }
+
+    fn param_values(&self) -> Vec<f32> { vec![self.inp] }
+
+    fn params(&self) -> &[ParamDesc] {
+        self.desc.as_slice()
+    }
+
     // maybe empty impl belongs in Module?
     fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
         _buf_in: &[&Buffer], _buf_out: &mut [Buffer])
@@ -13,31 +13,48 @@
 // limitations under the License.
 
 //! A simple module that just sums the inputs.
+//!
+//! For multi-channel audio, inputs are wired as consecutive groups of
+//! `channels` buffers, and same-index buffers are summed across groups; for
+//! stereo, `[(a, 0), (a, 1), (b, 0), (b, 1)]` sums into left and right.
 
 use module::{Module, Buffer};
 
-pub struct Sum;
+pub struct Sum {
+    channels: usize,
+}
 
 impl Sum {
     pub fn new() -> Sum {
-        Sum
+        Sum::new_channels(1)
+    }
+
+    /// Create a sum producing `channels` output buffers.
+    pub fn new_channels(channels: usize) -> Sum {
+        Sum { channels }
     }
 }
 
 impl Module for Sum {
-    fn n_bufs_out(&self) -> usize { 1 }
+    fn n_bufs_out(&self) -> usize { self.channels }
+
+    fn type_tag(&self) -> Option<&'static str> { Some("sum") }
+
+    fn param_values(&self) -> Vec<f32> { vec![self.channels as f32] }
 
     fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
         buf_in: &[&Buffer], buf_out: &mut [Buffer])
     {
-        let out = buf_out[0].get_mut();
-        for i in 0..out.len() {
-            out[i] = 0.0;
-        }
-        for buf in buf_in {
-            let buf = buf.get();
+        for (c, out) in buf_out.iter_mut().enumerate() {
+            let out = out.get_mut();
             for i in 0..out.len() {
-                out[i] += buf[i];
+                out[i] = 0.0;
+            }
+            for buf in buf_in.iter().skip(c).step_by(self.channels) {
+                let buf = buf.get();
+                for i in 0..out.len() {
+                    out[i] += buf[i];
+                }
             }
         }
     }
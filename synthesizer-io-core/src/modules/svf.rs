@@ -0,0 +1,123 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A state variable filter with lowpass, bandpass, highpass, and notch
+//! outputs, computed simultaneously from one shared state.
+
+use std::f32::consts;
+
+use module::{Module, Buffer};
+use super::biquad::{raise_output, raise_state, svf};
+
+pub struct Svf {
+    sr_offset: f32,
+    state: [f32; 2],
+}
+
+impl Svf {
+    pub fn new(sample_rate: f32) -> Svf {
+        Svf {
+            sr_offset: consts::PI.log2() - sample_rate.log2(),
+            state: [0.0; 2],
+        }
+    }
+}
+
+fn dot(row: &[f32; 4], x0: f32, x1: f32, state0: f32, state1: f32) -> f32 {
+    row[0] * x0 + row[1] * x1 + row[2] * state0 + row[3] * state1
+}
+
+impl Module for Svf {
+    fn n_bufs_out(&self) -> usize { 4 }
+
+    fn reset(&mut self) {
+        self.state = [0.0; 2];
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let log_f = control_in[0];
+        let res = control_in[1];
+        let params = svf(log_f + self.sr_offset, res);
+        let state_m = raise_state(params.a, params.b);
+        let mut out_m = [[[0.0; 4]; 2]; 4];
+        for (m, &(c, d)) in out_m.iter_mut().zip(params.taps.iter()) {
+            *m = raise_output(params.a, params.b, c, d);
+        }
+        let inb = buf_in[0].get();
+        let mut state0 = self.state[0];
+        let mut state1 = self.state[1];
+        let mut i = 0;
+        while i < inb.len() {
+            let x0 = inb[i];
+            let x1 = inb[i + 1];
+            for (buf, m) in buf_out.iter_mut().zip(out_m.iter()) {
+                let out = buf.get_mut();
+                out[i] = dot(&m[0], x0, x1, state0, state1);
+                out[i + 1] = dot(&m[1], x0, x1, state0, state1);
+            }
+            let s0 = dot(&state_m[0], x0, x1, state0, state1);
+            state1 = dot(&state_m[1], x0, x1, state0, state1);
+            state0 = s0;
+            i += 2;
+        }
+        self.state[0] = state0;
+        self.state[1] = state1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use test_util::magnitude;
+    use super::Svf;
+
+    #[test]
+    fn lp_hp_crossover() {
+        let sample_rate = 44_100.0;
+        let cutoff = 1000.0f32;
+        let n = 16384;
+        let mut svf = Svf::new(sample_rate);
+        let mut rng = 1u32;
+        let mut inb = Buffer::default();
+        let mut out = [Buffer::default(), Buffer::default(), Buffer::default(),
+            Buffer::default()];
+        let mut lp = Vec::new();
+        let mut hp = Vec::new();
+        for _ in 0..n / N_SAMPLES_PER_CHUNK {
+            for x in inb.get_mut().iter_mut() {
+                rng = rng.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                *x = rng as f32 * (2.0 / 4_294_967_296.0) - 1.0;
+            }
+            svf.process(&[cutoff.log2(), 0.0], &mut [], &[&inb], &mut out);
+            lp.extend_from_slice(out[0].get());
+            hp.extend_from_slice(out[2].get());
+        }
+        // Hann window to limit leakage between bins
+        for (i, (l, h)) in lp.iter_mut().zip(hp.iter_mut()).enumerate() {
+            let w = 0.5 - 0.5 * (2.0 * PI * i as f32 / n as f32).cos();
+            *l *= w;
+            *h *= w;
+        }
+        let bin = sample_rate / n as f32;
+        let crossover = (0..)
+            .map(|i| 500.0 + i as f32 * bin)
+            .find(|&f| magnitude(&hp, f, sample_rate) >= magnitude(&lp, f, sample_rate))
+            .unwrap();
+        assert!((crossover / cutoff - 1.0).abs() < 0.02, "crossover at {}", crossover);
+    }
+}
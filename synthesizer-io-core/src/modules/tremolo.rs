@@ -0,0 +1,120 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A stereo tremolo, modulating the amplitude of a left and right input with
+//! an internal LFO.
+//!
+//! Control inputs are rate (log2 Hz), depth (0 to 1, the fraction by which
+//! the gain dips at the trough), waveform (as for `Lfo`), and stereo phase
+//! (in cycles, the LFO offset of the right channel from the left). A stereo
+//! phase of 0.5 gives a ping-pong tremolo, the gains of the two channels
+//! summing to one. A single input is treated as mono, feeding both channels.
+
+use module::{Module, Buffer};
+use super::lfo::Waveform;
+
+pub struct Tremolo {
+    sr_offset: f32,
+    phase: f32,  // in cycles, always in [0, 1)
+    last_depth: Option<f32>,
+}
+
+impl Tremolo {
+    pub fn new(sample_rate: f32) -> Tremolo {
+        Tremolo {
+            sr_offset: -sample_rate.log2(),
+            phase: 0.0,
+            last_depth: None,
+        }
+    }
+}
+
+impl Module for Tremolo {
+    fn n_bufs_out(&self) -> usize { 2 }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+        self.last_depth = None;
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        // phase increment per sample, in cycles
+        let dphase = (control_in[0] + self.sr_offset).exp2();
+        let depth = control_in[1].clamp(0.0, 1.0);
+        let waveform = Waveform::from_ctrl(control_in[2]);
+        let offset = control_in[3].rem_euclid(1.0);
+        let (l_in, r_in) = (buf_in[0].get(), buf_in.get(1).unwrap_or(&buf_in[0]).get());
+        let (l, r) = buf_out.split_at_mut(1);
+        let (l, r) = (l[0].get_mut(), r[0].get_mut());
+        let mut d = self.last_depth.unwrap_or(depth);
+        let dd = (depth - d) * (1.0 / l.len() as f32);
+        self.last_depth = Some(depth);
+        // the gain swings between 1 - d and 1
+        let gain = |d: f32, phase: f32| 1.0 - 0.5 * d * (1.0 - waveform.eval(phase));
+        let mut phase = self.phase;
+        for i in 0..l.len() {
+            d += dd;
+            l[i] = l_in[i] * gain(d, phase);
+            r[i] = r_in[i] * gain(d, (phase + offset).fract());
+            phase = (phase + dphase).fract();
+        }
+        self.phase = phase;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use test_util::magnitude;
+    use super::Tremolo;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    fn run(ctrl: &[f32], n_chunks: usize) -> (Vec<f32>, Vec<f32>) {
+        let mut inb = Buffer::default();
+        for x in inb.get_mut().iter_mut() {
+            *x = 1.0;
+        }
+        let mut tremolo = Tremolo::new(SAMPLE_RATE);
+        let mut out = [Buffer::default(), Buffer::default()];
+        let (mut l, mut r) = (Vec::new(), Vec::new());
+        for _ in 0..n_chunks {
+            tremolo.process(ctrl, &mut [], &[&inb, &inb], &mut out);
+            l.extend_from_slice(out[0].get());
+            r.extend_from_slice(out[1].get());
+        }
+        (l, r)
+    }
+
+    #[test]
+    fn modulates_at_rate_with_stereo_offset() {
+        // a whole number of cycles at 11.025 Hz
+        let freq = SAMPLE_RATE / 4000.0;
+        let n_chunks = 4 * 4000 / N_SAMPLES_PER_CHUNK;
+        let (l, r) = run(&[freq.log2(), 1.0, 0.0, 0.5], n_chunks);
+        // the gain is (1 + sin) / 2, so the swing is half an amplitude
+        let swing = magnitude(&l, freq, SAMPLE_RATE);
+        assert!((swing - 0.5).abs() < 0.01, "swing {}", swing);
+        assert!(magnitude(&l, 2.0 * freq, SAMPLE_RATE) < 0.01);
+        // out of phase, the two gains sum to one
+        assert!(l.iter().zip(&r).all(|(a, b)| (a + b - 1.0).abs() < 1e-4));
+        assert!(l.iter().any(|&a| a < 0.01) && l.iter().any(|&a| a > 0.99));
+
+        let (l, r) = run(&[freq.log2(), 0.5, 0.0, 0.0], n_chunks);
+        assert_eq!(l, r);
+        assert!(l.iter().all(|&a| (0.5 - 1e-4..=1.0 + 1e-4).contains(&a)));
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A through-zero flanger.
+//!
+//! The dry path is delayed by the depth, and the wet path is delayed by
+//! anywhere from zero to twice the depth, so the relative delay between them
+//! sweeps through zero, as with two tape machines.
+//!
+//! Control inputs are sweep position (-1 to 1, typically from an `Lfo`),
+//! depth (seconds), and mix (-1 to 1, negative inverts the wet path). The
+//! output is half the sum of dry and mixed wet paths, so an inverted mix
+//! cancels completely where the sweep crosses zero.
+
+use module::{Module, Buffer};
+
+pub struct TzFlanger {
+    sample_rate: f32,
+    buf: Vec<f32>,
+    // index of the most recently written sample
+    ix: usize,
+    // sweep position at the end of the last chunk, for smoothing
+    last_sweep: Option<f32>,
+}
+
+impl TzFlanger {
+    /// Create a new flanger. The buffer is allocated here, so `max_depth_secs`
+    /// bounds the depth for the lifetime of the module.
+    pub fn new(sample_rate: f32, max_depth_secs: f32) -> TzFlanger {
+        let len = (2.0 * max_depth_secs * sample_rate).ceil() as usize + 2;
+        TzFlanger {
+            sample_rate,
+            buf: vec![0.0; len],
+            ix: 0,
+            last_sweep: None,
+        }
+    }
+
+    // Read the sample `delay` samples in the past, with linear interpolation.
+    // A delay of 0 is the most recently written sample.
+    fn read(&self, delay: f32) -> f32 {
+        let len = self.buf.len();
+        let d_int = delay as usize;
+        let frac = delay - d_int as f32;
+        let i0 = (self.ix + len - d_int) % len;
+        let i1 = (i0 + len - 1) % len;
+        let y0 = self.buf[i0];
+        let y1 = self.buf[i1];
+        y0 + (y1 - y0) * frac
+    }
+}
+
+impl Module for TzFlanger {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        for x in self.buf.iter_mut() {
+            *x = 0.0;
+        }
+        self.ix = 0;
+        self.last_sweep = None;
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let max_depth = 0.5 * (self.buf.len() - 2) as f32;
+        let depth = (control_in[1] * self.sample_rate).clamp(0.0, max_depth);
+        let sweep = control_in[0].clamp(-1.0, 1.0);
+        let mix = control_in[2];
+        let inb = buf_in[0].get();
+        let out = buf_out[0].get_mut();
+        let mut s = self.last_sweep.unwrap_or(sweep);
+        let ds = (sweep - s) * (1.0 / out.len() as f32);
+        self.last_sweep = Some(sweep);
+        let len = self.buf.len();
+        for (x, y) in inb.iter().zip(out.iter_mut()) {
+            s += ds;
+            self.ix = (self.ix + 1) % len;
+            self.buf[self.ix] = *x;
+            let dry = self.read(depth);
+            let wet = self.read(depth * (1.0 + s));
+            *y = 0.5 * (dry + mix * wet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use test_util::sine_buf;
+    use super::TzFlanger;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    fn run(ctrl: &[f32], input: &dyn Fn(usize) -> Buffer, n_chunks: usize) -> Vec<f32> {
+        let mut flanger = TzFlanger::new(SAMPLE_RATE, 0.01);
+        let mut out = [Buffer::default()];
+        let mut result = Vec::new();
+        for i in 0..n_chunks {
+            let inb = input(i * N_SAMPLES_PER_CHUNK);
+            flanger.process(ctrl, &mut [], &[&inb], &mut out);
+            result.extend_from_slice(out[0].get());
+        }
+        result
+    }
+
+    #[test]
+    fn wet_path_leads_below_zero() {
+        // depth of 100 samples, sweep -0.5: wet delayed 50, dry 100
+        let impulse = |start| {
+            let mut buf = Buffer::default();
+            if start == 0 {
+                buf.get_mut()[0] = 1.0;
+            }
+            buf
+        };
+        let out = run(&[-0.5, 100.0 / SAMPLE_RATE, 1.0], &impulse, 8);
+        assert!((out[50] - 0.5).abs() < 1e-3);
+        assert!((out[100] - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn inverted_mix_nulls_at_zero() {
+        let sine = |start| sine_buf(1000.0, SAMPLE_RATE, start);
+        let peak = |sweep: f32| {
+            let out = run(&[sweep, 0.005, -1.0], &sine, 64);
+            out[1024..].iter().fold(0.0f32, |a, &y| a.max(y.abs()))
+        };
+        // Complete cancellation at zero relative delay, which a positive-only
+        // flanger can't reach.
+        assert!(peak(0.0) < 1e-4);
+        assert!(peak(0.01) > 0.1);
+        assert!((peak(0.01) - peak(-0.01)).abs() < 0.01);
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A wavetable oscillator playing user-supplied tables, each holding one
+//! cycle of a waveform.
+//!
+//! Control inputs are pitch (log2 Hz) and morph, which interpolates across
+//! tables (0 is the first table, 1 the second, and so on).
+//!
+//! Tables are band-limited at construction, with one version per octave, in
+//! the style of `SAWTAB`, so that high pitches don't alias.
+
+use std::cmp::min;
+use std::f64::consts::PI;
+
+use module::{Module, Buffer};
+
+const LG_N_SAMPLES: usize = 10;
+const N_SAMPLES: usize = 1 << LG_N_SAMPLES;
+
+pub struct Wavetable {
+    sr_offset: f32,
+    phase: f32,
+    // indexed by table, then band-limit level; each has a guard sample
+    tables: Vec<Vec<Vec<f32>>>,
+}
+
+impl Wavetable {
+    /// Create an oscillator from the given tables, which may be of any
+    /// length, and are band-limited here. Allocates and does a fair amount
+    /// of computation, so don't call this on the audio thread.
+    pub fn new(sample_rate: f32, tables: Vec<Vec<f32>>) -> Wavetable {
+        let tables = tables.iter().map(|t| band_limit(t)).collect();
+        Wavetable::from_levels(sample_rate, tables)
+    }
+
+    /// Create an oscillator from the given tables without band-limiting.
+    /// This is cheaper, and preserves the table exactly, but will alias.
+    pub fn new_unfiltered(sample_rate: f32, tables: Vec<Vec<f32>>) -> Wavetable {
+        let tables = tables.iter().map(|t| vec![resample(t)]).collect();
+        Wavetable::from_levels(sample_rate, tables)
+    }
+
+    fn from_levels(sample_rate: f32, tables: Vec<Vec<Vec<f32>>>) -> Wavetable {
+        assert!(!tables.is_empty(), "need at least one table");
+        Wavetable {
+            sr_offset: LG_N_SAMPLES as f32 - sample_rate.log2(),
+            phase: 0.0,
+            tables,
+        }
+    }
+}
+
+// Resample to N_SAMPLES by linear interpolation, adding a guard sample.
+fn resample(table: &[f32]) -> Vec<f32> {
+    let len = table.len();
+    let mut result = Vec::with_capacity(N_SAMPLES + 1);
+    for i in 0..N_SAMPLES {
+        let x = i as f32 * len as f32 / N_SAMPLES as f32;
+        let ix = x as usize;
+        let y0 = table[ix];
+        let y1 = table[(ix + 1) % len];
+        result.push(y0 + (y1 - y0) * (x - ix as f32));
+    }
+    result.push(result[0]);
+    result
+}
+
+// Compute versions of the table for each octave, level j having harmonics up
+// to (N_SAMPLES / 2) >> j.
+fn band_limit(table: &[f32]) -> Vec<Vec<f32>> {
+    let len = table.len();
+    let n_harmonics = min(len / 2, N_SAMPLES / 2);
+    let mut coeffs = Vec::with_capacity(n_harmonics + 1);
+    for k in 0..n_harmonics + 1 {
+        let (mut re, mut im) = (0.0f64, 0.0f64);
+        for (i, &x) in table.iter().enumerate() {
+            let th = 2.0 * PI * (k * i) as f64 / len as f64;
+            re += x as f64 * th.cos();
+            im += x as f64 * th.sin();
+        }
+        let scale = if k == 0 { 1.0 } else { 2.0 } / len as f64;
+        coeffs.push((re * scale, im * scale));
+    }
+    (0..LG_N_SAMPLES).map(|j| {
+        let max_k = min(n_harmonics, (N_SAMPLES / 2) >> j);
+        let mut level: Vec<f32> = (0..N_SAMPLES).map(|i| {
+            let mut y = coeffs[0].0;
+            for (k, &(re, im)) in coeffs.iter().enumerate().take(max_k + 1).skip(1) {
+                let th = 2.0 * PI * (k * i) as f64 / N_SAMPLES as f64;
+                y += re * th.cos() + im * th.sin();
+            }
+            y as f32
+        }).collect();
+        level.push(level[0]);
+        level
+    }).collect()
+}
+
+impl Module for Wavetable {
+    fn n_bufs_out(&self) -> usize { 1 }
+
+    fn reset(&mut self) {
+        self.phase = 0.0;
+    }
+
+    fn process(&mut self, control_in: &[f32], _control_out: &mut [f32],
+        _buf_in: &[&Buffer], buf_out: &mut [Buffer])
+    {
+        let logf = control_in[0] + self.sr_offset;
+        let freq = logf.exp2();
+        let morph = control_in[1].clamp(0.0, (self.tables.len() - 1) as f32);
+        let t0 = morph as usize;
+        let t1 = min(t0 + 1, self.tables.len() - 1);
+        let mfrac = morph - t0 as f32;
+        // Choose the level with no harmonics above Nyquist.
+        let n_levels = self.tables[t0].len();
+        let level = min(logf.ceil().max(0.0) as usize, n_levels - 1);
+        let tab0 = &self.tables[t0][level];
+        let tab1 = &self.tables[t1][level];
+        let out = buf_out[0].get_mut();
+        let mut phase = self.phase;
+        for y in out.iter_mut() {
+            let phaseint = phase as i32;
+            let tab_ix = phaseint as usize % N_SAMPLES;
+            let phasefrac = phase - phaseint as f32;
+            let y0 = tab0[tab_ix] + (tab0[tab_ix + 1] - tab0[tab_ix]) * phasefrac;
+            let y1 = tab1[tab_ix] + (tab1[tab_ix + 1] - tab1[tab_ix]) * phasefrac;
+            *y = y0 + (y1 - y0) * mfrac;
+            phase += freq;
+        }
+        let phaseint = phase as i32;
+        self.phase = phase - (phaseint & -(N_SAMPLES as i32)) as f32;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use module::{Buffer, Module};
+    use modules::Sin;
+    use super::Wavetable;
+
+    #[test]
+    fn sine_table_matches_sin() {
+        let sample_rate = 44_100.0;
+        let table: Vec<f32> = (0..2048).map(|i| (i as f32 * (2.0 * PI / 2048.0)).sin())
+            .collect();
+        let mut wt = Wavetable::new(sample_rate, vec![table]);
+        let mut sin = Sin::new(sample_rate);
+        let ctrl = [440f32.log2(), 0.0];
+        let mut out_wt = [Buffer::default()];
+        let mut out_sin = [Buffer::default()];
+        for _ in 0..100 {
+            wt.process(&ctrl, &mut [], &[], &mut out_wt);
+            sin.process(&ctrl, &mut [], &[], &mut out_sin);
+            for (a, b) in out_wt[0].get().iter().zip(out_sin[0].get().iter()) {
+                assert!((a - b).abs() < 1e-3, "{} vs {}", a, b);
+            }
+        }
+    }
+}
@@ -14,10 +14,11 @@
 
 //! A lock-free queue suitable for real-time audio threads.
 
-use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 use std::sync::atomic::Ordering::{Relaxed, Release};
 use std::sync::Arc;
 use std::thread;
+use std::mem;
 use std::ptr;
 use std::ptr::NonNull;
 use std::ops::{Deref, DerefMut};
@@ -48,12 +49,11 @@ impl<T> Node<T> {
 /// special property that it can be sent back over a channel with zero
 /// allocation.
 ///
-/// Note: in the current implementation, dropping an `Item` just leaks the
-/// storage.
+/// Dropping an `Item` frees its storage, like `Box`, so on a real-time thread
+/// it should be sent back rather than dropped.
 pub struct Item<T> {
     ptr: NonNull<Node<T>>,
 }
-// TODO: it would be great to disable drop
 
 unsafe impl<T: Send> Send for Item<T> {}
 
@@ -70,6 +70,19 @@ impl<T> Item<T> {
             Item { ptr: NonNull::new_unchecked(ptr) }
         }
     }
+
+    // Give up ownership of the storage, to be linked into a queue.
+    fn into_raw(self) -> NonNull<Node<T>> {
+        let ptr = self.ptr;
+        mem::forget(self);
+        ptr
+    }
+}
+
+impl<T> Drop for Item<T> {
+    fn drop(&mut self) {
+        unsafe { drop(Box::from_raw(self.ptr.as_ptr())); }
+    }
 }
 
 impl<T> Deref for Item<T> {
@@ -105,9 +118,16 @@ impl<T> DerefMut for Item<T> {
 /// might have better performance due to not needing to reverse. See
 /// [this thread](https://groups.google.com/forum/#!topic/lock-free/i0eE2-A7eIA) for discussion
 /// of performance and an argument why this design is in fact multi-producer safe.
+///
+/// A queue can also be created with a capacity. Then the `try_send` methods
+/// fail rather than grow it past that many values, so a producer that gets
+/// ahead of the consumer can drop or coalesce its messages instead.
 
 pub struct Queue<T> {
     head: AtomicPtr<Node<T>>,
+    // number of values in the queue
+    len: AtomicUsize,
+    capacity: usize,
 }
 
 // implement Send (so queue can be transferred into worker thread)
@@ -151,6 +171,28 @@ impl<T: Send + 'static> Sender<T> {
     pub fn send_item(&self, item: Item<T>) {
         self.queue.send_item(item);
     }
+
+    /// Enqueue a value, unless the queue is full, in which case it's handed
+    /// back. Note: this method allocates.
+    pub fn try_send(&self, payload: T) -> Result<(), T> {
+        if self.queue.reserve() {
+            self.queue.push_raw(Item::make_item(payload).into_raw());
+            Ok(())
+        } else {
+            Err(payload)
+        }
+    }
+
+    /// Enqueue a value held in an `Item`, unless the queue is full, in which
+    /// case it's handed back. This method does not allocate.
+    pub fn try_send_item(&self, item: Item<T>) -> Result<(), Item<T>> {
+        if self.queue.reserve() {
+            self.queue.push_raw(item.into_raw());
+            Ok(())
+        } else {
+            Err(item)
+        }
+    }
 }
 
 impl<T: Send + 'static> Receiver<T> {
@@ -167,13 +209,33 @@ impl<T: Send + 'static> Receiver<T> {
     pub fn recv_items(&self) -> QueueItemIter<T> {
         self.queue.recv_items()
     }
+
+    /// The number of values waiting in the queue, without dequeuing them.
+    /// With producers running concurrently this is only a snapshot, and may
+    /// count a value a moment before it can actually be received.
+    pub fn approx_len(&self) -> usize {
+        self.queue.len.load(Relaxed)
+    }
+
+    /// Whether there is nothing to receive right now.
+    pub fn is_empty(&self) -> bool {
+        self.queue.head.load(Relaxed).is_null()
+    }
 }
 
 impl<T: Send + 'static> Queue<T> {
     /// Create a new queue, and return endpoints for sending and receiving.
     pub fn new() -> (Sender<T>, Receiver<T>) {
+        Self::with_capacity(usize::MAX)
+    }
+
+    /// Create a new queue holding at most `capacity` values sent by the
+    /// `try_send` methods. The plain `send` methods ignore the capacity.
+    pub fn with_capacity(capacity: usize) -> (Sender<T>, Receiver<T>) {
         let queue = Arc::new(Queue {
             head: AtomicPtr::new(ptr::null_mut()),
+            len: AtomicUsize::new(0),
+            capacity,
         });
         (Sender {
             queue: queue.clone(),
@@ -190,15 +252,43 @@ impl<T: Send + 'static> Queue<T> {
     }
 
     fn recv(&self) -> QueueMoveIter<T> {
-        unsafe { QueueMoveIter(Node::reverse(self.pop_all())) }
+        QueueMoveIter(self.take_all())
     }
 
     fn send_item(&self, item: Item<T>) {
-        self.push_raw(item.ptr);
+        self.len.fetch_add(1, Relaxed);
+        self.push_raw(item.into_raw());
     }
 
     fn recv_items(&self) -> QueueItemIter<T> {
-        unsafe { QueueItemIter(Node::reverse(self.pop_all())) }
+        QueueItemIter(self.take_all())
+    }
+
+    // Count a value about to be pushed, if there's room for it.
+    fn reserve(&self) -> bool {
+        let mut len = self.len.load(Relaxed);
+        loop {
+            if len >= self.capacity {
+                return false;
+            }
+            match self.len.compare_exchange_weak(len, len + 1, Relaxed, Relaxed) {
+                Ok(_) => return true,
+                Err(old) => len = old,
+            }
+        }
+    }
+
+    // Dequeue everything in sending order, releasing its capacity.
+    fn take_all(&self) -> Option<NonNull<Node<T>>> {
+        let list = unsafe { Node::reverse(self.pop_all()) };
+        let mut n = 0;
+        let mut p = list;
+        while let Some(node) = p {
+            p = unsafe { node.as_ref().child };
+            n += 1;
+        }
+        self.len.fetch_sub(n, Relaxed);
+        list
     }
 
     fn push_raw(&self, mut n: NonNull<Node<T>>) {
@@ -233,6 +323,12 @@ impl<T: Send + 'static> Iterator for QueueItemIter<T> {
     }
 }
 
+impl<T: Send + 'static> Drop for QueueItemIter<T> {
+    fn drop(&mut self) {
+        self.all(|_| true);
+    }
+}
+
 /// An iterator yielding the values dequeued by a `recv` call.
 pub struct QueueMoveIter<T: Send + 'static>(Option<NonNull<Node<T>>>);
 
@@ -311,3 +407,169 @@ pub fn try_queue() {
     let _ = child.join();
     //println!("done");
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use super::{Item, Queue};
+
+    const N_PRODUCERS: usize = 4;
+    const N_PER_PRODUCER: usize = 10_000;
+
+    #[test]
+    fn multi_producer_exactly_once() {
+        let (tx, rx) = Queue::new();
+        let producers: Vec<_> = (0..N_PRODUCERS).map(|p| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for i in 0..N_PER_PRODUCER {
+                    tx.send((p, i));
+                }
+            })
+        }).collect();
+        let mut next = [0; N_PRODUCERS];
+        let mut n_recv = 0;
+        while n_recv < N_PRODUCERS * N_PER_PRODUCER {
+            for (p, i) in rx.recv() {
+                // per-producer FIFO implies no loss or duplication
+                assert_eq!(i, next[p]);
+                next[p] += 1;
+                n_recv += 1;
+            }
+            thread::yield_now();
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(rx.recv().count(), 0);
+        assert!(next.iter().all(|&n| n == N_PER_PRODUCER));
+    }
+
+    #[test]
+    fn item_round_trip_under_contention() {
+        let (tx, relay_rx) = Queue::new();
+        let (relay_tx, rx) = Queue::new();
+        let total = N_PRODUCERS * N_PER_PRODUCER;
+        // Allocate all items up front, remembering where they live.
+        let mut addrs = HashSet::new();
+        let batches: Vec<Vec<Item<usize>>> = (0..N_PRODUCERS).map(|p| {
+            (0..N_PER_PRODUCER).map(|i| {
+                let item = Item::make_item(p * N_PER_PRODUCER + i);
+                addrs.insert(&*item as *const usize as usize);
+                item
+            }).collect()
+        }).collect();
+        let relay = thread::spawn(move || {
+            let mut n = 0;
+            while n < total {
+                for item in relay_rx.recv_items() {
+                    relay_tx.send_item(item);
+                    n += 1;
+                }
+                thread::yield_now();
+            }
+        });
+        let producers: Vec<_> = batches.into_iter().map(|batch| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for item in batch {
+                    tx.send_item(item);
+                }
+            })
+        }).collect();
+        let mut seen = vec![false; total];
+        let mut n_recv = 0;
+        while n_recv < total {
+            for item in rx.recv_items() {
+                assert!(!seen[*item], "duplicate {}", *item);
+                seen[*item] = true;
+                // the same storage came back, so nothing was reallocated
+                assert!(addrs.remove(&(&*item as *const usize as usize)));
+                n_recv += 1;
+            }
+            thread::yield_now();
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        relay.join().unwrap();
+        assert!(addrs.is_empty());
+    }
+
+    // Counts how many times it's been dropped.
+    struct Counted(Arc<AtomicUsize>);
+
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn items_freed_exactly_once() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        drop(Item::make_item(Counted(drops.clone())));
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+
+        let (tx, rx) = Queue::new();
+        tx.send_item(Item::make_item(Counted(drops.clone())));
+        tx.send(Counted(drops.clone()));
+        assert_eq!(drops.load(Ordering::Relaxed), 1);
+        // received but not consumed by the iterator
+        drop(rx.recv_items());
+        assert_eq!(drops.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn approx_len_tracks_producers() {
+        let (tx, rx) = Queue::new();
+        assert!(rx.is_empty());
+        let total = N_PRODUCERS * N_PER_PRODUCER;
+        let producers: Vec<_> = (0..N_PRODUCERS).map(|_| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                for i in 0..N_PER_PRODUCER {
+                    tx.send(i);
+                }
+            })
+        }).collect();
+        let mut n_recv = 0;
+        while n_recv < total {
+            // never more than are still to come, however the peek interleaves
+            let len = rx.approx_len();
+            assert!(len <= total - n_recv, "len {} after {}", len, n_recv);
+            if len >= N_PER_PRODUCER || len == total - n_recv {
+                n_recv += rx.recv().count();
+            }
+            thread::yield_now();
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(rx.approx_len(), 0);
+        assert!(rx.is_empty());
+        tx.send(0);
+        tx.send(1);
+        assert_eq!(rx.approx_len(), 2);
+        assert!(!rx.is_empty());
+        assert_eq!(rx.recv().count(), 2);
+    }
+
+    #[test]
+    fn bounded_reports_overflow() {
+        let (tx, rx) = Queue::with_capacity(4);
+        for i in 0..4 {
+            assert_eq!(tx.try_send(i), Ok(()));
+        }
+        assert_eq!(tx.try_send(4), Err(4));
+        let item = tx.try_send_item(Item::make_item(5)).unwrap_err();
+        assert_eq!(*item, 5);
+        assert_eq!(rx.recv().collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+        assert_eq!(tx.try_send_item(item).map_err(|_| ()), Ok(()));
+        assert_eq!(rx.recv().collect::<Vec<_>>(), vec![5]);
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sample rate conversion, for running the graph at a rate other than the
+//! device's.
+//!
+//! This is a polyphase windowed-sinc FIR. The filter is tabulated at a fixed
+//! number of fractional phases, and coefficients are linearly interpolated
+//! between adjacent phases, so arbitrary (including time-varying) ratios work.
+
+use std::f64::consts::PI;
+
+use module::N_SAMPLES_PER_CHUNK;
+
+/// Half the number of taps in each phase of the filter.
+const HALF_TAPS: usize = 16;
+const N_TAPS: usize = 2 * HALF_TAPS;
+
+/// Number of tabulated fractional phases.
+const N_PHASES: usize = 256;
+
+pub struct Resampler {
+    // input samples consumed per output sample
+    step: f64,
+    // position of the next output sample, in input samples relative to buf[0]
+    pos: f64,
+    // input history, with enough look-ahead for the current position
+    buf: Vec<f32>,
+    // N_PHASES + 1 rows of N_TAPS coefficients
+    table: Vec<f32>,
+}
+
+impl Resampler {
+    /// Create a resampler converting from `in_rate` to `out_rate`.
+    ///
+    /// When downsampling, the cutoff is lowered to the output Nyquist frequency
+    /// so the conversion doesn't alias.
+    pub fn new(in_rate: f32, out_rate: f32) -> Resampler {
+        let step = in_rate as f64 / out_rate as f64;
+        // Leave some room for the transition band.
+        let fc = 0.92 * (1.0 / step).min(1.0);
+        let mut table = Vec::with_capacity((N_PHASES + 1) * N_TAPS);
+        for phase in 0..N_PHASES + 1 {
+            let frac = phase as f64 / N_PHASES as f64;
+            for j in 0..N_TAPS {
+                // distance from the output position to tap j
+                let u = frac + (HALF_TAPS - 1) as f64 - j as f64;
+                table.push(windowed_sinc(u, fc) as f32);
+            }
+        }
+        let mut buf = Vec::with_capacity(N_TAPS + 2 * N_SAMPLES_PER_CHUNK);
+        buf.resize(N_TAPS, 0.0);
+        Resampler {
+            step,
+            pos: (HALF_TAPS - 1) as f64,
+            buf,
+            table,
+        }
+    }
+
+    /// Produce one output sample. The `input` callback is called to fill a
+    /// chunk of `N_SAMPLES_PER_CHUNK` input samples whenever more are needed.
+    pub fn next_sample<F: FnMut(&mut [f32])>(&mut self, mut input: F) -> f32 {
+        let i0 = self.pos as usize;
+        while i0 + HALF_TAPS >= self.buf.len() {
+            let len = self.buf.len();
+            self.buf.resize(len + N_SAMPLES_PER_CHUNK, 0.0);
+            input(&mut self.buf[len..]);
+        }
+        let frac = (self.pos - i0 as f64) * N_PHASES as f64;
+        let phase = frac as usize;
+        let t = (frac - phase as f64) as f32;
+        let row0 = &self.table[phase * N_TAPS..(phase + 1) * N_TAPS];
+        let row1 = &self.table[(phase + 1) * N_TAPS..(phase + 2) * N_TAPS];
+        let x = &self.buf[i0 + 1 - HALF_TAPS..i0 + 1 + HALF_TAPS];
+        let mut y = 0.0;
+        for j in 0..N_TAPS {
+            y += x[j] * (row0[j] + t * (row1[j] - row0[j]));
+        }
+        self.pos += self.step;
+        // Discard history that's no longer needed, a chunk at a time.
+        let start = self.pos as usize + 1 - HALF_TAPS;
+        if start >= N_SAMPLES_PER_CHUNK {
+            self.buf.drain(..start);
+            self.pos -= start as f64;
+        }
+        y
+    }
+
+    /// Fill `out` with output samples; see `next_sample`.
+    pub fn process<F: FnMut(&mut [f32])>(&mut self, out: &mut [f32], mut input: F) {
+        for y in out {
+            *y = self.next_sample(&mut input);
+        }
+    }
+}
+
+// Lowpass with cutoff `fc` (as a fraction of Nyquist), Blackman window.
+fn windowed_sinc(u: f64, fc: f64) -> f64 {
+    let half = HALF_TAPS as f64;
+    if u.abs() >= half {
+        return 0.0;
+    }
+    let w = 0.42 + 0.5 * (PI * u / half).cos() + 0.08 * (2.0 * PI * u / half).cos();
+    let x = PI * fc * u;
+    let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+    fc * sinc * w
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+    use super::Resampler;
+
+    #[test]
+    fn sine_keeps_frequency() {
+        let (in_rate, out_rate) = (48_000.0, 44_100.0);
+        let freq = 1000.0;
+        let mut resampler = Resampler::new(in_rate, out_rate);
+        let mut n = 0;
+        let mut out = vec![0.0; out_rate as usize];
+        resampler.process(&mut out, |chunk| {
+            for x in chunk {
+                *x = (n as f32 * (2.0 * PI * freq / in_rate)).sin();
+                n += 1;
+            }
+        });
+        // skip the filter's startup transient
+        let out = &out[1000..];
+        let secs = out.len() as f32 / out_rate;
+        let crossings = out.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        assert!((crossings as f32 - freq * secs).abs() <= 1.0,
+            "got {} cycles in {} s", crossings, secs);
+        let peak = out.iter().fold(0.0f32, |a, &x| a.max(x.abs()));
+        assert!((peak - 1.0).abs() < 0.01, "peak {}", peak);
+    }
+}
@@ -0,0 +1,43 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers shared by the unit tests of the modules.
+
+use std::f32::consts::PI;
+
+use module::Buffer;
+
+/// A chunk-sized buffer of a sine wave, starting at sample `start`.
+pub fn sine_buf(freq: f32, sample_rate: f32, start: usize) -> Buffer {
+    let mut buf = Buffer::default();
+    for (i, x) in buf.get_mut().iter_mut().enumerate() {
+        *x = ((start + i) as f32 * (2.0 * PI * freq / sample_rate)).sin();
+    }
+    buf
+}
+
+/// Amplitude of the component of `samples` at `freq`, by the Goertzel
+/// algorithm. A full-scale sine measures as 1.0.
+pub fn magnitude(samples: &[f32], freq: f32, sample_rate: f32) -> f32 {
+    let w = 2.0 * PI * freq / sample_rate;
+    let coeff = 2.0 * w.cos();
+    let (mut s1, mut s2) = (0.0f32, 0.0f32);
+    for &x in samples {
+        let s = x + coeff * s1 - s2;
+        s2 = s1;
+        s1 = s;
+    }
+    let power = s1 * s1 + s2 * s2 - coeff * s1 * s2;
+    2.0 * power.max(0.0).sqrt() / samples.len() as f32
+}
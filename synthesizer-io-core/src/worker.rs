@@ -17,14 +17,29 @@
 use std::ops::Deref;
 
 use queue::{Queue, Sender, Receiver, Item};
-use module::Buffer;
+use module::{Buffer, N_SAMPLES_PER_CHUNK};
 use graph::{Graph, Node, Message};
 
+// maximum number of messages deferred for future chunks
+const MAX_PENDING: usize = 1024;
+
 pub struct Worker {
     to_worker: Receiver<Message>,
     from_worker: Sender<Message>,
     graph: Graph,
-    root: usize,
+    // the nodes rendered by each call to `work`; the first is the main output
+    roots: Vec<usize>,
+
+    // duration of a sample, for timestamping chunks in `work_var`
+    sample_ns: f64,
+    // samples of the last chunk not yet consumed by `work_var`, left and right
+    carry: [Buffer; 2],
+    // index of the first unconsumed sample in `carry`
+    carry_pos: usize,
+
+    // messages held back, with the time each is due, in the order they're to
+    // be handled; see `enqueue`
+    pending: Vec<(u64, Item<Message>)>,
 }
 
 impl Worker {
@@ -38,11 +53,49 @@ impl Worker {
             to_worker: to_worker,
             from_worker: from_worker,
             graph: graph,
-            root: 0,
+            roots: vec![0],
+            sample_ns: 1e9 / 44_100.0,
+            carry: [Buffer::default(), Buffer::default()],
+            carry_pos: N_SAMPLES_PER_CHUNK,
+            pending: Vec::with_capacity(MAX_PENDING),
         };
         (worker, tx, rx)
     }
 
+    /// Set the sample rate, used for computing timestamps of chunks in
+    /// `work_var`. The default is 44.1kHz.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_ns = 1e9 / sample_rate as f64;
+    }
+
+    /// Set the nodes rendered on each chunk, by default just node 0. The
+    /// first is the main output, returned by `work`; the others are extra
+    /// buses, for example for monitoring, retrieved with `work_multi`.
+    /// Allocates, so call before starting audio.
+    pub fn set_roots(&mut self, roots: &[usize]) {
+        assert!(!roots.is_empty(), "the worker needs at least one root");
+        self.roots.clear();
+        self.roots.extend_from_slice(roots);
+    }
+
+    /// Enable or disable catching panics in modules; see
+    /// `Graph::set_catch_panics`. Panicking modules are reported by sending
+    /// `Message::Panicked` on the return queue.
+    pub fn set_catch_panics(&mut self, catch_panics: bool) {
+        self.graph.set_catch_panics(catch_panics);
+    }
+
+    /// Set the number of threads used to run the graph; see
+    /// `Graph::set_threads`.
+    pub fn set_threads(&mut self, n_threads: usize) {
+        self.graph.set_threads(n_threads);
+    }
+
+    /// The number of nodes currently in the graph.
+    pub fn node_count(&self) -> usize {
+        self.graph.node_count()
+    }
+
     /// Process a message. In normal operation, messages are sent to the
     /// queue, but this function is available to initialize the graph into
     /// a good state before starting any work. Allocates.
@@ -70,7 +123,22 @@ impl Worker {
                 }
                 None
             }
-            _ => return, // NYI
+            Message::Reset(ix) => {
+                self.graph.get_module_mut(ix).reset();
+                None
+            }
+            Message::ResetAll => {
+                self.graph.reset_all();
+                None
+            }
+            Message::Remove(ix) => {
+                if let Some(old_item) = self.graph.remove(ix) {
+                    self.from_worker.send_item(old_item);
+                }
+                None
+            }
+            // NYI; returned so that it isn't freed on this thread
+            _ => None,
         };
         if let Some(ix) = ix {
             let old_item = self.graph.replace(ix, Some(item));
@@ -84,13 +152,321 @@ impl Worker {
 
     /// Process the incoming items, run the graph, and return the rendered audio
     /// buffers. Lock-free.
-    // TODO: leave incoming items in the queue if they have a timestamp in the
-    // future.
+    ///
+    /// The chunk starts at `timestamp`. Parameter changes and notes are applied
+    /// at the start of the chunk containing their timestamp, so they're held
+    /// back if they arrive early. Other messages, which change the graph, are
+    /// never handled ahead of any message sent before them. If too many are
+    /// held back, they're all applied at once.
     pub fn work(&mut self, timestamp: u64) -> &[Buffer] {
+        self.run(timestamp);
+        self.graph.get_out_bufs(self.roots[0])
+    }
+
+    /// Like `work`, but returns the buffers of every root set by `set_roots`,
+    /// in order. Lock-free.
+    pub fn work_multi(&mut self, timestamp: u64) -> impl Iterator<Item = &[Buffer]> {
+        self.run(timestamp);
+        let graph = &self.graph;
+        self.roots.iter().map(move |&root| graph.get_out_bufs(root))
+    }
+
+    fn run(&mut self, timestamp: u64) {
         for item in self.to_worker.recv_items() {
+            self.enqueue(item);
+        }
+        let chunk_end = timestamp + (self.sample_ns * N_SAMPLES_PER_CHUNK as f64) as u64;
+        while self.pending.first().is_some_and(|&(t, _)| t < chunk_end) {
+            let (_, item) = self.pending.remove(0);
             self.handle_item(item);
         }
-        self.graph.run_graph(self.root, timestamp);
-        self.graph.get_out_bufs(self.root)
+        self.graph.run_graph_roots(&self.roots, timestamp);
+        for ix in self.graph.drain_panicked() {
+            // Allocates, but only in the exceptional case.
+            self.from_worker.send(Message::Panicked(ix));
+        }
+    }
+
+    // Hold back a message until it's due. A message without a timestamp is
+    // due once everything sent before it has been handled, and a timestamped
+    // message sent after it is kept behind it, so that params and notes never
+    // reach a node that has since been removed, or replaced under a reused id.
+    fn enqueue(&mut self, item: Item<Message>) {
+        if self.pending.len() == MAX_PENDING {
+            // Doesn't allocate: the capacity is handed back afterwards.
+            let mut pending = ::std::mem::take(&mut self.pending);
+            for (_, item) in pending.drain(..) {
+                self.handle_item(item);
+            }
+            self.pending = pending;
+        }
+        let barrier = self.pending.iter().rposition(|(_, p)| message_timestamp(p).is_none());
+        match message_timestamp(&item) {
+            Some(t) => {
+                let start = barrier.map_or(0, |i| i + 1);
+                let t = barrier.map_or(t, |i| t.max(self.pending[i].0));
+                // after any with the same timestamp, to keep sending order
+                let pos = self.pending[start..].iter()
+                    .position(|&(p, _)| p > t)
+                    .map_or(self.pending.len(), |i| start + i);
+                self.pending.insert(pos, (t, item));
+            }
+            None => match self.pending.last() {
+                Some(&(t, _)) => self.pending.push((t, item)),
+                None => self.handle_item(item),
+            },
+        }
+    }
+
+    /// Fill `out` with rendered audio, interleaved with the given number of
+    /// channels, where `timestamp` is the time of the first sample. The length
+    /// needn't be a multiple of the chunk size; samples left over from the
+    /// last chunk are kept for the next call. A mono root is copied to every
+    /// channel, and a stereo root to the first two. Lock-free.
+    ///
+    /// Panics if `channels` is 0.
+    pub fn work_var(&mut self, out: &mut [f32], channels: usize, timestamp: u64) {
+        assert!(channels > 0, "work_var needs at least one channel");
+        let mut i = 0;
+        while i < out.len() {
+            if self.carry_pos == N_SAMPLES_PER_CHUNK {
+                let frame = i / channels;
+                let ts = timestamp + (frame as f64 * self.sample_ns) as u64;
+                self.work(ts);
+                let bufs = self.graph.get_out_bufs(self.roots[0]);
+                let right = &bufs[bufs.len().min(2) - 1];
+                let (l, r) = self.carry.split_at_mut(1);
+                l[0].get_mut().copy_from_slice(bufs[0].get());
+                r[0].get_mut().copy_from_slice(right.get());
+                self.carry_pos = 0;
+            }
+            let n = (N_SAMPLES_PER_CHUNK - self.carry_pos).min((out.len() - i) / channels);
+            for frame in out[i..i + n * channels].chunks_mut(channels) {
+                for (c, y) in frame.iter_mut().enumerate() {
+                    *y = self.carry[c.min(1)].get()[self.carry_pos];
+                }
+                self.carry_pos += 1;
+            }
+            i += n * channels;
+            if n == 0 {
+                // a trailing partial frame
+                break;
+            }
+        }
+    }
+}
+
+fn message_timestamp(msg: &Message) -> Option<u64> {
+    match *msg {
+        Message::SetParam(ref param) => Some(param.timestamp),
+        Message::Note(ref note) => Some(note.timestamp),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use graph::{Message, Node, SetParam};
+    use module::{Buffer, Module, N_SAMPLES_PER_CHUNK};
+    use modules;
+    use super::Worker;
+
+    struct PanicModule;
+
+    impl Module for PanicModule {
+        fn n_bufs_out(&self) -> usize { 1 }
+
+        fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
+            _buf_in: &[&Buffer], buf_out: &mut [Buffer])
+        {
+            buf_out[0].get_mut()[0] = 1.0;
+            panic!("deliberate panic");
+        }
+    }
+
+    // Outputs the last value set by param 0.
+    struct ParamEcho(f32);
+
+    impl Module for ParamEcho {
+        fn n_bufs_out(&self) -> usize { 1 }
+
+        fn set_param(&mut self, _param_ix: usize, val: f32, _timestamp: u64) {
+            self.0 = val;
+        }
+
+        fn process(&mut self, _control_in: &[f32], _control_out: &mut [f32],
+            _buf_in: &[&Buffer], buf_out: &mut [Buffer])
+        {
+            for y in buf_out[0].get_mut().iter_mut() {
+                *y = self.0;
+            }
+        }
+    }
+
+    #[test]
+    fn future_param_waits_for_its_chunk() {
+        let (mut worker, tx, _rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(ParamEcho(0.0)), 0, [], []));
+        let chunk_ns = (N_SAMPLES_PER_CHUNK as f64 * 1e9 / 44_100.0) as u64;
+        let start = 1_000_000_000;
+        tx.send(Message::SetParam(SetParam {
+            ix: 0,
+            param_ix: 0,
+            val: 1.0,
+            timestamp: start + chunk_ns + 10,
+        }));
+        assert_eq!(worker.work(start)[0].get()[0], 0.0);
+        assert_eq!(worker.work(start + chunk_ns)[0].get()[0], 1.0);
+    }
+
+    #[test]
+    fn remove_waits_for_earlier_param() {
+        let (mut worker, tx, rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(ParamEcho(0.0)), 3, [], []));
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0, [(3, 0)], []));
+        let chunk_ns = (N_SAMPLES_PER_CHUNK as f64 * 1e9 / 44_100.0) as u64;
+        // In one batch, a param for node 3 due in the next chunk, then 3 is
+        // unwired and removed.
+        tx.send(Message::SetParam(SetParam {
+            ix: 3,
+            param_ix: 0,
+            val: 1.0,
+            timestamp: chunk_ns + 10,
+        }));
+        tx.send(Message::Node(Node::create(Box::new(modules::Sum::new()), 0, [], [])));
+        tx.send(Message::Remove(3));
+        worker.work(0);
+        assert_eq!(worker.node_count(), 2);
+        worker.work(chunk_ns);
+        assert_eq!(worker.node_count(), 1);
+        rx.recv().count();
+
+        // The same with the id reused: the param still goes to the old node.
+        worker.handle_node(Node::create(Box::new(ParamEcho(0.0)), 3, [], []));
+        tx.send(Message::SetParam(SetParam {
+            ix: 3,
+            param_ix: 0,
+            val: 1.0,
+            timestamp: 3 * chunk_ns + 10,
+        }));
+        tx.send(Message::Remove(3));
+        tx.send(Message::Node(Node::create(Box::new(ParamEcho(0.0)), 3, [], [])));
+        tx.send(Message::Node(Node::create(Box::new(modules::Sum::new()), 0, [(3, 0)], [])));
+        worker.work(2 * chunk_ns);
+        assert_eq!(worker.work(3 * chunk_ns)[0].get()[0], 0.0);
+    }
+
+    #[test]
+    fn panicking_module_is_disabled() {
+        let (mut worker, _tx, rx) = Worker::create(16);
+        worker.set_catch_panics(true);
+        worker.handle_node(Node::create(Box::new(PanicModule), 1, [], []));
+        let pitch = modules::ConstCtrl::new(440f32.log2());
+        worker.handle_node(Node::create(Box::new(pitch), 3, [], []));
+        let sine = modules::Sin::new(44_100.0);
+        worker.handle_node(Node::create(Box::new(sine), 2, [], [(3, 0)]));
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0,
+            [(1, 0), (2, 0)], []));
+        rx.recv().count();
+
+        for _ in 0..2 {
+            let out = worker.work(0)[0].get();
+            // the sine still plays
+            assert!(out.iter().any(|&x| x != 0.0));
+        }
+        let msgs: Vec<_> = rx.recv().collect();
+        assert_eq!(msgs.len(), 1);
+        match msgs[0] {
+            Message::Panicked(ix) => assert_eq!(ix, 1),
+            _ => panic!("expected Panicked"),
+        }
+    }
+
+    #[test]
+    fn two_roots_render_together() {
+        let (mut worker, _tx, _rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(ParamEcho(1.0)), 1, [], []));
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0, [(1, 0)], []));
+        worker.handle_node(Node::create(Box::new(ParamEcho(2.0)), 3, [], []));
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 2, [(3, 0)], []));
+        worker.set_roots(&[0, 2]);
+        let levels: Vec<Vec<f32>> = worker.work_multi(0)
+            .map(|bufs| bufs[0].get().to_vec())
+            .collect();
+        assert_eq!(levels.len(), 2);
+        assert!(levels[0].iter().all(|&y| y == 1.0));
+        assert!(levels[1].iter().all(|&y| y == 2.0));
+    }
+
+    #[test]
+    fn stereo_chain() {
+        let (mut worker, _tx, _rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(modules::Buzz), 1, [], []));
+        worker.handle_node(Node::create(Box::new(modules::ConstCtrl::new(-1.0)), 2, [], []));
+        worker.handle_node(Node::create(Box::new(modules::ConstCtrl::new(0.0)), 3, [], []));
+        worker.handle_node(Node::create(Box::new(modules::Pan::new()), 4, [(1, 0)], [(2, 0)]));
+        worker.handle_node(Node::create(Box::new(modules::Pan::new()), 5, [(1, 0)], [(3, 0)]));
+        worker.handle_node(Node::create(Box::new(modules::Sum::new_channels(2)), 6,
+            vec![(4, 0), (4, 1), (5, 0), (5, 1)], []));
+        let (monitor, _monitor_tx, _monitor_rx) = modules::Monitor::new_stereo();
+        worker.handle_node(Node::create(Box::new(monitor), 7, [(6, 0), (6, 1)], []));
+        worker.handle_node(Node::create(Box::new(modules::Sum::new_channels(2)), 0,
+            [(7, 0), (7, 1)], []));
+
+        let out = worker.work(0);
+        assert_eq!(out.len(), 2);
+        let center = 0.5f32.sqrt();
+        let buzz = out[0].get().iter().zip(out[1].get().iter()).enumerate();
+        for (i, (&l, &r)) in buzz {
+            let x = i as f32 * (2.0 / N_SAMPLES_PER_CHUNK as f32) - 1.0;
+            assert!((l - x * (1.0 + center)).abs() < 1e-5, "left {} at {}", l, i);
+            assert!((r - x * center).abs() < 1e-5, "right {} at {}", r, i);
+        }
+    }
+
+    #[test]
+    fn reset_all_clears_delay_tail() {
+        let (mut worker, _tx, _rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(modules::Buzz), 1, [], []));
+        worker.handle_node(Node::create(Box::new(modules::ConstCtrl::new(0.01)), 2, [], []));
+        worker.handle_node(Node::create(Box::new(modules::ConstCtrl::new(0.9)), 3, [], []));
+        worker.handle_node(Node::create(Box::new(modules::Delay::new(44_100.0, 1.0)), 0,
+            [(1, 0)], vec![(2, 0), (3, 0), (3, 0)]));
+        for _ in 0..100 {
+            worker.work(0);
+        }
+        // Silence the input; the feedback keeps the tail going.
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 1, [], []));
+        for _ in 0..20 {
+            worker.work(0);
+        }
+        assert!(worker.work(0)[0].get().iter().any(|&x| x.abs() > 0.01));
+        worker.handle_message(Message::ResetAll);
+        for _ in 0..20 {
+            assert!(worker.work(0)[0].get().iter().all(|&x| x == 0.0));
+        }
+    }
+
+    #[test]
+    fn odd_block_sizes() {
+        let (mut worker, _tx, _rx) = Worker::create(16);
+        worker.handle_node(Node::create(Box::new(modules::Buzz), 0, [], []));
+        let mut out = Vec::new();
+        for &len in &[100, 7, 64, 1] {
+            let mut block = vec![0.0; len];
+            worker.work_var(&mut block, 1, 0);
+            out.extend_from_slice(&block);
+        }
+        assert_eq!(out.len(), 172);
+        let mut stereo = vec![0.0; 2 * 100];
+        worker.work_var(&mut stereo, 2, 0);
+        out.extend(stereo.chunks(2).map(|frame| {
+            assert_eq!(frame[0], frame[1]);
+            frame[0]
+        }));
+        for (i, &y) in out.iter().enumerate() {
+            let x = (i % N_SAMPLES_PER_CHUNK) as f32 * (2.0 / N_SAMPLES_PER_CHUNK as f32) - 1.0;
+            assert_eq!(y, x, "sample {}", i);
+        }
     }
 }
@@ -0,0 +1,236 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Offline rendering of a patch playing a sequence of MIDI events, to a WAV
+//! file, with no audio device needed.
+//!
+//! ```text
+//! synthesizer-io-render [--preset FILE] [--duration SECS] [--sample-rate HZ]
+//!     EVENTS OUT.wav
+//! ```
+//!
+//! The preset is a patch in JSON, as saved by `Engine::save_preset`. Without
+//! one, the monosynth is used. The events file has one MIDI message per
+//! line, a time in seconds followed by the bytes in hex, and `#` starts a
+//! comment:
+//!
+//! ```text
+//! 0.0 90 3c 64      # note on, middle C
+//! 0.5 80 3c 00      # note off
+//! ```
+//!
+//! The duration defaults to two seconds past the last event, and the sample
+//! rate to 44.1kHz. The WAV is 32-bit float, stereo if the patch is.
+
+extern crate hound;
+extern crate synthesizer_io_core;
+
+use std::env;
+use std::fs;
+use std::process;
+
+use synthesizer_io_core::engine::Engine;
+use synthesizer_io_core::module::N_SAMPLES_PER_CHUNK;
+use synthesizer_io_core::worker::Worker;
+
+#[cfg(test)]
+mod regress;
+
+const USAGE: &str = "usage: synthesizer-io-render [--preset FILE] [--duration SECS] \
+    [--sample-rate HZ] EVENTS OUT.wav";
+
+struct Options {
+    preset: Option<String>,
+    events: String,
+    out: String,
+    duration: Option<f64>,
+    sample_rate: f32,
+}
+
+#[derive(Debug, PartialEq)]
+struct Event {
+    time: f64,
+    data: Vec<u8>,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut args: I) -> Result<Options, String> {
+    let mut preset = None;
+    let mut duration = None;
+    let mut sample_rate = 44_100.0;
+    let mut files = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--preset" => preset = Some(value("--preset")?),
+            "--duration" => duration = Some(parse_num(&value("--duration")?)?),
+            "--sample-rate" => sample_rate = parse_num(&value("--sample-rate")?)?,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ => files.push(arg),
+        }
+    }
+    if files.len() != 2 {
+        return Err(USAGE.into());
+    }
+    let out = files.pop().unwrap();
+    let events = files.pop().unwrap();
+    Ok(Options { preset, events, out, duration, sample_rate })
+}
+
+fn parse_num<T: std::str::FromStr>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("bad number {}", s))
+}
+
+// The non-comment words of each non-empty line, with its line number.
+fn lines(text: &str) -> impl Iterator<Item = (usize, Vec<&str>)> {
+    text.lines().enumerate()
+        .map(|(i, line)| (i + 1, line.split('#').next().unwrap().split_whitespace().collect()))
+        .filter(|(_, words): &(usize, Vec<&str>)| !words.is_empty())
+}
+
+// Events are sorted by time, keeping the file order of simultaneous ones.
+fn parse_events(text: &str) -> Result<Vec<Event>, String> {
+    let mut events = Vec::new();
+    for (line, words) in lines(text) {
+        let err = || format!("events line {}: can't parse {}", line, words.join(" "));
+        let time: f64 = parse_num(words[0]).map_err(|_| err())?;
+        let data = words[1..].iter()
+            .map(|w| u8::from_str_radix(w, 16))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| err())?;
+        if !time.is_finite() || time < 0.0 || data.is_empty() {
+            return Err(err());
+        }
+        events.push(Event { time, data });
+    }
+    events.sort_by(|a, b| a.time.partial_cmp(&b.time).unwrap());
+    Ok(events)
+}
+
+// Render the preset, or the monosynth without one, returning the number of
+// channels and the interleaved samples.
+fn render(preset: Option<&str>, events: &[Event], duration: f64, sample_rate: f32)
+    -> Result<(usize, Vec<f32>), String>
+{
+    let (mut worker, tx, rx) = Worker::create(1024);
+    worker.set_sample_rate(sample_rate);
+    let mut engine = Engine::new(sample_rate, rx, tx);
+    match preset {
+        Some(preset) => engine.load_preset(preset)?,
+        None => engine.init_monosynth(),
+    }
+    let ns = |seconds: f64| (seconds * 1e9) as u64;
+    let chunk_secs = N_SAMPLES_PER_CHUNK as f64 / sample_rate as f64;
+    let n_frames = (duration * sample_rate as f64).round() as usize;
+    let mut channels = 0;
+    let mut samples = Vec::new();
+    let mut next = 0;
+    let mut chunk = 0;
+    while samples.len() < n_frames * channels.max(1) {
+        let ts = ns(chunk as f64 * chunk_secs);
+        let chunk_end = ns((chunk + 1) as f64 * chunk_secs);
+        while next < events.len() && ns(events[next].time) < chunk_end {
+            engine.dispatch_midi(&events[next].data, ns(events[next].time));
+            next += 1;
+        }
+        {
+            let bufs = worker.work(ts);
+            channels = bufs.len().min(2);
+            for i in 0..N_SAMPLES_PER_CHUNK {
+                for buf in &bufs[..channels] {
+                    samples.push(buf.get()[i]);
+                }
+            }
+        }
+        engine.poll_rx_at(ts);
+        chunk += 1;
+    }
+    samples.truncate(n_frames * channels);
+    Ok((channels, samples))
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let read = |path: &str| fs::read_to_string(path)
+        .map_err(|e| format!("can't read {}: {}", path, e));
+    let preset = options.preset.as_ref().map(|path| read(path)).transpose()?;
+    let events = parse_events(&read(&options.events)?)?;
+    let duration = options.duration
+        .unwrap_or_else(|| events.last().map(|e| e.time).unwrap_or(0.0) + 2.0);
+    let (channels, samples) = render(preset.as_deref(), &events, duration, options.sample_rate)?;
+    let spec = hound::WavSpec {
+        channels: channels as u16,
+        sample_rate: options.sample_rate as u32,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
+    };
+    let write_err = |e: hound::Error| format!("can't write {}: {}", options.out, e);
+    let mut writer = hound::WavWriter::create(&options.out, spec).map_err(write_err)?;
+    for &x in &samples {
+        writer.write_sample(x).map_err(write_err)?;
+    }
+    writer.finalize().map_err(write_err)
+}
+
+fn main() {
+    let result = parse_args(env::args().skip(1)).and_then(run);
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use synthesizer_io_core::engine::Engine;
+    use synthesizer_io_core::worker::Worker;
+    use super::{parse_events, render, Event};
+
+    /// A preset for the polysynth, with the given number of voices and key
+    /// pan.
+    pub fn polysynth_preset(max_voices: usize, key_pan: f32) -> String {
+        let (_worker, tx, rx) = Worker::create(1024);
+        let mut engine = Engine::new(44_100.0, rx, tx);
+        engine.init_polysynth(max_voices);
+        engine.set_key_pan(key_pan);
+        engine.save_preset().unwrap()
+    }
+
+    #[test]
+    fn parse_events_file() {
+        let events = parse_events("0.5 80 3c 00\n\n0 90 3c 64\n").unwrap();
+        assert_eq!(events, vec![
+            Event { time: 0.0, data: vec![0x90, 0x3c, 0x64] },
+            Event { time: 0.5, data: vec![0x80, 0x3c, 0x00] },
+        ]);
+        assert!(parse_events("0.5 zz").is_err());
+        assert!(parse_events("nan 90 3c 64\n0 80 3c 00").is_err());
+        assert!(parse_events("inf 90 3c 64").is_err());
+    }
+
+    #[test]
+    fn renders_note_then_silence() {
+        let preset = polysynth_preset(4, 0.0);
+        let events = parse_events("0.1 90 45 64\n0.3 80 45 00\n").unwrap();
+        let sample_rate = 44_100.0;
+        let (channels, samples) = render(Some(&preset), &events, 2.0, sample_rate).unwrap();
+        assert_eq!(channels, 2);
+        assert_eq!(samples.len(), 2 * 88_200);
+        let peak = |start: f32, end: f32| {
+            let frame = |t: f32| 2 * (t * sample_rate) as usize;
+            samples[frame(start)..frame(end)].iter().fold(0.0f32, |m, x| m.max(x.abs()))
+        };
+        assert_eq!(peak(0.0, 0.09), 0.0);
+        assert!(peak(0.15, 0.3) > 0.01);
+        assert!(peak(1.9, 2.0) < 1e-4);
+    }
+}
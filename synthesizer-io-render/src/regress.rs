@@ -0,0 +1,133 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Audio regression tests: fixed patches and sequences are rendered and
+//! compared against reference renderings, so that changes to the DSP which
+//! alter the sound are noticed.
+//!
+//! The references are 16-bit WAV files in the `reference` directory of this
+//! crate, one per test. A rendering matches if the error relative to the
+//! reference is below -60dB, which allows for rounding differences between
+//! platforms and compilers but catches any audible change. When a change to
+//! the sound is intended, regenerate the references by running the tests
+//! with `UPDATE_REFERENCES=1` set, listen to the new files, and commit them.
+//! A new test's reference is written the same way; without the variable, a
+//! missing reference fails the test.
+
+use std::env;
+use std::path::PathBuf;
+
+use hound;
+
+// maximum error power relative to the reference, in dB
+const TOLERANCE_DB: f64 = -60.0;
+
+fn reference_path(name: &str) -> PathBuf {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("reference");
+    path.push(format!("{}.wav", name));
+    path
+}
+
+// Samples as stored in a reference file.
+fn quantize(x: f32) -> i16 {
+    (x.clamp(-1.0, 1.0) * 32767.0).round() as i16
+}
+
+fn write_reference(name: &str, channels: usize, sample_rate: u32, samples: &[f32]) {
+    let spec = hound::WavSpec {
+        channels: channels as u16,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(reference_path(name), spec).unwrap();
+    for &x in samples {
+        writer.write_sample(quantize(x)).unwrap();
+    }
+    writer.finalize().unwrap();
+}
+
+/// The error of `samples` relative to `reference`, in dB. Both must be
+/// the same length.
+pub fn relative_error_db(samples: &[f32], reference: &[f32]) -> f64 {
+    let mut err = 1e-20;
+    let mut power = 1e-20;
+    for (&x, &y) in samples.iter().zip(reference) {
+        err += ((x - y) as f64).powi(2);
+        power += (y as f64).powi(2);
+    }
+    10.0 * (err / power).log10()
+}
+
+/// Compare a rendering with the named reference, panicking if it doesn't
+/// match or is missing. Writes the reference instead if `UPDATE_REFERENCES`
+/// is set.
+pub fn check_reference(name: &str, channels: usize, sample_rate: u32, samples: &[f32]) {
+    let path = reference_path(name);
+    if env::var_os("UPDATE_REFERENCES").is_some() {
+        write_reference(name, channels, sample_rate, samples);
+        println!("wrote reference {}", path.display());
+        return;
+    }
+    assert!(path.exists(), "{}: no reference at {}; run with UPDATE_REFERENCES=1 to write it",
+        name, path.display());
+    let mut reader = hound::WavReader::open(&path).unwrap();
+    let spec = reader.spec();
+    assert_eq!((spec.channels as usize, spec.sample_rate), (channels, sample_rate),
+        "{}: format differs from reference", name);
+    let to_f32 = |s: i16| s as f32 * (1.0 / 32767.0);
+    let reference: Vec<f32> = reader.samples::<i16>().map(|s| to_f32(s.unwrap())).collect();
+    assert_eq!(samples.len(), reference.len(), "{}: length differs from reference", name);
+    // compare what would be stored, so that clipping isn't counted as error
+    let samples: Vec<f32> = samples.iter().map(|&x| to_f32(quantize(x))).collect();
+    let error = relative_error_db(&samples, &reference);
+    assert!(error < TOLERANCE_DB, "{}: differs from reference by {:.1}dB", name, error);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_reference, relative_error_db};
+    use tests::polysynth_preset;
+    use {parse_events, render};
+
+    fn check(name: &str, preset: Option<&str>, events: &str) {
+        let sample_rate = 44_100;
+        let events = parse_events(events).unwrap();
+        let (channels, samples) = render(preset, &events, 0.5, sample_rate as f32).unwrap();
+        check_reference(name, channels, sample_rate, &samples);
+    }
+
+    #[test]
+    fn tolerance_allows_rounding_only() {
+        let reference: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.1).sin() * 0.5).collect();
+        let rounded: Vec<f32> = reference.iter().map(|x| (x * 32767.0).round() / 32767.0)
+            .collect();
+        assert!(relative_error_db(&rounded, &reference) < -80.0);
+        let quieter: Vec<f32> = reference.iter().map(|x| x * 0.99).collect();
+        assert!(relative_error_db(&quieter, &reference) > -60.0);
+    }
+
+    #[test]
+    fn monosynth_reference() {
+        check("monosynth", None, "0 90 30 64\n0.1 b0 01 20\n0.2 b0 02 60\n0.3 80 30 00\n");
+    }
+
+    #[test]
+    fn polysynth_reference() {
+        let preset = polysynth_preset(4, 0.7);
+        check("polysynth", Some(&preset), "0 90 30 64\n0.05 90 3c 50\n0.1 90 50 40\n0.3 80 30 00\n\
+            0.3 80 3c 00\n0.35 80 50 00\n");
+    }
+}
@@ -24,12 +24,12 @@ function startAudio(wasm) {
     
         var ctx = new AudioContext();
     
-        let scriptNode = ctx.createScriptProcessor(256, 0, 1);
+        let scriptNode = ctx.createScriptProcessor(256, 0, 2);
         let bufSize = scriptNode.bufferSize;
         synth.setup_saw(8.781);
         scriptNode.onaudioprocess = function(audioProcessingEvent) {
-            let obuf = audioProcessingEvent.outputBuffer.getChannelData(0);
-            synth.get_samples(obuf);
+            let obuf = audioProcessingEvent.outputBuffer;
+            synth.get_samples_stereo(obuf.getChannelData(0), obuf.getChannelData(1));
         };
         scriptNode.connect(ctx.destination);
     }
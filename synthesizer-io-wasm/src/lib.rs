@@ -22,23 +22,57 @@ use std::cell::RefCell;
 
 use synthesizer_io_core::modules;
 
+use synthesizer_io_core::engine::Engine;
 use synthesizer_io_core::worker::Worker;
-use synthesizer_io_core::queue::{Receiver, Sender};
-use synthesizer_io_core::graph::{Message, Node};
+use synthesizer_io_core::graph::Node;
 use synthesizer_io_core::module::N_SAMPLES_PER_CHUNK;
 
+const SAMPLE_RATE: f32 = 44_100.0;
+
 #[wasm_bindgen]
 pub struct Synth {
     worker: Worker,
-    tx: Sender<Message>,
-    rx: Receiver<Message>,
+    // owns the channels to and from the worker
+    engine: Engine,
+    // the left and right of the last chunk rendered, of which the samples
+    // from `chunk_pos` on haven't been handed out yet
+    chunk: [[f32; N_SAMPLES_PER_CHUNK]; 2],
+    chunk_pos: usize,
+    // chunks rendered so far, which is the clock for the worker
+    n_chunks: u64,
 }
 
 #[wasm_bindgen]
 impl Synth {
     pub fn new() -> Synth {
         let (worker, tx, rx) = Worker::create(1024);
-        Synth { worker, tx, rx }
+        let engine = Engine::new(SAMPLE_RATE, rx, tx);
+        let chunk = [[0.0; N_SAMPLES_PER_CHUNK]; 2];
+        let chunk_pos = N_SAMPLES_PER_CHUNK;
+        let n_chunks = 0;
+        Synth { worker, engine, chunk, chunk_pos, n_chunks }
+    }
+
+    /// A synth with the engine's full monosynth patch, played with `note_on`
+    /// and `note_off`. This is the constructor from JS, `new Synth()`.
+    #[wasm_bindgen(constructor)]
+    pub fn monosynth() -> Synth {
+        let mut synth = Synth::new();
+        synth.engine.init_monosynth();
+        synth
+    }
+
+    pub fn note_on(&mut self, midi_num: u8, velocity: u8) {
+        self.engine.dispatch_midi(&[0x90, midi_num, velocity], 0);
+    }
+
+    pub fn note_off(&mut self, midi_num: u8) {
+        self.engine.dispatch_midi(&[0x80, midi_num, 0], 0);
+    }
+
+    /// Set parameter `param_ix` of graph node `node` directly.
+    pub fn set_param(&mut self, node: usize, param_ix: usize, val: f32) {
+        self.engine.set_param(node, param_ix, val, 0);
     }
 
     pub fn setup_saw(&mut self, val: f32) {
@@ -49,18 +83,141 @@ impl Synth {
         worker.handle_node(Node::create(module, 1, [], []));
     }
 
+    /// The output rendered since the last call, mixed down to mono, for a
+    /// scope display. Only the monosynth has a monitor; otherwise this is
+    /// always empty.
+    pub fn poll_monitor(&mut self) -> Vec<f32> {
+        self.engine.poll_monitor()
+    }
+
+    /// Fill `obuf` with the next samples, of any length, mixed down to mono;
+    /// the rest of a chunk carries over to the next call.
     pub fn get_samples(&mut self, obuf: &mut[f32]) {
-        let mut worker = &mut self.worker;
-        let mut i = 0;
-        let mut timestamp = 0;  // TODO: figure this out
-        while i < obuf.len() {
-            // should let the graph generate stereo
-            let buf = worker.work(timestamp)[0].get();
-            for j in 0..N_SAMPLES_PER_CHUNK {
-                obuf[i + j] = buf[j];
+        self.fill(obuf.len(), |i, l, r| obuf[i] = 0.5 * (l + r));
+    }
+
+    /// Fill `left` and `right` with the next samples, as `get_samples` does.
+    /// A mono graph plays in both.
+    pub fn get_samples_stereo(&mut self, left: &mut [f32], right: &mut [f32]) {
+        let n = left.len().min(right.len());
+        self.fill(n, |i, l, r| {
+            left[i] = l;
+            right[i] = r;
+        });
+    }
+}
+
+impl Synth {
+    // Hand `n` samples, each left and right, to `write`.
+    fn fill<F: FnMut(usize, f32, f32)>(&mut self, n: usize, mut write: F) {
+        self.engine.flush();
+        for i in 0..n {
+            if self.chunk_pos == N_SAMPLES_PER_CHUNK {
+                self.render_chunk();
             }
-            timestamp += 1451247;  // 64 * 1e9 / 44_100
-            i += N_SAMPLES_PER_CHUNK;
+            write(i, self.chunk[0][self.chunk_pos], self.chunk[1][self.chunk_pos]);
+            self.chunk_pos += 1;
         }
+        // Reclaim the messages the worker is done with.
+        let timestamp = self.timestamp();
+        self.engine.poll_rx_at(timestamp);
+    }
+
+    fn render_chunk(&mut self) {
+        let timestamp = self.timestamp();
+        let bufs = self.worker.work(timestamp);
+        self.chunk[0] = *bufs[0].get();
+        self.chunk[1] = *bufs[bufs.len().min(2) - 1].get();
+        self.chunk_pos = 0;
+        self.n_chunks += 1;
+    }
+
+    // The time of the next chunk to render, in ns.
+    fn timestamp(&self) -> u64 {
+        (self.n_chunks as f64 * N_SAMPLES_PER_CHUNK as f64 * 1e9 / SAMPLE_RATE as f64) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Synth, N_SAMPLES_PER_CHUNK};
+
+    #[test]
+    fn partial_chunks() {
+        let render = |sizes: &[usize]| {
+            let mut synth = Synth::new();
+            synth.setup_saw(8.781);
+            let mut out = Vec::new();
+            for &size in sizes {
+                let mut obuf = vec![0.0; size];
+                synth.get_samples(&mut obuf);
+                out.extend(obuf);
+            }
+            assert_eq!(synth.n_chunks as usize, 512 / N_SAMPLES_PER_CHUNK);
+            out
+        };
+        let whole = render(&[512]);
+        assert_eq!(render(&[100, 37, 1, 0, 300, 74]), whole);
+        assert!(whole.iter().any(|&x| x != 0.0));
+    }
+
+    #[test]
+    fn stereo_from_mono() {
+        let mut synth = Synth::monosynth();
+        synth.note_on(60, 100);
+        let mut left = vec![0.0; 300];
+        let mut right = vec![1.0; 300];
+        synth.get_samples_stereo(&mut left, &mut right);
+        assert!(left.iter().any(|&x| x.abs() > 1e-3));
+        assert_eq!(left, right);
+    }
+
+    #[test]
+    fn monitor_sees_output() {
+        let mut synth = Synth::monosynth();
+        synth.note_on(57, 100);
+        let mut obuf = vec![0.0; 4096];
+        synth.get_samples(&mut obuf);
+        let monitored = synth.poll_monitor();
+        assert!(!monitored.is_empty() && monitored.len() <= obuf.len());
+        assert!(monitored.iter().any(|&x| x.abs() > 1e-3));
+        // What's come through is what was played.
+        assert_eq!(monitored[..], obuf[..monitored.len()]);
+        assert!(Synth::new().poll_monitor().is_empty());
+    }
+
+    #[test]
+    fn note_on_makes_sound() {
+        let mut synth = Synth::monosynth();
+        let mut obuf = vec![0.0; 1024];
+        synth.get_samples(&mut obuf);
+        assert!(obuf.iter().all(|&x| x.abs() < 1e-3));
+        synth.note_on(69, 100);
+        synth.get_samples(&mut obuf);
+        assert!(obuf.iter().any(|&x| x.abs() > 1e-3));
+        synth.note_off(69);
+        for _ in 0..100 {
+            synth.get_samples(&mut obuf);
+        }
+        assert!(obuf.iter().all(|&x| x.abs() < 1e-3));
+    }
+}
+
+// These run under node, with `cargo test --target wasm32-unknown-unknown` and
+// wasm-bindgen-test-runner from wasm-bindgen-cli as the target's runner.
+#[cfg(all(test, target_arch = "wasm32"))]
+mod wasm_tests {
+    extern crate wasm_bindgen_test;
+
+    use self::wasm_bindgen_test::wasm_bindgen_test;
+    use super::Synth;
+
+    #[wasm_bindgen_test]
+    fn note_on_makes_sound() {
+        let mut synth = Synth::monosynth();
+        synth.note_on(69, 100);
+        let mut obuf = vec![0.0; 1024];
+        synth.get_samples(&mut obuf);
+        assert!(obuf.iter().any(|&x| x.abs() > 1e-3));
     }
 }
@@ -25,10 +25,13 @@ pub struct WireGrid {
 #[derive(Default)]
 pub struct ModuleGrid {
     modules: Vec<ModuleInstance>,
+    next_id: usize,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct ModuleInstance {
+    /// Unique id of the instance, so deltas can refer to it after it moves.
+    pub id: usize,
     pub loc: (u16, u16),
     pub spec: ModuleSpec,
 }
@@ -43,9 +46,11 @@ pub struct ModuleSpec {
 pub enum Delta {
     Wire(WireDelta),
     Jumper(JumperDelta),
-    /// Add a module. Note: we need to encode moving and deleting as well, and
-    /// probably have a unique id mechanism. Later.
+    /// Add a module.
     Module(ModuleInstance),
+    /// Remove the module with the given id.
+    RemoveModule(usize),
+    MoveModule { id: usize, new_loc: (u16, u16) },
 }
 
 #[derive(Clone, Debug)]
@@ -89,13 +94,15 @@ impl WireGrid {
         }
     }
 
+    /// Apply a jumper delta. Deleting removes the jumper between the two
+    /// points, whichever way round it was added.
     pub fn apply_jumper_delta(&mut self, delta: JumperDelta) {
         //println!("apply jumper {:?}", delta);
         let coords = (delta.start.0, delta.start.1, delta.end.0, delta.end.1);
         if delta.val {
             self.jumpers.push(coords);
         } else {
-            if let Some(pos) = self.jumpers.iter().position(|&c| c == coords) {
+            if let Some(pos) = self.jumper_pos(delta.start, delta.end) {
                 self.jumpers.remove(pos);
             } else {
                 println!("trying to delete nonexistent jumper");
@@ -103,6 +110,16 @@ impl WireGrid {
         }
     }
 
+    /// Determine whether there's a jumper between two points, either way round.
+    pub fn has_jumper(&self, start: (u16, u16), end: (u16, u16)) -> bool {
+        self.jumper_pos(start, end).is_some()
+    }
+
+    fn jumper_pos(&self, start: (u16, u16), end: (u16, u16)) -> Option<usize> {
+        self.jumpers.iter().position(|&c| c == (start.0, start.1, end.0, end.1)
+            || c == (end.0, end.1, start.0, start.1))
+    }
+
     pub fn iter(&self) -> impl Iterator<Item = &(u16, u16, bool)> {
         self.grid.iter()
     }
@@ -114,20 +131,59 @@ impl WireGrid {
 
 impl ModuleInstance {
     /// Determine whether this instance conflicts with another proposed instance.
+    /// An instance never conflicts with itself, so that it can be moved.
     fn is_conflict(&self, other: &ModuleInstance) -> bool {
-        self.loc.0 + self.spec.size.0 >= other.loc.0
-            && other.loc.0 + other.spec.size.0 >= self.loc.0
-            && self.loc.1 + self.spec.size.1 >= other.loc.1
-            && other.loc.1 + other.spec.size.1 >= self.loc.1
+        self.id != other.id
+            && self.loc.0 + self.spec.size.0 > other.loc.0
+            && other.loc.0 + other.spec.size.0 > self.loc.0
+            && self.loc.1 + self.spec.size.1 > other.loc.1
+            && other.loc.1 + other.spec.size.1 > self.loc.1
     }
 }
 
 impl ModuleGrid {
     /// Add a module instance to the grid.
     pub fn add(&mut self, instance: ModuleInstance) {
+        self.next_id = self.next_id.max(instance.id + 1);
         self.modules.push(instance);
     }
 
+    /// An id not used by any instance added so far.
+    pub fn new_id(&self) -> usize {
+        self.next_id
+    }
+
+    pub fn get(&self, id: usize) -> Option<&ModuleInstance> {
+        self.iter().find(|inst| inst.id == id)
+    }
+
+    /// The instance covering a grid cell, if any.
+    pub fn at(&self, loc: (u16, u16)) -> Option<&ModuleInstance> {
+        self.iter().find(|inst| loc.0 >= inst.loc.0 && loc.0 < inst.loc.0 + inst.spec.size.0
+            && loc.1 >= inst.loc.1 && loc.1 < inst.loc.1 + inst.spec.size.1)
+    }
+
+    /// Remove an instance from the grid, returning it.
+    pub fn remove(&mut self, id: usize) -> Option<ModuleInstance> {
+        let pos = self.modules.iter().position(|inst| inst.id == id)?;
+        Some(self.modules.remove(pos))
+    }
+
+    /// Move an instance to a new location. Returns false, leaving it where it
+    /// was, if there's no such instance or it would overlap another.
+    pub fn move_module(&mut self, id: usize, new_loc: (u16, u16)) -> bool {
+        let pos = match self.modules.iter().position(|inst| inst.id == id) {
+            Some(pos) => pos,
+            None => return false,
+        };
+        let moved = ModuleInstance { loc: new_loc, ..self.modules[pos].clone() };
+        if self.is_conflict(&moved) {
+            return false;
+        }
+        self.modules[pos] = moved;
+        true
+    }
+
     /// Iterate through the instances on the grid.
     pub fn iter(&self) -> impl Iterator<Item = &ModuleInstance> {
         self.modules.iter()
@@ -138,8 +194,56 @@ impl ModuleGrid {
         self.iter().any(|inst| inst.is_conflict(other))
     }
 
+    /// Determine the `i`th input pin of a module, counting down its left side.
+    /// A module at the left edge of the grid has nowhere to connect its inputs.
+    pub fn determine_input_pin(inst: &ModuleInstance, i: u16) -> Option<(u16, u16)> {
+        inst.loc.0.checked_sub(1).map(|x| (x, inst.loc.1 + i))
+    }
+
     /// Determine the output pin of a module. Needs rework for multi-output modules.
     pub fn determine_output_pin(inst: &ModuleInstance) -> (u16, u16) {
         (inst.loc.0 + 2, inst.loc.1)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{JumperDelta, ModuleGrid, ModuleInstance, ModuleSpec, WireGrid};
+
+    fn inst(id: usize, loc: (u16, u16), size: (u16, u16)) -> ModuleInstance {
+        ModuleInstance { id, loc, spec: ModuleSpec { size, name: "saw".into() } }
+    }
+
+    #[test]
+    fn module_conflicts() {
+        let mut grid = ModuleGrid::default();
+        grid.add(inst(0, (4, 4), (2, 2)));
+        // overlapping
+        assert!(grid.is_conflict(&inst(1, (5, 5), (2, 2))));
+        assert!(grid.is_conflict(&inst(1, (3, 3), (4, 4))));
+        // edge-adjacent, on each side and at a corner
+        assert!(!grid.is_conflict(&inst(1, (6, 4), (2, 2))));
+        assert!(!grid.is_conflict(&inst(1, (2, 4), (2, 2))));
+        assert!(!grid.is_conflict(&inst(1, (4, 6), (2, 1))));
+        assert!(!grid.is_conflict(&inst(1, (4, 3), (2, 1))));
+        assert!(!grid.is_conflict(&inst(1, (6, 6), (1, 1))));
+        // fully separate
+        assert!(!grid.is_conflict(&inst(1, (10, 0), (2, 3))));
+    }
+
+    #[test]
+    fn add_and_remove_jumpers() {
+        let mut grid = WireGrid::default();
+        let jumper = |start, end, val| JumperDelta { start, end, val };
+        grid.apply_jumper_delta(jumper((1, 2), (5, 6), true));
+        grid.apply_jumper_delta(jumper((3, 3), (4, 4), true));
+        assert_eq!(grid.iter_jumpers().count(), 2);
+        assert!(grid.has_jumper((5, 6), (1, 2)));
+        assert!(!grid.has_jumper((6, 6), (5, 6)));
+        // Removal matches either way round.
+        grid.apply_jumper_delta(jumper((5, 6), (1, 2), false));
+        assert_eq!(grid.iter_jumpers().collect::<Vec<_>>(), vec![&(3, 3, 4, 4)]);
+        grid.apply_jumper_delta(jumper((3, 3), (4, 4), false));
+        assert_eq!(grid.iter_jumpers().count(), 0);
+    }
+}
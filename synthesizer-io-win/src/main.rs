@@ -29,6 +29,7 @@ extern crate dxgi;
 extern crate union_find;
 
 mod grid;
+mod registry;
 mod synth;
 mod ui;
 
@@ -45,6 +46,7 @@ use synthesizer_io_core::engine::{Engine, NoteEvent};
 use synthesizer_io_core::worker::Worker;
 use synthesizer_io_core::graph::Node;
 use synthesizer_io_core::module::N_SAMPLES_PER_CHUNK;
+use synthesizer_io_core::resample::Resampler;
 
 use druid_win_shell::win_main;
 use druid_win_shell::window::WindowBuilder;
@@ -73,8 +75,6 @@ fn build_ui(synth_state: SynthState, ui: &mut UiState) -> Id {
     let scope = Scope::new().ui(ui);
     let piano = Piano::new().ui(ui);
 
-    let modules = &["sine", "control", "saw", "biquad", "adsr", "gain"];
-
     let wire_b = Button::new("wire").ui(ui);
     ui.add_listener(wire_b, move |_: &mut bool, mut ctx| {
         ctx.poke(patcher, &mut PatcherAction::WireMode);
@@ -84,7 +84,8 @@ fn build_ui(synth_state: SynthState, ui: &mut UiState) -> Id {
         ctx.poke(patcher, &mut PatcherAction::JumperMode);
     });
     let mut buttons = vec![wire_b, jumper_b];
-    for &module in modules {
+    for entry in registry::MODULES {
+        let module = entry.name;
         let button = Button::new(module).ui(ui);
         ui.add_listener(button, move |_: &mut bool, mut ctx| {
             ctx.poke(patcher, &mut PatcherAction::Module(module.into()));
@@ -112,6 +113,8 @@ fn build_ui(synth_state: SynthState, ui: &mut UiState) -> Id {
             //println!("polled {} events", _n_msg);
         }
     });
+    // Let the piano take keys from the computer keyboard.
+    ui.set_focus(Some(piano));
     ui.add_listener(piano, move |event: &mut NoteEvent, mut ctx| {
         ctx.poke_up(&mut Action::Note(event.clone()));
     });
@@ -121,8 +124,9 @@ fn build_ui(synth_state: SynthState, ui: &mut UiState) -> Id {
 fn main() {
     druid_win_shell::init();
     let (mut worker, tx, rx) = Worker::create(1024);
-    // TODO: get sample rate from cpal
-    let mut engine = Engine::new(48_000.0, rx, tx);
+    // The graph runs at a fixed rate, resampled to the device rate on output.
+    let sample_rate = 48_000.0;
+    let mut engine = Engine::new(sample_rate, rx, tx);
     engine.init_monosynth();
 
     let engine = Arc::new(Mutex::new(engine));
@@ -143,7 +147,7 @@ fn main() {
     builder.set_title("Synthesizer IO");
     let window = builder.build().unwrap();
     let _midi_connection = setup_midi(engine);  // keep from being dropped
-    thread::spawn(move || run_cpal(worker));
+    thread::spawn(move || run_cpal(worker, sample_rate));
     window.show();
     run_loop.run();
 }
@@ -162,7 +166,7 @@ fn setup_midi(engine: Arc<Mutex<Engine>>) -> Option<MidiInputConnection<()>> {
     result.ok()
 }
 
-fn run_cpal(mut worker: Worker) {
+fn run_cpal(mut worker: Worker, sample_rate: f32) {
     let event_loop = EventLoop::new();
     let device = cpal::default_output_device().expect("no output device");
     let mut supported_formats_range = device.supported_output_formats()
@@ -174,24 +178,41 @@ fn run_cpal(mut worker: Worker) {
     let stream_id = event_loop.build_output_stream(&device, &format).unwrap();
     event_loop.play_stream(stream_id);
 
+    let device_rate = format.sample_rate.0 as f32;
+    // one resampler per device channel
+    let mut resamplers = if device_rate != sample_rate {
+        Some([Resampler::new(sample_rate, device_rate), Resampler::new(sample_rate, device_rate)])
+    } else {
+        None
+    };
+    let mut right_chunk = [0.0; N_SAMPLES_PER_CHUNK];
+    let chunk_ns = (N_SAMPLES_PER_CHUNK as f64 * 1e9 / sample_rate as f64) as u64;
+    worker.set_sample_rate(sample_rate);
+
     event_loop.run(move |_stream_id, stream_data| {
         match stream_data {
             StreamData::Output { buffer: UnknownTypeOutputBuffer::F32(mut buf) } => {
                 let mut buf_slice = buf.deref_mut();
-                let mut i = 0;
                 let mut timestamp = time::precise_time_ns();
-                while i < buf_slice.len() {
-                    // should let the graph generate stereo
-                    let buf = worker.work(timestamp)[0].get();
-                    for j in 0..N_SAMPLES_PER_CHUNK {
-                        buf_slice[i + j * 2] = buf[j];
-                        buf_slice[i + j * 2 + 1] = buf[j];
+                if let Some([ref mut left, ref mut right]) = resamplers {
+                    // The resamplers run in step, so the right one asks for a
+                    // chunk just after the left one has rendered it.
+                    for frame in buf_slice.chunks_mut(2) {
+                        frame[0] = left.next_sample(|chunk| {
+                            let bufs = worker.work(timestamp);
+                            chunk.copy_from_slice(bufs[0].get());
+                            // a mono root plays on both channels
+                            right_chunk.copy_from_slice(bufs[bufs.len().min(2) - 1].get());
+                            timestamp += chunk_ns;
+                        });
+                        let y = right.next_sample(|chunk| chunk.copy_from_slice(&right_chunk));
+                        if let Some(x) = frame.get_mut(1) {
+                            *x = y;
+                        }
                     }
-
-                    // TODO: calculate properly, magic value is 64 * 1e9 / 44_100
-                    timestamp += 1451247 * (N_SAMPLES_PER_CHUNK as u64) / 64;
-                    i += N_SAMPLES_PER_CHUNK * 2;
+                    return;
                 }
+                worker.work_var(buf_slice, 2, timestamp);
             }
             _ => panic!("Can't handle output buffer format"),
         }
@@ -0,0 +1,82 @@
+// Copyright 2018 The Synthesizer IO Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Registry of the modules that can be placed on the patching grid.
+
+use synthesizer_io_core::engine::ModuleType;
+
+use grid::ModuleSpec;
+
+/// Everything the UI and the synth state need to know about a kind of module.
+pub struct ModuleEntry {
+    pub name: &'static str,
+    /// Size in grid cells.
+    pub size: (u16, u16),
+    /// Whether the module has a row of pins down each side.
+    pub pins: bool,
+    /// How many of the left side pins, from the top, are audio inputs.
+    pub inputs: u16,
+    /// The engine module to make, if the engine can make it yet.
+    pub module_type: Option<ModuleType>,
+}
+
+/// All the modules, in the order they appear in the UI.
+pub const MODULES: &[ModuleEntry] = &[
+    ModuleEntry {
+        name: "sine", size: (2, 1), pins: true, inputs: 0, module_type: Some(ModuleType::Sin),
+    },
+    ModuleEntry { name: "control", size: (1, 1), pins: false, inputs: 0, module_type: None },
+    ModuleEntry {
+        name: "saw", size: (2, 1), pins: true, inputs: 0, module_type: Some(ModuleType::Saw),
+    },
+    ModuleEntry {
+        name: "biquad", size: (2, 2), pins: true, inputs: 1,
+        module_type: Some(ModuleType::Biquad),
+    },
+    ModuleEntry { name: "adsr", size: (2, 3), pins: true, inputs: 0, module_type: None },
+    ModuleEntry { name: "gain", size: (2, 2), pins: true, inputs: 0, module_type: None },
+];
+
+/// Look up a module by name.
+pub fn lookup(name: &str) -> Option<&'static ModuleEntry> {
+    MODULES.iter().find(|entry| entry.name == name)
+}
+
+impl ModuleEntry {
+    pub fn spec(&self) -> ModuleSpec {
+        ModuleSpec {
+            size: self.size,
+            name: self.name.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lookup, MODULES};
+
+    #[test]
+    fn every_module_is_registered() {
+        // The size of the patcher grid.
+        let grid_size = (20, 16);
+        for entry in MODULES {
+            assert_eq!(MODULES.iter().filter(|e| e.name == entry.name).count(), 1);
+            let (w, h) = entry.size;
+            assert!(w > 0 && h > 0 && w <= grid_size.0 && h <= grid_size.1, "{}", entry.name);
+            assert!(entry.inputs <= h && (entry.pins || entry.inputs == 0), "{}", entry.name);
+        }
+        assert!(lookup("sine").unwrap().module_type.is_some());
+        assert!(lookup("theremin").is_none());
+    }
+}
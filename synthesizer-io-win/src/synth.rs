@@ -25,6 +25,7 @@ use druid::{HandlerCtx, Id, Ui, Widget};
 use synthesizer_io_core::engine::{Engine, ModuleType, NoteEvent};
 
 use grid::{Delta, ModuleGrid, ModuleInstance, WireDelta, WireGrid};
+use registry;
 
 /// Synthesizer engine state.
 ///
@@ -44,6 +45,9 @@ pub struct SynthState {
     // Map from grid location of output pin to engine id.
     outputs: HashMap<(u16, u16), usize>,
 
+    // Map from module instance id to engine id.
+    ll_ids: HashMap<usize, usize>,
+
     grid: WireGrid,
 
     // This might not be needed, we keep track of outputs already.
@@ -76,6 +80,7 @@ impl SynthState {
             engine,
             coord_to_node: HashMap::new(),
             outputs: HashMap::new(),
+            ll_ids: HashMap::new(),
             grid: Default::default(),
             modules: Default::default(),
             uf: QuickUnionUf::new(0),
@@ -115,6 +120,12 @@ impl SynthState {
                 Delta::Module(inst) => {
                     self.add_module(inst);
                 }
+                Delta::RemoveModule(id) => {
+                    self.remove_module(*id);
+                }
+                Delta::MoveModule { id, new_loc } => {
+                    self.move_module(*id, *new_loc);
+                }
             }
         }
     }
@@ -122,14 +133,39 @@ impl SynthState {
     fn add_module(&mut self, inst: &ModuleInstance) {
         self.modules.add(inst.clone());
         let output_pin_coords = ModuleGrid::determine_output_pin(inst);
-        let mut engine = self.engine.lock().unwrap();
-        let module_type = match inst.spec.name.as_str() {
-            "sin" => ModuleType::Sin,
-            "saw" => ModuleType::Saw,
-            _ => ModuleType::Sin,  // just to do something
-        };
-        let ll_id = engine.instantiate_module(0, module_type);
+        let module_type = registry::lookup(&inst.spec.name)
+            .and_then(|entry| entry.module_type)
+            .unwrap_or(ModuleType::Sin);  // just to do something
+        let ll_id = self.engine.lock().unwrap().instantiate_module(0, module_type);
         self.outputs.insert(output_pin_coords, ll_id);
+        self.ll_ids.insert(inst.id, ll_id);
+        // The module may have been placed on a wire that's already there.
+        self.update_wiring();
+    }
+
+    fn remove_module(&mut self, id: usize) {
+        if let Some(inst) = self.modules.remove(id) {
+            self.outputs.remove(&ModuleGrid::determine_output_pin(&inst));
+            // Take the module off the output bus before its node goes away.
+            self.update_wiring();
+            if let Some(ll_id) = self.ll_ids.remove(&id) {
+                self.engine.lock().unwrap().remove_module(ll_id);
+            }
+        }
+    }
+
+    fn move_module(&mut self, id: usize, new_loc: (u16, u16)) {
+        let old_pin = match self.modules.get(id) {
+            Some(inst) => ModuleGrid::determine_output_pin(inst),
+            None => return,
+        };
+        if self.modules.move_module(id, new_loc) {
+            let new_pin = ModuleGrid::determine_output_pin(self.modules.get(id).unwrap());
+            if let Some(ll_id) = self.outputs.remove(&old_pin) {
+                self.outputs.insert(new_pin, ll_id);
+            }
+            self.update_wiring();
+        }
     }
 
     // Return uf node.
@@ -156,7 +192,40 @@ impl SynthState {
             }
         }
 
+        // Each module input is fed by an output on the same net, if there is
+        // one, taking the lowest engine id to be deterministic.
+        let mut net_outputs = HashMap::new();
+        for (&coords, &node) in &outputs_clone {
+            let uf = self.find_node(coords);
+            let uf = self.uf.find(uf);
+            net_outputs.entry(uf).or_insert_with(Vec::new).push(node);
+        }
+        let mut input_wiring = Vec::new();
+        let modules_clone = self.modules.iter().cloned().collect::<Vec<_>>();
+        for inst in &modules_clone {
+            let ll_id = match self.ll_ids.get(&inst.id) {
+                Some(&ll_id) => ll_id,
+                None => continue,
+            };
+            let n_inputs = registry::lookup(&inst.spec.name).map_or(0, |entry| entry.inputs);
+            let mut inputs = Vec::new();
+            for pin in (0..n_inputs).filter_map(|i| ModuleGrid::determine_input_pin(inst, i)) {
+                let uf = self.find_node(pin);
+                let uf = self.uf.find(uf);
+                // A module can't feed its own input.
+                let source = net_outputs.get(&uf)
+                    .and_then(|nodes| nodes.iter().filter(|&&node| node != ll_id).min());
+                if let Some(&node) = source {
+                    inputs.push(node);
+                }
+            }
+            input_wiring.push((ll_id, inputs));
+        }
+
         let mut engine = self.engine.lock().unwrap();
+        for (ll_id, inputs) in input_wiring {
+            engine.set_module_inputs(ll_id, &inputs);
+        }
         engine.set_outputs(&output_bus);
     }
 
@@ -181,3 +250,81 @@ impl SynthState {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use synthesizer_io_core::engine::Engine;
+    use synthesizer_io_core::graph::Node;
+    use synthesizer_io_core::modules;
+    use synthesizer_io_core::worker::Worker;
+
+    use grid::{Delta, JumperDelta, ModuleInstance, ModuleSpec, WireDelta};
+    use super::SynthState;
+
+    #[test]
+    fn add_move_remove_module() {
+        let (mut worker, tx, rx) = Worker::create(64);
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0, [], []));
+        let engine = Arc::new(Mutex::new(Engine::new(44_100.0, rx, tx)));
+        let mut state = SynthState::new(engine);
+        let mut peak = |ts| worker.work(ts)[0].get().iter().fold(0.0f32, |m, x| m.max(x.abs()));
+        // A wire from (17, 15) to the output at (19, 15).
+        state.apply_patch_delta(&[
+            Delta::Wire(WireDelta { grid_ix: (17, 15, false), val: true }),
+            Delta::Wire(WireDelta { grid_ix: (18, 15, false), val: true }),
+        ]);
+        let spec = ModuleSpec { size: (2, 1), name: "saw".into() };
+        let inst = ModuleInstance { id: 3, loc: (15, 15), spec };
+        state.apply_patch_delta(&[Delta::Module(inst)]);
+        assert_eq!(state.modules.new_id(), 4);
+        peak(0);
+        assert!(peak(1_000_000) > 0.1);
+
+        state.apply_patch_delta(&[Delta::MoveModule { id: 3, new_loc: (5, 5) }]);
+        assert_eq!(state.modules.get(3).unwrap().loc, (5, 5));
+        assert_eq!(state.outputs.keys().collect::<Vec<_>>(), vec![&(7, 5)]);
+        peak(2_000_000);
+        assert_eq!(peak(3_000_000), 0.0);
+
+        state.apply_patch_delta(&[Delta::RemoveModule(3)]);
+        assert!(state.modules.get(3).is_none());
+        assert!(state.outputs.is_empty());
+        peak(4_000_000);
+        assert_eq!(worker.node_count(), 1);
+    }
+
+    #[test]
+    fn oscillator_into_filter() {
+        let (mut worker, tx, rx) = Worker::create(64);
+        worker.handle_node(Node::create(Box::new(modules::Sum::new()), 0, [], []));
+        let engine = Arc::new(Mutex::new(Engine::new(44_100.0, rx, tx)));
+        let mut state = SynthState::new(engine.clone());
+        let mut peak = |ts| worker.work(ts)[0].get().iter().fold(0.0f32, |m, x| m.max(x.abs()));
+        let module = |id, loc, size, name: &str| {
+            let spec = ModuleSpec { size, name: name.into() };
+            Delta::Module(ModuleInstance { id, loc, spec })
+        };
+        // The saw's output at (12, 5) is wired to the filter's input at (13, 5),
+        // and a jumper takes the filter's output at (16, 5) to the speaker.
+        let wire = |val| Delta::Wire(WireDelta { grid_ix: (12, 5, false), val });
+        let jumper = JumperDelta { start: (16, 5), end: (19, 15), val: true };
+        state.apply_patch_delta(&[
+            module(0, (10, 5), (2, 1), "saw"),
+            module(1, (14, 5), (2, 2), "biquad"),
+            Delta::Jumper(jumper),
+            wire(true),
+        ]);
+        let (saw, filter) = (state.ll_ids[&0], state.ll_ids[&1]);
+        assert_eq!(engine.lock().unwrap().module_inputs(filter), Some(vec![saw]));
+        assert_eq!(state.outputs.values().filter(|&&node| node == filter).count(), 1);
+        peak(0);
+        assert!(peak(1_000_000) > 0.1);
+
+        // Cutting the wire leaves the filter with no input.
+        state.apply_patch_delta(&[wire(false)]);
+        peak(2_000_000);
+        assert_eq!(peak(3_000_000), 0.0);
+    }
+}
@@ -34,6 +34,7 @@ use druid::{MouseEvent, Widget};
 use druid::widget::MouseButton;
 
 use grid::{Delta, JumperDelta, ModuleGrid, ModuleInstance, ModuleSpec, WireDelta, WireGrid};
+use registry;
 
 pub struct Patcher {
     size: (f32, f32),
@@ -171,6 +172,17 @@ impl Widget for Patcher {
                     ctx.set_active(false);
                 }
             }
+            PatcherMode::Module if event.which == MouseButton::Right => {
+                // Right click removes the module under the cursor.
+                if event.count > 0 {
+                    let id = self.xy_to_cell(event.x, event.y)
+                        .and_then(|loc| self.modules.at(loc))
+                        .map(|inst| inst.id);
+                    if let Some(id) = id {
+                        self.apply_and_send_delta(vec![Delta::RemoveModule(id)], ctx);
+                    }
+                }
+            }
             PatcherMode::Module => {
                 if let Some(mut inst) = self.mod_hover.take() {
                     // TODO: reduce dupl
@@ -196,7 +208,9 @@ impl Widget for Patcher {
                     if let Some(start) = self.jumper_start.take() {
                         if let Some(end) = self.jumper_hover {
                             if start != end {
-                                let jumper_delta = JumperDelta { start, end, val: true };
+                                // Joining two points that already have a jumper removes it.
+                                let val = !self.grid.has_jumper(start, end);
+                                let jumper_delta = JumperDelta { start, end, val };
                                 let delta = vec![Delta::Jumper(jumper_delta)];
                                 self.apply_and_send_delta(delta, ctx);
                             }
@@ -239,7 +253,7 @@ impl Widget for Patcher {
                 let xc = x - 0.5 * self.scale * (spec.size.0 as f32 - 1.0);
                 let yc = y - 0.5 * self.scale * (spec.size.1 as f32 - 1.0);
                 let instance = self.xy_to_cell(xc, yc).map(|loc| {
-                    ModuleInstance { loc, spec }
+                    ModuleInstance { id: self.modules.new_id(), loc, spec }
                 });
                 self.update_hover(instance, ctx);
             }
@@ -389,7 +403,7 @@ impl Patcher {
                 x0 + (inst.spec.size.0 as f32 - inset) * self.scale,
                 y0 + (inst.spec.size.1 as f32 - inset) * self.scale),
             &resources.module_color);
-        if inst.spec.name == "control" {
+        if !registry::lookup(&inst.spec.name).map_or(true, |entry| entry.pins) {
             return;
         }
         for j in 0..inst.spec.size.1 {
@@ -554,24 +568,24 @@ impl Patcher {
                 Delta::Module(inst) => {
                     self.modules.add(inst.clone());
                 }
+                Delta::RemoveModule(id) => {
+                    self.modules.remove(*id);
+                }
+                Delta::MoveModule { id, new_loc } => {
+                    self.modules.move_module(*id, *new_loc);
+                }
             }
         }
     }
 }
 
-/// Make a module spec given a name.
-///
-/// This will probably grow into a registry, but for now can be basically
-/// hard-coded.
+/// Make a module spec given a name, from the registry.
 fn make_mod_spec(name: &str) -> ModuleSpec {
-    let size = match name {
-        "sine" | "saw" => (2, 1),
-        "adsr" => (2, 3),
-        "control" => (1, 1),
-        _ => (2, 2),
-    };
-    ModuleSpec {
-        size: size,
-        name: name.into(),
+    match registry::lookup(name) {
+        Some(entry) => entry.spec(),
+        None => ModuleSpec {
+            size: (2, 2),
+            name: name.into(),
+        },
     }
 }
@@ -19,7 +19,7 @@ use direct2d::RenderTarget;
 
 use druid::{BoxConstraints, Geometry, LayoutResult, Ui};
 use druid::{Id, HandlerCtx, LayoutCtx, PaintCtx};
-use druid::MouseEvent;
+use druid::{KeyEvent, KeyVariant, MouseEvent};
 use druid::widget::Widget;
 
 use synthesizer_io_core::engine::NoteEvent;
@@ -31,6 +31,8 @@ pub struct Piano {
     pressed: [bool; 128],
     // Note corresponding to mouse press.
     cur_note: Option<u8>,
+    // Whether the mouse button is down, so dragging onto a key plays it.
+    dragging: bool,
 
     // Note: we could probably eliminate this if we had access to size
     // in HandlerCtx. Alternatively, we could precompute width_scale.
@@ -56,6 +58,12 @@ const NOTE_POS: &[(u8, u8)] = &[
 
 const INSET: f32 = 2.0;
 
+// Computer keyboard keys, as virtual key codes, for two octaves of notes up
+// from the start note: the bottom two rows of letters, then the top two.
+const KEY_NOTES: &[u8] = b"ZSXDCVGBHNJMQ2W3ER5T6Y7U";
+
+const KEY_VELOCITY: u8 = 100;
+
 impl Widget for Piano {
     fn paint(&mut self, paint_ctx: &mut PaintCtx, geom: &Geometry) {
         let rt = paint_ctx.render_target();
@@ -89,31 +97,55 @@ impl Widget for Piano {
     fn mouse(&mut self, event: &MouseEvent, ctx: &mut HandlerCtx) -> bool {
         if event.count > 0 {
             ctx.set_active(true);
-            let u = event.x / self.size.0;
-            let v = event.y / self.size.1;
-            for note in self.start_note .. self.end_note {
-                let (u0, v0, u1, v1) = self.note_geom(note);
-                if u >= u0 && u < u1 && v >= v0 && v < v1 {
-                    self.cur_note = Some(note);
-                    break;
-                }
-            }
-            if let Some(note) = self.cur_note {
-                self.pressed[note as usize] = true;
-                ctx.send_event(NoteEvent { down: true, note: note, velocity: 100 });
-                ctx.invalidate();
-            }
+            self.dragging = true;
+            let events = self.drag_to(event.x / self.size.0, event.y / self.size.1);
+            Piano::send_notes(events, ctx);
         } else {
             ctx.set_active(false);
-            if let Some(note) = self.cur_note {
+            self.dragging = false;
+            if let Some(note) = self.cur_note.take() {
                 self.pressed[note as usize] = false;
-                ctx.send_event(NoteEvent { down: false, note: note, velocity: 0 });
-                ctx.invalidate();
+                Piano::send_notes(vec![NoteEvent { down: false, note, velocity: 0 }], ctx);
             }
-            self.cur_note = None;
         }
         true
     }
+
+    fn mouse_moved(&mut self, x: f32, y: f32, ctx: &mut HandlerCtx) {
+        if self.dragging {
+            let events = self.drag_to(x / self.size.0, y / self.size.1);
+            Piano::send_notes(events, ctx);
+        }
+    }
+
+    fn key_down(&mut self, event: &KeyEvent, ctx: &mut HandlerCtx) -> bool {
+        match self.key_note(event) {
+            Some(note) => {
+                // Ignore auto-repeat.
+                if !self.pressed[note as usize] {
+                    self.pressed[note as usize] = true;
+                    ctx.send_event(NoteEvent { down: true, note, velocity: KEY_VELOCITY });
+                    ctx.invalidate();
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn key_up(&mut self, event: &KeyEvent, ctx: &mut HandlerCtx) -> bool {
+        match self.key_note(event) {
+            Some(note) => {
+                if self.pressed[note as usize] {
+                    self.pressed[note as usize] = false;
+                    ctx.send_event(NoteEvent { down: false, note, velocity: 0 });
+                    ctx.invalidate();
+                }
+                true
+            }
+            None => false,
+        }
+    }
 }
 
 impl Piano {
@@ -123,6 +155,7 @@ impl Piano {
             end_note: 72,
             pressed: [false; 128],
             cur_note: None,
+            dragging: false,
             size: (0.0, 0.0),
         }
     }
@@ -147,4 +180,111 @@ impl Piano {
         let v = y as f32 * 0.5;
         (u, 0.5 - v, 2.0 * width_scale + u, 1.0 - v)
     }
+
+    // Move the pressed mouse to a point in the unit square, returning the
+    // events for releasing the key it leaves and pressing the one it enters.
+    fn drag_to(&mut self, u: f32, v: f32) -> Vec<NoteEvent> {
+        let hit = self.note_at(u, v);
+        let mut events = Vec::new();
+        if hit.map(|(note, _)| note) != self.cur_note {
+            if let Some(note) = self.cur_note.take() {
+                self.pressed[note as usize] = false;
+                events.push(NoteEvent { down: false, note, velocity: 0 });
+            }
+            if let Some((note, velocity)) = hit {
+                self.cur_note = Some(note);
+                self.pressed[note as usize] = true;
+                events.push(NoteEvent { down: true, note, velocity });
+            }
+        }
+        events
+    }
+
+    fn send_notes(events: Vec<NoteEvent>, ctx: &mut HandlerCtx) {
+        if !events.is_empty() {
+            for event in events {
+                ctx.send_event(event);
+            }
+            ctx.invalidate();
+        }
+    }
+
+    fn key_note(&self, event: &KeyEvent) -> Option<u8> {
+        match event.key {
+            KeyVariant::Vkey(vk) => self.vkey_note(vk),
+            _ => None,
+        }
+    }
+
+    // The note played by a computer keyboard key, if it's on the piano.
+    fn vkey_note(&self, vk: i32) -> Option<u8> {
+        let offset = KEY_NOTES.iter().position(|&key| key as i32 == vk)?;
+        let note = self.start_note as usize + offset;
+        if note < self.end_note as usize { Some(note as u8) } else { None }
+    }
+
+    // The note at a point in the unit square, and the velocity of a press
+    // there, from 1 at the top of the key to 127 at the bottom.
+    fn note_at(&self, u: f32, v: f32) -> Option<(u8, u8)> {
+        (self.start_note..self.end_note).find_map(|note| {
+            let (u0, v0, u1, v1) = self.note_geom(note);
+            if u >= u0 && u < u1 && v >= v0 && v < v1 {
+                let velocity = 1.0 + 126.0 * (v - v0) / (v1 - v0);
+                Some((note, velocity.round() as u8))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Piano;
+
+    #[test]
+    fn velocity_from_key_position() {
+        let piano = Piano::new();
+        // middle C and the C# above it
+        for &note in &[60, 61] {
+            let (u0, v0, u1, v1) = piano.note_geom(note);
+            let u = 0.5 * (u0 + u1);
+            let (bottom, top) = (piano.note_at(u, v1 - 1e-3), piano.note_at(u, v0 + 1e-3));
+            assert_eq!(bottom.map(|(n, _)| n), Some(note));
+            assert!(bottom.unwrap().1 >= 120 && bottom.unwrap().1 <= 127, "{:?}", bottom);
+            assert!(top.unwrap().1 >= 1 && top.unwrap().1 <= 8, "{:?}", top);
+        }
+        assert_eq!(piano.note_at(0.5, 1.5), None);
+    }
+
+    #[test]
+    fn keys_map_to_notes() {
+        let mut piano = Piano::new();
+        let notes: Vec<_> = b"ZSXMQ2UA".iter().map(|&key| piano.vkey_note(key as i32)).collect();
+        let expected = [Some(48), Some(49), Some(50), Some(59), Some(60), Some(61), Some(71), None];
+        assert_eq!(notes, expected);
+        piano.end_note = 60;
+        assert_eq!(piano.vkey_note('Q' as i32), None);
+    }
+
+    #[test]
+    fn glissando() {
+        let mut piano = Piano::new();
+        let center = |piano: &Piano, note| {
+            let (u0, v0, u1, v1) = piano.note_geom(note);
+            (0.5 * (u0 + u1), 0.5 * (v0 + v1))
+        };
+        let mut events = Vec::new();
+        // C, D and E are next to each other along the white keys.
+        for &note in &[60, 62, 62, 64] {
+            let (u, v) = center(&piano, note);
+            events.extend(piano.drag_to(u, v).into_iter().map(|e| (e.down, e.note)));
+        }
+        assert_eq!(events, vec![(true, 60), (false, 60), (true, 62), (false, 62), (true, 64)]);
+        assert_eq!(piano.cur_note, Some(64));
+        assert!(piano.pressed[64] && !piano.pressed[60] && !piano.pressed[62]);
+        // Dragging off the keys releases the last one.
+        let released: Vec<_> = piano.drag_to(0.5, 1.5).into_iter().map(|e| e.note).collect();
+        assert_eq!(released, vec![64]);
+    }
 }
@@ -15,6 +15,7 @@
 //! Widget for oscilloscope display.
 
 use std::any::Any;
+use std::collections::VecDeque;
 
 use direct2d::enums::BitmapInterpolationMode;
 use direct2d::image::Bitmap;
@@ -33,8 +34,30 @@ pub struct Scope {
     // I might want to call the data structure ScopeBuf or some such,
     // too many name collisions :/
     s: s::Scope,
+
+    // Samples polled from the monitor, not yet provided to the scope. This
+    // decouples the audio rate from the UI frame rate.
+    ring: VecDeque<f32>,
+
+    // Number of samples (fractional) owed to the scope based on elapsed time.
+    budget: f64,
+
+    // Scratch space for handing contiguous samples to the scope.
+    scratch: Vec<f32>,
+
+    // The rendered image, reused from frame to frame.
+    rgba: Vec<u8>,
 }
 
+// TODO: get this from the engine rather than hardcoding.
+const SAMPLE_RATE: f64 = 48_000.0;
+
+// Capacity of the ring buffer, in samples.
+const RING_SIZE: usize = 16_384;
+
+// If more than this many samples are waiting, skip ahead to bound latency.
+const MAX_BACKLOG: usize = 4_096;
+
 #[derive(Clone, Debug)]
 pub enum ScopeCommand {
     Start,
@@ -46,13 +69,13 @@ impl Widget for Scope {
         let rt = paint_ctx.render_target();
         let w = 640;
         let h = 480;
-        let data = self.s.as_rgba();
+        self.s.render_into(&mut self.rgba);
         let b = Bitmap::create(rt)
             .with_raw_data(
                 SizeU(D2D_SIZE_U {
                     width: w as UINT32, height: h as UINT32
                 }),
-                &data,
+                &self.rgba,
                 w as UINT32 * 4)
             .with_format(Format::R8G8B8A8Unorm)
             .build().expect("error creating bitmap");
@@ -81,7 +104,7 @@ impl Widget for Scope {
         if let Some(cmd) = payload.downcast_ref::<ScopeCommand>() {
             match cmd {
                 ScopeCommand::Start => ctx.request_anim_frame(),
-                ScopeCommand::Samples(samples) => self.s.provide_samples(&samples),
+                ScopeCommand::Samples(samples) => self.push_samples(&samples),
             }
             true
         } else {
@@ -90,8 +113,9 @@ impl Widget for Scope {
         }
     }
 
-    fn anim_frame(&mut self, _interval: u64, ctx: &mut HandlerCtx) {
+    fn anim_frame(&mut self, interval: u64, ctx: &mut HandlerCtx) {
         ctx.send_event(());
+        self.consume_samples(interval);
         ctx.request_anim_frame();
     }
 }
@@ -99,7 +123,11 @@ impl Widget for Scope {
 impl Scope {
     pub fn new() -> Scope {
         let s = s::Scope::new(640, 480);
-        Scope { s }
+        let ring = VecDeque::with_capacity(RING_SIZE);
+        let budget = 0.0;
+        let scratch = Vec::with_capacity(RING_SIZE);
+        let rgba = vec![0; 640 * 480 * 4];
+        Scope { s, ring, budget, scratch, rgba }
     }
 
     pub fn ui(self, ui: &mut Ui) -> Id {
@@ -108,6 +136,35 @@ impl Scope {
         id
     }
 
+    fn push_samples(&mut self, samples: &[f32]) {
+        let overflow = (self.ring.len() + samples.len()).saturating_sub(RING_SIZE);
+        let overflow = overflow.min(self.ring.len());
+        self.ring.drain(..overflow);
+        let skip = samples.len().saturating_sub(RING_SIZE);
+        self.ring.extend(&samples[skip..]);
+    }
+
+    // Provide the scope with the number of samples corresponding to the time
+    // elapsed since the last frame, so the trace advances at a steady rate even
+    // when frames are late. The interval is in nanoseconds.
+    fn consume_samples(&mut self, interval: u64) {
+        if self.ring.len() > MAX_BACKLOG {
+            let excess = self.ring.len() - MAX_BACKLOG;
+            self.ring.drain(..excess);
+        }
+        self.budget += interval as f64 * 1e-9 * SAMPLE_RATE;
+        let n = (self.budget as usize).min(self.ring.len());
+        if n == self.ring.len() {
+            // Underrun; don't accumulate a debt that would cause a burst later.
+            self.budget = 0.0;
+        } else {
+            self.budget -= n as f64;
+        }
+        self.scratch.clear();
+        self.scratch.extend(self.ring.drain(..n));
+        self.s.provide_samples(&self.scratch);
+    }
+
     fn draw_test_pattern(&mut self) {
         let mut xylast = None;
         // sinewave!